@@ -48,7 +48,7 @@ echo "     -p profiles/lifepo4_3s.json"
 echo ""
 echo "Available Profiles:"
 echo "  - profiles/lifepo4.json       (LiFePO4 1S, 3.2Ah, CH1)"
-echo "  - profiles/lifepo4_3s.json    (LiFePO4 3S, 10Ah, CH3)"
+echo "  - profiles/lifepo4_3s.json    (LiFePO4 3S, 10Ah, CH1)"
 echo "  - profiles/liion_18650.json   (Li-ion 18650, 2.5Ah, CH2)"
 echo "  - profiles/lipo_1s.json       (LiPo 1S, 1.2Ah, CH3)"
 echo "  - profiles/nimh_aa.json       (NiMH AA, 2.0Ah, CH1)"
@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (C) 2025 Marcus Folkesson
+
+//! Minimal ZIP archive writer
+//!
+//! Entries are stored uncompressed, which every unzip tool understands and
+//! keeps the tool free of a compression dependency. Log and CSV files are
+//! small enough that this does not matter in practice.
+
+use chrono::{Datelike, Timelike};
+use std::fs::File;
+use std::io::{self, Write};
+
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+pub struct ZipWriter {
+    file: File,
+    entries: Vec<Entry>,
+    offset: u32,
+    dos_time: u16,
+    dos_date: u16,
+}
+
+impl ZipWriter {
+    pub fn create(path: &str) -> io::Result<Self> {
+        let now = chrono::Local::now();
+        Ok(Self {
+            file: File::create(path)?,
+            entries: Vec::new(),
+            offset: 0,
+            dos_time: ((now.hour() << 11) | (now.minute() << 5) | (now.second() / 2)) as u16,
+            dos_date: (((now.year().max(1980) - 1980) << 9) as u32 | (now.month() << 5) | now.day()) as u16,
+        })
+    }
+
+    /// Add a file named `name` (use `/` for directories) with the given contents
+    pub fn add(&mut self, name: &str, data: &[u8]) -> io::Result<()> {
+        let size = u32::try_from(data.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is too large", name)))?;
+        let crc = crc32(data);
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+        self.common_fields(&mut header, name, crc, size);
+        header.extend_from_slice(name.as_bytes());
+        self.file.write_all(&header)?;
+        self.file.write_all(data)?;
+
+        self.entries.push(Entry {
+            name: name.to_string(),
+            crc,
+            size,
+            offset: self.offset,
+        });
+        self.offset += header.len() as u32 + size;
+        Ok(())
+    }
+
+    /// Write the central directory; the archive is incomplete until this is called
+    pub fn finish(mut self) -> io::Result<()> {
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            directory.extend_from_slice(&20u16.to_le_bytes()); // version made by
+            self.common_fields(&mut directory, &entry.name, entry.crc, entry.size);
+            directory.extend_from_slice(&0u16.to_le_bytes()); // comment length
+            directory.extend_from_slice(&0u16.to_le_bytes()); // disk number
+            directory.extend_from_slice(&0u16.to_le_bytes()); // internal attributes
+            directory.extend_from_slice(&0u32.to_le_bytes()); // external attributes
+            directory.extend_from_slice(&entry.offset.to_le_bytes());
+            directory.extend_from_slice(entry.name.as_bytes());
+        }
+
+        let count = self.entries.len() as u16;
+        let mut end = Vec::new();
+        end.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes()); // this disk
+        end.extend_from_slice(&0u16.to_le_bytes()); // disk with directory
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&count.to_le_bytes());
+        end.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        end.extend_from_slice(&self.offset.to_le_bytes());
+        end.extend_from_slice(&0u16.to_le_bytes()); // comment length
+
+        self.file.write_all(&directory)?;
+        self.file.write_all(&end)?;
+        self.file.flush()
+    }
+
+    /// Fields shared by the local header and the central directory entry
+    fn common_fields(&self, buf: &mut Vec<u8>, name: &str, crc: u32, size: u32) {
+        buf.extend_from_slice(&20u16.to_le_bytes()); // version needed
+        buf.extend_from_slice(&0x0800u16.to_le_bytes()); // UTF-8 names
+        buf.extend_from_slice(&0u16.to_le_bytes()); // stored
+        buf.extend_from_slice(&self.dos_time.to_le_bytes());
+        buf.extend_from_slice(&self.dos_date.to_le_bytes());
+        buf.extend_from_slice(&crc.to_le_bytes());
+        buf.extend_from_slice(&size.to_le_bytes()); // compressed size
+        buf.extend_from_slice(&size.to_le_bytes()); // uncompressed size
+        buf.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        buf.extend_from_slice(&0u16.to_le_bytes()); // extra field length
+    }
+}
+
+/// CRC-32 (IEEE) as required by the ZIP format
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
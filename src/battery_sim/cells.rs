@@ -0,0 +1,79 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (C) 2025 Marcus Folkesson
+
+//! Per-cell coulomb-count records
+//!
+//! A run tagged with a cell serial resumes from the SoC the previous session
+//! with that serial ended at, and keeps a running total of the charge that
+//! has passed through the cell.
+
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Resume data older than this may not match the cell (self-discharge)
+pub const STALE_AFTER_DAYS: i64 = 30;
+
+/// Last known state of one physical cell
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CellRecord {
+    pub serial: String,
+    /// Profile the cell was last simulated with
+    pub profile: String,
+    pub soc: f64,
+    /// Cumulative charge in either direction over all sessions
+    pub throughput_ah: f64,
+    /// RFC 3339 time of the last save
+    pub updated: String,
+}
+
+impl CellRecord {
+    pub fn new(serial: &str, profile: &str) -> Self {
+        Self {
+            serial: serial.to_string(),
+            profile: profile.to_string(),
+            soc: 1.0,
+            throughput_ah: 0.0,
+            updated: Local::now().to_rfc3339(),
+        }
+    }
+
+    /// `<data dir>/dp832-battery/cells/<serial>.json`
+    pub fn path_for(serial: &str) -> Option<PathBuf> {
+        let name: String = serial
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        let base = dirs_next::data_dir()?;
+        Some(base.join("dp832-battery").join("cells").join(format!("{}.json", name)))
+    }
+
+    /// Load the record for `serial`, or `None` if the cell has not been seen before
+    pub fn load(serial: &str) -> Result<Option<Self>, String> {
+        let path = Self::path_for(serial).ok_or("No data directory to keep cell records in")?;
+        if !path.exists() {
+            return Ok(None);
+        }
+        let json = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        let record = serde_json::from_str(&json)
+            .map_err(|e| format!("Failed to parse {}: {}", path.display(), e))?;
+        Ok(Some(record))
+    }
+
+    pub fn save(&mut self) -> Result<(), String> {
+        let path = Self::path_for(&self.serial).ok_or("No data directory to keep cell records in")?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+        }
+        self.updated = Local::now().to_rfc3339();
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(&path, json + "\n").map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+    }
+
+    /// Age of the record in whole days, if the timestamp is readable
+    pub fn age_days(&self) -> Option<i64> {
+        let updated = DateTime::parse_from_rfc3339(&self.updated).ok()?;
+        Some((Local::now().fixed_offset() - updated).num_days())
+    }
+}
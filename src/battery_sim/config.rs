@@ -1,24 +1,282 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 // Copyright (C) 2025 Marcus Folkesson
 
-/// Battery simulator configuration
+//! Battery simulator configuration
 
+use chrono::NaiveTime;
 use serde::Deserialize;
-use crate::common::DeviceConfig;
+use std::time::Duration;
+use crate::common::{round_setpoint, DeviceConfig};
+use crate::scpi::{set_current_command, set_voltage_command};
+use crate::units;
+use super::hooks::OnCompleteConfig;
+use super::noise::NoiseConfig;
 
 #[derive(Debug, Deserialize, Default)]
 pub struct Config {
     pub device: Option<DeviceConfig>,
     pub battery: Option<BatteryConfig>,
     pub logging: Option<LoggingConfig>,
+    pub simulation: Option<SimulationConfig>,
+    pub on_complete: Option<OnCompleteConfig>,
+    pub noise: Option<NoiseConfig>,
+    pub schedule: Option<ScheduleConfig>,
+    pub ui: Option<UiConfig>,
+}
+
+/// `[ui]`: how the TUI labels this instance
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UiConfig {
+    /// TUI header text (default: "DP832 Battery Simulator")
+    pub title: Option<String>,
+    /// Flag the OCV while the SoC is outside what the curve covers (default: true)
+    pub ocv_range_indicator: Option<bool>,
+}
+
+/// `[schedule]`: time windows with a reduced CSV rate
+///
+/// Only logging slows down; the control loop keeps its full rate.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ScheduleConfig {
+    #[serde(default)]
+    pub quiet: Vec<QuietWindow>,
+}
+
+/// One quiet window in local time, e.g. 22:00 to 06:00
+#[derive(Debug, Clone, Deserialize)]
+pub struct QuietWindow {
+    /// Start, "HH:MM"
+    pub from: String,
+    /// End, "HH:MM"; before `from` for a window that spans midnight
+    pub to: String,
+    /// At most one CSV sample per this many seconds inside the window
+    pub log_interval_s: u64,
+}
+
+impl QuietWindow {
+    fn bounds(&self) -> Result<(NaiveTime, NaiveTime), String> {
+        let parse = |t: &str| {
+            NaiveTime::parse_from_str(t, "%H:%M").map_err(|_| format!("[schedule] quiet time \"{}\" is not HH:MM", t))
+        };
+        Ok((parse(&self.from)?, parse(&self.to)?))
+    }
+
+    fn contains(&self, now: NaiveTime) -> bool {
+        match self.bounds() {
+            Ok((from, to)) if from <= to => now >= from && now < to,
+            Ok((from, to)) => now >= from || now < to,
+            Err(_) => false,
+        }
+    }
+}
+
+impl ScheduleConfig {
+    /// Check every window once at startup
+    pub fn validate(&self) -> Result<(), String> {
+        for window in &self.quiet {
+            window.bounds()?;
+            if window.log_interval_s == 0 {
+                return Err(format!("[schedule] quiet window {}-{}: log_interval_s must be at least 1", window.from, window.to));
+            }
+        }
+        Ok(())
+    }
+
+    /// CSV interval in effect at `now`; `None` outside all quiet windows
+    ///
+    /// Where windows overlap the longest interval wins.
+    pub fn log_interval_at(&self, now: NaiveTime) -> Option<Duration> {
+        self.quiet
+            .iter()
+            .filter(|w| w.contains(now))
+            .map(|w| Duration::from_secs(w.log_interval_s))
+            .max()
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct BatteryConfig {
     pub profile: String,
+    /// Serial of the physical cell, see `--serial`
+    pub serial: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct LoggingConfig {
     pub csv: Option<String>,
+    /// Write only every Nth sample to the CSV
+    pub csv_decimate: Option<u64>,
+    pub csv_layout: Option<CsvLayout>,
+    /// When CSV rows are flushed to the file (default: every row)
+    pub flush: Option<FlushMode>,
+    /// `periodic`: flush after this many rows (default: 100)
+    pub flush_every_rows: Option<u64>,
+    /// `periodic`: flush at least this often in milliseconds (default: 5000)
+    pub flush_interval_ms: Option<u64>,
+    /// fsync the CSV files at most this often in seconds (default: never)
+    pub fsync_interval_s: Option<u64>,
+    /// Appended to the run id, and so to the log file names (default: the UI title)
+    pub run_name: Option<String>,
+}
+
+/// When buffered CSV rows are written to the file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlushMode {
+    /// After every row
+    #[default]
+    Record,
+    /// After `flush_every_rows` rows or `flush_interval_ms`, whichever comes first
+    Periodic,
+    /// Only when the channel stops
+    Exit,
+}
+
+/// How CSV samples of several channels are laid out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum CsvLayout {
+    /// One file per channel, `<name>_ch<N>.csv`
+    #[default]
+    PerChannel,
+    /// One file with a row per time bucket and columns for every channel
+    Wide,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SimulationConfig {
+    pub read_failure: Option<ReadFailureStrategy>,
+    /// Target a fixed loop period instead of sleeping a fixed time after each step
+    pub fixed_cadence: Option<bool>,
+    /// How to treat SoC outside the range covered by the OCV curve
+    pub ocv_range: Option<OcvRangeMode>,
+    /// Channels that are only measured, never driven
+    pub monitor_channels: Option<Vec<u8>>,
+    /// Channels whose current and power are shown and logged with inverted sign
+    pub invert_current: Option<Vec<u8>>,
+    /// Reports from `dp832 calibrate-check --save`; each corrects its channel's current readings
+    pub current_calibration: Option<Vec<String>>,
+    /// Cell temperature used to pick between temperature-tagged OCV curves
+    pub temperature_c: Option<f64>,
+    /// `q` closes the TUI but keeps simulating in the background
+    pub detach_on_quit: Option<bool>,
+    /// Stop all channels after this many seconds, whatever the SoC
+    pub max_runtime_s: Option<u64>,
+    /// Failed current reads in a row before the error action or stop (default: 5)
+    pub max_consecutive_errors: Option<u32>,
+    /// How long reads must keep failing before a channel is stopped (default: 10)
+    pub error_grace_s: Option<u64>,
+    pub on_errors: Option<ErrorAction>,
+    pub drive: Option<DriveMode>,
+    /// Delay between the init sequences of successive channels (default: 0)
+    pub startup_stagger_ms: Option<u64>,
+    /// Continue below cutoff on profiles with `absolute_min_voltage` (default: false)
+    pub tail_capture: Option<bool>,
+    /// Seconds below `no_load_current_a` before a loaded channel counts as disconnected (default: off)
+    pub no_load_timeout_s: Option<u64>,
+    /// Current below which a channel counts as unloaded (default: 0.005)
+    #[serde(default, deserialize_with = "units::amps_opt")]
+    pub no_load_current_a: Option<f64>,
+    pub on_no_load: Option<NoLoadAction>,
+    /// Pause all channels on the first read error instead of retrying (default: false)
+    pub freeze_on_error: Option<bool>,
+    pub freeze_outputs: Option<FreezeOutputs>,
+    /// Seconds between the runs of a `--queue` (default: 0)
+    pub queue_delay_s: Option<u64>,
+    /// Wait for Enter in the TUI before each queued run, to swap the cell (default: false)
+    pub queue_prompt: Option<bool>,
+}
+
+/// What the simulator programs on the channel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DriveMode {
+    /// Model terminal voltage as `VOLT`, current limited: a voltage source
+    #[default]
+    Voltage,
+    /// Model current as `CURR` with `VOLT` at `max_voltage`: the device under
+    /// test sets the voltage and the channel sinks what the cell would deliver
+    Current,
+}
+
+impl DriveMode {
+    /// Settings made on the channel before its output goes on
+    ///
+    /// CC drive starts at 0 A below a fixed `max_voltage` compliance and
+    /// raises the current from there.
+    pub fn setup_commands(self, channel: u8, max_voltage: f64, current_limit: f64) -> Vec<String> {
+        match self {
+            DriveMode::Voltage => vec![set_current_command(channel, current_limit)],
+            DriveMode::Current => vec![
+                set_voltage_command(channel, round_setpoint(max_voltage)),
+                set_current_command(channel, 0.0),
+            ],
+        }
+    }
+
+    /// The setting that applies one model step: the terminal voltage, or the current in CC drive
+    pub fn step_command(self, channel: u8, setpoint: f64) -> String {
+        match self {
+            DriveMode::Voltage => set_voltage_command(channel, setpoint),
+            DriveMode::Current => set_current_command(channel, setpoint),
+        }
+    }
+}
+
+/// What a channel does when its current reads keep failing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorAction {
+    /// Reopen the connection every `max_consecutive_errors` failures until the
+    /// grace period runs out
+    #[default]
+    Reconnect,
+    /// Only wait out the grace period
+    Stop,
+}
+
+/// What the outputs do while `freeze_on_error` has the channels paused
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum FreezeOutputs {
+    /// Keep the last setpoints applied, so the device under test sees no change
+    #[default]
+    Hold,
+    /// Switch the outputs off until the run resumes
+    Off,
+}
+
+/// What a channel does once its load has been gone for `no_load_timeout_s`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum NoLoadAction {
+    /// Log it and mark the CSV, then keep simulating
+    #[default]
+    Warn,
+    /// Turn the output off and stop the channel
+    Stop,
+}
+
+/// What the control loop does with a step whose current read failed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadFailureStrategy {
+    /// Skip the step; SoC integration pauses until a read succeeds
+    #[default]
+    Skip,
+    /// Integrate the step with the last successfully read current
+    Hold,
+    /// Integrate the step as if no current flowed
+    Zero,
+}
+
+/// Voltage model for SoC values the OCV curve does not cover
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OcvRangeMode {
+    /// Hold the voltage of the nearest curve endpoint
+    #[default]
+    Hold,
+    /// Extend the curve to SoC 0 and 1 along its outermost segments
+    Extrapolate,
 }
@@ -0,0 +1,223 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (C) 2025 Marcus Folkesson
+
+//! CSV output, one file per channel or one wide file for all channels
+
+use super::config::{FlushMode, LoggingConfig};
+use crate::common::EffectiveConfig;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// One sample: SoC (empty for monitor channels), voltage, current, power
+pub type CsvValues = [String; 4];
+
+/// When CSV rows reach the file and the disk
+#[derive(Debug, Clone, Copy)]
+pub struct FlushPolicy {
+    pub mode: FlushMode,
+    /// `periodic`: flush after this many rows...
+    pub every_rows: u64,
+    /// ...or this long after the last flush, whichever comes first
+    pub interval: Duration,
+    /// fsync at most this often, so a power loss keeps all but the last interval
+    pub fsync_interval: Option<Duration>,
+}
+
+impl FlushPolicy {
+    /// Resolve the `[logging]` flush settings; the default flushes every row without fsync
+    pub fn resolve(effective: &mut EffectiveConfig, cfg: Option<&LoggingConfig>) -> Self {
+        Self {
+            mode: effective.resolve("logging.flush", None, cfg.and_then(|l| l.flush), FlushMode::default()),
+            every_rows: effective.resolve("logging.flush_every_rows", None, cfg.and_then(|l| l.flush_every_rows), 100).max(1),
+            interval: Duration::from_millis(
+                effective.resolve("logging.flush_interval_ms", None, cfg.and_then(|l| l.flush_interval_ms), 5000),
+            ),
+            fsync_interval: effective
+                .resolve_opt("logging.fsync_interval_s", None, cfg.and_then(|l| l.fsync_interval_s))
+                .map(Duration::from_secs),
+        }
+    }
+}
+
+/// A CSV file that flushes and syncs according to a `FlushPolicy`
+pub struct CsvFile {
+    writer: csv::Writer<File>,
+    policy: FlushPolicy,
+    unflushed: u64,
+    last_flush: Instant,
+    last_sync: Instant,
+}
+
+impl CsvFile {
+    pub fn create(path: &str, policy: FlushPolicy) -> Result<Self, String> {
+        let writer = csv::Writer::from_path(path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+        Ok(Self { writer, policy, unflushed: 0, last_flush: Instant::now(), last_sync: Instant::now() })
+    }
+
+    pub fn write_record<I: IntoIterator<Item = T>, T: AsRef<[u8]>>(&mut self, record: I) -> Result<(), String> {
+        self.writer.write_record(record).map_err(|e| e.to_string())?;
+        self.unflushed += 1;
+
+        let flush = match self.policy.mode {
+            FlushMode::Record => true,
+            FlushMode::Periodic => {
+                self.unflushed >= self.policy.every_rows || self.last_flush.elapsed() >= self.policy.interval
+            }
+            FlushMode::Exit => false,
+        };
+        let sync = self.policy.fsync_interval.is_some_and(|i| self.last_sync.elapsed() >= i);
+        if flush || sync {
+            self.flush(sync)?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self, sync: bool) -> Result<(), String> {
+        self.writer.flush().map_err(|e| e.to_string())?;
+        self.unflushed = 0;
+        self.last_flush = Instant::now();
+        if sync {
+            self.writer.get_ref().sync_data().map_err(|e| e.to_string())?;
+            self.last_sync = self.last_flush;
+        }
+        Ok(())
+    }
+
+    /// Write out everything, syncing too if fsync is enabled
+    pub fn finish(&mut self) -> Result<(), String> {
+        self.flush(self.policy.fsync_interval.is_some())
+    }
+}
+
+/// Where a channel thread writes its CSV samples
+pub enum CsvSink {
+    /// `time,soc,voltage,current,power,marker` in the channel's own file
+    PerChannel(Box<CsvFile>),
+    /// A column group in the shared wide file
+    Wide(Arc<Mutex<WideCsv>>, u8),
+}
+
+impl CsvSink {
+    /// The wide file buckets samples itself, so it wants every one of them
+    pub fn wants_every_sample(&self) -> bool {
+        matches!(self, CsvSink::Wide(..))
+    }
+
+    pub fn write(&mut self, start: Instant, now: Instant, values: CsvValues, marker: String) -> Result<(), String> {
+        match self {
+            CsvSink::PerChannel(w) => {
+                let [soc, voltage, current, power] = values;
+                let time = format!("{:.3}", now.duration_since(start).as_secs_f64());
+                w.write_record([time, soc, voltage, current, power, marker])
+            }
+            CsvSink::Wide(wide, channel) => lock(wide)?.record(*channel, now, values, marker),
+        }
+    }
+
+    /// The channel has stopped: flush its file, or stop the wide file waiting for it
+    pub fn finish(&mut self) -> Result<(), String> {
+        match self {
+            CsvSink::PerChannel(w) => w.finish(),
+            CsvSink::Wide(wide, channel) => lock(wide)?.finish(*channel),
+        }
+    }
+}
+
+fn lock(wide: &Mutex<WideCsv>) -> Result<std::sync::MutexGuard<'_, WideCsv>, String> {
+    wide.lock().map_err(|_| "the wide CSV file is unusable after a failed write".to_string())
+}
+
+#[derive(Default)]
+struct Bucket {
+    values: BTreeMap<u8, CsvValues>,
+    markers: Vec<String>,
+}
+
+/// Assembles one row per time bucket from the independent channel threads
+///
+/// A row is written once every active channel has moved on to a later
+/// bucket, so no more samples can arrive for it. Within a bucket the last
+/// sample of each channel wins; a channel without a sample in a bucket
+/// leaves its columns empty.
+pub struct WideCsv {
+    writer: CsvFile,
+    start: Instant,
+    bucket: Duration,
+    /// Channels in column order
+    channels: Vec<u8>,
+    active: Vec<u8>,
+    /// Latest bucket each channel has reported
+    latest: BTreeMap<u8, u64>,
+    pending: BTreeMap<u64, Bucket>,
+}
+
+impl WideCsv {
+    pub fn create(path: &str, channels: Vec<u8>, bucket: Duration, policy: FlushPolicy) -> Result<Self, String> {
+        let mut writer = CsvFile::create(path, policy)?;
+
+        // Unlike the per-channel files the columns are not fixed, so name them
+        let mut header = vec!["time".to_string()];
+        for ch in &channels {
+            for column in ["soc", "voltage", "current", "power"] {
+                header.push(format!("ch{}_{}", ch, column));
+            }
+        }
+        header.push("marker".to_string());
+        writer.write_record(&header)?;
+
+        Ok(Self {
+            writer,
+            start: Instant::now(),
+            bucket: bucket.max(Duration::from_millis(1)),
+            active: channels.clone(),
+            channels,
+            latest: BTreeMap::new(),
+            pending: BTreeMap::new(),
+        })
+    }
+
+    fn record(&mut self, channel: u8, now: Instant, values: CsvValues, marker: String) -> Result<(), String> {
+        let index = (now.duration_since(self.start).as_nanos() / self.bucket.as_nanos()) as u64;
+        let bucket = self.pending.entry(index).or_default();
+        bucket.values.insert(channel, values);
+        // Every channel reports the same marker
+        if !marker.is_empty() && !bucket.markers.contains(&marker) {
+            bucket.markers.push(marker);
+        }
+        self.latest.insert(channel, index);
+        self.flush_ready()
+    }
+
+    fn finish(&mut self, channel: u8) -> Result<(), String> {
+        self.active.retain(|&ch| ch != channel);
+        self.flush_ready()?;
+        if self.active.is_empty() {
+            self.writer.finish()?;
+        }
+        Ok(())
+    }
+
+    fn flush_ready(&mut self) -> Result<(), String> {
+        while let Some(&index) = self.pending.keys().next() {
+            let done = self.active.iter().all(|ch| self.latest.get(ch).is_some_and(|&latest| latest > index));
+            if !done {
+                break;
+            }
+            let bucket = self.pending.remove(&index).unwrap_or_default();
+
+            let time = index as f64 * self.bucket.as_secs_f64();
+            let mut row = vec![format!("{:.3}", time)];
+            for ch in &self.channels {
+                match bucket.values.get(ch) {
+                    Some(values) => row.extend(values.iter().cloned()),
+                    None => row.extend(std::iter::repeat_n(String::new(), 4)),
+                }
+            }
+            row.push(bucket.markers.join("; "));
+            self.writer.write_record(&row)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (C) 2025 Marcus Folkesson
+
+//! Model fit against a measured discharge, for `dp832 fit`
+//!
+//! The reference current is fed through the same SoC/OCV/R/RC equations the
+//! simulator steps on the instrument, and the resulting terminal voltage is
+//! compared with the measured one sample by sample.
+
+use super::model::{BatteryProfile, TheveninState};
+
+/// One row of the reference recording
+#[derive(Debug, Clone, Copy)]
+pub struct ReferenceSample {
+    /// Seconds from the start of the recording
+    pub time: f64,
+    pub voltage: f64,
+    /// Positive while discharging; `None` when the file has no current column
+    pub current: Option<f64>,
+}
+
+/// Read `time` and `voltage` columns, plus `current` if present
+///
+/// Columns are found by header name (`time…`, `volt…`, `curr…`, case
+/// insensitive), so the simulator's own per-channel CSVs work as well. Times
+/// are shifted to start at zero. Rows with an unparseable time or voltage are
+/// skipped.
+pub fn load_reference(path: &str) -> Result<Vec<ReferenceSample>, String> {
+    let mut reader = csv::Reader::from_path(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+    let headers = reader.headers().map_err(|e| format!("Failed to read {}: {}", path, e))?.clone();
+    let column = |prefix: &str| headers.iter().position(|h| h.trim().to_lowercase().starts_with(prefix));
+    let time_col = column("time").ok_or_else(|| format!("{} has no time column", path))?;
+    let voltage_col = column("volt").ok_or_else(|| format!("{} has no voltage column", path))?;
+    let current_col = column("curr");
+
+    let mut samples = Vec::new();
+    for record in reader.records() {
+        let record = record.map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let field = |col: usize| record.get(col).and_then(|v| v.trim().parse::<f64>().ok());
+        if let (Some(time), Some(voltage)) = (field(time_col), field(voltage_col)) {
+            samples.push(ReferenceSample { time, voltage, current: current_col.and_then(field) });
+        }
+    }
+
+    if samples.len() < 2 {
+        return Err(format!("{} needs at least two samples with a time and voltage", path));
+    }
+    if samples.windows(2).any(|w| w[1].time < w[0].time) {
+        return Err(format!("{}: time must not go backwards", path));
+    }
+    let t0 = samples[0].time;
+    for s in &mut samples {
+        s.time -= t0;
+    }
+    Ok(samples)
+}
+
+/// Simulated terminal voltage at each reference time
+///
+/// `default_current` supplies the load for rows without a measured current.
+/// The model starts at `initial_soc` with the RC filter settled at the OCV,
+/// or a Thévenin profile's branches at rest, as the simulator does.
+pub fn simulate_reference(
+    profile: &BatteryProfile,
+    samples: &[ReferenceSample],
+    initial_soc: f64,
+    default_current: f64,
+    temperature_c: Option<f64>,
+) -> Vec<f64> {
+    let mut soc = initial_soc;
+    let mut direction = profile.initial_direction();
+    let mut v_filt = profile.ocv_at(soc, temperature_c, direction);
+    let tau = profile.rc_time_constant_ms as f64 / 1000.0;
+    let branches = profile.rc_branches().ok().flatten();
+    let mut thevenin = TheveninState::default();
+    let mut last_time = 0.0;
+
+    samples
+        .iter()
+        .map(|s| {
+            let i = s.current.unwrap_or(default_current);
+            let dt = s.time - last_time;
+            last_time = s.time;
+
+            soc = profile.soc_after(soc, i, dt);
+            direction = direction.after(i);
+            let voc = profile.ocv_at(soc, temperature_c, direction);
+
+            let v_target = voc - i * profile.internal_resistance_ohm;
+            match &branches {
+                Some(branches) => {
+                    thevenin.step(branches, i, dt);
+                    v_filt = v_target - thevenin.polarization();
+                }
+                None if dt > 0.0 => v_filt += dt / (tau + dt) * (v_target - v_filt),
+                None => {}
+            }
+            v_filt
+        })
+        .collect()
+}
+
+/// Error of the simulated voltage against the measurement, in volts
+#[derive(Debug, Clone, Copy)]
+pub struct FitError {
+    pub rmse: f64,
+    /// Mean of simulated minus measured; positive when the model reads high
+    pub bias: f64,
+    pub max_abs: f64,
+}
+
+pub fn fit_error(measured: &[f64], simulated: &[f64]) -> FitError {
+    let n = measured.len().min(simulated.len()).max(1) as f64;
+    let errors: Vec<f64> = simulated.iter().zip(measured).map(|(s, m)| s - m).collect();
+    FitError {
+        rmse: (errors.iter().map(|e| e * e).sum::<f64>() / n).sqrt(),
+        bias: errors.iter().sum::<f64>() / n,
+        max_abs: errors.iter().fold(0.0, |acc: f64, e| acc.max(e.abs())),
+    }
+}
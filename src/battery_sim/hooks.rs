@@ -0,0 +1,244 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (C) 2025 Marcus Folkesson
+
+//! End-of-run summaries and completion hooks
+
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Why a channel stopped simulating
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    /// Terminal voltage reached the profile's cutoff voltage
+    Cutoff,
+    /// Charge mode: the CV phase current fell below the termination current
+    Charged,
+    /// Too many consecutive communication errors
+    Errors,
+    /// The user quit the simulator
+    UserStop,
+    /// `--max-runtime` elapsed
+    MaxRuntime,
+    /// The model produced a non-finite voltage
+    ModelError,
+    /// The load current stayed below the no-load threshold for too long
+    NoLoad,
+}
+
+impl StopReason {
+    /// The run ended the way the profile intends: discharged to cutoff or charged full
+    pub fn completed(&self) -> bool {
+        matches!(self, StopReason::Cutoff | StopReason::Charged)
+    }
+}
+
+impl std::fmt::Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            StopReason::Cutoff => "cutoff",
+            StopReason::Charged => "charged",
+            StopReason::Errors => "errors",
+            StopReason::UserStop => "user_stop",
+            StopReason::MaxRuntime => "max_runtime",
+            StopReason::ModelError => "model_error",
+            StopReason::NoLoad => "no_load",
+        };
+        f.write_str(text)
+    }
+}
+
+/// Summary of one channel's run, passed to completion hooks
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunSummary {
+    pub channel: u8,
+    pub profile: String,
+    pub reason: StopReason,
+    pub duration_s: f64,
+    pub final_soc: f64,
+    pub final_voltage: f64,
+    pub charge_ah: f64,
+    pub energy_wh: f64,
+}
+
+/// Everything that belongs to one simulator run, written to `logs/run_<id>.json`
+///
+/// `dp832 export` reads this to collect the run's files into one archive.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunManifest {
+    pub run_id: String,
+    /// `[logging] run_name` or the UI title, if one was set
+    #[serde(default)]
+    pub run_name: Option<String>,
+    pub device_id: String,
+    pub config: Option<String>,
+    pub profiles: Vec<String>,
+    pub csv_files: Vec<String>,
+    pub event_log: String,
+    pub scpi_log: String,
+    pub raw_scpi_log: Option<String>,
+    pub summaries: Vec<RunSummary>,
+}
+
+impl RunManifest {
+    pub fn path_for(run_id: &str) -> String {
+        format!("logs/run_{}.json", run_id)
+    }
+
+    pub fn save(&self) -> Result<String, String> {
+        let path = Self::path_for(&self.run_id);
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        std::fs::write(&path, json + "\n").map_err(|e| format!("Failed to write {}: {}", path, e))?;
+        Ok(path)
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let json = std::fs::read_to_string(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        serde_json::from_str(&json).map_err(|e| format!("Failed to parse {}: {}", path, e))
+    }
+}
+
+/// When the completion hook fires
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HookScope {
+    /// Once, after every channel has stopped
+    #[default]
+    Run,
+    /// Each time a channel stops
+    Channel,
+}
+
+/// `[on_complete]` configuration
+#[derive(Debug, Clone, Deserialize)]
+pub struct OnCompleteConfig {
+    /// Shell command to run; the summary JSON path is in `DP832_SUMMARY_FILE`
+    pub command: Option<String>,
+    /// `http://` URL that receives the summary JSON as a POST body
+    pub webhook: Option<String>,
+    #[serde(default)]
+    pub scope: HookScope,
+}
+
+/// Run the configured command and/or webhook for the given summaries
+///
+/// Returns one line per action describing the outcome, for the event log.
+pub fn fire_on_complete(cfg: &OnCompleteConfig, summaries: &[RunSummary]) -> Vec<String> {
+    let mut results = Vec::new();
+    let json = match serde_json::to_string_pretty(summaries) {
+        Ok(json) => json,
+        Err(e) => {
+            results.push(format!("on_complete: failed to serialize summary: {}", e));
+            return results;
+        }
+    };
+
+    if let Some(command) = &cfg.command {
+        results.push(match run_command(command, &json, summaries) {
+            Ok(status) => format!("on_complete: command exited with {}", status),
+            Err(e) => format!("on_complete: command failed: {}", e),
+        });
+    }
+
+    if let Some(url) = &cfg.webhook {
+        results.push(match post_json(url, &json) {
+            Ok(status) => format!("on_complete: webhook answered '{}'", status),
+            Err(e) => format!("on_complete: webhook failed: {}", e),
+        });
+    }
+
+    results
+}
+
+fn run_command(command: &str, json: &str, summaries: &[RunSummary]) -> std::io::Result<std::process::ExitStatus> {
+    let path = std::env::temp_dir().join(format!(
+        "dp832_summary_{}_{}.json",
+        std::process::id(),
+        chrono::Local::now().format("%Y%m%d_%H%M%S%.3f")
+    ));
+    std::fs::write(&path, json)?;
+
+    let mut cmd = if cfg!(windows) {
+        let mut c = std::process::Command::new("cmd");
+        c.arg("/C").arg(command);
+        c
+    } else {
+        let mut c = std::process::Command::new("sh");
+        c.arg("-c").arg(command);
+        c
+    };
+    cmd.env("DP832_SUMMARY_FILE", &path);
+
+    // Per-channel values are only unambiguous when there is a single summary
+    if let [s] = summaries {
+        cmd.env("DP832_CHANNEL", s.channel.to_string())
+            .env("DP832_PROFILE", &s.profile)
+            .env("DP832_REASON", s.reason.to_string())
+            .env("DP832_DURATION_S", format!("{:.1}", s.duration_s))
+            .env("DP832_FINAL_SOC", format!("{:.4}", s.final_soc))
+            .env("DP832_CHARGE_AH", format!("{:.4}", s.charge_ah))
+            .env("DP832_ENERGY_WH", format!("{:.4}", s.energy_wh));
+    }
+
+    let status = cmd.stdout(std::process::Stdio::null())
+        .stderr(std::process::Stdio::null())
+        .status();
+    // The command has exited, so nothing reads the summary any more
+    let _ = std::fs::remove_file(&path);
+    status
+}
+
+/// Connect, send and reply timeout of the webhook, so an unreachable or
+/// stalled server cannot hold up the end of the run
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Minimal HTTP/1.1 POST; only plain `http://` URLs are supported
+fn post_json(url: &str, body: &str) -> Result<String, String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| "only http:// URLs are supported (use the command hook with curl for https)".to_string())?;
+    let (host_port, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let host = host_port.split(':').next().unwrap_or(host_port);
+    let addr = if host_port.contains(':') {
+        host_port.to_string()
+    } else {
+        format!("{}:80", host_port)
+    };
+
+    let sock_addr = addr
+        .to_socket_addrs()
+        .map_err(|e| format!("{}: {}", addr, e))?
+        .next()
+        .ok_or_else(|| format!("{} did not resolve to an address", addr))?;
+    let mut stream = TcpStream::connect_timeout(&sock_addr, HTTP_TIMEOUT).map_err(|e| format!("{}: {}", addr, e))?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT)).map_err(|e| e.to_string())?;
+
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    );
+    stream.write_all(request.as_bytes()).map_err(|e| e.to_string())?;
+
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response);
+    let status = String::from_utf8_lossy(&response)
+        .lines()
+        .next()
+        .unwrap_or("")
+        .to_string();
+
+    if status.split_whitespace().nth(1).is_some_and(|code| code.starts_with('2')) {
+        Ok(status)
+    } else {
+        Err(format!("unexpected response '{}'", status))
+    }
+}
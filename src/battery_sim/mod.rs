@@ -1,13 +1,18 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 // Copyright (C) 2025 Marcus Folkesson
 
-/// Battery Simulator Module
-/// 
-/// Simulates realistic battery behavior on the DP832 power supply
+//! Battery Simulator Module
+//!
+//! Simulates realistic battery behavior on the DP832 power supply
 
 pub mod model;
 pub mod config;
 pub mod ui;
+pub mod hooks;
+pub mod noise;
+pub mod cells;
+pub mod csvlog;
+pub mod fit;
 
 pub use model::*;
 pub use config::*;
@@ -1,37 +1,761 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 // Copyright (C) 2025 Marcus Folkesson
 
-/// Battery model and simulation logic
+//! Battery model and simulation logic
 
-use serde::Deserialize;
+use crate::units;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct OcvPoint {
     pub soc: f64,
+    #[serde(deserialize_with = "units::volts")]
     pub voltage: f64,
+    /// Temperature the point was measured at, for a curve over (SoC, temperature)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature_c: Option<f64>,
 }
 
-#[derive(Debug, Deserialize)]
+/// OCV curve measured at one temperature
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TemperatureCurve {
+    pub temperature_c: f64,
+    pub ocv_curve: Vec<OcvPoint>,
+}
+
+/// How the SoC values of a profile's OCV curves are written
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SocScale {
+    /// 0.0 to 1.0, what the model uses
+    Fraction,
+    /// 0 to 100, as in most datasheets
+    Percent,
+}
+
+/// Whether the load discharges the simulated cell or a charger fills it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProfileMode {
+    /// The device under test draws from a full cell until cutoff
+    #[default]
+    Discharge,
+    /// A CC-CV charger at `current_limit_charge_a` fills a depleted cell
+    Charge,
+}
+
+impl ProfileMode {
+    fn is_discharge(&self) -> bool {
+        *self == ProfileMode::Discharge
+    }
+}
+
+/// Which way the cell current last flowed, to pick the charge or discharge OCV curve
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CurrentDirection {
+    Discharge,
+    Charge,
+}
+
+impl CurrentDirection {
+    /// Direction after a step at cell current `i`, positive while discharging
+    ///
+    /// Currents within `HYSTERESIS_DEADBAND_A` of zero keep the previous
+    /// direction, so a resting cell does not flip between the curves on noise.
+    pub fn after(self, i: f64) -> Self {
+        if i > HYSTERESIS_DEADBAND_A {
+            CurrentDirection::Discharge
+        } else if i < -HYSTERESIS_DEADBAND_A {
+            CurrentDirection::Charge
+        } else {
+            self
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct BatteryProfile {
     pub name: String,
     pub channel: u8,
 
+    #[serde(deserialize_with = "units::amp_hours")]
     pub capacity_ah: f64,
+    #[serde(deserialize_with = "units::ohms")]
     pub internal_resistance_ohm: f64,
+    /// Relative change of `internal_resistance_ohm` per °C away from 25 °C,
+    /// e.g. -0.01 for 1% more resistance per degree colder
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resistance_temp_coeff: Option<f64>,
+    /// Rate dependence of the capacity; `capacity_ah` is the C/1 capacity and
+    /// 1.0 (the default) integrates the current as measured
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peukert_exponent: Option<f64>,
+    /// Share of the full capacity lost per day at rest, in percent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub self_discharge_percent_per_day: Option<f64>,
 
+    #[serde(deserialize_with = "units::amps")]
     pub current_limit_discharge_a: f64,
+    #[serde(deserialize_with = "units::amps")]
     pub current_limit_charge_a: f64,
 
+    #[serde(default, skip_serializing_if = "ProfileMode::is_discharge")]
+    pub mode: ProfileMode,
+    /// Charge mode: the charge ends once the CV phase current drops below this
+    /// (default: 10% of the charge limit)
+    #[serde(default, deserialize_with = "units::amps_opt", skip_serializing_if = "Option::is_none")]
+    pub termination_current_a: Option<f64>,
+    /// Charge mode: below this terminal voltage the charger only trickles
+    /// `precharge_current_a`, as for a deeply discharged cell
+    #[serde(default, deserialize_with = "units::volts_opt", skip_serializing_if = "Option::is_none")]
+    pub precharge_voltage: Option<f64>,
+    /// Charge mode: current below `precharge_voltage` (default: 10% of the charge limit)
+    #[serde(default, alias = "precharge_current", deserialize_with = "units::amps_opt", skip_serializing_if = "Option::is_none")]
+    pub precharge_current_a: Option<f64>,
+
+    #[serde(deserialize_with = "units::volts")]
     pub cutoff_voltage: f64,
+    #[serde(deserialize_with = "units::volts")]
     pub max_voltage: f64,
 
+    /// Tail capture: keep discharging below `cutoff_voltage` down to this voltage
+    #[serde(default, deserialize_with = "units::volts_opt", skip_serializing_if = "Option::is_none")]
+    pub absolute_min_voltage: Option<f64>,
+    /// Tail capture: current limit below `cutoff_voltage` (default: 10% of the discharge limit)
+    #[serde(default, deserialize_with = "units::amps_opt", skip_serializing_if = "Option::is_none")]
+    pub tail_current_limit_a: Option<f64>,
+
     pub rc_time_constant_ms: u64,
     pub update_interval_ms: u64,
 
+    /// Two-RC Thévenin model: polarization branches in series with the
+    /// internal resistance; all four are set together, `rc_time_constant_ms`
+    /// is not used then
+    #[serde(default, deserialize_with = "units::ohms_opt", skip_serializing_if = "Option::is_none")]
+    pub r1_ohm: Option<f64>,
+    #[serde(default, deserialize_with = "units::farads_opt", skip_serializing_if = "Option::is_none")]
+    pub c1_farad: Option<f64>,
+    #[serde(default, deserialize_with = "units::ohms_opt", skip_serializing_if = "Option::is_none")]
+    pub r2_ohm: Option<f64>,
+    #[serde(default, deserialize_with = "units::farads_opt", skip_serializing_if = "Option::is_none")]
+    pub c2_farad: Option<f64>,
+
+    /// Required unless `ocv_curve_csv` is given
+    #[serde(default)]
     pub ocv_curve: Vec<OcvPoint>,
+
+    /// CSV file with `soc,voltage` rows to read `ocv_curve` from, relative to the profile
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ocv_curve_csv: Option<String>,
+
+    /// OCV hysteresis: curves used while charging and discharging instead of
+    /// `ocv_curve`; either may be left out
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ocv_curve_charge: Vec<OcvPoint>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ocv_curve_discharge: Vec<OcvPoint>,
+
+    /// Cell temperature when the simulator sets none (`--temperature-c`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature_c: Option<f64>,
+
+    /// Scale of the SoC values in the OCV curves; detected from the values when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub soc_scale: Option<SocScale>,
+
+    /// Number of cells in series; when set, voltages and resistance are per cell
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub series_cells: Option<u32>,
+
+    /// Optional OCV curves at several temperatures; `ocv_curve` is used without them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ocv_curves: Vec<TemperatureCurve>,
+}
+
+impl BatteryProfile {
+    /// Check the values the model relies on, so a bad profile is reported at load time
+    ///
+    /// Every OCV curve must be non-empty with SoC strictly decreasing within
+    /// 0..1 (per temperature for a tagged curve), `cutoff_voltage` below
+    /// `max_voltage`, the channel 1-3, the capacity and resistances above 0,
+    /// the Peukert exponent within 1.0-2.0 and the self-discharge rate within
+    /// 0-100%.
+    /// `dp832 normalize-profile` sorts a curve that fails the ordering check.
+    pub fn validate(&self) -> Result<(), String> {
+        let fail = |what: String| Err(format!("Profile '{}': {}", self.name, what));
+
+        if !(1..=3).contains(&self.channel) {
+            return fail(format!("channel {} does not exist (use 1-3)", self.channel));
+        }
+        if self.capacity_ah <= 0.0 || self.capacity_ah.is_nan() {
+            return fail(format!("capacity_ah must be above 0, got {}", self.capacity_ah));
+        }
+        if self.internal_resistance_ohm <= 0.0 || self.internal_resistance_ohm.is_nan() {
+            return fail(format!("internal_resistance_ohm must be above 0, got {}", self.internal_resistance_ohm));
+        }
+        self.rc_branches()?;
+        if let Some(k) = self.peukert_exponent.filter(|k| !(1.0..=2.0).contains(k)) {
+            return fail(format!("peukert_exponent {} must be between 1.0 and 2.0", k));
+        }
+        if let Some(rate) = self.self_discharge_percent_per_day.filter(|r| !(0.0..=100.0).contains(r)) {
+            return fail(format!("self_discharge_percent_per_day must be between 0 and 100, got {}", rate));
+        }
+        if self.cutoff_voltage >= self.max_voltage || self.cutoff_voltage.is_nan() || self.max_voltage.is_nan() {
+            return fail(format!("cutoff_voltage {:.3}V must be below max_voltage {:.3}V",
+                                self.cutoff_voltage, self.max_voltage));
+        }
+
+        let mut curves = vec![("ocv_curve".to_string(), &self.ocv_curve)];
+        for (name, curve) in [("ocv_curve_charge", &self.ocv_curve_charge), ("ocv_curve_discharge", &self.ocv_curve_discharge)] {
+            if !curve.is_empty() {
+                curves.push((name.to_string(), curve));
+            }
+        }
+        curves.extend(self.ocv_curves.iter().map(|c| (format!("ocv_curves at {}°C", c.temperature_c), &c.ocv_curve)));
+        for (name, curve) in curves {
+            if let Err(e) = check_ocv_curve(curve) {
+                return fail(format!("{} {}", name, e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adjust the internal resistance to the cell temperature
+    ///
+    /// `temperature_c` is the simulator's temperature, which takes precedence
+    /// over the profile's own. Without a coefficient or any temperature the
+    /// resistance is kept. `resistance_temp_coeff` is cleared afterwards so
+    /// the resistance is not adjusted twice.
+    pub fn apply_temperature(&mut self, temperature_c: Option<f64>) -> Result<(), String> {
+        let (Some(coeff), Some(t)) = (self.resistance_temp_coeff.take(), temperature_c.or(self.temperature_c)) else {
+            return Ok(());
+        };
+        let scale = 1.0 + coeff * (t - REFERENCE_TEMPERATURE_C);
+        if scale <= 0.0 {
+            return Err(format!("Profile '{}': resistance_temp_coeff {} leaves no internal resistance at {:.1}°C",
+                               self.name, coeff, t));
+        }
+        self.internal_resistance_ohm *= scale;
+        Ok(())
+    }
+
+    /// Scale a per-cell profile up to the pack described by `series_cells`
+    ///
+    /// OCV points, cutoff and max voltage and the resistances are multiplied
+    /// and the RC branch capacitances divided by the cell count, so the
+    /// terminal voltage is that of N identical cells in series. `series_cells` is cleared afterwards so the
+    /// profile is not scaled twice.
+    pub fn apply_series_cells(&mut self) -> Result<(), String> {
+        let cells = match self.series_cells.take() {
+            None | Some(1) => return Ok(()),
+            Some(0) => return Err(format!("Profile '{}': series_cells must be at least 1", self.name)),
+            Some(n) => n as f64,
+        };
+
+        for point in self.ocv_points_mut() {
+            point.voltage *= cells;
+        }
+        self.cutoff_voltage *= cells;
+        self.max_voltage *= cells;
+        for v in [&mut self.absolute_min_voltage, &mut self.precharge_voltage].into_iter().flatten() {
+            *v *= cells;
+        }
+        self.internal_resistance_ohm *= cells;
+        for r in [&mut self.r1_ohm, &mut self.r2_ohm].into_iter().flatten() {
+            *r *= cells;
+        }
+        for c in [&mut self.c1_farad, &mut self.c2_farad].into_iter().flatten() {
+            *c /= cells;
+        }
+        Ok(())
+    }
+
+    /// Convert OCV curves written in percent to the 0..1 SoC the model uses
+    ///
+    /// Without `soc_scale`, curves with any SoC above 1 are taken as percent
+    /// and the returned note says so. A value outside the range of the scale
+    /// is an error instead of being clamped to the nearest curve endpoint.
+    /// `soc_scale` is cleared afterwards so the curves are not scaled twice.
+    pub fn apply_soc_scale(&mut self) -> Result<Option<String>, String> {
+        let explicit = self.soc_scale.take();
+        let points = || {
+            self.ocv_curve.iter()
+                .chain(&self.ocv_curve_charge)
+                .chain(&self.ocv_curve_discharge)
+                .chain(self.ocv_curves.iter().flat_map(|c| &c.ocv_curve))
+        };
+        let (scale, note) = match explicit {
+            Some(scale) => (scale, None),
+            None if points().any(|p| p.soc > 1.0) => (SocScale::Percent, Some(format!(
+                "Profile '{}': OCV curve SoC goes above 1, read as percent (set \"soc_scale\" to silence this)",
+                self.name))),
+            None => (SocScale::Fraction, None),
+        };
+
+        let max = if scale == SocScale::Percent { 100.0 } else { 1.0 };
+        if let Some(soc) = points().map(|p| p.soc).find(|soc| !(0.0..=max).contains(soc)) {
+            let hint = if scale == SocScale::Fraction && (0.0..=100.0).contains(&soc) {
+                "; use \"soc_scale\": \"percent\" for a 0-100 curve"
+            } else {
+                ""
+            };
+            return Err(format!("Profile '{}': OCV curve SoC {} is outside 0-{}{}", self.name, soc, max, hint));
+        }
+
+        if scale == SocScale::Percent {
+            for point in self.ocv_points_mut() {
+                point.soc /= 100.0;
+            }
+        }
+        Ok(note)
+    }
+
+    /// Every point of every OCV curve of the profile
+    fn ocv_points_mut(&mut self) -> impl Iterator<Item = &mut OcvPoint> {
+        self.ocv_curve.iter_mut()
+            .chain(self.ocv_curve_charge.iter_mut())
+            .chain(self.ocv_curve_discharge.iter_mut())
+            .chain(self.ocv_curves.iter_mut().flat_map(|c| c.ocv_curve.iter_mut()))
+    }
+
+    /// Whether the profile has a separate charge or discharge OCV curve
+    pub fn has_hysteresis(&self) -> bool {
+        !self.ocv_curve_charge.is_empty() || !self.ocv_curve_discharge.is_empty()
+    }
+
+    /// OCV curve for the current direction, falling back to `ocv_curve`
+    pub fn ocv_curve_for(&self, direction: CurrentDirection) -> &[OcvPoint] {
+        let curve = match direction {
+            CurrentDirection::Charge => &self.ocv_curve_charge,
+            CurrentDirection::Discharge => &self.ocv_curve_discharge,
+        };
+        if curve.is_empty() { &self.ocv_curve } else { curve }
+    }
+
+    /// Stop voltage and current limit of the tail below cutoff, if the profile has one
+    pub fn tail_limits(&self) -> Result<Option<(f64, f64)>, String> {
+        let Some(min_voltage) = self.absolute_min_voltage else {
+            return Ok(None);
+        };
+        if !(0.0..self.cutoff_voltage).contains(&min_voltage) {
+            return Err(format!("Profile '{}': absolute_min_voltage {:.3}V must be between 0 and cutoff_voltage {:.3}V",
+                               self.name, min_voltage, self.cutoff_voltage));
+        }
+        let current = self.tail_current_limit_a.unwrap_or(self.current_limit_discharge_a * 0.1);
+        if !(current > 0.0 && current <= self.current_limit_discharge_a) {
+            return Err(format!("Profile '{}': tail_current_limit_a {:.3}A must be above 0 and at most the discharge limit",
+                               self.name, current));
+        }
+        Ok(Some((min_voltage, current)))
+    }
+
+    /// Charge current and termination current of a charge-mode profile
+    pub fn charge_limits(&self) -> Result<(f64, f64), String> {
+        if self.current_limit_charge_a <= 0.0 || self.current_limit_charge_a.is_nan() {
+            return Err(format!("Profile '{}': charge mode needs a current_limit_charge_a above 0", self.name));
+        }
+        let termination = self.termination_current_a.unwrap_or(self.current_limit_charge_a * 0.1);
+        if termination <= 0.0 || termination >= self.current_limit_charge_a || termination.is_nan() {
+            return Err(format!("Profile '{}': termination_current_a {:.3}A must be above 0 and below the charge limit",
+                               self.name, termination));
+        }
+        Ok((self.current_limit_charge_a, termination))
+    }
+
+    /// Threshold voltage and trickle current of the pre-charge, if the profile has one
+    pub fn precharge_limits(&self) -> Result<Option<(f64, f64)>, String> {
+        let Some(threshold) = self.precharge_voltage else {
+            return Ok(None);
+        };
+        if !(0.0..self.max_voltage).contains(&threshold) {
+            return Err(format!("Profile '{}': precharge_voltage {:.3}V must be between 0 and max_voltage {:.3}V",
+                               self.name, threshold, self.max_voltage));
+        }
+        let current = self.precharge_current_a.unwrap_or(self.current_limit_charge_a * 0.1);
+        if !(current > 0.0 && current < self.current_limit_charge_a) {
+            return Err(format!("Profile '{}': precharge_current_a {:.3}A must be above 0 and below the charge limit",
+                               self.name, current));
+        }
+        Ok(Some((threshold, current)))
+    }
+
+    /// Current a CC-CV charger at `limit` supplies to the load and the cell together
+    ///
+    /// `ocv` is the cell's open-circuit voltage less any polarization and
+    /// `i_load` the current the device under test draws. The charger runs at
+    /// `limit` (CC) until the terminal voltage would pass `max_voltage`, then
+    /// supplies just enough to hold it there (CV).
+    pub fn charger_current(&self, ocv: f64, i_load: f64, limit: f64) -> f64 {
+        ((self.max_voltage - ocv) / self.internal_resistance_ohm + i_load).clamp(0.0, limit)
+    }
+
+    /// Current the SoC integrates for a measured current `i`
+    ///
+    /// With a Peukert exponent k, a discharge is scaled by (i / I1)^(k-1),
+    /// where I1 drains `capacity_ah` in one hour: above the C/1 rate the cell
+    /// empties faster than the charge delivered, below it slower. Charge
+    /// currents are not scaled.
+    pub fn soc_current(&self, i: f64) -> f64 {
+        match self.peukert_exponent {
+            Some(k) if i > 0.0 => i * (i / self.capacity_ah).powf(k - 1.0),
+            _ => i,
+        }
+    }
+
+    /// SoC lost to self-discharge over `dt` seconds, on top of the current drawn
+    pub fn self_discharge_soc(&self, dt: f64) -> f64 {
+        self.self_discharge_percent_per_day.unwrap_or(0.0) / 100.0 * dt / 86400.0
+    }
+
+    /// SoC after `dt` seconds at cell current `i`, negative while charging
+    ///
+    /// Integrates the Peukert-scaled current and the self-discharge over the
+    /// same step, within 0..1. The simulator and the fit step the SoC here.
+    pub fn soc_after(&self, soc: f64, i: f64, dt: f64) -> f64 {
+        let soc = soc - self.soc_current(i) * dt / (self.capacity_ah * 3600.0) - self.self_discharge_soc(dt);
+        soc.clamp(0.0, 1.0)
+    }
+
+    /// Capacity in Ah delivered at a constant discharge current `i`
+    pub fn capacity_at(&self, i: f64) -> f64 {
+        self.capacity_ah * i / self.soc_current(i)
+    }
+
+    /// The two RC branches of a Thévenin profile, `None` for the single RC filter
+    pub fn rc_branches(&self) -> Result<Option<[RcBranch; 2]>, String> {
+        let branch = |r: Option<f64>, c: Option<f64>, n: u8| match (r, c) {
+            (Some(r), Some(c)) if r > 0.0 && c > 0.0 => Ok(RcBranch { r_ohm: r, tau_s: r * c }),
+            (Some(_), Some(_)) => Err(format!("Profile '{}': r{n}_ohm and c{n}_farad must be above 0", self.name)),
+            _ => Err(format!("Profile '{}': the Thévenin model needs all of r1_ohm, c1_farad, r2_ohm and c2_farad",
+                             self.name)),
+        };
+        match (self.r1_ohm, self.c1_farad, self.r2_ohm, self.c2_farad) {
+            (None, None, None, None) => Ok(None),
+            (r1, c1, r2, c2) => Ok(Some([branch(r1, c1, 1)?, branch(r2, c2, 2)?])),
+        }
+    }
+
+    /// SoC of a cell without a saved record: full to discharge, empty to charge
+    pub fn initial_soc(&self) -> f64 {
+        match self.mode {
+            ProfileMode::Discharge => 1.0,
+            ProfileMode::Charge => 0.0,
+        }
+    }
+
+    /// Current direction before the first step, following the profile mode
+    pub fn initial_direction(&self) -> CurrentDirection {
+        match self.mode {
+            ProfileMode::Discharge => CurrentDirection::Discharge,
+            ProfileMode::Charge => CurrentDirection::Charge,
+        }
+    }
+
+    /// Open-circuit voltage at `soc`, using the temperature curves when a temperature is given
+    ///
+    /// `temperature_c` falls back to the profile's own. A temperature-tagged
+    /// `ocv_curve` without any temperature is read at 25 °C. Without
+    /// temperature curves, `direction` picks the charge or discharge curve.
+    pub fn ocv_at(&self, soc: f64, temperature_c: Option<f64>, direction: CurrentDirection) -> f64 {
+        match temperature_c.or(self.temperature_c) {
+            Some(t) if !self.ocv_curves.is_empty() => interpolate_ocv_temperature(&self.ocv_curves, soc, t),
+            t => interpolate_ocv_2d(self.ocv_curve_for(direction), soc, t.unwrap_or(REFERENCE_TEMPERATURE_C)),
+        }
+    }
+}
+
+/// Phase of a simulated charge
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChargePhase {
+    /// Trickle at the pre-charge current below `precharge_voltage`
+    Precharge,
+    /// `current_limit_charge_a` until the terminal voltage reaches `max_voltage`
+    ConstantCurrent,
+    /// Held at `max_voltage` while the current tapers
+    ConstantVoltage,
+    /// The CV current fell below the termination current, or the cell is full
+    Charged,
+}
+
+/// The CC-CV charger of a charge-mode profile, with its optional pre-charge
+#[derive(Debug, Clone, Copy)]
+pub struct Charger {
+    pub limit_a: f64,
+    pub termination_a: f64,
+    /// Threshold voltage and trickle current of the pre-charge
+    pub precharge: Option<(f64, f64)>,
+    pub phase: ChargePhase,
+}
+
+impl Charger {
+    /// Charger for a charge-mode profile, pre-charging if the cell starts below the threshold
+    pub fn new(profile: &BatteryProfile, terminal_voltage: f64) -> Result<Self, String> {
+        let (limit_a, termination_a) = profile.charge_limits()?;
+        let precharge = profile.precharge_limits()?;
+        let phase = match precharge {
+            Some((threshold, _)) if terminal_voltage < threshold => ChargePhase::Precharge,
+            _ => ChargePhase::ConstantCurrent,
+        };
+        Ok(Self { limit_a, termination_a, precharge, phase })
+    }
+
+    /// Current limit of the present phase
+    pub fn limit(&self) -> f64 {
+        match (self.phase, self.precharge) {
+            (ChargePhase::Precharge, Some((_, trickle))) => trickle,
+            _ => self.limit_a,
+        }
+    }
+
+    /// Current supplied to the load and the cell together for one step
+    ///
+    /// `ocv` is the open-circuit voltage less any polarization and `i_load`
+    /// the current the device under test draws. Falling below the CC limit
+    /// starts the CV phase.
+    pub fn current(&mut self, profile: &BatteryProfile, ocv: f64, i_load: f64) -> f64 {
+        let current = profile.charger_current(ocv, i_load, self.limit());
+        if self.phase == ChargePhase::ConstantCurrent && current < self.limit_a {
+            self.phase = ChargePhase::ConstantVoltage;
+        }
+        current
+    }
+
+    /// Move on from the terminal voltage, cell current and SoC after a step
+    pub fn update(&mut self, terminal_voltage: f64, i: f64, soc: f64) {
+        self.phase = match (self.phase, self.precharge) {
+            (ChargePhase::Precharge, Some((threshold, _))) if terminal_voltage >= threshold => ChargePhase::ConstantCurrent,
+            (ChargePhase::ConstantVoltage, _) if -i < self.termination_a => ChargePhase::Charged,
+            _ if soc >= 1.0 => ChargePhase::Charged,
+            (phase, _) => phase,
+        };
+    }
+}
+
+/// One polarization branch of the Thévenin model: a resistor parallel to a capacitor
+#[derive(Debug, Clone, Copy)]
+pub struct RcBranch {
+    pub r_ohm: f64,
+    /// R·C, in seconds
+    pub tau_s: f64,
+}
+
+/// Voltages across the two RC branches of a Thévenin model
+///
+/// The terminal voltage is OCV - I·R0 - `v1` - `v2`. Both start at 0 V, a
+/// cell at rest.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TheveninState {
+    pub v1: f64,
+    pub v2: f64,
+}
+
+impl TheveninState {
+    /// Advance both branches by `dt` seconds at cell current `i`
+    ///
+    /// Each branch relaxes toward I·R with its own time constant. The update
+    /// is exact for a current held over the step, so it does not depend on
+    /// the update interval.
+    pub fn step(&mut self, branches: &[RcBranch; 2], i: f64, dt: f64) {
+        for (v, branch) in [&mut self.v1, &mut self.v2].into_iter().zip(branches) {
+            let decay = (-dt / branch.tau_s).exp();
+            *v = *v * decay + i * branch.r_ohm * (1.0 - decay);
+        }
+    }
+
+    /// Total voltage across both branches
+    pub fn polarization(&self) -> f64 {
+        self.v1 + self.v2
+    }
+}
+
+/// Temperature that `internal_resistance_ohm` and untagged OCV points refer to
+pub const REFERENCE_TEMPERATURE_C: f64 = 25.0;
+
+/// Currents closer to zero than this keep the previous OCV hysteresis curve
+pub const HYSTERESIS_DEADBAND_A: f64 = 0.01;
+
+/// Largest SoC spacing between two curve points before it is reported as a gap
+const OCV_GAP_WARN: f64 = 0.25;
+
+/// Load a battery profile from a JSON file
+///
+/// A profile may name a base profile with `"extends": "base.json"` (relative
+/// to the profile's directory). The base is loaded first and every field
+/// present in the child replaces the base value. Only one level is
+/// supported: a base profile cannot itself extend another. An
+/// `ocv_curve_csv` is read into `ocv_curve`, relative to the profile that
+/// declares it (the base, when inherited).
+pub fn load_profile(path: &str) -> Result<BatteryProfile, String> {
+    load_profile_with_notes(path).map(|(profile, _)| profile)
+}
+
+/// `load_profile`, plus notes on conversions the file needed, for the caller to show
+pub fn load_profile_with_notes(path: &str) -> Result<(BatteryProfile, Vec<String>), String> {
+    let mut json = read_profile_json(path)?;
+    resolve_ocv_csv(&mut json, path);
+
+    if let Some(base_name) = json.get("extends").cloned() {
+        let base_name = base_name
+            .as_str()
+            .ok_or_else(|| format!("Profile {}: \"extends\" must be a file name", path))?;
+        let base_path = Path::new(path)
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .join(base_name);
+        let base_path = base_path.to_string_lossy();
+
+        let mut base = read_profile_json(&base_path)?;
+        if base.get("extends").is_some() {
+            return Err(format!(
+                "Profile {}: base profile {} extends another profile; only one level of inheritance is supported",
+                path, base_path
+            ));
+        }
+        resolve_ocv_csv(&mut base, &base_path);
+
+        if let (Some(base_fields), Some(child_fields)) = (base.as_object_mut(), json.as_object()) {
+            // An inline curve in the child replaces a CSV curve in the base and vice versa
+            if child_fields.contains_key("ocv_curve") || child_fields.contains_key("ocv_curve_csv") {
+                base_fields.remove("ocv_curve");
+                base_fields.remove("ocv_curve_csv");
+            }
+            for (key, value) in child_fields {
+                if key != "extends" {
+                    base_fields.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        json = base;
+    }
+
+    let mut profile: BatteryProfile =
+        serde_json::from_value(json).map_err(|e| format!("Failed to parse profile {}: {}", path, e))?;
+    if let Some(csv_path) = profile.ocv_curve_csv.take() {
+        if !profile.ocv_curve.is_empty() {
+            return Err(format!("Profile {}: give either \"ocv_curve\" or \"ocv_curve_csv\", not both", path));
+        }
+        profile.ocv_curve = load_ocv_csv(&csv_path).map_err(|e| format!("Profile {}: {}", path, e))?;
+    }
+    let notes = profile.apply_soc_scale()?.into_iter().collect();
+    Ok((profile, notes))
+}
+
+/// Make an `ocv_curve_csv` path relative to the profile file that declares it
+fn resolve_ocv_csv(json: &mut serde_json::Value, profile_path: &str) {
+    if let Some(csv) = json.get_mut("ocv_curve_csv") {
+        if let Some(name) = csv.as_str() {
+            let resolved = Path::new(profile_path).parent().unwrap_or_else(|| Path::new("")).join(name);
+            *csv = resolved.to_string_lossy().into_owned().into();
+        }
+    }
+}
+
+/// Read an OCV curve from a CSV file with SoC in the first column and voltage in the second
+///
+/// A first row that does not start with a number is taken as a header.
+/// Voltages may carry a unit (`3.2V`, `3200mV`). The points are returned
+/// sorted by descending SoC; SoC in percent is converted with the rest of the
+/// profile by `soc_scale`.
+pub fn load_ocv_csv(path: &str) -> Result<Vec<OcvPoint>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .map_err(|e| format!("Failed to open OCV curve {}: {}", path, e))?;
+
+    let mut curve = Vec::new();
+    for (row, record) in reader.records().enumerate() {
+        let record = record.map_err(|e| format!("Failed to read OCV curve {}: {}", path, e))?;
+        let soc = record.get(0).and_then(|v| v.parse::<f64>().ok());
+        let voltage = record.get(1).and_then(|v| units::parse_quantity(v, &["V"]));
+        match (soc, voltage) {
+            (Some(soc), Some(voltage)) => curve.push(OcvPoint { soc, voltage, temperature_c: None }),
+            (None, _) if row == 0 => continue,
+            _ => return Err(format!("OCV curve {} line {}: expected soc,voltage", path, row + 1)),
+        }
+    }
+    if curve.is_empty() {
+        return Err(format!("OCV curve {} has no points", path));
+    }
+    curve.sort_by(|a, b| b.soc.total_cmp(&a.soc));
+    Ok(curve)
+}
+
+/// Write a copy of the profile at `path` with a tuned RC time constant
+///
+/// The copy is `<name>_tuned.json` next to the original; everything else,
+/// including `extends`, is kept as written. Returns the new path.
+pub fn save_tuned_profile(path: &str, rc_time_constant_ms: u64) -> Result<String, String> {
+    let mut json = read_profile_json(path)?;
+    json["rc_time_constant_ms"] = rc_time_constant_ms.into();
+
+    let original = Path::new(path);
+    let stem = original.file_stem().unwrap_or_default().to_string_lossy();
+    let tuned = original.with_file_name(format!("{}_tuned.json", stem));
+    let text = serde_json::to_string_pretty(&json).map_err(|e| e.to_string())?;
+    std::fs::write(&tuned, text + "\n").map_err(|e| format!("Failed to write {}: {}", tuned.display(), e))?;
+    Ok(tuned.to_string_lossy().into_owned())
+}
+
+/// Candidate profile files in `dir`, sorted by name
+///
+/// Every `*.json` is listed except the `_tuned.json` copies written by
+/// `save_tuned_profile`, which would otherwise claim the original's channel.
+pub fn profile_files(dir: &str) -> Result<Vec<String>, String> {
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("Failed to read profile directory {}: {}", dir, e))?;
+    let mut files: Vec<String> = entries
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext == "json"))
+        .map(|path| path.to_string_lossy().into_owned())
+        .filter(|path| !path.ends_with("_tuned.json"))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn read_profile_json(path: &str) -> Result<serde_json::Value, String> {
+    let mut json = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut json))
+        .map_err(|e| format!("Failed to open profile {}: {}", path, e))?;
+
+    let value: serde_json::Value =
+        serde_json::from_str(&json).map_err(|e| format!("Failed to parse profile {}: {}", path, e))?;
+    if !value.is_object() {
+        return Err(format!("Failed to parse profile {}: expected a JSON object", path));
+    }
+    Ok(value)
+}
+
+/// Why a curve cannot be interpolated: empty, SoC outside 0..1 or not strictly decreasing
+fn check_ocv_curve(curve: &[OcvPoint]) -> Result<(), String> {
+    if curve.is_empty() {
+        return Err("is empty".to_string());
+    }
+    if is_temperature_tagged(curve) {
+        for slice in ocv_slices(curve) {
+            check_ocv_curve(&slice.ocv_curve).map_err(|e| format!("{} at {}°C", e, slice.temperature_c))?;
+        }
+        return Ok(());
+    }
+    if let Some(p) = curve.iter().find(|p| !(0.0..=1.0).contains(&p.soc)) {
+        return Err(format!("has SoC {} outside 0-1", p.soc));
+    }
+    if let Some(w) = curve.windows(2).find(|w| w[1].soc >= w[0].soc) {
+        return Err(format!("SoC must decrease from point to point, but {} is followed by {} \
+                            (dp832 normalize-profile sorts it)", w[0].soc, w[1].soc));
+    }
+    Ok(())
 }
 
 /// Interpolate OCV from the OCV curve based on SoC
+///
+/// The curve must be ordered by descending SoC. Outside the covered range
+/// the voltage of the nearest endpoint is returned.
 pub fn interpolate_ocv(curve: &[OcvPoint], soc: f64) -> f64 {
     let soc = soc.clamp(0.0, 1.0);
 
@@ -42,5 +766,603 @@ pub fn interpolate_ocv(curve: &[OcvPoint], soc: f64) -> f64 {
         }
     }
 
-    curve.last().unwrap().voltage
+    let first = curve.first().unwrap();
+    if soc >= first.soc {
+        first.voltage
+    } else {
+        curve.last().unwrap().voltage
+    }
+}
+
+/// Interpolate OCV over SoC and temperature from temperature-tagged curves
+///
+/// The OCV at `soc` is taken from the two curves whose temperatures bracket
+/// `temperature_c` and blended linearly. Below the coldest or above the
+/// warmest curve, that curve is used as is. `curves` must not be empty.
+pub fn interpolate_ocv_temperature(curves: &[TemperatureCurve], soc: f64, temperature_c: f64) -> f64 {
+    let mut sorted: Vec<&TemperatureCurve> = curves.iter().collect();
+    sorted.sort_by(|a, b| a.temperature_c.total_cmp(&b.temperature_c));
+
+    let coldest = sorted[0];
+    let warmest = sorted[sorted.len() - 1];
+    if temperature_c <= coldest.temperature_c {
+        return interpolate_ocv(&coldest.ocv_curve, soc);
+    }
+    if temperature_c >= warmest.temperature_c {
+        return interpolate_ocv(&warmest.ocv_curve, soc);
+    }
+
+    for w in sorted.windows(2) {
+        if temperature_c >= w[0].temperature_c && temperature_c <= w[1].temperature_c {
+            let t = (temperature_c - w[0].temperature_c) / (w[1].temperature_c - w[0].temperature_c);
+            let low = interpolate_ocv(&w[0].ocv_curve, soc);
+            let high = interpolate_ocv(&w[1].ocv_curve, soc);
+            return low + t * (high - low);
+        }
+    }
+
+    interpolate_ocv(&warmest.ocv_curve, soc)
+}
+
+/// Interpolate OCV over SoC and temperature from a curve with temperature-tagged points
+///
+/// The points are split into one curve per temperature, each in the order
+/// given, and blended as by `interpolate_ocv_temperature`; untagged points
+/// belong to the 25 °C curve. A curve without any tag is interpolated over
+/// SoC alone, whatever `temperature_c` is.
+pub fn interpolate_ocv_2d(curve: &[OcvPoint], soc: f64, temperature_c: f64) -> f64 {
+    if !is_temperature_tagged(curve) {
+        return interpolate_ocv(curve, soc);
+    }
+    interpolate_ocv_temperature(&ocv_slices(curve), soc, temperature_c)
+}
+
+/// Whether any point of the curve carries a temperature
+pub fn is_temperature_tagged(curve: &[OcvPoint]) -> bool {
+    curve.iter().any(|p| p.temperature_c.is_some())
+}
+
+/// Split a temperature-tagged curve into one untagged curve per temperature, coldest first
+pub fn ocv_slices(curve: &[OcvPoint]) -> Vec<TemperatureCurve> {
+    let mut slices: Vec<TemperatureCurve> = Vec::new();
+    for point in curve {
+        let temperature_c = point.temperature_c.unwrap_or(REFERENCE_TEMPERATURE_C);
+        let point = OcvPoint { temperature_c: None, ..point.clone() };
+        match slices.iter_mut().find(|s| s.temperature_c == temperature_c) {
+            Some(slice) => slice.ocv_curve.push(point),
+            None => slices.push(TemperatureCurve { temperature_c, ocv_curve: vec![point] }),
+        }
+    }
+    slices.sort_by(|a, b| a.temperature_c.total_cmp(&b.temperature_c));
+    slices
+}
+
+/// Apply `f` to each temperature of a tagged curve and tag the results again
+fn per_slice(curve: &[OcvPoint], f: impl Fn(&[OcvPoint]) -> Vec<OcvPoint>) -> Vec<OcvPoint> {
+    ocv_slices(curve)
+        .into_iter()
+        .flat_map(|slice| {
+            f(&slice.ocv_curve)
+                .into_iter()
+                .map(move |p| OcvPoint { temperature_c: Some(slice.temperature_c), ..p })
+        })
+        .collect()
+}
+
+/// SoC range covered by a curve as `(lowest, highest)`
+pub fn ocv_soc_range(curve: &[OcvPoint]) -> Option<(f64, f64)> {
+    let lowest = curve.iter().map(|p| p.soc).reduce(f64::min)?;
+    let highest = curve.iter().map(|p| p.soc).reduce(f64::max)?;
+    Some((lowest, highest))
+}
+
+/// Return a sorted copy of the curve extended to SoC 1.0 and 0.0
+///
+/// Missing endpoints are added by continuing the outermost segment at each
+/// end in a straight line. Voltages are kept non-negative. Curves with fewer
+/// than two points are returned sorted but otherwise unchanged. Like the
+/// other curve helpers below, a temperature-tagged curve is handled one
+/// temperature at a time.
+pub fn extrapolate_ocv(curve: &[OcvPoint]) -> Vec<OcvPoint> {
+    if is_temperature_tagged(curve) {
+        return per_slice(curve, extrapolate_ocv);
+    }
+    let mut sorted = sort_ocv(curve);
+    let n = sorted.len();
+    if n < 2 {
+        return sorted;
+    }
+
+    let extend = |a: &OcvPoint, b: &OcvPoint, soc: f64| {
+        let slope = (a.voltage - b.voltage) / (a.soc - b.soc);
+        OcvPoint {
+            soc,
+            voltage: (a.voltage + slope * (soc - a.soc)).max(0.0),
+            temperature_c: None,
+        }
+    };
+
+    if sorted[n - 1].soc > 0.0 {
+        let point = extend(&sorted[n - 1], &sorted[n - 2], 0.0);
+        sorted.push(point);
+    }
+    if sorted[0].soc < 1.0 {
+        let point = extend(&sorted[0], &sorted[1], 1.0);
+        sorted.insert(0, point);
+    }
+    sorted
+}
+
+/// Return a copy of the curve sorted by descending SoC with duplicate SoC points removed
+pub fn sort_ocv(curve: &[OcvPoint]) -> Vec<OcvPoint> {
+    if is_temperature_tagged(curve) {
+        return per_slice(curve, sort_ocv);
+    }
+    let mut sorted = curve.to_vec();
+    sorted.sort_by(|a, b| b.soc.total_cmp(&a.soc));
+    sorted.dedup_by(|a, b| (a.soc - b.soc).abs() < f64::EPSILON);
+    sorted
+}
+
+/// Resample an OCV curve onto `num_points` evenly spaced SoC values from 1.0 down to 0.0
+///
+/// The curve is sorted first, so the input may be in any order. SoC values
+/// outside the range covered by the input hold the nearest endpoint voltage.
+pub fn normalize_ocv(curve: &[OcvPoint], num_points: usize) -> Vec<OcvPoint> {
+    if is_temperature_tagged(curve) {
+        return per_slice(curve, |slice| normalize_ocv(slice, num_points));
+    }
+    let sorted = sort_ocv(curve);
+    if sorted.is_empty() {
+        return sorted;
+    }
+
+    let top = sorted[0].soc;
+    let bottom = sorted[sorted.len() - 1].soc;
+    let steps = num_points.max(2) - 1;
+
+    (0..=steps)
+        .map(|n| {
+            let soc = (steps - n) as f64 / steps as f64;
+            let voltage = interpolate_ocv(&sorted, soc.clamp(bottom, top));
+            // Round to 0.1 mV so the rewritten profile stays readable
+            OcvPoint {
+                soc,
+                voltage: (voltage * 10000.0).round() / 10000.0,
+                temperature_c: None,
+            }
+        })
+        .collect()
+}
+
+/// Describe problems with an OCV curve: ordering, overlaps, gaps and missing endpoints
+pub fn ocv_curve_warnings(curve: &[OcvPoint]) -> Vec<String> {
+    if is_temperature_tagged(curve) {
+        return ocv_slices(curve)
+            .iter()
+            .flat_map(|slice| {
+                ocv_curve_warnings(&slice.ocv_curve)
+                    .into_iter()
+                    .map(|w| format!("{} at {}°C", w, slice.temperature_c))
+            })
+            .collect();
+    }
+
+    let mut warnings = Vec::new();
+    if curve.is_empty() {
+        warnings.push("OCV curve is empty".to_string());
+        return warnings;
+    }
+
+    if curve.windows(2).any(|w| w[1].soc > w[0].soc) {
+        warnings.push("OCV curve is not ordered by descending SoC".to_string());
+    }
+
+    let sorted = sort_ocv(curve);
+    if sorted.len() < curve.len() {
+        warnings.push(format!(
+            "OCV curve has {} duplicate SoC point(s)",
+            curve.len() - sorted.len()
+        ));
+    }
+
+    for w in sorted.windows(2) {
+        if w[0].soc - w[1].soc > OCV_GAP_WARN {
+            warnings.push(format!(
+                "OCV curve has a gap between SoC {:.2} and {:.2}",
+                w[1].soc, w[0].soc
+            ));
+        }
+    }
+
+    if sorted.iter().any(|p| p.soc < 0.0 || p.soc > 1.0) {
+        warnings.push("OCV curve has SoC values outside 0..1".to_string());
+    }
+
+    let top = sorted[0].soc;
+    let bottom = sorted[sorted.len() - 1].soc;
+    if top < 1.0 {
+        warnings.push(format!("OCV curve does not reach SoC 1.0 (highest point is {:.2})", top));
+    }
+    if bottom > 0.0 {
+        warnings.push(format!("OCV curve does not reach SoC 0.0 (lowest point is {:.2})", bottom));
+    }
+
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Empty scratch directory for one test
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dp832_model_{}_{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn write(path: &Path, text: &str) -> String {
+        std::fs::write(path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    const CELL: &str = r#""name": "cell", "channel": 1, "capacity_ah": 1.0, "internal_resistance_ohm": 0.05,
+        "current_limit_discharge_a": 1.0, "current_limit_charge_a": 1.0,
+        "cutoff_voltage": 3.0, "max_voltage": 4.2, "rc_time_constant_ms": 100, "update_interval_ms": 100"#;
+
+    #[test]
+    fn ocv_csv_fixture_loads_sorted() {
+        let fixture = concat!(env!("CARGO_MANIFEST_DIR"), "/profiles/liion_18650_ocv.csv");
+        let curve = load_ocv_csv(fixture).unwrap();
+        assert_eq!(curve.len(), 13);
+        assert_eq!((curve[0].soc, curve[0].voltage), (1.0, 4.15));
+        assert_eq!((curve[12].soc, curve[12].voltage), (0.0, 3.00));
+        assert!(curve.windows(2).all(|w| w[0].soc > w[1].soc));
+    }
+
+    #[test]
+    fn ocv_csv_accepts_units_without_header() {
+        let dir = scratch_dir("csv_units");
+        let path = write(&dir.join("ocv.csv"), "0.0,3000mV\n1.0,4.2V\n0.5, 3.7\n");
+        let curve = load_ocv_csv(&path).unwrap();
+        let points: Vec<(f64, f64)> = curve.iter().map(|p| (p.soc, p.voltage)).collect();
+        assert_eq!(points, [(1.0, 4.2), (0.5, 3.7), (0.0, 3.0)]);
+    }
+
+    #[test]
+    fn ocv_csv_rejects_bad_rows() {
+        let dir = scratch_dir("csv_bad");
+        let path = write(&dir.join("ocv.csv"), "soc,voltage\n1.0,4.2\nhalf,3.7\n");
+        let err = load_ocv_csv(&path).unwrap_err();
+        assert!(err.contains("line 3"), "{}", err);
+    }
+
+    #[test]
+    fn inline_and_csv_curve_is_an_error() {
+        let dir = scratch_dir("csv_both");
+        write(&dir.join("ocv.csv"), "1.0,4.2\n0.0,3.0\n");
+        let profile = write(&dir.join("cell.json"), &format!(
+            r#"{{ {}, "ocv_curve_csv": "ocv.csv", "ocv_curve": [{{"soc": 1.0, "voltage": 4.2}}] }}"#, CELL));
+        let err = load_profile(&profile).unwrap_err();
+        assert!(err.contains("not both"), "{}", err);
+    }
+
+    #[test]
+    fn inherited_ocv_csv_is_relative_to_the_base() {
+        let dir = scratch_dir("csv_extends");
+        std::fs::create_dir_all(dir.join("base")).unwrap();
+        std::fs::create_dir_all(dir.join("packs")).unwrap();
+        write(&dir.join("base/ocv.csv"), "soc,voltage\n1.0,4.2\n0.0,3.0\n");
+        write(&dir.join("base/cell.json"), &format!(r#"{{ {}, "ocv_curve_csv": "ocv.csv" }}"#, CELL));
+        let pack = write(&dir.join("packs/pack.json"), r#"{ "extends": "../base/cell.json", "name": "pack" }"#);
+        let profile = load_profile(&pack).unwrap();
+        assert_eq!(profile.ocv_curve.len(), 2);
+        assert_eq!(profile.ocv_curve[0].voltage, 4.2);
+    }
+
+    /// A valid one-cell profile for the model tests to change
+    fn cell() -> BatteryProfile {
+        let curve = r#"[{"soc": 1.0, "voltage": 4.2}, {"soc": 0.5, "voltage": 3.7}, {"soc": 0.0, "voltage": 3.0}]"#;
+        serde_json::from_str(&format!(r#"{{ {}, "ocv_curve": {} }}"#, CELL, curve)).unwrap()
+    }
+
+    fn assert_invalid(profile: BatteryProfile, expected: &str) {
+        let err = profile.validate().unwrap_err();
+        assert!(err.contains(expected), "'{}' does not mention '{}'", err, expected);
+    }
+
+    #[test]
+    fn valid_profile_passes() {
+        cell().validate().unwrap();
+    }
+
+    #[test]
+    fn validate_rejects_missing_channel() {
+        assert_invalid(BatteryProfile { channel: 4, ..cell() }, "channel 4");
+        assert_invalid(BatteryProfile { channel: 0, ..cell() }, "channel 0");
+    }
+
+    #[test]
+    fn validate_rejects_zero_capacity() {
+        assert_invalid(BatteryProfile { capacity_ah: 0.0, ..cell() }, "capacity_ah");
+        assert_invalid(BatteryProfile { capacity_ah: f64::NAN, ..cell() }, "capacity_ah");
+    }
+
+    #[test]
+    fn validate_rejects_zero_resistance() {
+        assert_invalid(BatteryProfile { internal_resistance_ohm: -0.01, ..cell() }, "internal_resistance_ohm");
+    }
+
+    #[test]
+    fn validate_rejects_cutoff_at_max_voltage() {
+        assert_invalid(BatteryProfile { cutoff_voltage: 4.2, ..cell() }, "cutoff_voltage");
+    }
+
+    #[test]
+    fn validate_rejects_empty_curve() {
+        assert_invalid(BatteryProfile { ocv_curve: Vec::new(), ..cell() }, "ocv_curve is empty");
+    }
+
+    #[test]
+    fn validate_rejects_unordered_curve() {
+        let mut profile = cell();
+        profile.ocv_curve.swap(0, 1);
+        assert_invalid(profile, "must decrease");
+
+        let mut profile = cell();
+        profile.ocv_curve[1].soc = 1.0;
+        assert_invalid(profile, "must decrease");
+    }
+
+    #[test]
+    fn validate_rejects_soc_outside_range() {
+        let mut profile = cell();
+        profile.ocv_curve[0].soc = 1.2;
+        assert_invalid(profile, "outside 0-1");
+    }
+
+    #[test]
+    fn validate_rejects_peukert_out_of_range() {
+        assert_invalid(BatteryProfile { peukert_exponent: Some(5.0), ..cell() }, "peukert_exponent");
+        assert_invalid(BatteryProfile { peukert_exponent: Some(-1.0), ..cell() }, "peukert_exponent");
+        BatteryProfile { peukert_exponent: Some(1.2), ..cell() }.validate().unwrap();
+    }
+
+    #[test]
+    fn peukert_scales_discharge_above_rated_current() {
+        let plain = cell();
+        assert_eq!(plain.soc_current(2.0), 2.0);
+        let linear = BatteryProfile { peukert_exponent: Some(1.0), ..cell() };
+        assert_eq!(linear.soc_current(2.0), 2.0);
+        assert_eq!(linear.capacity_at(2.0), 1.0);
+
+        // 1 Ah cell: 1 A is the C/1 rate
+        let peukert = BatteryProfile { peukert_exponent: Some(1.2), ..cell() };
+        assert!((peukert.soc_current(1.0) - 1.0).abs() < 1e-12);
+        assert!((peukert.soc_current(2.0) - 2.0 * 2f64.powf(0.2)).abs() < 1e-12);
+        assert!(peukert.soc_current(2.0) > 2.0);
+        assert!(peukert.soc_current(0.5) < 0.5);
+        assert!(peukert.capacity_at(2.0) < peukert.capacity_ah);
+        // Charging is not scaled
+        assert_eq!(peukert.soc_current(-2.0), -2.0);
+    }
+
+    #[test]
+    fn thevenin_branch_follows_the_closed_form() {
+        let profile = BatteryProfile {
+            r1_ohm: Some(0.01),
+            c1_farad: Some(2000.0),
+            r2_ohm: Some(0.02),
+            c2_farad: Some(50000.0),
+            ..cell()
+        };
+        let branches = profile.rc_branches().unwrap().unwrap();
+        let (r1, tau) = (branches[0].r_ohm, branches[0].tau_s);
+        assert_eq!(tau, 20.0);
+
+        // A step of 2 A from rest, in steps of 100 ms
+        let i = 2.0;
+        let mut state = TheveninState::default();
+        let steps_per_tau = 200;
+        for _ in 0..steps_per_tau {
+            state.step(&branches, i, tau / steps_per_tau as f64);
+        }
+        let closed_form = i * r1 * (1.0 - (-1.0f64).exp());
+        assert!((state.v1 - closed_form).abs() < 1e-12, "{} vs {}", state.v1, closed_form);
+
+        for _ in 0..4 * steps_per_tau {
+            state.step(&branches, i, tau / steps_per_tau as f64);
+        }
+        assert!((state.v1 - i * r1).abs() < 0.01 * i * r1, "{} did not settle at {}", state.v1, i * r1);
+    }
+
+    #[test]
+    fn partial_thevenin_model_is_rejected() {
+        assert!(BatteryProfile { r1_ohm: Some(0.01), ..cell() }.rc_branches().is_err());
+        assert!(cell().rc_branches().unwrap().is_none());
+    }
+
+    #[test]
+    fn resistance_follows_temperature() {
+        let mut at_reference = BatteryProfile { resistance_temp_coeff: Some(-0.01), ..cell() };
+        at_reference.apply_temperature(Some(25.0)).unwrap();
+        assert!((at_reference.internal_resistance_ohm - 0.05).abs() < 1e-12);
+
+        let mut cold = BatteryProfile { resistance_temp_coeff: Some(-0.01), ..cell() };
+        cold.apply_temperature(Some(5.0)).unwrap();
+        assert!((cold.internal_resistance_ohm - 0.06).abs() < 1e-12);
+        assert!(cold.resistance_temp_coeff.is_none());
+
+        // The profile's own temperature applies when the simulator sets none
+        let mut own = BatteryProfile { resistance_temp_coeff: Some(-0.01), temperature_c: Some(35.0), ..cell() };
+        own.apply_temperature(None).unwrap();
+        assert!((own.internal_resistance_ohm - 0.045).abs() < 1e-12);
+    }
+
+    #[test]
+    fn resistance_coefficient_must_leave_some_resistance() {
+        let mut profile = BatteryProfile { resistance_temp_coeff: Some(-0.05), ..cell() };
+        let err = profile.apply_temperature(Some(45.0)).unwrap_err();
+        assert!(err.contains("resistance_temp_coeff"), "{}", err);
+    }
+
+    /// A curve over SoC 0-1 at 0 °C and 40 °C, 0.1 V apart
+    fn tagged_curve() -> Vec<OcvPoint> {
+        let point = |soc, voltage, t| OcvPoint { soc, voltage, temperature_c: Some(t) };
+        vec![point(1.0, 4.1, 0.0), point(0.0, 2.9, 0.0), point(1.0, 4.2, 40.0), point(0.0, 3.0, 40.0)]
+    }
+
+    #[test]
+    fn ocv_2d_at_the_corners() {
+        let curve = tagged_curve();
+        assert!((interpolate_ocv_2d(&curve, 1.0, 0.0) - 4.1).abs() < 1e-12);
+        assert!((interpolate_ocv_2d(&curve, 0.0, 0.0) - 2.9).abs() < 1e-12);
+        assert!((interpolate_ocv_2d(&curve, 1.0, 40.0) - 4.2).abs() < 1e-12);
+        assert!((interpolate_ocv_2d(&curve, 0.0, 40.0) - 3.0).abs() < 1e-12);
+        // Outside the measured temperatures the nearest slice holds
+        assert!((interpolate_ocv_2d(&curve, 1.0, -20.0) - 4.1).abs() < 1e-12);
+    }
+
+    #[test]
+    fn ocv_2d_between_slices() {
+        let curve = tagged_curve();
+        assert!((interpolate_ocv_2d(&curve, 1.0, 10.0) - 4.125).abs() < 1e-12);
+        assert!((interpolate_ocv_2d(&curve, 0.5, 20.0) - 3.55).abs() < 1e-12);
+        assert_eq!(ocv_slices(&curve).len(), 2);
+    }
+
+    #[test]
+    fn untagged_curve_ignores_temperature() {
+        let curve = cell().ocv_curve;
+        assert_eq!(interpolate_ocv_2d(&curve, 0.75, -10.0), interpolate_ocv(&curve, 0.75));
+    }
+
+    #[test]
+    fn current_direction_picks_the_hysteresis_curve() {
+        let charge = vec![OcvPoint { soc: 1.0, voltage: 4.25, temperature_c: None }, OcvPoint { soc: 0.0, voltage: 3.05, temperature_c: None }];
+        let discharge = vec![OcvPoint { soc: 1.0, voltage: 4.15, temperature_c: None }, OcvPoint { soc: 0.0, voltage: 2.95, temperature_c: None }];
+        let profile = BatteryProfile { ocv_curve_charge: charge, ocv_curve_discharge: discharge, ..cell() };
+
+        let direction = CurrentDirection::Discharge;
+        assert_eq!(direction.after(0.5), CurrentDirection::Discharge);
+        assert_eq!(direction.after(-0.5), CurrentDirection::Charge);
+        // Within the deadband the previous curve is kept
+        assert_eq!(CurrentDirection::Charge.after(0.005), CurrentDirection::Charge);
+        assert_eq!(CurrentDirection::Discharge.after(-0.005), CurrentDirection::Discharge);
+
+        assert_eq!(profile.ocv_at(1.0, None, direction.after(0.5)), 4.15);
+        assert_eq!(profile.ocv_at(1.0, None, direction.after(-0.5)), 4.25);
+    }
+
+    #[test]
+    fn missing_hysteresis_curve_falls_back_to_ocv_curve() {
+        let charge = vec![OcvPoint { soc: 1.0, voltage: 4.25, temperature_c: None }, OcvPoint { soc: 0.0, voltage: 3.05, temperature_c: None }];
+        let profile = BatteryProfile { ocv_curve_charge: charge, ..cell() };
+        assert!(profile.has_hysteresis());
+        assert_eq!(profile.ocv_curve_for(CurrentDirection::Charge)[0].voltage, 4.25);
+        assert_eq!(profile.ocv_curve_for(CurrentDirection::Discharge)[0].voltage, 4.2);
+        assert!(!cell().has_hysteresis());
+    }
+
+    #[test]
+    fn soc_after_integrates_current_and_self_discharge() {
+        let profile = cell();
+        assert!((profile.soc_after(1.0, 0.5, 3600.0) - 0.5).abs() < 1e-12);
+        assert!((profile.soc_after(0.5, -0.25, 3600.0) - 0.75).abs() < 1e-12);
+        assert_eq!(profile.soc_after(0.1, 1.0, 3600.0), 0.0);
+        assert_eq!(profile.soc_after(0.9, -1.0, 3600.0), 1.0);
+
+        let peukert = BatteryProfile { peukert_exponent: Some(1.2), ..cell() };
+        assert!((peukert.soc_after(1.0, 0.5, 3600.0) - (1.0 - peukert.soc_current(0.5))).abs() < 1e-12);
+    }
+
+    #[test]
+    fn self_discharge_over_a_day_at_rest() {
+        let profile = BatteryProfile { self_discharge_percent_per_day: Some(1.0), ..cell() };
+        let soc = (0..86400).fold(0.8, |soc, _| profile.soc_after(soc, 0.0, 1.0));
+        assert!((soc - 0.79).abs() < 1e-9, "{}", soc);
+        assert_eq!(cell().self_discharge_soc(86400.0), 0.0);
+    }
+
+    /// Every step of a charge with no load: (elapsed s, phase, charger current, terminal voltage)
+    fn charge(profile: &BatteryProfile, dt: f64) -> Vec<(f64, ChargePhase, f64, f64)> {
+        let mut soc = profile.initial_soc();
+        let mut terminal = profile.ocv_at(soc, None, CurrentDirection::Charge);
+        let mut charger = Charger::new(profile, terminal).unwrap();
+        let mut steps = Vec::new();
+        while charger.phase != ChargePhase::Charged {
+            let ocv = profile.ocv_at(soc, None, CurrentDirection::Charge);
+            let current = charger.current(profile, ocv, 0.0);
+            let phase = charger.phase;
+            soc = profile.soc_after(soc, -current, dt);
+            terminal = ocv + current * profile.internal_resistance_ohm;
+            charger.update(terminal, -current, soc);
+            steps.push((steps.len() as f64 * dt, phase, current, terminal));
+            assert!(steps.len() < 100_000, "charge did not terminate");
+        }
+        steps
+    }
+
+    #[test]
+    fn charge_runs_cc_then_cv_until_termination() {
+        let profile = BatteryProfile { mode: ProfileMode::Charge, ..cell() };
+        let steps = charge(&profile, 1.0);
+
+        let cv_start = steps.iter().position(|s| s.1 == ChargePhase::ConstantVoltage).expect("charge never reached CV");
+        assert!(steps[..cv_start].iter().all(|s| s.1 == ChargePhase::ConstantCurrent && s.2 == 1.0));
+        for &(_, _, current, terminal) in &steps[cv_start..] {
+            assert!(current < 1.0);
+            assert!((terminal - profile.max_voltage).abs() < 1e-9, "CV phase left max_voltage at {} V", terminal);
+        }
+        assert!(steps.last().unwrap().2 < 0.1);
+
+        // A 1Ah cell at 1A is full in about an hour, the CV taper adding a few minutes
+        let hours = steps.len() as f64 / 3600.0;
+        assert!((0.95..1.15).contains(&hours), "charged in {:.2} h", hours);
+    }
+
+    #[test]
+    fn precharge_defaults_to_a_tenth_of_the_charge_limit() {
+        let profile = BatteryProfile { precharge_voltage: Some(3.3), ..cell() };
+        assert_eq!(profile.precharge_limits(), Ok(Some((3.3, 0.1))));
+        assert_eq!(cell().precharge_limits(), Ok(None));
+    }
+
+    #[test]
+    fn precharge_limits_are_checked() {
+        let above_max = BatteryProfile { precharge_voltage: Some(4.2), ..cell() };
+        assert!(above_max.precharge_limits().unwrap_err().contains("precharge_voltage"));
+        let at_limit = BatteryProfile { precharge_voltage: Some(3.3), precharge_current_a: Some(1.0), ..cell() };
+        assert!(at_limit.precharge_limits().unwrap_err().contains("precharge_current_a"));
+    }
+
+    #[test]
+    fn charge_trickles_below_the_precharge_voltage() {
+        let profile = BatteryProfile {
+            mode: ProfileMode::Charge,
+            precharge_voltage: Some(3.3),
+            precharge_current_a: Some(0.05),
+            ..cell()
+        };
+        let steps = charge(&profile, 10.0);
+
+        let cc_start = steps.iter().position(|s| s.1 == ChargePhase::ConstantCurrent).expect("pre-charge never ended");
+        assert!(cc_start > 0);
+        assert!(steps[..cc_start].iter().all(|s| s.1 == ChargePhase::Precharge && s.2 == 0.05));
+        // The step that reaches the threshold is the last one at the trickle
+        assert!(steps[..cc_start - 1].iter().all(|s| s.3 < 3.3));
+        assert!(steps[cc_start - 1].3 >= 3.3);
+        assert_eq!(steps[cc_start].2, 1.0);
+    }
+
+    #[test]
+    fn charger_starts_at_cc_above_the_precharge_voltage() {
+        let profile = BatteryProfile { mode: ProfileMode::Charge, precharge_voltage: Some(2.5), ..cell() };
+        let charger = Charger::new(&profile, profile.ocv_at(0.0, None, CurrentDirection::Charge)).unwrap();
+        assert_eq!(charger.phase, ChargePhase::ConstantCurrent);
+        assert_eq!(charger.limit(), 1.0);
+    }
 }
@@ -0,0 +1,74 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (C) 2025 Marcus Folkesson
+
+//! Synthetic measurement noise for testing filters and estimators
+
+use crate::units;
+use serde::Deserialize;
+
+/// `[noise]` configuration: Gaussian noise and sinusoidal ripple on the model current
+#[derive(Debug, Clone, Deserialize)]
+pub struct NoiseConfig {
+    /// Standard deviation of the Gaussian noise in amperes
+    #[serde(default, deserialize_with = "units::amps_opt")]
+    pub current_stddev_a: Option<f64>,
+    /// Peak amplitude of the periodic ripple in amperes
+    #[serde(default, deserialize_with = "units::amps_opt")]
+    pub ripple_amplitude_a: Option<f64>,
+    /// Ripple frequency in hertz
+    pub ripple_frequency_hz: Option<f64>,
+    /// Seed for reproducible noise (each channel offsets it by its number)
+    pub seed: Option<u64>,
+}
+
+/// Per-channel noise source
+///
+/// Uses a xorshift64* generator with the Box-Muller transform, which is
+/// plenty for test noise and avoids pulling in a random number crate.
+pub struct NoiseGenerator {
+    state: u64,
+    stddev: f64,
+    ripple_amplitude: f64,
+    ripple_frequency: f64,
+}
+
+impl NoiseGenerator {
+    pub fn new(cfg: &NoiseConfig, channel: u8) -> Self {
+        let seed = cfg.seed.unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(1)
+        });
+        Self {
+            // xorshift must not start from zero
+            state: seed.wrapping_add(channel as u64).max(1),
+            stddev: cfg.current_stddev_a.unwrap_or(0.0),
+            ripple_amplitude: cfg.ripple_amplitude_a.unwrap_or(0.0),
+            ripple_frequency: cfg.ripple_frequency_hz.unwrap_or(0.0),
+        }
+    }
+
+    /// Uniform sample in (0, 1]
+    fn next_uniform(&mut self) -> f64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let bits = self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11;
+        (bits as f64 + 1.0) / (1u64 << 53) as f64
+    }
+
+    /// Standard normal sample
+    fn next_gaussian(&mut self) -> f64 {
+        let u1 = self.next_uniform();
+        let u2 = self.next_uniform();
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+
+    /// Noise plus ripple to add to the current at `t` seconds into the run
+    pub fn sample(&mut self, t: f64) -> f64 {
+        let noise = if self.stddev > 0.0 { self.stddev * self.next_gaussian() } else { 0.0 };
+        let ripple = self.ripple_amplitude * (2.0 * std::f64::consts::PI * self.ripple_frequency * t).sin();
+        noise + ripple
+    }
+}
@@ -2,7 +2,7 @@
 // Copyright (C) 2025 Marcus Folkesson
 
 use crossterm::{
-    event::{self, Event, KeyCode},
+    event::KeyCode,
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
@@ -11,19 +11,29 @@ use ratatui::{
     layout::{Constraint, Direction, Layout},
     style::{Color, Modifier, Style},
     symbols,
+    text::{Line, Span},
     widgets::{Axis, Block, Borders, Chart, Dataset, Gauge, GraphType, Paragraph},
     Terminal,
 };
 use std::collections::VecDeque;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::common::{RuntimeState, ChannelState};
+use crate::common::{self, stop_on_signal, LatencyWindow, LogWriters, RuntimeState, ChannelState};
+use crate::keylog::KeyInput;
+
+/// Interval between status lines in line-output mode
+const HEADLESS_STATUS_INTERVAL: Duration = Duration::from_secs(10);
 
 struct ChannelHistory {
     voltage: VecDeque<(f64, f64)>,
     current: VecDeque<(f64, f64)>,
     power: VecDeque<(f64, f64)>,
+    soc: VecDeque<(f64, f64)>,
+    /// OCV minus terminal voltage in mV: IR drop plus RC transient
+    sag: VecDeque<(f64, f64)>,
 }
 
 impl ChannelHistory {
@@ -32,13 +42,17 @@ impl ChannelHistory {
             voltage: VecDeque::new(),
             current: VecDeque::new(),
             power: VecDeque::new(),
+            soc: VecDeque::new(),
+            sag: VecDeque::new(),
         }
     }
 
-    fn add_sample(&mut self, time: f64, voltage: f64, current: f64, power: f64, max_points: usize) {
-        self.voltage.push_back((time, voltage));
-        self.current.push_back((time, current));
-        self.power.push_back((time, power));
+    fn add_sample(&mut self, time: f64, ch: &ChannelState, max_points: usize) {
+        self.voltage.push_back((time, ch.voltage));
+        self.current.push_back((time, ch.current));
+        self.power.push_back((time, ch.power));
+        self.soc.push_back((time, ch.soc * 100.0));
+        self.sag.push_back((time, sag_mv(ch)));
 
         if self.voltage.len() > max_points {
             self.voltage.pop_front();
@@ -49,6 +63,12 @@ impl ChannelHistory {
         if self.power.len() > max_points {
             self.power.pop_front();
         }
+        if self.soc.len() > max_points {
+            self.soc.pop_front();
+        }
+        if self.sag.len() > max_points {
+            self.sag.pop_front();
+        }
     }
 
     fn is_empty(&self) -> bool {
@@ -60,6 +80,8 @@ struct HistoryData {
     channels: [ChannelHistory; 3],
     time: f64,
     max_points: usize,
+    /// History times of markers added with `m`
+    markers: VecDeque<f64>,
 }
 
 impl HistoryData {
@@ -68,16 +90,34 @@ impl HistoryData {
             channels: [ChannelHistory::new(), ChannelHistory::new(), ChannelHistory::new()],
             time: 0.0,
             max_points,
+            markers: VecDeque::new(),
         }
     }
 
+    fn add_marker(&mut self) {
+        self.markers.push_back(self.time);
+        if self.markers.len() > self.max_points {
+            self.markers.pop_front();
+        }
+    }
+
+    /// Vertical lines at the markers inside the visible time window
+    fn marker_lines(&self, y_bounds: (f64, f64)) -> Vec<[(f64, f64); 2]> {
+        let (start, end) = self.get_time_bounds();
+        self.markers
+            .iter()
+            .filter(|&&t| t >= start && t <= end)
+            .map(|&t| [(t, y_bounds.0), (t, y_bounds.1)])
+            .collect()
+    }
+
     fn update_time(&mut self, dt: f64) {
         self.time += dt;
     }
 
-    fn add_sample(&mut self, channel: usize, voltage: f64, current: f64, power: f64) {
+    fn add_sample(&mut self, channel: usize, ch: &ChannelState) {
         if channel < 3 {
-            self.channels[channel].add_sample(self.time, voltage, current, power, self.max_points);
+            self.channels[channel].add_sample(self.time, ch, self.max_points);
         }
     }
 
@@ -113,6 +153,18 @@ impl HistoryData {
         }
     }
 
+    fn get_sag_bounds(&self, channel: usize) -> (f64, f64) {
+        if channel >= 3 || self.channels[channel].is_empty() {
+            (0.0, 100.0)
+        } else {
+            let values: Vec<f64> = self.channels[channel].sag.iter().map(|(_, v)| *v).collect();
+            let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+            let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+            let margin = (max - min).abs() * 0.1 + 1.0;
+            (min - margin, max + margin)
+        }
+    }
+
     fn get_current_bounds(&self, channel: usize) -> (f64, f64) {
         if channel >= 3 || self.channels[channel].is_empty() {
             (0.0, 5.0)
@@ -138,8 +190,26 @@ impl HistoryData {
     }
 }
 
-pub fn run_tui(state: Arc<Mutex<RuntimeState>>, addr: String) {
-    enable_raw_mode().unwrap();
+/// Run the TUI until `q` or the end of the simulation
+///
+/// With `detach_on_quit` the simulation keeps running after `q`. Keys come
+/// from `input`, which may also record them or replay a script.
+pub fn run_tui(
+    state: Arc<Mutex<RuntimeState>>,
+    writers: Arc<Mutex<LogWriters>>,
+    addr: String,
+    title: String,
+    detach_on_quit: bool,
+    mut input: KeyInput,
+) {
+    if !std::io::stdout().is_terminal() {
+        println!("stdout is not a terminal; the TUI is unavailable, using line output");
+        return run_headless(state);
+    }
+    if let Err(e) = enable_raw_mode() {
+        println!("Cannot enable raw terminal mode ({}); the TUI is unavailable, using line output", e);
+        return run_headless(state);
+    }
     let mut stdout = std::io::stdout();
     execute!(stdout, EnterAlternateScreen).unwrap();
 
@@ -148,8 +218,19 @@ pub fn run_tui(state: Arc<Mutex<RuntimeState>>, addr: String) {
 
     let mut history = HistoryData::new(200);
     let mut last_update = std::time::Instant::now();
+    // Label being typed after `m`; other keys are ignored until Enter or Esc
+    let mut marker_input: Option<String> = None;
+    // Channel whose RC time constant the tuning keys change
+    let mut selected: usize = state.lock().unwrap().channels.iter().position(|ch| ch.enabled && !ch.monitor_only).unwrap_or(0);
+    // `v` swaps the voltage charts for the OCV - terminal voltage sag
+    let mut show_sag = false;
+    // `d`: one row per channel plus the selected channel in detail
+    let mut dashboard = false;
 
     loop {
+        if !state.lock().unwrap().running {
+            break;
+        }
         let now = std::time::Instant::now();
         let dt = now.duration_since(last_update).as_secs_f64();
         
@@ -175,7 +256,7 @@ pub fn run_tui(state: Arc<Mutex<RuntimeState>>, addr: String) {
 
                     f.render_widget(
                         Paragraph::new(format!("Device: {}", addr))
-                            .block(Block::default().borders(Borders::ALL).title("DP832 Battery Simulator")),
+                            .block(Block::default().borders(Borders::ALL).title(title.as_str())),
                         chunks[0],
                     );
 
@@ -196,8 +277,13 @@ pub fn run_tui(state: Arc<Mutex<RuntimeState>>, addr: String) {
 
                     // Split main area vertically for channels + footer
                     let mut constraints = vec![Constraint::Length(3)]; // Header
-                    for _ in 0..num_enabled {
-                        constraints.push(Constraint::Percentage((100 / num_enabled as u16).max(1)));
+                    if dashboard {
+                        constraints.push(Constraint::Length(num_enabled as u16 + 2)); // Channel rows
+                        constraints.push(Constraint::Min(0)); // Selected channel
+                    } else {
+                        for _ in 0..num_enabled {
+                            constraints.push(Constraint::Percentage((100 / num_enabled as u16).max(1)));
+                        }
                     }
                     constraints.push(Constraint::Length(3)); // Footer
 
@@ -207,26 +293,59 @@ pub fn run_tui(state: Arc<Mutex<RuntimeState>>, addr: String) {
                         .split(vertical_split[0]);
 
                     // Header
+                    let countdown = match s.deadline {
+                        Some(deadline) => {
+                            let left = deadline.saturating_duration_since(Instant::now()).as_secs();
+                            format!("   Stops in {:02}:{:02}:{:02}", left / 3600, left / 60 % 60, left % 60)
+                        }
+                        None => String::new(),
+                    };
                     f.render_widget(
-                        Paragraph::new(format!("Device: {}   Active Channels: {}", addr, num_enabled))
-                            .block(Block::default().borders(Borders::ALL).title("DP832 Battery Simulator")),
+                        Paragraph::new(format!("Device: {}   Active Channels: {}   Total: {:.2} W{}",
+                                               addr, num_enabled, total_power(&s.channels), countdown))
+                            .block(Block::default().borders(Borders::ALL).title(title.as_str())),
                         main_chunks[0],
                     );
 
+                    if dashboard {
+                        // The selection may point at a channel that is not running
+                        let detail = if enabled_channels.contains(&selected) { selected } else { enabled_channels[0] };
+                        render_channel_rows(f, main_chunks[1], &s.channels, &enabled_channels, detail);
+                        render_channel(f, main_chunks[2], &s.channels[detail], &history, detail, true, show_sag);
+                    }
+
                     // Render each enabled channel
-                    for (idx, &ch_num) in enabled_channels.iter().enumerate() {
+                    for (idx, &ch_num) in enabled_channels.iter().enumerate().filter(|_| !dashboard) {
                         render_channel(
                             f,
                             main_chunks[idx + 1],
                             &s.channels[ch_num],
                             &history,
                             ch_num,
+                            ch_num == selected,
+                            show_sag,
                         );
                     }
 
                     // Footer
+                    let swap = s.channels.iter().enumerate().find_map(|(i, ch)| Some((i, ch.swap_prompt.as_ref()?)));
+                    let footer = match (&marker_input, &s.frozen, swap) {
+                        (Some(label), _, _) => format!("Marker label: {}_   (Enter: add   Esc: cancel)", label),
+                        (None, Some(error), _) => format!("FROZEN on {}   c: resume   q: quit", error),
+                        (None, None, Some((i, next))) => {
+                            format!("CH{}: swap the cell for '{}', then press Enter   q: quit", i + 1, next)
+                        }
+                        (None, None, None) => format!("{}   r: reset SoC   m: add marker   z: zero Ah/Wh   Tab: select   +/-: RC   w: save tuned   v: voltage/sag   d: dashboard   l/s: clear logs",
+                                        if detach_on_quit { "q: detach" } else { "q: quit" }),
+                    };
+                    let footer_style = match (&marker_input, &s.frozen, swap) {
+                        (None, Some(_), _) => Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+                        (None, None, Some(_)) => Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD),
+                        _ => Style::default(),
+                    };
                     f.render_widget(
-                        Paragraph::new("q: quit   r: reset SoC   l: clear event log   s: clear SCPI log")
+                        Paragraph::new(footer)
+                            .style(footer_style)
                             .block(Block::default().borders(Borders::ALL)),
                         main_chunks[main_chunks.len() - 1],
                     );
@@ -276,9 +395,14 @@ pub fn run_tui(state: Arc<Mutex<RuntimeState>>, addr: String) {
                         .map(|msg| format!("{}\n", msg))
                         .collect();
                     
+                    let scpi_title = match LatencyWindow::combined(&s.scpi_latency).summary() {
+                        Some(summary) => format!("SCPI Commands ({})", summary),
+                        None => "SCPI Commands".to_string(),
+                    };
+
                     f.render_widget(
                         Paragraph::new(scpi_log_text)
-                            .block(Block::default().borders(Borders::ALL).title("SCPI Commands"))
+                            .block(Block::default().borders(Borders::ALL).title(scpi_title))
                             .style(Style::default().fg(Color::DarkGray))
                             .scroll((scpi_scroll, 0)),
                         log_split[1],
@@ -293,44 +417,212 @@ pub fn run_tui(state: Arc<Mutex<RuntimeState>>, addr: String) {
             history.update_time(dt);
             for (ch_num, ch) in s.channels.iter().enumerate() {
                 if ch.enabled {
-                    history.add_sample(ch_num, ch.voltage, ch.current, ch.power);
+                    history.add_sample(ch_num, ch);
                 }
             }
             last_update = now;
         }
 
         // Input handling
-        if event::poll(Duration::from_millis(100)).unwrap() {
-            if let Event::Key(k) = event::read().unwrap() {
+        if let Some(k) = input.next_key(Duration::from_millis(100)).unwrap() {
+            if let Some(label) = marker_input.as_mut() {
                 match k.code {
-                    KeyCode::Char('q') => {
-                        state.lock().unwrap().running = false;
-                        break;
+                    KeyCode::Char(c) if label.len() < 60 => label.push(c),
+                    KeyCode::Backspace => {
+                        label.pop();
                     }
-                    KeyCode::Char('r') => {
-                        let mut s = state.lock().unwrap();
-                        for ch in &mut s.channels {
-                            if ch.enabled {
-                                ch.soc = 1.0;
+                    KeyCode::Enter => {
+                        let label = marker_input.take().unwrap_or_default().trim().to_string();
+                        if !label.is_empty() {
+                            let msg = format!("Marker: {}", label);
+                            if let Ok(mut w) = writers.lock() {
+                                w.write_event(&msg);
                             }
+                            let mut s = state.lock().unwrap();
+                            s.add_log(msg);
+                            s.markers.push(label);
+                            history.add_marker();
+                        }
+                    }
+                    KeyCode::Esc => marker_input = None,
+                    _ => {}
+                }
+                continue;
+            }
+            match k.code {
+                KeyCode::Char('m') => marker_input = Some(String::new()),
+                KeyCode::Char('v') => show_sag = !show_sag,
+                KeyCode::Char('d') => dashboard = !dashboard,
+                KeyCode::Char(c @ '1'..='3') if dashboard => {
+                    let ch = c as usize - '1' as usize;
+                    if state.lock().unwrap().channels[ch].enabled {
+                        selected = ch;
+                    }
+                }
+                KeyCode::Tab => {
+                    let s = state.lock().unwrap();
+                    let tunable = |i: &usize| s.channels[*i].enabled && !s.channels[*i].monitor_only;
+                    if let Some(next) = (1..=3).map(|n| (selected + n) % 3).find(tunable) {
+                        selected = next;
+                    }
+                }
+                KeyCode::Char(c @ ('+' | '=' | '-')) => {
+                    // 10% steps so both short and long time constants tune quickly
+                    let mut s = state.lock().unwrap();
+                    let ch = &mut s.channels[selected];
+                    if ch.enabled && !ch.monitor_only {
+                        let step = (ch.rc_time_constant_ms / 10).max(1);
+                        ch.rc_time_constant_ms = if c == '-' {
+                            ch.rc_time_constant_ms.saturating_sub(step).max(1)
+                        } else {
+                            ch.rc_time_constant_ms + step
+                        };
+                    }
+                }
+                KeyCode::Char('w') => {
+                    let mut s = state.lock().unwrap();
+                    if !s.channels[selected].monitor_only {
+                        s.channels[selected].save_tuned = true;
+                    }
+                }
+                KeyCode::Char('q') => {
+                    if !detach_on_quit {
+                        state.lock().unwrap().running = false;
+                    }
+                    break;
+                }
+                KeyCode::Char('r') => {
+                    let mut s = state.lock().unwrap();
+                    for ch in &mut s.channels {
+                        if ch.enabled {
+                            ch.soc = 1.0;
                         }
                     }
-                    KeyCode::Char('l') => {
-                        let mut s = state.lock().unwrap();
-                        s.log_messages.clear();
+                }
+                KeyCode::Enter => {
+                    let mut s = state.lock().unwrap();
+                    if let Some((idx, ch)) = s.channels.iter_mut().enumerate().find(|(_, ch)| ch.swap_prompt.is_some()) {
+                        ch.swap_prompt = None;
+                        let msg = format!("CH{}: Cell swap confirmed", idx + 1);
+                        if let Ok(mut w) = writers.lock() {
+                            w.write_event(&msg);
+                        }
+                        s.add_log(msg);
                     }
-                    KeyCode::Char('s') => {
-                        let mut s = state.lock().unwrap();
-                        s.scpi_log_messages.clear();
+                }
+                KeyCode::Char('z') => {
+                    // Only the interval counters; SoC and the lifetime totals carry on
+                    let mut s = state.lock().unwrap();
+                    let mut zeroed = Vec::new();
+                    for (idx, ch) in s.channels.iter_mut().enumerate().filter(|(_, ch)| ch.enabled && !ch.monitor_only) {
+                        zeroed.push(format!("CH{} {:.3}Ah/{:.3}Wh", idx + 1,
+                                            ch.charge_ah - ch.counter_base.0, ch.energy_wh - ch.counter_base.1));
+                        ch.counter_base = (ch.charge_ah, ch.energy_wh);
                     }
-                    _ => {}
+                    if !zeroed.is_empty() {
+                        let msg = format!("Counters zeroed (since last zero: {})", zeroed.join(", "));
+                        if let Ok(mut w) = writers.lock() {
+                            w.write_event(&msg);
+                        }
+                        s.add_log(msg);
+                    }
+                }
+                KeyCode::Char('c') => {
+                    let resumed = state.lock().unwrap().frozen.take().is_some();
+                    if resumed {
+                        if let Ok(mut w) = writers.lock() {
+                            w.write_event("Resuming after freeze");
+                        }
+                        state.lock().unwrap().add_log("Resuming after freeze".to_string());
+                    }
+                }
+                KeyCode::Char('l') => {
+                    let mut s = state.lock().unwrap();
+                    s.log_messages.clear();
                 }
+                KeyCode::Char('s') => {
+                    let mut s = state.lock().unwrap();
+                    s.scpi_log_messages.clear();
+                }
+                _ => {}
             }
         }
     }
 
     disable_raw_mode().unwrap();
     execute!(terminal.backend_mut(), LeaveAlternateScreen).unwrap();
+
+    if detach_on_quit && state.lock().unwrap().running {
+        run_detached(state, writers);
+    }
+}
+
+/// Keep simulating without a terminal until SIGTERM/SIGINT or a stop file
+///
+/// Nothing is printed after the initial message, so the terminal can be
+/// closed; events keep going to the log files.
+fn run_detached(state: Arc<Mutex<RuntimeState>>, writers: Arc<Mutex<LogWriters>>) {
+    let pid = std::process::id();
+    let stop_file = std::path::PathBuf::from(format!("logs/stop_{}", pid));
+    println!("Detached: still simulating and logging to logs/ (PID {})", pid);
+    println!("Stop with `kill {}` or `touch {}`", pid, stop_file.display());
+
+    // Closing the terminal sends SIGHUP, which must not end the run
+    let _ = signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::new(AtomicBool::new(false)));
+    let stop = stop_on_signal();
+    let msg = format!("Detached from the terminal (PID {}, stop file {})", pid, stop_file.display());
+    if let Ok(mut w) = writers.lock() {
+        w.write_event(&msg);
+    }
+    state.lock().unwrap().add_log(msg);
+
+    while state.lock().unwrap().running {
+        if stop.load(Ordering::Relaxed) || stop_file.exists() {
+            let _ = std::fs::remove_file(&stop_file);
+            state.lock().unwrap().running = false;
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// Line-output mode: print event messages and a periodic status line
+///
+/// Runs until the simulation stops or SIGINT/SIGTERM is received, which
+/// stops the channels the same way as `q` in the TUI.
+pub fn run_headless(state: Arc<Mutex<RuntimeState>>) {
+    let stop = stop_on_signal();
+    state.lock().unwrap().echo_logs = true;
+    println!("Press Ctrl-C to stop");
+
+    let mut last_status = Instant::now();
+    loop {
+        std::thread::sleep(Duration::from_millis(200));
+
+        if stop.load(Ordering::Relaxed) {
+            println!("Stopping...");
+            state.lock().unwrap().running = false;
+            break;
+        }
+
+        let s = state.lock().unwrap().clone();
+        if !s.running {
+            break;
+        }
+
+        if last_status.elapsed() >= HEADLESS_STATUS_INTERVAL {
+            last_status = Instant::now();
+            for (idx, ch) in s.channels.iter().enumerate().filter(|(_, ch)| ch.enabled) {
+                if ch.monitor_only {
+                    println!("CH{} monitor: {:.3}V {:.3}A {:.2}W", idx + 1, ch.voltage, ch.current, ch.power);
+                } else {
+                    println!("CH{} SoC {:5.1}%: {:.3}V {:.3}A {:.2}W (OCV {:.3}V, {})",
+                             idx + 1, ch.soc * 100.0, ch.voltage, ch.current, ch.power, ch.ocv, remaining_text(ch));
+                }
+            }
+            println!("Total: {:.2}W", total_power(&s.channels));
+        }
+    }
 }
 
 fn render_channel(
@@ -339,6 +631,8 @@ fn render_channel(
     channel: &ChannelState,
     history: &HistoryData,
     ch_num: usize,
+    selected: bool,
+    show_sag: bool,
 ) {
     let chunks = Layout::default()
         .direction(Direction::Horizontal)
@@ -348,23 +642,57 @@ fn render_channel(
         ])
         .split(area);
 
-    // Left side: Metrics and SoC
+    // Left side: SoC, load and metrics
     let left_chunks = Layout::default()
         .direction(Direction::Vertical)
         .constraints([
             Constraint::Length(5),
+            Constraint::Length(if channel.current_limit > 0.0 { 3 } else { 0 }),
             Constraint::Min(0),
         ])
         .split(chunks[0]);
 
-    // SoC gauge
-    f.render_widget(
-        Gauge::default()
-            .block(Block::default().borders(Borders::ALL).title(format!("CH{} SoC", ch_num + 1)))
-            .gauge_style(Style::default().fg(get_channel_color(ch_num)).add_modifier(Modifier::BOLD))
-            .percent((channel.soc * 100.0) as u16),
-        left_chunks[0],
-    );
+    // SoC gauge (measurement-only channels have no SoC)
+    if channel.monitor_only {
+        f.render_widget(
+            Paragraph::new("Measurement only\nOutput not driven")
+                .style(Style::default().fg(get_channel_color(ch_num)))
+                .block(Block::default().borders(Borders::ALL).title(format!("CH{} Monitor", ch_num + 1))),
+            left_chunks[0],
+        );
+    } else {
+        let outside_curve = channel.ocv_covered.is_some_and(|(lo, hi)| channel.soc < lo || channel.soc > hi);
+        let ocv_note = match (outside_curve, channel.ocv_extrapolated) {
+            (false, _) => "",
+            (true, true) => " · OCV extrapolated",
+            (true, false) => " · OCV held",
+        };
+        f.render_widget(
+            Gauge::default()
+                .block(Block::default().borders(Borders::ALL).title(format!("CH{} SoC{}", ch_num + 1, ocv_note)))
+                .gauge_style(Style::default().fg(get_channel_color(ch_num)).add_modifier(Modifier::BOLD))
+                .percent((channel.soc * 100.0) as u16),
+            left_chunks[0],
+        );
+    }
+
+    // Load against the current limit; close to it the channel is about to go CC
+    if channel.current_limit > 0.0 {
+        let load = (channel.current.abs() / channel.current_limit).clamp(0.0, 1.0);
+        let color = match load {
+            l if l >= 0.95 => Color::Red,
+            l if l >= 0.8 => Color::Yellow,
+            _ => get_channel_color(ch_num),
+        };
+        f.render_widget(
+            Gauge::default()
+                .block(Block::default().borders(Borders::ALL).title("Load"))
+                .gauge_style(Style::default().fg(color))
+                .ratio(load)
+                .label(format!("{:.0}% of {:.3} A", load * 100.0, channel.current_limit)),
+            left_chunks[1],
+        );
+    }
 
     // Metrics
     f.render_widget(
@@ -373,24 +701,41 @@ fn render_channel(
              Voltage: {:>6.3} V\n\
              Current: {:>6.3} A\n\
              Power  : {:>6.2} W\n\
-             OCV    : {:>6.3} V",
+             OCV    : {:>6.3} V\n\
+             Sag    : {:>6.1} mV\n\
+             RC     : {:>6} ms\n\
+             Left   : {}\n\
+             Since z: {:.3} Ah {:.2} Wh\n\
+             Total  : {:.3} Ah {:.2} Wh",
             channel.profile_name,
             channel.voltage,
             channel.current,
             channel.power,
-            channel.ocv
+            channel.ocv,
+            sag_mv(channel),
+            channel.rc_time_constant_ms,
+            remaining_text(channel),
+            channel.charge_ah - channel.counter_base.0,
+            channel.energy_wh - channel.counter_base.1,
+            channel.charge_ah,
+            channel.energy_wh
         ))
-        .block(Block::default().borders(Borders::ALL).title(format!("Channel {}", ch_num + 1))),
-        left_chunks[1],
+        .block(Block::default().borders(Borders::ALL).title(format!(
+            "Channel {}{}",
+            ch_num + 1,
+            if selected { " [selected]" } else { "" }
+        ))),
+        left_chunks[2],
     );
 
     // Right side: History charts
     let chart_chunks = Layout::default()
         .direction(Direction::Horizontal)
         .constraints([
-            Constraint::Percentage(33),
-            Constraint::Percentage(34),
-            Constraint::Percentage(33),
+            Constraint::Percentage(25),
+            Constraint::Percentage(25),
+            Constraint::Percentage(25),
+            Constraint::Percentage(25),
         ])
         .split(chunks[1]);
 
@@ -401,23 +746,32 @@ fn render_channel(
 
     let channel_color = get_channel_color(ch_num);
 
-    // Voltage chart
+    // Voltage chart, or the sag for simulated channels with `v`
+    let show_sag = show_sag && !channel.monitor_only;
+    let (voltage_title, voltage_bounds) = if show_sag {
+        ("Sag OCV-V (mV)", history.get_sag_bounds(ch_num))
+    } else {
+        ("Voltage (V)", voltage_bounds)
+    };
     if !history.channels[ch_num].is_empty() {
-        let voltage_data: Vec<(f64, f64)> = history.channels[ch_num].voltage.iter().cloned().collect();
+        let voltage_series = if show_sag { &history.channels[ch_num].sag } else { &history.channels[ch_num].voltage };
+        let voltage_data: Vec<(f64, f64)> = voltage_series.iter().cloned().collect();
         
-        let voltage_dataset = vec![
+        let voltage_markers = history.marker_lines(voltage_bounds);
+        let mut voltage_dataset = vec![
             Dataset::default()
                 .marker(symbols::Marker::Braille)
                 .style(Style::default().fg(channel_color))
                 .graph_type(GraphType::Line)
                 .data(&voltage_data),
         ];
+        voltage_dataset.extend(marker_datasets(&voltage_markers));
 
         let voltage_chart = Chart::new(voltage_dataset)
             .block(
                 Block::default()
                     .borders(Borders::ALL)
-                    .title("Voltage (V)")
+                    .title(voltage_title)
             )
             .x_axis(
                 Axis::default()
@@ -441,13 +795,15 @@ fn render_channel(
     if !history.channels[ch_num].is_empty() {
         let current_data: Vec<(f64, f64)> = history.channels[ch_num].current.iter().cloned().collect();
         
-        let current_dataset = vec![
+        let current_markers = history.marker_lines(current_bounds);
+        let mut current_dataset = vec![
             Dataset::default()
                 .marker(symbols::Marker::Braille)
                 .style(Style::default().fg(channel_color))
                 .graph_type(GraphType::Line)
                 .data(&current_data),
         ];
+        current_dataset.extend(marker_datasets(&current_markers));
 
         let current_chart = Chart::new(current_dataset)
             .block(
@@ -477,13 +833,15 @@ fn render_channel(
     if !history.channels[ch_num].is_empty() {
         let power_data: Vec<(f64, f64)> = history.channels[ch_num].power.iter().cloned().collect();
         
-        let power_dataset = vec![
+        let power_markers = history.marker_lines(power_bounds);
+        let mut power_dataset = vec![
             Dataset::default()
                 .marker(symbols::Marker::Braille)
                 .style(Style::default().fg(channel_color))
                 .graph_type(GraphType::Line)
                 .data(&power_data),
         ];
+        power_dataset.extend(marker_datasets(&power_markers));
 
         let power_chart = Chart::new(power_dataset)
             .block(
@@ -508,6 +866,112 @@ fn render_channel(
 
         f.render_widget(power_chart, chart_chunks[2]);
     }
+
+    // SoC chart
+    if !history.channels[ch_num].is_empty() {
+        let soc_data: Vec<(f64, f64)> = history.channels[ch_num].soc.iter().cloned().collect();
+
+        let soc_markers = history.marker_lines((0.0, 100.0));
+        let mut soc_dataset = vec![
+            Dataset::default()
+                .marker(symbols::Marker::Braille)
+                .style(Style::default().fg(channel_color))
+                .graph_type(GraphType::Line)
+                .data(&soc_data),
+        ];
+        soc_dataset.extend(marker_datasets(&soc_markers));
+
+        let soc_chart = Chart::new(soc_dataset)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("SoC (%)")
+            )
+            .x_axis(
+                Axis::default()
+                    .style(Style::default().fg(Color::Gray))
+                    .bounds([time_bounds.0, time_bounds.1]),
+            )
+            .y_axis(
+                Axis::default()
+                    .style(Style::default().fg(Color::Gray))
+                    .bounds([0.0, 100.0])
+                    .labels(vec!["0".into(), "50".into(), "100".into()]),
+            );
+
+        f.render_widget(soc_chart, chart_chunks[3]);
+    }
+}
+
+fn total_power(channels: &[ChannelState]) -> f64 {
+    common::total_power(channels.iter().map(|ch| (ch.enabled, ch.power)))
+}
+
+/// Dashboard summary: one line per enabled channel, the selected one highlighted
+fn render_channel_rows(
+    f: &mut ratatui::Frame,
+    area: ratatui::layout::Rect,
+    channels: &[ChannelState],
+    enabled: &[usize],
+    selected: usize,
+) {
+    let lines: Vec<Line> = enabled
+        .iter()
+        .map(|&ch_num| {
+            let ch = &channels[ch_num];
+            let status = if ch.monitor_only {
+                format!("CH{} {:<16} monitor", ch_num + 1, "")
+            } else {
+                format!("CH{} {:<16} {:5.1}%", ch_num + 1, truncate(&ch.profile_name, 16), ch.soc * 100.0)
+            };
+            let mut text = format!("{}  {:>7.3} V  {:>7.3} A  {:>7.2} W", status, ch.voltage, ch.current, ch.power);
+            if !ch.monitor_only {
+                text.push_str(&format!("  {}", remaining_text(ch)));
+            }
+            let mut style = Style::default().fg(get_channel_color(ch_num));
+            if ch_num == selected {
+                style = style.add_modifier(Modifier::BOLD | Modifier::REVERSED);
+            }
+            Line::from(Span::styled(text, style))
+        })
+        .collect();
+
+    f.render_widget(
+        Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title("Channels (1-3: show in detail)")),
+        area,
+    );
+}
+
+fn truncate(text: &str, max: usize) -> String {
+    text.chars().take(max).collect()
+}
+
+fn marker_datasets(lines: &[[(f64, f64); 2]]) -> impl Iterator<Item = Dataset<'_>> {
+    lines.iter().map(|line| {
+        Dataset::default()
+            .marker(symbols::Marker::Braille)
+            .style(Style::default().fg(Color::Magenta))
+            .graph_type(GraphType::Line)
+            .data(line)
+    })
+}
+
+/// Remaining runtime, e.g. "~42 min remaining"
+fn remaining_text(ch: &ChannelState) -> String {
+    match ch.remaining_s {
+        Some(s) if s >= 5940.0 => format!("~{:.1} h remaining", s / 3600.0),
+        Some(s) => format!("~{:.0} min remaining", (s / 60.0).max(1.0)),
+        None => "idle / indefinite".to_string(),
+    }
+}
+
+/// OCV minus terminal voltage: the IR drop plus the RC transient
+fn sag_mv(ch: &ChannelState) -> f64 {
+    if ch.monitor_only {
+        0.0
+    } else {
+        (ch.ocv - ch.voltage) * 1000.0
+    }
 }
 
 fn get_channel_color(ch_num: usize) -> Color {
@@ -1,16 +1,20 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 // Copyright (C) 2025 Marcus Folkesson
 
-/// DP832 Battery Simulator
-/// 
-/// Simulates realistic battery behavior on the Rigol DP832 power supply
+//! DP832 Battery Simulator
+//!
+//! Simulates realistic battery behavior on the Rigol DP832 power supply
 
 use clap::Parser;
-use dp832_battery_sim::battery_sim::{BatteryProfile, Config, interpolate_ocv};
-use dp832_battery_sim::common::{LogWriters, RuntimeState};
-use dp832_battery_sim::scpi::{send, query};
-use std::fs::File;
-use std::io::Read;
+use dp832_battery_sim::battery_sim::{BatteryProfile, ChargePhase, Charger, Config, CsvLayout, DriveMode, ScheduleConfig, ErrorAction, FreezeOutputs, NoLoadAction, OcvRangeMode, ProfileMode, REFERENCE_TEMPERATURE_C, ReadFailureStrategy, CurrentDirection, TheveninState, extrapolate_ocv, is_temperature_tagged, load_profile, load_profile_with_notes, ocv_slices, ocv_soc_range, profile_files, save_tuned_profile, sort_ocv};
+use dp832_battery_sim::battery_sim::cells::{CellRecord, STALE_AFTER_DAYS};
+use dp832_battery_sim::battery_sim::csvlog::{CsvFile, CsvSink, FlushPolicy, WideCsv};
+use dp832_battery_sim::battery_sim::hooks::{fire_on_complete, HookScope, OnCompleteConfig, RunManifest, RunSummary, StopReason};
+use dp832_battery_sim::battery_sim::noise::{NoiseConfig, NoiseGenerator};
+use dp832_battery_sim::keylog::KeyInput;
+use dp832_battery_sim::common::{new_run_id, round_setpoint, CalibrationReport, ChannelLimits, ChannelState, EffectiveConfig, LatencyWindow, LogWriters, Origin, RuntimeState};
+use dp832_battery_sim::scpi::{send, query, query_idn, query_raw, query_raw_retry, response_text, parse_f64, parse_bool_scpi, probe_capabilities, check_idn, connect, enable_raw_log, set_current_command, Capabilities, ConnectOptions};
+use std::io::Write;
 use std::net::TcpStream;
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
@@ -50,6 +54,10 @@ struct Args {
     #[arg(long)]
     config: Option<String>,
 
+    /// Print the resolved settings and where each one comes from, then exit
+    #[arg(long)]
+    print_effective_config: bool,
+
     /// DP832 IP address
     #[arg(long)]
     ip: Option<String>,
@@ -58,85 +66,900 @@ struct Args {
     #[arg(long)]
     port: Option<u16>,
 
+    /// Skip the startup probe of optional SCPI features
+    #[arg(long)]
+    skip_probe: bool,
+
+    /// Continue even if *IDN? does not identify a Rigol DP800 series supply
+    #[arg(long)]
+    ignore_idn: bool,
+
+    /// TCP connect timeout in milliseconds
+    #[arg(long)]
+    connect_timeout_ms: Option<u64>,
+
+    /// Read timeout for query responses in milliseconds
+    #[arg(long)]
+    read_timeout_ms: Option<u64>,
+
+    /// Ask a timed-out query again up to this many times (only queries that are safe to repeat)
+    #[arg(long)]
+    query_retries: Option<u32>,
+
+    /// Log the exact bytes sent and received to logs/scpi_raw_*.log
+    #[arg(long)]
+    raw_scpi_log: bool,
+
     /// Battery profile JSON files (can specify multiple, e.g., -p ch1.json -p ch2.json)
     #[arg(short, long)]
     profile: Vec<String>,
 
+    /// Also load every profile in this directory, each on the channel it declares
+    #[arg(long)]
+    profile_dir: Option<String>,
+
+    /// Serial of the physical cell on each profile, in -p order (resumes its last SoC)
+    #[arg(long)]
+    serial: Vec<String>,
+
     /// CSV log file
     #[arg(long)]
     log: Option<String>,
+
+    /// Write only every Nth sample to the CSV (samples near cutoff are always written)
+    #[arg(long)]
+    csv_decimate: Option<u64>,
+
+    /// One CSV per channel, or one wide CSV with all channels per row
+    #[arg(long, value_enum)]
+    csv_layout: Option<CsvLayout>,
+
+    /// How to treat a step whose current read failed
+    #[arg(long, value_enum)]
+    read_failure: Option<ReadFailureStrategy>,
+
+    /// Run the control loop on a fixed period instead of sleeping after each step
+    #[arg(long)]
+    fixed_cadence: bool,
+
+    /// How to model SoC outside the range covered by the OCV curve
+    #[arg(long, value_enum)]
+    ocv_range: Option<OcvRangeMode>,
+
+    /// Only measure this channel, never drive it (can specify multiple)
+    #[arg(long)]
+    monitor: Vec<u8>,
+
+    /// Show and log current/power of this channel with inverted sign (can specify multiple)
+    #[arg(long)]
+    invert_current: Vec<u8>,
+
+    /// Report from `dp832 calibrate-check --save`; corrects the current readings of its channel (can specify multiple)
+    #[arg(long)]
+    current_calibration: Vec<String>,
+
+    /// Cell temperature for profiles with temperature-tagged OCV curves
+    #[arg(long, allow_negative_numbers = true)]
+    temperature_c: Option<f64>,
+
+    /// Let q close the TUI while the simulation keeps running in the background
+    #[arg(long)]
+    detach_on_quit: bool,
+
+    /// Record the TUI key presses with their timing to this file
+    #[arg(long)]
+    record_input: Option<String>,
+
+    /// Replay key presses recorded with --record-input into the TUI
+    #[arg(long)]
+    replay_input: Option<String>,
+
+    /// Stop all channels and turn their outputs off after this many seconds
+    #[arg(long)]
+    max_runtime: Option<u64>,
+
+    /// Failed current reads in a row before reconnecting or stopping
+    #[arg(long)]
+    max_errors: Option<u32>,
+
+    /// Seconds the reads must keep failing before a channel is stopped
+    #[arg(long)]
+    error_grace: Option<u64>,
+
+    /// What to do when current reads keep failing
+    #[arg(long, value_enum)]
+    on_errors: Option<ErrorAction>,
+
+    /// Seconds without load current before a channel reports "no load detected"
+    #[arg(long)]
+    no_load_timeout: Option<u64>,
+
+    /// Current in amps below which a channel counts as unloaded
+    #[arg(long)]
+    no_load_current: Option<f64>,
+
+    /// What to do when the load stays away
+    #[arg(long, value_enum)]
+    on_no_load: Option<NoLoadAction>,
+
+    /// Pause all channels on the first read error and write a diagnostic snapshot
+    #[arg(long)]
+    freeze_on_error: bool,
+
+    /// Whether paused channels keep their outputs on or switch them off
+    #[arg(long, value_enum)]
+    freeze_outputs: Option<FreezeOutputs>,
+
+    /// Profiles to run one after another on a channel, e.g. ch1:a.json,b.json (one per channel)
+    #[arg(long)]
+    queue: Vec<String>,
+
+    /// Seconds to wait between queued runs
+    #[arg(long)]
+    queue_delay: Option<u64>,
+
+    /// Before each queued run, wait for Enter in the TUI so the cell can be swapped
+    #[arg(long)]
+    queue_prompt: bool,
+
+    /// Drive the modelled voltage (CV) or the modelled current (CC)
+    #[arg(long, value_enum)]
+    drive: Option<DriveMode>,
+
+    /// Milliseconds between the initialization of successive channels
+    #[arg(long)]
+    startup_stagger_ms: Option<u64>,
+
+    /// Keep discharging past cutoff at a reduced current, down to the profile's absolute_min_voltage
+    #[arg(long)]
+    tail_capture: bool,
+
+    /// TUI header text, also used as the run name unless `[logging] run_name` is set
+    #[arg(long)]
+    title: Option<String>,
+}
+
+/// Run options shared by all channel threads
+#[derive(Clone)]
+struct SimOptions {
+    read_failure: ReadFailureStrategy,
+    csv_decimate: u64,
+    fixed_cadence: bool,
+    /// Completion hook fired from the channel thread (`scope = "channel"`)
+    channel_hook: Option<OnCompleteConfig>,
+    /// Channels whose displayed and logged current is negated
+    invert_current: Vec<u8>,
+    /// Readback corrections applied to the measured current before the model uses it
+    current_calibration: Vec<CalibrationReport>,
+    /// Synthetic noise added to the measured current before the model uses it
+    noise: Option<NoiseConfig>,
+    /// Temperature for profiles with `ocv_curves`
+    temperature_c: Option<f64>,
+    errors: ErrorPolicy,
+    drive: DriveMode,
+    /// Quiet windows with a reduced CSV rate
+    schedule: ScheduleConfig,
+    /// Used to reopen a channel's connection after repeated errors
+    addr: String,
+    connect_options: ConnectOptions,
+    /// Wait before the channel's init sequence, so channels don't start in one burst
+    startup_delay: Duration,
+    /// Continue below cutoff on profiles with `absolute_min_voltage`
+    tail_capture: bool,
+    no_load: Option<NoLoadPolicy>,
+    /// `--freeze-on-error`, with what the outputs do while paused
+    freeze: Option<FreezeOutputs>,
+    /// Between the runs of a `--queue`
+    queue_delay: Duration,
+    queue_prompt: bool,
+}
+
+/// When a channel stops because its current reads keep failing
+///
+/// Both limits must be exceeded: `max_consecutive` failures in a row, over at
+/// least `grace`. Until then the channel keeps retrying and, with
+/// `ErrorAction::Reconnect`, reopens its connection after every
+/// `max_consecutive` failures.
+#[derive(Clone)]
+struct ErrorPolicy {
+    max_consecutive: u32,
+    grace: Duration,
+    action: ErrorAction,
+}
+
+/// When a channel that has been carrying load is considered disconnected
+///
+/// The check arms once the current has been at or above `current` after
+/// warm-up, so a channel that never saw a load is not flagged. A rest
+/// shorter than `timeout` only resets it; any load re-arms it.
+#[derive(Clone)]
+struct NoLoadPolicy {
+    current: f64,
+    timeout: Duration,
+    action: NoLoadAction,
+}
+
+/// One profile of a channel's run queue, prepared at startup
+struct QueuedRun {
+    profile: BatteryProfile,
+    source: ChannelSource,
+    csv: Option<CsvSink>,
+    /// Covered SoC range of an incomplete OCV curve, for the TUI
+    ocv_covered: Option<(f64, f64)>,
+}
+
+/// Parse `--queue ch1:a.json,b.json` into the channel and its profiles
+fn parse_queue(spec: &str) -> Result<(u8, Vec<String>), String> {
+    let usage = || format!("--queue {}: expected ch<1-3>:<profile>,<profile>...", spec);
+    let (channel, files) = spec.split_once(':').ok_or_else(usage)?;
+    let channel = channel.trim().to_lowercase();
+    let channel = channel
+        .strip_prefix("ch")
+        .unwrap_or(&channel)
+        .parse::<u8>()
+        .ok()
+        .filter(|ch| (1..=3).contains(ch))
+        .ok_or_else(usage)?;
+    let files: Vec<String> = files.split(',').map(str::trim).filter(|f| !f.is_empty()).map(String::from).collect();
+    if files.is_empty() {
+        return Err(usage());
+    }
+    Ok((channel, files))
+}
+
+/// Where a channel's profile and cell state came from
+struct ChannelSource {
+    /// Profile file, used as the base for a tuned copy
+    path: String,
+    /// Tracked cell, if the run was tagged with `--serial`
+    cell: Option<CellRecord>,
+}
+
+/// Paces the control loop between steps
+///
+/// By default the loop sleeps the update interval after each step, so the
+/// period is work time plus interval. In fixed-cadence mode steps are due on
+/// a regular grid and only the remainder of the interval is slept.
+struct Pacer {
+    interval: Duration,
+    fixed: bool,
+    next: Instant,
+}
+
+impl Pacer {
+    fn new(interval: Duration, fixed: bool) -> Self {
+        Self {
+            interval,
+            fixed,
+            next: Instant::now(),
+        }
+    }
+
+    /// Sleep until the next step; returns how late the step is if it overran the period
+    fn wait(&mut self) -> Option<Duration> {
+        if !self.fixed {
+            sleep(self.interval);
+            return None;
+        }
+
+        self.next += self.interval;
+        let now = Instant::now();
+        if now >= self.next {
+            // Behind schedule: start the next step now rather than bursting to catch up
+            let late = now - self.next;
+            self.next = now;
+            Some(late)
+        } else {
+            sleep(self.next - now);
+            None
+        }
+    }
+
+    /// Schedule the next step one period from now, e.g. after a pause
+    fn reset(&mut self) {
+        self.next = Instant::now();
+    }
+}
+
+/// Thins out a channel's CSV samples inside the `[schedule]` quiet windows
+struct QuietHours {
+    schedule: ScheduleConfig,
+    interval: Option<Duration>,
+    last_write: Option<Instant>,
+}
+
+impl QuietHours {
+    fn new(schedule: ScheduleConfig) -> Self {
+        Self { schedule, interval: None, last_write: None }
+    }
+
+    /// Re-read the wall clock; describes the change when a window starts or ends
+    fn update(&mut self) -> Option<String> {
+        if self.schedule.quiet.is_empty() {
+            return None;
+        }
+        let interval = self.schedule.log_interval_at(chrono::Local::now().time());
+        if interval == self.interval {
+            return None;
+        }
+        self.interval = interval;
+        self.last_write = None;
+        Some(match interval {
+            Some(i) => format!("Quiet hours: logging one CSV sample every {} s", i.as_secs()),
+            None => "Quiet hours over: normal CSV rate".to_string(),
+        })
+    }
+
+    fn active(&self) -> bool {
+        self.interval.is_some()
+    }
+
+    /// Inside a quiet window: whether a sample is due, counting it as written if so
+    fn due(&mut self, now: Instant) -> bool {
+        let due = match (self.interval, self.last_write) {
+            (Some(interval), Some(last)) => now.duration_since(last) >= interval,
+            _ => true,
+        };
+        if due {
+            self.last_write = Some(now);
+        }
+        due
+    }
+}
+
+/// Actual step period of a channel, compared against its `update_interval_ms`
+///
+/// Each channel has its own connection, so a channel falling behind points at
+/// the instrument's response time or a too short interval, not lock contention.
+struct IntervalStats {
+    requested: Duration,
+    steps: u32,
+    total: Duration,
+    max: Duration,
+    window_start: Instant,
+}
+
+impl IntervalStats {
+    /// How often the actual interval is reported
+    const REPORT_EVERY: Duration = Duration::from_secs(60);
+
+    fn new(requested: Duration) -> Self {
+        Self {
+            requested,
+            steps: 0,
+            total: Duration::ZERO,
+            max: Duration::ZERO,
+            window_start: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, period: Duration) {
+        self.steps += 1;
+        self.total += period;
+        self.max = self.max.max(period);
+    }
+
+    /// Once per report period: a description of the window, after which it starts over
+    fn report(&mut self) -> Option<String> {
+        if self.window_start.elapsed() < Self::REPORT_EVERY {
+            return None;
+        }
+        let line = self.summary();
+        *self = Self::new(self.requested);
+        line
+    }
+
+    fn summary(&self) -> Option<String> {
+        if self.steps == 0 {
+            return None;
+        }
+        let mean = self.total / self.steps;
+        let slow = mean.as_secs_f64() > self.requested.as_secs_f64() * 1.1;
+        Some(format!("interval {} ms requested, {} ms actual (max {} ms){}",
+                     self.requested.as_millis(), mean.as_millis(), self.max.as_millis(),
+                     if slow { " - can't keep up" } else { "" }))
+    }
 }
 
 fn main() {
     let args = Args::parse();
 
     let cfg: Config = dp832_battery_sim::common::load_optional_config(args.config.as_deref());
-    
+    let config_path = dp832_battery_sim::common::resolve_config_path(args.config.as_deref());
+    // Every setting below is resolved command line > config > default through this
+    let mut effective = EffectiveConfig::default();
+    let device = cfg.device.as_ref();
+    let sim = cfg.simulation.as_ref();
+
     // Resolve IP
-    let ip = args
-        .ip
-        .or_else(|| cfg.device.as_ref().map(|d| d.ip.clone()))
-        .unwrap_or_else(|| "192.168.1.100".to_string());
+    let ip = effective.resolve("device.ip", args.ip.clone(), device.map(|d| d.ip.clone()), "192.168.1.100".to_string());
 
     // Resolve port
-    let port = args
-        .port
-        .or_else(|| cfg.device.as_ref().and_then(|d| d.port))
-        .unwrap_or(5555);
+    let port = effective.resolve("device.port", args.port, device.and_then(|d| d.port), 5555);
+
+    // Resolve capability probe
+    let probe = effective.resolve("device.probe", args.skip_probe.then_some(false), device.and_then(|d| d.probe), true);
+    let ignore_idn = effective.flag("device.ignore_idn", args.ignore_idn, device.and_then(|d| d.ignore_idn), false);
+
+    // Resolve connection timeouts
+    let connect_options =
+        ConnectOptions::resolve(&mut effective, args.connect_timeout_ms, args.read_timeout_ms, args.query_retries, device);
 
     // Resolve battery profiles
+    let mut startup_notes = Vec::new();
     let mut profile_paths = args.profile;
-    if profile_paths.is_empty() {
+    if let Some(dir) = &args.profile_dir {
+        let files = profile_files(dir).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        });
+        // Other JSON files may live next to the profiles; skip what doesn't load
+        for path in files {
+            match load_profile(&path).and_then(|p| p.validate()) {
+                Ok(_) => profile_paths.push(path),
+                Err(e) => {
+                    let note = format!("Skipping {}: {}", path, e);
+                    println!("{}", note);
+                    startup_notes.push(note);
+                }
+            }
+        }
+        if profile_paths.is_empty() {
+            eprintln!("Error: no profiles found in {}", dir);
+            std::process::exit(1);
+        }
+    }
+    let mut serials = args.serial;
+    let mut profiles_from = Origin::Cli;
+    if profile_paths.is_empty() && args.queue.is_empty() {
+        profiles_from = Origin::Default;
         if let Some(battery_cfg) = cfg.battery {
+            profiles_from = Origin::Config;
             profile_paths.push(battery_cfg.profile);
+            if serials.is_empty() {
+                serials.extend(battery_cfg.serial);
+            }
+        }
+    }
+    // Queued profiles join the list; `queue_of` names the channel each one is queued on
+    let mut queue_of: Vec<Option<u8>> = vec![None; profile_paths.len()];
+    for spec in &args.queue {
+        let (channel, paths) = parse_queue(spec).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        });
+        if queue_of.contains(&Some(channel)) {
+            eprintln!("Error: more than one --queue for CH{}; list all its profiles in one", channel);
+            std::process::exit(1);
         }
+        for path in paths {
+            profile_paths.push(path);
+            queue_of.push(Some(channel));
+        }
+    }
+    effective.record("battery.profile", format!("{:?}", profile_paths), profiles_from);
+    if serials.len() > profile_paths.len() {
+        eprintln!("Error: {} serials given for {} profiles", serials.len(), profile_paths.len());
+        std::process::exit(1);
     }
 
-    if profile_paths.is_empty() {
+    // Resolve measurement-only channels
+    let monitor_channels = effective.resolve(
+        "simulation.monitor_channels",
+        Some(args.monitor).filter(|m| !m.is_empty()),
+        sim.and_then(|s| s.monitor_channels.clone()),
+        Vec::new(),
+    );
+    if let Some(ch) = monitor_channels.iter().find(|ch| !(1..=3).contains(*ch)) {
+        eprintln!("Error: monitor channel {} does not exist (use 1-3)", ch);
+        std::process::exit(1);
+    }
+
+    if profile_paths.is_empty() && monitor_channels.is_empty() {
         eprintln!("Error: No battery profile specified");
         eprintln!("Use: -p <profile.json> (can specify multiple times for multiple channels)");
         std::process::exit(1);
     }
 
+    let ocv_range = effective.choice("simulation.ocv_range", args.ocv_range, sim.and_then(|s| s.ocv_range), Default::default());
+    let temperature_c = effective.resolve_opt("simulation.temperature_c", args.temperature_c, sim.and_then(|s| s.temperature_c));
+    let tail_capture = effective.flag("simulation.tail_capture", args.tail_capture, sim.and_then(|s| s.tail_capture), false);
+
+    // Current readback corrections, at most one per channel
+    let calibration_paths = effective.resolve(
+        "simulation.current_calibration",
+        Some(args.current_calibration).filter(|c| !c.is_empty()),
+        sim.and_then(|s| s.current_calibration.clone()),
+        Vec::new(),
+    );
+    let mut current_calibration: Vec<CalibrationReport> = Vec::new();
+    for path in &calibration_paths {
+        let report = CalibrationReport::load(path).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        });
+        if current_calibration.iter().any(|c| c.channel == report.channel) {
+            eprintln!("Error: more than one current calibration for CH{}", report.channel);
+            std::process::exit(1);
+        }
+        let note = format!("CH{}: correcting current readings with {} (offset {:+.2} mA, scale {:.5})",
+                           report.channel, path, report.current_offset * 1000.0, report.current_scale);
+        println!("{}", note);
+        startup_notes.push(note);
+        current_calibration.push(report);
+    }
+
     // Load all profiles
-    let mut profiles = Vec::new();
-    for profile_path in &profile_paths {
-        let mut json = String::new();
-        File::open(profile_path)
-            .unwrap_or_else(|e| {
-                eprintln!("Failed to open profile {}: {}", profile_path, e);
+    let mut profiles: Vec<BatteryProfile> = Vec::new();
+    let mut cells = Vec::new();
+    let mut ocv_covered = Vec::new();
+    let ocv_range_indicator =
+        effective.resolve("ui.ocv_range_indicator", None, cfg.ui.as_ref().and_then(|u| u.ocv_range_indicator), true);
+    for (n, profile_path) in profile_paths.iter().enumerate() {
+        let (mut profile, notes) = load_profile_with_notes(profile_path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+        if let Err(e) = profile.validate() {
+            eprintln!("Error: {}", e);
+            std::process::exit(1);
+        }
+        
+        let note = format!("Loaded profile '{}' from {} for CH{}", profile.name, profile_path, profile.channel);
+        for note in std::iter::once(note).chain(notes) {
+            println!("{}", note);
+            startup_notes.push(note);
+        }
+
+        if let Some(channel) = queue_of[n].filter(|&ch| ch != profile.channel) {
+            let note = format!("'{}' runs on CH{} as queued (the profile names CH{})", profile.name, channel, profile.channel);
+            println!("{}", note);
+            startup_notes.push(note);
+            profile.channel = channel;
+        }
+
+        // The profiles of one queue share their channel
+        let taken = |&m: &usize| profiles[m].channel == profile.channel && (queue_of[m].is_none() || queue_of[m] != queue_of[n]);
+        if let Some(other) = (0..profiles.len()).find(taken) {
+            eprintln!("Error: CH{} is assigned to both {} and {}", profile.channel, profile_paths[other], profile_path);
+            std::process::exit(1);
+        }
+
+        if let Some(cells) = profile.series_cells {
+            if let Err(e) = profile.apply_series_cells() {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            println!("  {}S pack: {:.3}V max, {:.3}V cutoff, {:.3}Ω",
+                     cells, profile.max_voltage, profile.cutoff_voltage, profile.internal_resistance_ohm);
+        }
+
+        if profile.resistance_temp_coeff.is_some() {
+            let nominal = profile.internal_resistance_ohm;
+            if let Err(e) = profile.apply_temperature(temperature_c) {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+            if let Some(t) = temperature_c.or(profile.temperature_c) {
+                let note = format!("'{}': internal resistance {:.3}Ω at {:.1}°C ({:.3}Ω at {:.0}°C)",
+                                   profile.name, profile.internal_resistance_ohm, t, nominal, REFERENCE_TEMPERATURE_C);
+                println!("{}", note);
+                startup_notes.push(note);
+            }
+        }
+
+        let Some(limits) = ChannelLimits::for_channel(profile.channel) else {
+            eprintln!("Error: profile '{}' uses CH{}, which does not exist (use 1-3)", profile.name, profile.channel);
+            std::process::exit(1);
+        };
+        if let Err(e) = limits.check_voltage(profile.channel, profile.max_voltage) {
+            eprintln!("Error: profile '{}' max_voltage {}", profile.name, e);
+            std::process::exit(1);
+        }
+        if profile.current_limit_discharge_a > limits.max_current {
+            let note = format!("Warning: '{}' current limit {:.3}A exceeds the CH{} maximum; using {:.3}A",
+                               profile.name, profile.current_limit_discharge_a, profile.channel, limits.max_current);
+            println!("{}", note);
+            startup_notes.push(note);
+            profile.current_limit_discharge_a = limits.max_current;
+        }
+
+        if tail_capture {
+            let note = match profile.tail_limits() {
+                Ok(Some((min_voltage, current))) => format!(
+                    "'{}': tail capture below {:.3}V at {:.3}A, down to {:.3}V",
+                    profile.name, profile.cutoff_voltage, current, min_voltage),
+                Ok(None) => format!("'{}' has no absolute_min_voltage; it stops at cutoff", profile.name),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            println!("{}", note);
+            startup_notes.push(note);
+        }
+
+        if profile.mode == ProfileMode::Charge {
+            let note = match profile.charge_limits() {
+                Ok((current, termination)) => format!(
+                    "'{}': charging CC-CV at {:.3}A up to {:.3}V, ending below {:.3}A",
+                    profile.name, current, profile.max_voltage, termination),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            };
+            println!("{}", note);
+            startup_notes.push(note);
+
+            match profile.precharge_limits() {
+                Ok(Some((threshold, current))) => {
+                    let note = format!("'{}': pre-charging at {:.3}A below {:.3}V", profile.name, current, threshold);
+                    println!("{}", note);
+                    startup_notes.push(note);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        if let Some(k) = profile.peukert_exponent {
+            let current = profile.current_limit_discharge_a;
+            let note = format!("'{}': Peukert exponent {:.2}, {:.3}Ah at {:.3}A",
+                               profile.name, k, profile.capacity_at(current), current);
+            println!("{}", note);
+            startup_notes.push(note);
+        }
+
+        match profile.rc_branches() {
+            Ok(Some([b1, b2])) => {
+                let note = format!("'{}': two-RC Thévenin model, {:.3}Ω/{:.1}s and {:.3}Ω/{:.1}s",
+                                   profile.name, b1.r_ohm, b1.tau_s, b2.r_ohm, b2.tau_s);
+                println!("{}", note);
+                startup_notes.push(note);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("Error: {}", e);
                 std::process::exit(1);
-            })
-            .read_to_string(&mut json)
-            .unwrap();
+            }
+        }
+
+        // Flag curves that leave part of the SoC range undefined
+        let covered = ocv_soc_range(&profile.ocv_curve).filter(|&(lo, hi)| lo > 0.0 || hi < 1.0);
+        ocv_covered.push(covered.filter(|_| ocv_range_indicator));
+        if let Some((lowest, highest)) = covered {
+            let note = match ocv_range {
+                OcvRangeMode::Hold => format!(
+                    "Warning: '{}' OCV curve only covers SoC {:.2}-{:.2}; voltage is held flat outside it",
+                    profile.name, lowest, highest),
+                OcvRangeMode::Extrapolate => format!(
+                    "'{}' OCV curve only covers SoC {:.2}-{:.2}; extrapolating linearly to 0-1",
+                    profile.name, lowest, highest),
+            };
+            println!("{}", note);
+            startup_notes.push(note);
+        }
+        if !profile.ocv_curves.is_empty() {
+            let note = match temperature_c.or(profile.temperature_c) {
+                Some(t) => format!("'{}': OCV from {} temperature curves at {:.1}°C", profile.name, profile.ocv_curves.len(), t),
+                None => format!("'{}' has temperature OCV curves but no temperature_c is set; using ocv_curve", profile.name),
+            };
+            println!("{}", note);
+            startup_notes.push(note);
+        }
 
-        let profile: BatteryProfile = serde_json::from_str(&json)
-            .unwrap_or_else(|e| {
-                eprintln!("Failed to parse profile {}: {}", profile_path, e);
+        if profile.ocv_curves.is_empty() && is_temperature_tagged(&profile.ocv_curve) {
+            let t = temperature_c.or(profile.temperature_c).unwrap_or(REFERENCE_TEMPERATURE_C);
+            let note = format!("'{}': OCV from {} temperature slices of ocv_curve at {:.1}°C",
+                               profile.name, ocv_slices(&profile.ocv_curve).len(), t);
+            println!("{}", note);
+            startup_notes.push(note);
+        }
+
+        if profile.has_hysteresis() {
+            let given = |missing: bool| if missing { "ocv_curve" } else { "its own curve" };
+            let note = format!("'{}': OCV hysteresis, charge uses {}, discharge uses {}", profile.name,
+                               given(profile.ocv_curve_charge.is_empty()), given(profile.ocv_curve_discharge.is_empty()));
+            println!("{}", note);
+            startup_notes.push(note);
+        }
+
+        let prepare = |curve: &[_]| match ocv_range {
+            OcvRangeMode::Hold => sort_ocv(curve),
+            OcvRangeMode::Extrapolate => extrapolate_ocv(curve),
+        };
+        profile.ocv_curve = prepare(&profile.ocv_curve);
+        profile.ocv_curve_charge = prepare(&profile.ocv_curve_charge);
+        profile.ocv_curve_discharge = prepare(&profile.ocv_curve_discharge);
+        for curve in &mut profile.ocv_curves {
+            curve.ocv_curve = prepare(&curve.ocv_curve);
+        }
+
+        // Resume a tracked cell from where its last session ended
+        let cell = serials.get(n).map(|serial| {
+            let record = CellRecord::load(serial).unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
                 std::process::exit(1);
             });
-        
-        println!("Loaded profile '{}' for channel {}", profile.name, profile.channel);
+            let note = match &record {
+                Some(r) => format!("Cell {}: resuming at SoC {:.1}% ({:.3}Ah throughput, last saved {})",
+                                   serial, r.soc * 100.0, r.throughput_ah, r.updated),
+                None => format!("Cell {}: no previous record, starting at SoC {:.0}%", serial, profile.initial_soc() * 100.0),
+            };
+            println!("{}", note);
+            startup_notes.push(note);
+            if let Some(days) = record.as_ref().and_then(|r| r.age_days()).filter(|&d| d > STALE_AFTER_DAYS) {
+                let note = format!("Warning: cell {} was last saved {} days ago; it may have self-discharged", serial, days);
+                println!("{}", note);
+                startup_notes.push(note);
+            }
+            if let Some(r) = record.as_ref().filter(|r| r.profile != profile.name) {
+                let note = format!("Warning: cell {} was last run with profile '{}'", serial, r.profile);
+                println!("{}", note);
+                startup_notes.push(note);
+            }
+            let mut record = record.unwrap_or_else(|| CellRecord { soc: profile.initial_soc(), ..CellRecord::new(serial, &profile.name) });
+            record.profile = profile.name.clone();
+            record
+        });
+
         profiles.push(profile);
+        cells.push(cell);
+    }
+
+    if let Some(profile) = profiles.iter().find(|p| monitor_channels.contains(&p.channel)) {
+        eprintln!("Error: CH{} is both simulated by '{}' and a monitor channel", profile.channel, profile.name);
+        std::process::exit(1);
     }
 
     // Resolve CSV log
-    let csv_log = args.log.or_else(|| cfg.logging.and_then(|l| l.csv));
+    let logging = cfg.logging.as_ref();
+    let csv_log = effective.resolve_opt("logging.csv", args.log.clone(), logging.and_then(|l| l.csv.clone()));
+    let csv_layout = effective.choice("logging.csv_layout", args.csv_layout, logging.and_then(|l| l.csv_layout), Default::default());
+    let flush_policy = FlushPolicy::resolve(&mut effective, logging);
+    if csv_log.is_some() && csv_layout == CsvLayout::Wide && queue_of.iter().any(Option::is_some) {
+        eprintln!("Error: --queue needs the per-channel CSV layout, each run gets its own file");
+        std::process::exit(1);
+    }
+
+    let schedule = effective.resolve("schedule", None, cfg.schedule.clone(), ScheduleConfig::default());
+    if let Err(e) = schedule.validate() {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+
+    // Resolve simulation options
+    // The dependent settings (no-load current, freeze outputs) are resolved
+    // even when their switch is off, so all of them show up in the printout
+    let no_load_timeout = effective.resolve_opt("simulation.no_load_timeout_s", args.no_load_timeout, sim.and_then(|s| s.no_load_timeout_s));
+    let no_load = NoLoadPolicy {
+        current: effective.resolve("simulation.no_load_current_a", args.no_load_current, sim.and_then(|s| s.no_load_current_a), 0.005),
+        timeout: Duration::from_secs(no_load_timeout.unwrap_or(0)),
+        action: effective.choice("simulation.on_no_load", args.on_no_load, sim.and_then(|s| s.on_no_load), Default::default()),
+    };
+    let freeze_on_error = effective.flag("simulation.freeze_on_error", args.freeze_on_error, sim.and_then(|s| s.freeze_on_error), false);
+    let freeze_outputs = effective.choice("simulation.freeze_outputs", args.freeze_outputs, sim.and_then(|s| s.freeze_outputs), Default::default());
+    // Config-only tables, shown whole
+    let noise = effective.resolve_opt("noise", None, cfg.noise.clone());
+    let on_complete = effective.resolve_opt("on_complete", None, cfg.on_complete.clone());
+    let options = SimOptions {
+        read_failure: effective.choice("simulation.read_failure", args.read_failure, sim.and_then(|s| s.read_failure), Default::default()),
+        csv_decimate: effective.resolve("logging.csv_decimate", args.csv_decimate, logging.and_then(|l| l.csv_decimate), 1).max(1),
+        fixed_cadence: effective.flag("simulation.fixed_cadence", args.fixed_cadence, sim.and_then(|s| s.fixed_cadence), false),
+        channel_hook: on_complete.clone().filter(|h| h.scope == HookScope::Channel),
+        invert_current: effective.resolve(
+            "simulation.invert_current",
+            Some(args.invert_current).filter(|c| !c.is_empty()),
+            sim.and_then(|s| s.invert_current.clone()),
+            Vec::new(),
+        ),
+        current_calibration,
+        noise,
+        schedule,
+        temperature_c,
+        errors: ErrorPolicy {
+            max_consecutive: effective
+                .resolve("simulation.max_consecutive_errors", args.max_errors, sim.and_then(|s| s.max_consecutive_errors), 5)
+                .max(1),
+            grace: Duration::from_secs(effective.resolve("simulation.error_grace_s", args.error_grace, sim.and_then(|s| s.error_grace_s), 10)),
+            action: effective.choice("simulation.on_errors", args.on_errors, sim.and_then(|s| s.on_errors), Default::default()),
+        },
+        drive: effective.choice("simulation.drive", args.drive, sim.and_then(|s| s.drive), Default::default()),
+        addr: format!("{}:{}", ip, port),
+        connect_options: connect_options.clone(),
+        startup_delay: Duration::ZERO,
+        tail_capture,
+        no_load: no_load_timeout.filter(|&secs| secs > 0).map(|_| no_load),
+        queue_delay: Duration::from_secs(effective.resolve("simulation.queue_delay_s", args.queue_delay, sim.and_then(|s| s.queue_delay_s), 0)),
+        queue_prompt: effective.flag("simulation.queue_prompt", args.queue_prompt, sim.and_then(|s| s.queue_prompt), false),
+        freeze: freeze_on_error.then_some(freeze_outputs),
+    };
+    if let Some(profile) = profiles.iter().find(|p| p.mode == ProfileMode::Charge).filter(|_| options.drive == DriveMode::Current) {
+        eprintln!("Error: profile '{}' charges, which needs the simulator to set the voltage; it cannot run with --drive current",
+                  profile.name);
+        std::process::exit(1);
+    }
+    let startup_stagger = Duration::from_millis(
+        effective.resolve("simulation.startup_stagger_ms", args.startup_stagger_ms, sim.and_then(|s| s.startup_stagger_ms), 0),
+    );
+    let max_runtime = effective.resolve_opt("simulation.max_runtime_s", args.max_runtime, sim.and_then(|s| s.max_runtime_s));
+    let detach_on_quit = effective.flag("simulation.detach_on_quit", args.detach_on_quit, sim.and_then(|s| s.detach_on_quit), false);
+    let run_hook = on_complete.filter(|h| h.scope == HookScope::Run);
+
+    // A label for this instance: the TUI header, log file names and the manifest
+    let title = effective.resolve_opt("ui.title", args.title.clone(), cfg.ui.as_ref().and_then(|u| u.title.clone()));
+    let run_name = effective.resolve_opt("logging.run_name", None, logging.and_then(|l| l.run_name.clone())).or_else(|| title.clone());
+
+    if args.print_effective_config {
+        effective.print(config_path.as_deref());
+        return;
+    }
+
+    let key_input = KeyInput::open(args.record_input.as_deref(), args.replay_input.as_deref()).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
 
     println!("DP832: {}:{}", ip, port);
-    println!("Active channels: {}", profiles.len());
+    // Queued profiles share a channel
+    let mut simulated: Vec<u8> = profiles.iter().map(|p| p.channel).collect();
+    simulated.sort();
+    simulated.dedup();
+    println!("Active channels: {}", simulated.len());
+    if !monitor_channels.is_empty() {
+        let list: Vec<String> = monitor_channels.iter().map(|ch| format!("CH{}", ch)).collect();
+        println!("Measurement-only channels: {}", list.join(", "));
+    }
 
-    let addr = format!("{}:{}", ip, port);
-    let mut stream = TcpStream::connect(&addr).unwrap();
+    let run_id = new_run_id(run_name.as_deref());
 
-    // Set blocking mode with 1 second read timeout (as in working version)
-    stream
-        .set_read_timeout(Some(Duration::from_secs(1)))
-        .unwrap();
+    let mut raw_log_path = None;
+    if args.raw_scpi_log {
+        match enable_raw_log(&run_id) {
+            Ok(path) => {
+                println!("Raw SCPI log: {}", path);
+                raw_log_path = Some(path);
+            }
+            Err(e) => eprintln!("Failed to open raw SCPI log: {}", e),
+        }
+    }
+
+    let addr = format!("{}:{}", ip, port);
+    let mut stream = connect(&addr, &connect_options).unwrap_or_else(|e| {
+        eprintln!("Failed to connect to {}: {}", addr, e);
+        std::process::exit(1);
+    });
 
     // Clear errors and get ID
-    send(&mut stream, "*CLS");
-    println!("{}", query(&mut stream, "*IDN?"));
+    let device_id = send(&mut stream, "*CLS").and_then(|()| query_idn(&mut stream)).unwrap_or_else(|e| {
+        eprintln!("Failed to talk to {}: {}", addr, e);
+        std::process::exit(1);
+    });
+    println!("{}", device_id);
+    if let Err(e) = check_idn(&device_id) {
+        if !ignore_idn {
+            eprintln!("Error: {}", e);
+            eprintln!("Pass --ignore-idn (or ignore_idn = true under [device]) to continue anyway");
+            std::process::exit(1);
+        }
+        eprintln!("Warning: {} (continuing, IDN check disabled)", e);
+    }
+
+    let capabilities = if probe {
+        probe_capabilities(&mut stream).unwrap_or_else(|e| {
+            eprintln!("Failed to probe {}: {}", addr, e);
+            std::process::exit(1);
+        })
+    } else {
+        Capabilities::default()
+    };
+    for line in capabilities.report() {
+        println!("{}", line);
+    }
 
     // Initialize shared state
     let state = Arc::new(Mutex::new(RuntimeState {
@@ -144,124 +967,507 @@ fn main() {
         running: true,
         log_messages: Default::default(),
         scpi_log_messages: Default::default(),
+        scpi_latency: Default::default(),
+        echo_logs: false,
+        markers: Vec::new(),
+        deadline: None,
+        frozen: None,
     }));
 
     // Initialize log writers
-    let writers = Arc::new(Mutex::new(LogWriters::new()));
+    let writers = Arc::new(Mutex::new(LogWriters::with_run_id(run_id)));
+    for line in capabilities.report().iter().chain(&startup_notes) {
+        log_message!(state, writers, "{}", line);
+    }
 
-    // Set up each channel
-    for profile in &profiles {
-        let ch_idx = (profile.channel - 1) as usize;
-        if ch_idx < 3 {
-            let mut s = state.lock().unwrap();
-            s.channels[ch_idx].enabled = true;
-            s.channels[ch_idx].soc = 1.0;
-            s.channels[ch_idx].profile_name = profile.name.clone();
+    // One thread per channel; the profiles of a queue run in order on the same thread
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for n in 0..profiles.len() {
+        match groups.iter_mut().find(|g| queue_of[n].is_some() && queue_of[g[0]] == queue_of[n]) {
+            Some(group) => group.push(n),
+            None => groups.push(vec![n]),
         }
     }
 
+    // Set up each channel with the first profile it runs
+    for group in &groups {
+        let n = group[0];
+        state.lock().unwrap().channels[(profiles[n].channel - 1) as usize].ocv_extrapolated =
+            ocv_range == OcvRangeMode::Extrapolate;
+        show_profile(&state, &profiles[n], cells[n].as_ref().map_or(profiles[n].initial_soc(), |c| c.soc), ocv_covered[n]);
+    }
+    for &ch in &monitor_channels {
+        let mut s = state.lock().unwrap();
+        let channel = &mut s.channels[(ch - 1) as usize];
+        channel.enabled = true;
+        channel.monitor_only = true;
+        channel.profile_name = "Monitor".to_string();
+    }
+
     // Start TUI in separate thread
     let tui_state = state.clone();
+    let tui_writers = writers.clone();
     let addr_clone = addr.clone();
-    std::thread::spawn(move || {
-        dp832_battery_sim::battery_sim::ui::run_tui(tui_state, addr_clone);
+    let title = title.unwrap_or_else(|| "DP832 Battery Simulator".to_string());
+    let ui_thread = std::thread::spawn(move || {
+        dp832_battery_sim::battery_sim::ui::run_tui(tui_state, tui_writers, addr_clone, title, detach_on_quit, key_input);
     });
 
     // Start simulation threads for each channel
     // Each channel gets its own TCP connection to avoid race conditions
     let mut sim_threads = Vec::new();
+    let mut csv_files = Vec::new();
+
+    if let Some(secs) = max_runtime {
+        state.lock().unwrap().deadline = Some(Instant::now() + Duration::from_secs(secs));
+        log_message!(state, writers, "Max runtime {} s: all channels stop at the limit", secs);
+    }
+
+    // The wide file gets a row per bucket of the slowest channel's interval
+    let wide_csv = match (&csv_log, csv_layout) {
+        (Some(path), CsvLayout::Wide) => {
+            let mut channels: Vec<u8> = profiles.iter().map(|p| p.channel).chain(monitor_channels.iter().copied()).collect();
+            channels.sort();
+            let interval_ms = profiles
+                .iter()
+                .map(|p| p.update_interval_ms)
+                .chain(monitor_channels.iter().map(|_| MONITOR_INTERVAL.as_millis() as u64))
+                .max()
+                .unwrap_or(1000);
+            let bucket = Duration::from_millis(interval_ms * options.csv_decimate);
+            let wide = WideCsv::create(path, channels, bucket, flush_policy).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
+            csv_files.push(path.clone());
+            Some(Arc::new(Mutex::new(wide)))
+        }
+        _ => None,
+    };
+    // Queued runs are numbered from 1 and each get their own file
+    let csv_sink = |channel: u8, run: Option<usize>, csv_files: &mut Vec<String>| -> Option<CsvSink> {
+        if let Some(wide) = &wide_csv {
+            return Some(CsvSink::Wide(wide.clone(), channel));
+        }
+        csv_log.as_ref().map(|p| {
+            let path = match run {
+                Some(run) => format!("{}_ch{}_run{}.csv", p.trim_end_matches(".csv"), channel, run),
+                None => format!("{}_ch{}.csv", p.trim_end_matches(".csv"), channel),
+            };
+            csv_files.push(path.clone());
+            let file = CsvFile::create(&path, flush_policy).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
+            CsvSink::PerChannel(Box::new(file))
+        })
+    };
     
-    for profile in profiles {
+    let mut runs = Vec::new();
+    for (n, ((profile, cell), path)) in profiles.into_iter().zip(cells).zip(profile_paths.iter().cloned()).enumerate() {
+        let queued = groups
+            .iter()
+            .find(|g| g.len() > 1 && g.contains(&n))
+            .and_then(|g| g.iter().position(|&m| m == n))
+            .map(|position| position + 1);
+        let csv = csv_sink(profile.channel, queued, &mut csv_files);
+        let source = ChannelSource { path, cell };
+        runs.push(Some(QueuedRun { profile, source, csv, ocv_covered: ocv_covered[n] }));
+    }
+
+    for (idx, group) in groups.iter().enumerate() {
+        let queue: Vec<QueuedRun> = group.iter().filter_map(|&n| runs[n].take()).collect();
         let state_clone = state.clone();
         let writers_clone = writers.clone();
+        let mut options_clone = options.clone();
+        options_clone.startup_delay = startup_stagger * idx as u32;
         
         // Create separate TCP stream for this channel (key to avoiding Command errors!)
-        let mut stream_clone = TcpStream::connect(&addr).unwrap();
-        stream_clone
-            .set_read_timeout(Some(Duration::from_secs(1)))
-            .unwrap();
+        let mut stream_clone = connect(&addr, &connect_options).unwrap_or_else(|e| {
+            eprintln!("Failed to open connection for CH{}: {}", queue[0].profile.channel, e);
+            std::process::exit(1);
+        });
         
         // Clear any errors on this connection before starting
-        send(&mut stream_clone, "*CLS");
-        
-        let csv_clone = csv_log.as_ref().map(|p| {
-            let path = format!("{}_ch{}.csv", p.trim_end_matches(".csv"), profile.channel);
-            csv::Writer::from_path(path).unwrap()
-        });
+        if let Err(e) = send(&mut stream_clone, "*CLS") {
+            eprintln!("Failed to write to CH{}'s connection: {}", queue[0].profile.channel, e);
+            std::process::exit(1);
+        }
 
         let thread = std::thread::spawn(move || {
-            simulate_channel(state_clone, writers_clone, stream_clone, profile, csv_clone);
+            run_queue(state_clone, writers_clone, stream_clone, queue, options_clone)
         });
         
         sim_threads.push(thread);
     }
 
-    // Wait for all simulation threads to complete
-    for thread in sim_threads {
+    // Measurement-only channels run until the simulation ends or the user quits
+    let mut monitor_threads = Vec::new();
+    for ch in monitor_channels {
+        let state_clone = state.clone();
+        let writers_clone = writers.clone();
+        let options_clone = options.clone();
+        let stream_clone = connect(&addr, &connect_options).unwrap_or_else(|e| {
+            eprintln!("Failed to open connection for CH{}: {}", ch, e);
+            std::process::exit(1);
+        });
+        let csv_clone = csv_sink(ch, None, &mut csv_files);
+
+        monitor_threads.push(std::thread::spawn(move || {
+            let sign = display_sign(&options_clone, ch);
+            monitor_channel(state_clone, writers_clone, stream_clone, ch, csv_clone, sign, options_clone.schedule);
+        }));
+    }
+
+    // Wait for all simulation threads to complete; each one switches its
+    // output off and closes its CSV file before it returns
+    let summaries: Vec<RunSummary> = sim_threads.into_iter().flat_map(|t| t.join().unwrap()).collect();
+
+    if !summaries.is_empty() {
+        state.lock().unwrap().running = false;
+    }
+    for thread in monitor_threads {
         thread.join().unwrap();
     }
+    // The UI exits once `running` is cleared; restore the terminal before the process ends
+    ui_thread.join().unwrap();
+
+    if let Some(hook) = run_hook.filter(|_| !summaries.is_empty()) {
+        for line in fire_on_complete(&hook, &summaries) {
+            log_message!(state, writers, "{}", line);
+        }
+    }
+
+    // Record the run so `dp832 export` can bundle it
+    let manifest = {
+        let w = writers.lock().unwrap();
+        RunManifest {
+            run_id: w.run_id().to_string(),
+            run_name,
+            device_id,
+            config: config_path.map(|p| p.to_string_lossy().into_owned()),
+            profiles: profile_paths,
+            csv_files,
+            event_log: w.event_log_path(),
+            scpi_log: w.scpi_log_path(),
+            raw_scpi_log: raw_log_path,
+            summaries,
+        }
+    };
+    match manifest.save() {
+        Ok(path) => log_message!(state, writers, "Run manifest written to {}", path),
+        Err(e) => log_message!(state, writers, "{}", e),
+    }
+}
+
+/// Put a channel's TUI panel on `profile`, before its run starts
+///
+/// Everything left from a previous run on the channel is cleared, including
+/// the charge and energy counters.
+fn show_profile(state: &Mutex<RuntimeState>, profile: &BatteryProfile, soc: f64, ocv_covered: Option<(f64, f64)>) {
+    let mut s = state.lock().unwrap();
+    let channel = &mut s.channels[(profile.channel - 1) as usize];
+    *channel = ChannelState {
+        enabled: true,
+        soc,
+        rc_time_constant_ms: profile.rc_time_constant_ms,
+        profile_name: profile.name.clone(),
+        current_limit: profile.current_limit_discharge_a,
+        ocv_covered,
+        ocv_extrapolated: channel.ocv_extrapolated,
+        ..Default::default()
+    };
+}
+
+/// Run a channel's profiles one after another, each on a fresh connection
+///
+/// The queue moves on only when a run ends at cutoff; any other stop (the
+/// user, max runtime, errors) ends the channel as without a queue. A single
+/// `-p` profile is a queue of one.
+fn run_queue(
+    state: Arc<Mutex<RuntimeState>>,
+    writers: Arc<Mutex<LogWriters>>,
+    stream: TcpStream,
+    queue: Vec<QueuedRun>,
+    mut options: SimOptions,
+) -> Vec<RunSummary> {
+    let channel = queue[0].profile.channel;
+    let total = queue.len();
+    let mut stream = Some(stream);
+    let mut summaries = Vec::new();
+
+    for (n, run) in queue.into_iter().enumerate() {
+        if n > 0 {
+            if !wait_for_next_run(&state, &writers, &run.profile, &options) {
+                break;
+            }
+            let reconnect = connect(&options.addr, &options.connect_options)
+                .and_then(|mut new_stream| send(&mut new_stream, "*CLS").map(|()| new_stream));
+            match reconnect {
+                Ok(new_stream) => {
+                    stream = Some(new_stream);
+                    state.lock().unwrap().scpi_latency[(channel - 1) as usize].clear();
+                }
+                Err(e) => {
+                    log_message!(state, writers, "CH{}: Queue stopped, failed to reconnect: {}", channel, e);
+                    break;
+                }
+            }
+            options.startup_delay = Duration::ZERO;
+            show_profile(&state, &run.profile, run.source.cell.as_ref().map_or(run.profile.initial_soc(), |c| c.soc), run.ocv_covered);
+        }
+        let Some(stream) = stream.take() else { break };
+        if total > 1 {
+            log_message!(state, writers, "CH{}: Queue run {}/{}: '{}'", channel, n + 1, total, run.profile.name);
+        }
+
+        let summary = simulate_channel(state.clone(), writers.clone(), stream, run.profile, run.source, run.csv, options.clone());
+        let completed = summary.reason.completed();
+        summaries.push(summary);
+        if !completed {
+            break;
+        }
+    }
+
+    if total > 1 {
+        let completed = summaries.iter().filter(|s| s.reason.completed()).count();
+        log_message!(state, writers, "CH{}: Queue finished, {} of {} runs completed", channel, completed, total);
+    }
+    summaries
+}
+
+/// Between queued runs: wait out `queue_delay`, then with `queue_prompt` for Enter in the TUI
+///
+/// Returns false if the simulation was stopped meanwhile.
+fn wait_for_next_run(
+    state: &Arc<Mutex<RuntimeState>>,
+    writers: &Arc<Mutex<LogWriters>>,
+    next: &BatteryProfile,
+    options: &SimOptions,
+) -> bool {
+    let ch_idx = (next.channel - 1) as usize;
+    let running = || state.lock().is_ok_and(|s| s.running);
+
+    if !options.queue_delay.is_zero() {
+        log_message!(state, writers, "CH{}: Next run '{}' in {} s", next.channel, next.name, options.queue_delay.as_secs());
+        let until = Instant::now() + options.queue_delay;
+        while Instant::now() < until && running() {
+            sleep(Duration::from_millis(100));
+        }
+    }
+
+    if options.queue_prompt && running() {
+        // Line output has no key to confirm with
+        if state.lock().unwrap().echo_logs {
+            log_message!(state, writers, "CH{}: No TUI to confirm the cell swap, starting '{}'", next.channel, next.name);
+        } else {
+            state.lock().unwrap().channels[ch_idx].swap_prompt = Some(next.name.clone());
+            log_message!(state, writers, "CH{}: Swap the cell for '{}' and press Enter", next.channel, next.name);
+            while state.lock().is_ok_and(|s| s.running && s.channels[ch_idx].swap_prompt.is_some()) {
+                sleep(Duration::from_millis(100));
+            }
+        }
+    }
+    running()
+}
+
+/// Sign applied to current and power for display and CSV output
+fn display_sign(options: &SimOptions, channel: u8) -> f64 {
+    if options.invert_current.contains(&channel) {
+        -1.0
+    } else {
+        1.0
+    }
 }
 
 fn simulate_channel(
     state: Arc<Mutex<RuntimeState>>,
     writers: Arc<Mutex<LogWriters>>,
     mut stream: TcpStream,
-    profile: BatteryProfile,
-    mut csv: Option<csv::Writer<File>>,
-) {
+    mut profile: BatteryProfile,
+    source: ChannelSource,
+    mut csv: Option<CsvSink>,
+    options: SimOptions,
+) -> RunSummary {
     let ch_idx = (profile.channel - 1) as usize;
     let ch_name = format!("CH{}", profile.channel);
     
-    // Initialize channel - select it once at the start
-    // Since each channel has its own TCP connection, this selection persists
-    log_scpi!(state, writers, "CH{} → INST:NSEL {}", profile.channel, profile.channel);
-    send(&mut stream, &format!("INST:NSEL {}", profile.channel));
-    
-    log_scpi!(state, writers, "CH{} → OUTP OFF", profile.channel);
-    send(&mut stream, "OUTP OFF");
-    
-    log_scpi!(state, writers, "CH{} → CURR {:.3}", profile.channel, profile.current_limit_discharge_a);
-    send(&mut stream, &format!("CURR {:.3}", profile.current_limit_discharge_a));
-    
-    log_scpi!(state, writers, "CH{} → OUTP ON", profile.channel);
-    send(&mut stream, "OUTP ON");
+    // Every setting names its channel: INST:NSEL is instrument-wide, so a
+    // selection made on this connection can be changed by another channel's
+    // thread (or the front panel) before a bare VOLT/CURR arrives
+    let output_off = format!("OUTP {},OFF", ch_name);
+    let output_on = format!("OUTP {},ON", ch_name);
+    let setup_cmds = options.drive.setup_commands(profile.channel, profile.max_voltage, profile.current_limit_discharge_a);
+
+    if !options.startup_delay.is_zero() {
+        log_message!(state, writers, "CH{}: Waiting {} ms before initializing",
+                    profile.channel, options.startup_delay.as_millis());
+        sleep(options.startup_delay);
+    }
+
+    // A failed write is counted like a failed read in the next step, so
+    // the error policy (stop or reconnect) applies to it as well
+    let mut pending_error: Option<String> = None;
+    for cmd in std::iter::once(&output_off).chain(&setup_cmds).chain([&output_on]) {
+        log_scpi!(state, writers, "{} → {}", ch_name, cmd);
+        if let Err(e) = send(&mut stream, cmd) {
+            pending_error = Some(format!("failed to send '{}': {}", cmd, e));
+            break;
+        }
+    }
     
+    // `calibrate-check` fit of this channel's current readback
+    let calibration = options.current_calibration.iter().find(|c| c.channel == profile.channel);
+    // Display/CSV only; the model always works with the measured sign
+    let sign = display_sign(&options, profile.channel);
+    if sign < 0.0 {
+        log_message!(state, writers, "CH{}: Current and power are shown and logged with inverted sign", profile.channel);
+    }
+
     log_message!(state, writers, "CH{}: Initialized - {} ({:.1}Ah, {:.3}Ω)", 
                 profile.channel, 
                 profile.name,
                 profile.capacity_ah,
                 profile.internal_resistance_ohm);
+    if options.drive == DriveMode::Current {
+        log_message!(state, writers, "CH{}: CC drive - commanding the model current, voltage held at {:.3}V",
+                    profile.channel, profile.max_voltage);
+    }
 
-    let mut soc = 1.0;
+    let mut cell = source.cell;
+    let mut soc = cell.as_ref().map_or(profile.initial_soc(), |c| c.soc);
     let mut last = Instant::now();
-    let mut v_filt = interpolate_ocv(&profile.ocv_curve, soc);
+    let start = last;
+    let mut pacer = Pacer::new(Duration::from_millis(profile.update_interval_ms), options.fixed_cadence);
+    let mut intervals = IntervalStats::new(Duration::from_millis(profile.update_interval_ms));
+    let mut behind_schedule = false;
+    // OCV hysteresis: which of the charge and discharge curves is in use
+    let mut direction = profile.initial_direction();
+    let mut v_filt = profile.ocv_at(soc, options.temperature_c, direction);
     let mut last_voltage_set = v_filt;  // Track last voltage we sent to PSU
+    let mut i_model = 0.0;  // CC drive: filtered model current
+    let mut last_current_set = 0.0;
+    const CURRENT_CHANGE_THRESHOLD: f64 = 0.001;  // CC drive: only update if current changes by >1mA
     let mut consecutive_errors = 0;
-    const MAX_CONSECUTIVE_ERRORS: u32 = 5;
+    let mut first_error: Option<Instant> = None;
+    let mut last_good_current = 0.0;
     const VOLTAGE_CHANGE_THRESHOLD: f64 = 0.001;  // Only update if voltage changes by >1mV
+    const CSV_CUTOFF_MARGIN: f64 = 0.1;  // Log every sample in the last 10% of the voltage range
+    let mut csv_samples: u64 = 0;
+    let mut markers_seen = state.lock().unwrap().markers.len();
+    let mut quiet_hours = QuietHours::new(options.schedule.clone());
+    // Tail capture: (absolute minimum voltage, current limit) once past cutoff
+    let tail = if options.tail_capture { profile.tail_limits().ok().flatten() } else { None };
+    let mut in_tail = false;
+    let mut tail_started = false;
+    // Charge mode: the simulated CC-CV charger. It is part of the model, so a
+    // pre-charge trickle limits the charger and not the channel.
+    let mut charger = if profile.mode == ProfileMode::Charge { Charger::new(&profile, v_filt).ok() } else { None };
+    if let Some(Charger { phase: ChargePhase::Precharge, precharge: Some((threshold, trickle)), .. }) = charger {
+        log_message!(state, writers, "CH{}: Pre-charge at {:.3}A below {:.3}V", profile.channel, trickle, threshold);
+    }
+    // Two-RC Thévenin profiles replace the single RC filter
+    let branches = profile.rc_branches().ok().flatten();
+    let mut thevenin = TheveninState::default();
+    // No-load detection: armed by the first loaded step, then the start of the current idle stretch
+    let mut load_seen = false;
+    let mut idle_since: Option<Instant> = None;
+    let mut no_load_reported = false;
+    let mut no_load_started = false;
+    let mut charge_ah = 0.0;
+    let mut energy_wh = 0.0;
+    // Exponential average over about a minute, so the estimate follows load changes
+    const RUNTIME_WINDOW_S: f64 = 60.0;
+    const IDLE_CURRENT_A: f64 = 0.001;
+    let mut avg_current: Option<f64> = None;
+    const CELL_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+    let mut last_cell_save = Instant::now();
+
+    // Warm-up: the first reads after OUTP ON can be stale or zero
+    const WARMUP_STABLE_READINGS: u32 = 2;  // Consecutive stable reads required
+    const WARMUP_MAX_STEPS: u32 = 50;       // Give up waiting and integrate anyway
+    const WARMUP_TOLERANCE_A: f64 = 0.01;   // Reads within 10mA (or 5%) count as stable
+    let mut warming_up = true;
+    let mut warmup_steps = 0;
+    let mut stable_readings = 0;
+    let mut warmup_prev: Option<f64> = None;
+
+    let mut noise = options.noise.as_ref().map(|cfg| NoiseGenerator::new(cfg, profile.channel));
+    if noise.is_some() {
+        log_message!(state, writers, "CH{}: Injecting synthetic current noise", profile.channel);
+    }
+
+    let reason = loop {
+        if let Some(outputs) = options.freeze.filter(|_| state.lock().unwrap().frozen.is_some()) {
+            if outputs == FreezeOutputs::Off {
+                log_scpi!(state, writers, "{} → {}", ch_name, output_off);
+                if let Err(e) = send(&mut stream, &output_off) {
+                    log_message!(state, writers, "CH{}: Failed to switch the output off for the pause: {}",
+                                profile.channel, e);
+                }
+            }
+            log_message!(state, writers, "CH{}: Paused", profile.channel);
+            while state.lock().is_ok_and(|s| s.running && s.frozen.is_some()) {
+                sleep(Duration::from_millis(100));
+            }
+            if !state.lock().unwrap().running {
+                break StopReason::UserStop;
+            }
+            if outputs == FreezeOutputs::Off {
+                log_scpi!(state, writers, "{} → {}", ch_name, output_on);
+                if let Err(e) = send(&mut stream, &output_on) {
+                    pending_error = Some(format!("failed to send '{}': {}", output_on, e));
+                }
+                warming_up = true;
+                warmup_steps = 0;
+                stable_readings = 0;
+                warmup_prev = None;
+            }
+            log_message!(state, writers, "CH{}: Resumed", profile.channel);
+            // The pause is neither integrated nor counted as a late step
+            last = Instant::now();
+            pacer.reset();
+        }
 
-    loop {
         let now = Instant::now();
         let dt = now.duration_since(last).as_secs_f64();
+        intervals.record(now.duration_since(last));
         last = now;
+        if let Some(line) = intervals.report() {
+            log_message!(state, writers, "CH{}: {}", profile.channel, line);
+        }
 
-        // Query current using channel-specific syntax (more reliable than relying on INST:NSEL)
-        let curr_cmd = format!("MEAS:CURR? {}", ch_name);
-        log_scpi!(state, writers, "{} → {}", ch_name, curr_cmd);
-        let curr_str = query(&mut stream, &curr_cmd);
-        log_scpi!(state, writers, "{} ← {}", ch_name, curr_str.trim());
-        
-        // Check for error responses before parsing
-        let curr_result: Result<f64, String> = {
-            let trimmed = curr_str.trim();
-            if trimmed.contains("error") || trimmed.contains("Error") || trimmed.contains("ERROR") {
-                // PSU returned error - clear it and retry
-                log_message!(state, writers, "CH{}: PSU error response '{}' - clearing error state", 
-                            profile.channel, trimmed);
-                send(&mut stream, "*CLS");  // Clear error state
-                Err(trimmed.to_string())
-            } else {
-                trimmed.parse().map_err(|_| trimmed.to_string())
+        // A write that failed since the last step fails this one, without another query
+        let curr_result: Result<f64, String> = if let Some(e) = pending_error.take() {
+            Err(e)
+        } else {
+            // Query current using channel-specific syntax (more reliable than relying on INST:NSEL)
+            let curr_cmd = format!("MEAS:CURR? {}", ch_name);
+            log_scpi!(state, writers, "{} → {}", ch_name, curr_cmd);
+            let query_start = Instant::now();
+            match query_raw_retry(&mut stream, &curr_cmd, options.connect_options.query_retries) {
+                // A lost connection is a failed read like any other, so the error policy applies
+                Err(e) => Err(format!("failed to read current: {}", e)),
+                Ok(curr_raw) => {
+                    if let Ok(mut s) = state.lock() {
+                        s.scpi_latency[ch_idx].push(query_start.elapsed());
+                    }
+                    let curr_str = response_text(&curr_raw);
+                    log_scpi!(state, writers, "{} ← {}", ch_name, curr_str);
+
+                    // Check for error responses before parsing
+                    if curr_str.contains("error") || curr_str.contains("Error") || curr_str.contains("ERROR") {
+                        // PSU returned error - clear it and retry
+                        log_message!(state, writers, "CH{}: PSU error response '{}' - clearing error state", 
+                                    profile.channel, curr_str);
+                        if let Err(e) = send(&mut stream, "*CLS") {
+                            log_message!(state, writers, "CH{}: Failed to clear the error state: {}", profile.channel, e);
+                        }
+                        Err(format!("failed to parse current '{}'", curr_str))
+                    } else {
+                        parse_f64(&curr_raw).map_err(|e| format!("failed to parse current {}", e))
+                    }
+                }
             }
         };
 
@@ -269,72 +1475,375 @@ fn simulate_channel(
         let i = match curr_result {
             Ok(current) => {
                 consecutive_errors = 0;  // Reset error counter on success
+                first_error = None;
+                let current = calibration.map_or(current, |c| c.correct_current(current));
+                last_good_current = current;
                 current
             }
-            Err(raw_response) => {
+            Err(response) if options.freeze.is_some() => {
+                freeze(&state, &writers, &format!("CH{}: {}", profile.channel, response));
+                continue;
+            }
+            Err(response) => {
                 consecutive_errors += 1;
-                log_message!(state, writers, "CH{}: ERROR #{} - Failed to parse current '{}'. Retrying...", 
-                            profile.channel, consecutive_errors, raw_response.trim());
+                let failing_for = now.duration_since(*first_error.get_or_insert(now));
+                log_message!(state, writers, "CH{}: ERROR #{} - {}. Retrying...", 
+                            profile.channel, consecutive_errors, response);
                 
-                if consecutive_errors >= MAX_CONSECUTIVE_ERRORS {
-                    log_message!(state, writers, "CH{}: Too many consecutive errors. Stopping simulation for safety.", 
-                                profile.channel);
-                    // Turn off output for safety
-                    log_scpi!(state, writers, "CH{} → OUTP OFF", profile.channel);
-                    send(&mut stream, "OUTP OFF");
-                    break;
+                let policy = &options.errors;
+                if consecutive_errors >= policy.max_consecutive && failing_for >= policy.grace {
+                    // Name the limit that was reached last, i.e. the one that stopped the channel
+                    let limit = if consecutive_errors == policy.max_consecutive {
+                        format!("error count limit of {} reached", policy.max_consecutive)
+                    } else {
+                        format!("grace period of {} s ran out", policy.grace.as_secs())
+                    };
+                    log_message!(state, writers, "CH{}: {} errors in a row over {:.1} s, {}. Stopping simulation for safety.",
+                                profile.channel, consecutive_errors, failing_for.as_secs_f64(), limit);
+                    break StopReason::Errors;
+                }
+
+                if policy.action == ErrorAction::Reconnect && consecutive_errors % policy.max_consecutive == 0 {
+                    log_message!(state, writers, "CH{}: {} errors in a row, reconnecting to {}",
+                                profile.channel, consecutive_errors, options.addr);
+                    let reconnect = connect(&options.addr, &options.connect_options)
+                        .and_then(|mut new_stream| send(&mut new_stream, "*CLS").map(|()| new_stream));
+                    match reconnect {
+                        Ok(new_stream) => {
+                            stream = new_stream;
+                            // Round trips of the dead connection say nothing about the new one
+                            state.lock().unwrap().scpi_latency[ch_idx].clear();
+                            log_message!(state, writers, "CH{}: Reconnected", profile.channel);
+                        }
+                        Err(e) => log_message!(state, writers, "CH{}: Reconnect failed: {}", profile.channel, e),
+                    }
                 }
                 
-                // Skip this iteration and retry next time
-                sleep(Duration::from_millis(profile.update_interval_ms));
+                match options.read_failure {
+                    ReadFailureStrategy::Skip => {
+                        // Skip this iteration and retry next time
+                        pacer.wait();
+                        continue;
+                    }
+                    ReadFailureStrategy::Hold => {
+                        log_message!(state, writers, "CH{}: Holding last good current {:.3}A for this step",
+                                    profile.channel, last_good_current);
+                        last_good_current
+                    }
+                    ReadFailureStrategy::Zero => {
+                        log_message!(state, writers, "CH{}: Using 0 A for this step", profile.channel);
+                        0.0
+                    }
+                }
+            }
+        };
+
+        // Don't integrate until the output is confirmed ON and the current has settled
+        if warming_up {
+            let out_cmd = format!("OUTP? {}", ch_name);
+            log_scpi!(state, writers, "{} → {}", ch_name, out_cmd);
+            // A lost connection leaves the output unconfirmed; the next current read counts the error
+            let out_str = query_raw_retry(&mut stream, &out_cmd, options.connect_options.query_retries)
+                .map(|raw| response_text(&raw))
+                .unwrap_or_default();
+            log_scpi!(state, writers, "{} ← {}", ch_name, out_str);
+
+            let stable = warmup_prev
+                .is_some_and(|prev| (i - prev).abs() <= WARMUP_TOLERANCE_A.max(prev.abs() * 0.05));
+            warmup_prev = Some(i);
+            let output_on = parse_bool_scpi(&out_str) == Some(true);
+            stable_readings = if output_on && stable { stable_readings + 1 } else { 0 };
+            warmup_steps += 1;
+
+            if stable_readings >= WARMUP_STABLE_READINGS {
+                log_message!(state, writers, "CH{}: Warm-up complete ({:.3}A), starting integration", profile.channel, i);
+                warming_up = false;
+            } else if warmup_steps >= WARMUP_MAX_STEPS {
+                log_message!(state, writers, "CH{}: Current did not settle after {} reads, starting integration anyway",
+                            profile.channel, warmup_steps);
+                warming_up = false;
+            } else {
+                if warmup_steps == 1 {
+                    log_message!(state, writers, "CH{}: Warming up - waiting for output ON and stable current", profile.channel);
+                }
+                pacer.wait();
                 continue;
             }
+        }
+
+        if let Some(policy) = &options.no_load {
+            if i.abs() >= policy.current {
+                if std::mem::take(&mut no_load_reported) {
+                    log_message!(state, writers, "CH{}: Load current back ({:.3}A)", profile.channel, i);
+                }
+                load_seen = true;
+                idle_since = None;
+            } else if load_seen && !no_load_reported {
+                let since = *idle_since.get_or_insert(now);
+                if now.duration_since(since) >= policy.timeout {
+                    log_message!(state, writers, "CH{}: No load detected - below {:.3}A for {} s (load disconnected?)",
+                                profile.channel, policy.current, policy.timeout.as_secs());
+                    if policy.action == NoLoadAction::Stop {
+                        break StopReason::NoLoad;
+                    }
+                    no_load_reported = true;
+                    no_load_started = true;
+                }
+            }
+        }
+
+        // Synthetic noise is applied after warm-up so it can't prevent settling
+        let i = match noise.as_mut() {
+            Some(gen) => i + gen.sample(now.duration_since(start).as_secs_f64()),
+            None => i,
         };
 
-        // Discharge / charge integration
-        soc -= i * dt / (profile.capacity_ah * 3600.0);
-        soc = soc.clamp(0.0, 1.0);
+        // Charge mode: the charger supplies the load and the cell together, at
+        // its current limit (CC) until the terminal voltage would pass
+        // max_voltage, then just enough to hold it there (CV). While pre-charging
+        // the trickle current is the limit. From here on `i` is the cell
+        // current, negative while it charges.
+        let charger_a = charger.as_mut().map(|charger| {
+            let ocv = profile.ocv_at(soc, options.temperature_c, direction) - thevenin.polarization();
+            let phase = charger.phase;
+            let current = charger.current(&profile, ocv, i);
+            if phase != charger.phase {
+                log_message!(state, writers, "CH{}: CV phase at {:.3}V, charge current tapering", profile.channel, profile.max_voltage);
+            }
+            current
+        });
+        let i = i - charger_a.unwrap_or(0.0);
+
+        // Discharge / charge integration, plus self-discharge over the same step
+        soc = profile.soc_after(soc, i, dt);
 
-        let voc = interpolate_ocv(&profile.ocv_curve, soc);
+        if profile.has_hysteresis() && direction.after(i) != direction {
+            direction = direction.after(i);
+            let curve = if direction == CurrentDirection::Charge { "charge" } else { "discharge" };
+            log_message!(state, writers, "CH{}: OCV switched to the {} curve ({:.3}A)", profile.channel, curve, i);
+        }
+        let voc = profile.ocv_at(soc, options.temperature_c, direction);
+
+        // Pick up RC time constant changes and save requests from the TUI
+        let (rc_ms, save_tuned) = {
+            let mut s = state.lock().unwrap();
+            let ch = &mut s.channels[ch_idx];
+            (ch.rc_time_constant_ms, std::mem::take(&mut ch.save_tuned))
+        };
+        if rc_ms != profile.rc_time_constant_ms {
+            log_message!(state, writers, "CH{}: RC time constant {} → {} ms",
+                        profile.channel, profile.rc_time_constant_ms, rc_ms);
+            profile.rc_time_constant_ms = rc_ms;
+        }
+        if save_tuned {
+            match save_tuned_profile(&source.path, profile.rc_time_constant_ms) {
+                Ok(path) => log_message!(state, writers, "CH{}: Tuned profile written to {}", profile.channel, path),
+                Err(e) => log_message!(state, writers, "CH{}: {}", profile.channel, e),
+            }
+        }
 
         // RC smoothing
         let tau = profile.rc_time_constant_ms as f64 / 1000.0;
         let alpha = dt / (tau + dt);
 
-        let v_target = voc - i * profile.internal_resistance_ohm;
-        v_filt += alpha * (v_target - v_filt);
+        match options.drive {
+            DriveMode::Voltage => {
+                let v_target = voc - i * profile.internal_resistance_ohm;
+                match &branches {
+                    Some(branches) => {
+                        thevenin.step(branches, i, dt);
+                        v_filt = v_target - thevenin.polarization();
+                    }
+                    None => v_filt += alpha * (v_target - v_filt),
+                }
+            }
+            DriveMode::Current => {
+                // The device under test sets the terminal voltage; the cell delivers (OCV - V) / R
+                let volt_cmd = format!("MEAS:VOLT? {}", ch_name);
+                log_scpi!(state, writers, "{} → {}", ch_name, volt_cmd);
+                let volt_result = match query_raw_retry(&mut stream, &volt_cmd, options.connect_options.query_retries) {
+                    Ok(volt_raw) => {
+                        log_scpi!(state, writers, "{} ← {}", ch_name, response_text(&volt_raw));
+                        parse_f64(&volt_raw).map_err(|e| format!("failed to parse voltage {}", e))
+                    }
+                    Err(e) => Err(format!("failed to read voltage: {}", e)),
+                };
+                match volt_result {
+                    Ok(v) => v_filt = v,
+                    Err(e) => {
+                        log_message!(state, writers, "CH{}: {}, keeping {:.3}V", profile.channel, e, v_filt);
+                        if options.freeze.is_some() {
+                            freeze(&state, &writers, &format!("CH{}: {}", profile.channel, e));
+                        }
+                    }
+                }
+                let i_limit = if in_tail { tail.map_or(0.0, |(_, a)| a) } else { profile.current_limit_discharge_a };
+                let i_target = ((voc - thevenin.polarization() - v_filt) / profile.internal_resistance_ohm).clamp(0.0, i_limit);
+                match &branches {
+                    Some(branches) => {
+                        i_model = i_target;
+                        thevenin.step(branches, i_model, dt);
+                    }
+                    None => i_model += alpha * (i_target - i_model),
+                }
+            }
+        }
 
-        if v_filt <= profile.cutoff_voltage {
-            log_message!(state, writers, "CH{}: Cutoff voltage reached ({:.3}V)", profile.channel, v_filt);
-            log_scpi!(state, writers, "CH{} → OUTP OFF", profile.channel);
-            send(&mut stream, "OUTP OFF");
-            break;
+        charge_ah += i * dt / 3600.0;
+        energy_wh += v_filt * i * dt / 3600.0;
+
+        let avg = match avg_current {
+            Some(avg) => avg + dt / (RUNTIME_WINDOW_S + dt) * (i - avg),
+            None => i,
+        };
+        avg_current = Some(avg);
+        let remaining_s = (avg > IDLE_CURRENT_A).then(|| soc * profile.capacity_ah * 3600.0 / profile.soc_current(avg));
+
+        // Keep the cell record current so an interrupted session can still resume
+        if let Some(record) = cell.as_mut() {
+            record.soc = soc;
+            record.throughput_ah += (i * dt / 3600.0).abs();
+            if last_cell_save.elapsed() >= CELL_SAVE_INTERVAL {
+                last_cell_save = Instant::now();
+                if let Err(e) = record.save() {
+                    log_message!(state, writers, "CH{}: {}", profile.channel, e);
+                }
+            }
+        }
+
+        // The OCV curve is flat below 0%, so an empty cell may never reach the absolute minimum
+        if in_tail && soc <= 0.0 {
+            log_message!(state, writers, "CH{}: SoC reached 0% during tail capture ({:.3}V)", profile.channel, v_filt);
+            break StopReason::Cutoff;
+        }
+
+        if let Some(charger) = charger.as_mut() {
+            let phase = charger.phase;
+            charger.update(v_filt, i, soc);
+            match (phase, charger.phase) {
+                (ChargePhase::Precharge, ChargePhase::ConstantCurrent) => {
+                    log_message!(state, writers, "CH{}: Pre-charge done at {:.3}V, charging at {:.3}A",
+                                profile.channel, v_filt, charger.limit_a);
+                }
+                (_, ChargePhase::Charged) => {
+                    log_message!(state, writers, "CH{}: Charge complete ({:.3}A into the cell, SoC {:.1}%)",
+                                profile.channel, -i, soc * 100.0);
+                    break StopReason::Charged;
+                }
+                _ => {}
+            }
         }
 
-        if v_filt >= profile.max_voltage {
+        // In CC drive the terminal voltage is not ours, so an OCV at cutoff also ends the run.
+        // A charge run starts from an empty cell, at or below cutoff, and only ends charged.
+        let stop_voltage = match tail {
+            Some((min_voltage, _)) if in_tail => min_voltage,
+            _ => profile.cutoff_voltage,
+        };
+        let cc_empty = options.drive == DriveMode::Current && voc <= stop_voltage;
+        if charger.is_none() && (v_filt <= stop_voltage || cc_empty) {
+            match tail {
+                Some((min_voltage, tail_a)) if !in_tail => {
+                    log_message!(state, writers, "CH{}: Cutoff voltage reached ({:.3}V), tail capture down to {:.3}V at {:.3}A",
+                                profile.channel, v_filt, min_voltage, tail_a);
+                    if options.drive == DriveMode::Voltage {
+                        let curr_cmd = set_current_command(profile.channel, round_setpoint(tail_a));
+                        log_scpi!(state, writers, "{} → {}", ch_name, curr_cmd);
+                        if let Err(e) = send(&mut stream, &curr_cmd) {
+                            pending_error = Some(format!("failed to send '{}': {}", curr_cmd, e));
+                        }
+                    }
+                    state.lock().unwrap().channels[ch_idx].current_limit = tail_a;
+                    in_tail = true;
+                    tail_started = true;
+                }
+                Some(_) => {
+                    log_message!(state, writers, "CH{}: Absolute minimum voltage reached ({:.3}V)", profile.channel, v_filt);
+                    break StopReason::Cutoff;
+                }
+                None => {
+                    log_message!(state, writers, "CH{}: Cutoff voltage reached ({:.3}V)", profile.channel, v_filt);
+                    break StopReason::Cutoff;
+                }
+            }
+        }
+
+        if v_filt >= profile.max_voltage && options.drive == DriveMode::Voltage {
             v_filt = profile.max_voltage;
         }
 
         // Set voltage - only if it has changed significantly (reduces SCPI traffic)
-        // No need to re-select channel since it was selected at init and persists on this connection
-        if (v_filt - last_voltage_set).abs() > VOLTAGE_CHANGE_THRESHOLD {
-            let volt_cmd = format!("VOLT {:.3}", v_filt);
+        if !v_filt.is_finite() {
+            // Never transmit NaN/inf to the instrument; a broken profile must not reach the hardware
+            log_message!(state, writers, "CH{}: Model produced invalid voltage {} (check the profile). Stopping simulation for safety.",
+                        profile.channel, v_filt);
+            break StopReason::ModelError;
+        }
+
+        if options.drive == DriveMode::Current {
+            if !i_model.is_finite() {
+                log_message!(state, writers, "CH{}: Model produced invalid current {} (check the profile). Stopping simulation for safety.",
+                            profile.channel, i_model);
+                break StopReason::ModelError;
+            }
+            if (i_model - last_current_set).abs() > CURRENT_CHANGE_THRESHOLD {
+                let i_set = round_setpoint(i_model);
+                let curr_cmd = options.drive.step_command(profile.channel, i_set);
+                log_scpi!(state, writers, "{} → {}", ch_name, curr_cmd);
+                match send(&mut stream, &curr_cmd) {
+                    Ok(()) => last_current_set = i_set,
+                    Err(e) => pending_error = Some(format!("failed to send '{}': {}", curr_cmd, e)),
+                }
+            }
+        } else if (v_filt - last_voltage_set).abs() > VOLTAGE_CHANGE_THRESHOLD {
+            let v_set = round_setpoint(v_filt);
+            let volt_cmd = options.drive.step_command(profile.channel, v_set);
             log_scpi!(state, writers, "{} → {}", ch_name, volt_cmd);
-            send(&mut stream, &volt_cmd);
-            
-            last_voltage_set = v_filt;
+            // Left unchanged on failure, so the next step sends the setpoint again
+            match send(&mut stream, &volt_cmd) {
+                Ok(()) => last_voltage_set = v_set,
+                Err(e) => pending_error = Some(format!("failed to send '{}': {}", volt_cmd, e)),
+            }
+        }
+
+        // Decimate CSV output, but keep full resolution close to cutoff
+        let near_cutoff = v_filt
+            <= profile.cutoff_voltage + CSV_CUTOFF_MARGIN * (profile.max_voltage - profile.cutoff_voltage);
+        let mut marker = state.lock().unwrap().new_markers(&mut markers_seen);
+        if std::mem::take(&mut tail_started) {
+            if !marker.is_empty() {
+                marker.push_str("; ");
+            }
+            marker.push_str("tail capture");
+        }
+        if std::mem::take(&mut no_load_started) {
+            if !marker.is_empty() {
+                marker.push_str("; ");
+            }
+            marker.push_str("no load");
+        }
+        if let Some(msg) = quiet_hours.update() {
+            log_message!(state, writers, "CH{}: {}", profile.channel, msg);
         }
+        let must_write = near_cutoff || !marker.is_empty();
+        let write_sample = if quiet_hours.active() {
+            // Quiet hours override decimation and the wide file's bucketing
+            quiet_hours.due(now) || must_write
+        } else {
+            csv_samples.is_multiple_of(options.csv_decimate) || must_write
+        };
+        csv_samples += 1;
 
-        if let Some(w) = csv.as_mut() {
-            w.write_record(&[
-                format!("{:.3}", now.elapsed().as_secs_f64()),
+        if let Some(w) = csv.as_mut().filter(|w| write_sample || (w.wants_every_sample() && !quiet_hours.active())) {
+            let values = [
                 format!("{:.4}", soc),
                 format!("{:.3}", v_filt),
-                format!("{:.3}", i),
-                format!("{:.3}", v_filt * i),
-            ])
-            .unwrap();
-            w.flush().unwrap();
+                format!("{:.3}", sign * i),
+                format!("{:.3}", sign * v_filt * i),
+            ];
+            if let Err(e) = w.write(start, now, values, marker) {
+                pending_error = Some(format!("failed to write the CSV log: {}", e));
+            }
         }
 
         // Update shared state
@@ -343,20 +1852,249 @@ fn simulate_channel(
             if ch_idx < 3 {
                 s.channels[ch_idx].soc = soc;
                 s.channels[ch_idx].voltage = v_filt;
-                s.channels[ch_idx].current = i;
-                s.channels[ch_idx].power = v_filt * i;
+                s.channels[ch_idx].current = sign * i;
+                s.channels[ch_idx].power = sign * v_filt * i;
                 s.channels[ch_idx].ocv = voc;
+                s.channels[ch_idx].remaining_s = remaining_s;
+                s.channels[ch_idx].charge_ah = sign * charge_ah;
+                s.channels[ch_idx].energy_wh = sign * energy_wh;
             }
         }
 
-        if !state.lock().unwrap().running {
-            log_scpi!(state, writers, "CH{} → OUTP OFF", profile.channel);
-            send(&mut stream, "OUTP OFF");
+        let (running, deadline) = {
+            let s = state.lock().unwrap();
+            (s.running, s.deadline)
+        };
+        if !running {
+            break StopReason::UserStop;
+        }
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            log_message!(state, writers, "CH{}: Max runtime reached", profile.channel);
+            break StopReason::MaxRuntime;
+        }
+
+        match pacer.wait() {
+            Some(late) if !behind_schedule => {
+                log_message!(state, writers, "CH{}: Can't keep up with {} ms cadence (step {} ms late)",
+                            profile.channel, profile.update_interval_ms, late.as_millis());
+                behind_schedule = true;
+            }
+            Some(_) => {}
+            None => behind_schedule = false,
+        }
+    };
+    
+    // Shutdown order: the model has stopped, so switch the output off and
+    // confirm it before the last CSV row is written and the file is closed
+    log_scpi!(state, writers, "{} → {}", ch_name, output_off);
+    if let Err(e) = send(&mut stream, &output_off) {
+        // Nothing more can be done over this connection; finish the run regardless
+        log_message!(state, writers, "CH{}: Failed to switch the output off ({}) - check the instrument",
+                    profile.channel, e);
+    } else {
+        let out_cmd = format!("OUTP? {}", ch_name);
+        log_scpi!(state, writers, "{} → {}", ch_name, out_cmd);
+        match query(&mut stream, &out_cmd) {
+            Ok(out_str) => {
+                log_scpi!(state, writers, "{} ← {}", ch_name, out_str);
+                if parse_bool_scpi(&out_str) == Some(false) {
+                    log_message!(state, writers, "CH{}: Output OFF confirmed", profile.channel);
+                } else {
+                    log_message!(state, writers, "CH{}: Output did not read back OFF ('{}') - check the instrument",
+                                profile.channel, out_str);
+                }
+            }
+            Err(e) => log_message!(state, writers, "CH{}: Could not read back the output state ({}) - check the instrument",
+                                   profile.channel, e),
+        }
+    }
+
+    log_message!(state, writers, "CH{}: Simulation stopped ({}, {:.3}Ah / {:.3}Wh delivered)",
+                profile.channel, reason, charge_ah, energy_wh);
+    if let Some(mut w) = csv.take() {
+        let (current, power) = {
+            let s = state.lock().unwrap();
+            (s.channels[ch_idx].current, s.channels[ch_idx].power)
+        };
+        let values = [
+            format!("{:.4}", soc),
+            format!("{:.3}", v_filt),
+            format!("{:.3}", current),
+            format!("{:.3}", power),
+        ];
+        if let Err(e) = w.write(start, Instant::now(), values, format!("stop: {}", reason)).and_then(|_| w.finish()) {
+            log_message!(state, writers, "CH{}: Failed to write the CSV log: {}", profile.channel, e);
+        }
+    }
+    if let Some(line) = intervals.summary() {
+        log_message!(state, writers, "CH{}: {}", profile.channel, line);
+    }
+
+    if let Some(record) = cell.as_mut() {
+        match record.save() {
+            Ok(()) => log_message!(state, writers, "CH{}: Saved cell {} at SoC {:.1}% ({:.3}Ah throughput)",
+                                   profile.channel, record.serial, record.soc * 100.0, record.throughput_ah),
+            Err(e) => log_message!(state, writers, "CH{}: {}", profile.channel, e),
+        }
+    }
+
+    let summary = RunSummary {
+        channel: profile.channel,
+        profile: profile.name.clone(),
+        reason,
+        duration_s: start.elapsed().as_secs_f64(),
+        final_soc: soc,
+        final_voltage: v_filt,
+        charge_ah,
+        energy_wh,
+    };
+
+    if let Some(hook) = &options.channel_hook {
+        for line in fire_on_complete(hook, std::slice::from_ref(&summary)) {
+            log_message!(state, writers, "CH{}: {}", profile.channel, line);
+        }
+    }
+
+    summary
+}
+
+/// `--freeze-on-error`: pause every channel and record the state around the error
+///
+/// Only the first error freezes the run; the other channels are paused by it
+/// before they can add their own. The snapshot is appended to
+/// `logs/freeze_<run>.txt`, so every freeze of a run ends up in one file.
+fn freeze(state: &Arc<Mutex<RuntimeState>>, writers: &Arc<Mutex<LogWriters>>, error: &str) {
+    let (event_log, scpi_log, path) = {
+        let w = writers.lock().unwrap();
+        (w.event_log_path(), w.scpi_log_path(), format!("logs/freeze_{}.txt", w.run_id()))
+    };
+    let snapshot = {
+        let mut s = state.lock().unwrap();
+        if s.frozen.is_some() {
+            return;
+        }
+        s.frozen = Some(error.to_string());
+
+        let mut text = format!("=== Frozen at {}: {}\n\n", chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"), error);
+        for (idx, ch) in s.channels.iter().enumerate().filter(|(_, ch)| ch.enabled) {
+            text += &format!("CH{} '{}': SoC {:.4}  {:.3}V  {:.3}A  {:.3}W  OCV {:.3}V  limit {:.3}A{}\n",
+                             idx + 1, ch.profile_name, ch.soc, ch.voltage, ch.current, ch.power, ch.ocv,
+                             ch.current_limit, if ch.monitor_only { "  (monitor)" } else { "" });
+        }
+        if let Some(latency) = LatencyWindow::combined(&s.scpi_latency).summary() {
+            text += &format!("SCPI latency: {}\n", latency);
+        }
+        text += "\nRecent events:\n";
+        for line in &s.log_messages {
+            text += &format!("  {}\n", line);
+        }
+        text += "\nRecent SCPI transactions:\n";
+        for line in &s.scpi_log_messages {
+            text += &format!("  {}\n", line);
+        }
+        text += &format!("\nFull logs: {} and {}\n\n", event_log, scpi_log);
+        text
+    };
+
+    let written = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut f| f.write_all(snapshot.as_bytes()));
+    match written {
+        Ok(()) => log_message!(state, writers, "Frozen on error - {}; snapshot in {}", error, path),
+        Err(e) => log_message!(state, writers, "Frozen on error - {}; failed to write {}: {}", error, path, e),
+    }
+}
+
+/// Poll period of measurement-only channels
+const MONITOR_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Read back a channel without ever sending `VOLT` or `OUTP` to it
+fn monitor_channel(
+    state: Arc<Mutex<RuntimeState>>,
+    writers: Arc<Mutex<LogWriters>>,
+    mut stream: TcpStream,
+    channel: u8,
+    mut csv: Option<CsvSink>,
+    sign: f64,
+    schedule: ScheduleConfig,
+) {
+    let ch_idx = (channel - 1) as usize;
+    let ch_name = format!("CH{}", channel);
+    let start = Instant::now();
+    let mut markers_seen = state.lock().unwrap().markers.len();
+    let mut quiet_hours = QuietHours::new(schedule);
+
+    log_message!(state, writers, "CH{}: Measurement only - output is not driven", channel);
+    if sign < 0.0 {
+        log_message!(state, writers, "CH{}: Current and power are shown and logged with inverted sign", channel);
+    }
+
+    loop {
+        let (running, deadline) = {
+            let s = state.lock().unwrap();
+            (s.running, s.deadline)
+        };
+        if deadline.is_some_and(|d| Instant::now() >= d) {
+            log_message!(state, writers, "CH{}: Max runtime reached", channel);
+            break;
+        }
+        if !running {
             break;
         }
+        // A freeze pauses monitoring too, so the SCPI log ends at the error
+        if state.lock().unwrap().frozen.is_some() {
+            sleep(MONITOR_INTERVAL);
+            continue;
+        }
+
+        let mut read = |cmd: String| {
+            log_scpi!(state, writers, "{} → {}", ch_name, cmd);
+            let query_start = Instant::now();
+            let raw = query_raw(&mut stream, &cmd).map_err(|e| e.to_string())?;
+            if let Ok(mut s) = state.lock() {
+                s.scpi_latency[ch_idx].push(query_start.elapsed());
+            }
+            log_scpi!(state, writers, "{} ← {}", ch_name, response_text(&raw));
+            parse_f64(&raw).map_err(|e| e.to_string())
+        };
+
+        match (read(format!("MEAS:VOLT? {}", ch_name)), read(format!("MEAS:CURR? {}", ch_name))) {
+            (Ok(v), Ok(i)) => {
+                if let Some(msg) = quiet_hours.update() {
+                    log_message!(state, writers, "CH{}: {}", channel, msg);
+                }
+                let now = Instant::now();
+                let marker = state.lock().unwrap().new_markers(&mut markers_seen);
+                let write = !quiet_hours.active() || quiet_hours.due(now) || !marker.is_empty();
+                if let Some(w) = csv.as_mut().filter(|_| write) {
+                    let values = [
+                        String::new(),
+                        format!("{:.3}", v),
+                        format!("{:.3}", sign * i),
+                        format!("{:.3}", sign * v * i),
+                    ];
+                    if let Err(e) = w.write(start, now, values, marker) {
+                        log_message!(state, writers, "CH{}: Failed to write the CSV log: {}", channel, e);
+                    }
+                }
 
-        sleep(Duration::from_millis(profile.update_interval_ms));
+                let mut s = state.lock().unwrap();
+                s.channels[ch_idx].voltage = v;
+                s.channels[ch_idx].current = sign * i;
+                s.channels[ch_idx].power = sign * v * i;
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                log_message!(state, writers, "CH{}: Failed to read measurement {}", channel, e);
+            }
+        }
+
+        sleep(MONITOR_INTERVAL);
     }
-    
-    log_message!(state, writers, "CH{}: Simulation stopped", profile.channel);
+
+    if let Some(Err(e)) = csv.as_mut().map(|w| w.finish()) {
+        log_message!(state, writers, "CH{}: Failed to write the CSV log: {}", channel, e);
+    }
+    log_message!(state, writers, "CH{}: Monitoring stopped", channel);
 }
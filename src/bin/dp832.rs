@@ -0,0 +1,1213 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (C) 2025 Marcus Folkesson
+
+//! DP832 Multitool
+//!
+//! Utility commands for battery profiles and the Rigol DP832 power supply
+
+use clap::{Parser, Subcommand};
+use dp832_battery_sim::archive::ZipWriter;
+use dp832_battery_sim::battery_sim::fit::{fit_error, load_reference, simulate_reference};
+use dp832_battery_sim::battery_sim::hooks::RunManifest;
+use dp832_battery_sim::battery_sim::{load_profile, load_profile_with_notes, normalize_ocv, ocv_curve_warnings, ocv_soc_range, sort_ocv};
+use dp832_battery_sim::common::{fit_line, stop_on_signal, total_power, CalibrationPoint, CalibrationReport, ChannelLimits, DeviceConfig, DP832_CHANNEL_LIMITS};
+use dp832_battery_sim::remote_control::{DP832Controller, TriggerOutcome, TriggerSource};
+use dp832_battery_sim::scpi::{connect, parse_bool_scpi, parse_raw_log_line, parse_scpi_tuple, check_idn, query, query_f64, query_idn, read_response, response_text, send, send_raw, probe_capabilities, ConnectOptions};
+use dp832_battery_sim::serve::{self, Access, ChannelReading, ExitPolicy, Health, Level, LogFormat, ServeConfig};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::io::IsTerminal;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Parser)]
+#[command(name = "dp832")]
+#[command(about = "Utility commands for the DP832 multitool")]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// Connection options shared by the subcommands that talk to the instrument
+#[derive(clap::Args)]
+struct DeviceArgs {
+    /// Config file (TOML); only the [device] section is used
+    #[arg(long)]
+    config: Option<String>,
+
+    /// DP832 IP address
+    #[arg(long)]
+    ip: Option<String>,
+
+    /// SCPI port
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// Continue even if *IDN? does not identify a Rigol DP800 series supply
+    #[arg(long)]
+    ignore_idn: bool,
+}
+
+/// The part of the tool configs that the utility commands read
+#[derive(Debug, Deserialize, Default)]
+struct ToolConfig {
+    device: Option<DeviceConfig>,
+    serve: Option<ServeConfig>,
+}
+
+impl DeviceArgs {
+    fn load_config(&self) -> ToolConfig {
+        dp832_battery_sim::common::load_optional_config(self.config.as_deref())
+    }
+
+    /// Resolve the address (CLI > config > default), connect and check `*IDN?`
+    ///
+    /// Returns the stream and the `*IDN?` reply.
+    fn connect(&self) -> Result<(TcpStream, String), String> {
+        self.connect_with(&self.load_config())
+    }
+
+    fn connect_with(&self, cfg: &ToolConfig) -> Result<(TcpStream, String), String> {
+        let (addr, options) = self.endpoint(cfg);
+        let mut stream = connect(&addr, &options).map_err(|e| format!("Failed to connect to {}: {}", addr, e))?;
+        command(&mut stream, "*CLS")?;
+        let id = query_idn(&mut stream).map_err(|e| format!("*IDN? failed: {}", e))?;
+        if let Err(e) = check_idn(&id) {
+            if !self.ignore_idn(cfg) {
+                return Err(format!("{} (pass --ignore-idn to continue anyway)", e));
+            }
+            eprintln!("Warning: {} (continuing, IDN check disabled)", e);
+        }
+        Ok((stream, id))
+    }
+
+    fn ignore_idn(&self, cfg: &ToolConfig) -> bool {
+        self.ignore_idn || cfg.device.as_ref().and_then(|d| d.ignore_idn).unwrap_or(false)
+    }
+
+    /// Address and timeouts, resolved CLI > config > default
+    fn endpoint(&self, cfg: &ToolConfig) -> (String, ConnectOptions) {
+        let ip = self
+            .ip
+            .clone()
+            .or_else(|| cfg.device.as_ref().map(|d| d.ip.clone()))
+            .unwrap_or_else(|| "192.168.1.100".to_string());
+        let port = self
+            .port
+            .or_else(|| cfg.device.as_ref().and_then(|d| d.port))
+            .unwrap_or(5555);
+
+        let defaults = ConnectOptions::default();
+        let options = ConnectOptions {
+            connect_timeout: cfg
+                .device
+                .as_ref()
+                .and_then(|d| d.connect_timeout_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.connect_timeout),
+            read_timeout: cfg
+                .device
+                .as_ref()
+                .and_then(|d| d.read_timeout_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.read_timeout),
+            query_retries: cfg.device.as_ref().and_then(|d| d.query_retries).unwrap_or(defaults.query_retries),
+        };
+
+        (format!("{}:{}", ip, port), options)
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Rewrite a profile with a sorted, uniformly sampled OCV curve
+    NormalizeProfile {
+        /// Input profile JSON file
+        input: String,
+
+        /// Output profile JSON file
+        output: String,
+
+        /// Number of points in the resampled curve
+        #[arg(long, default_value_t = 21)]
+        points: usize,
+    },
+
+    /// Print a profile as the simulator resolves it, with derived values
+    ShowProfile {
+        /// Profile JSON file
+        input: String,
+    },
+
+    /// Step a channel through known voltages and compare the readback
+    ///
+    /// The output is switched on; connect only a high-impedance or known load.
+    CalibrateCheck {
+        #[command(flatten)]
+        device: DeviceArgs,
+
+        /// Channel to check (1-3)
+        #[arg(long)]
+        channel: u8,
+
+        /// Number of voltage steps between 10% and 90% of the channel range
+        #[arg(long, default_value_t = 5)]
+        steps: usize,
+
+        /// Current limit while checking, in amperes
+        #[arg(long, default_value_t = 0.1)]
+        current: f64,
+
+        /// Resistance of a known load, so the current readback can be fitted too (default: open load)
+        #[arg(long)]
+        load_ohm: Option<f64>,
+
+        /// Time to let the output settle before each reading, in milliseconds
+        #[arg(long, default_value_t = 1000)]
+        settle_ms: u64,
+
+        /// Readback error above which a point is flagged, in millivolts
+        #[arg(long, default_value_t = 25.0)]
+        tolerance_mv: f64,
+
+        /// Write the fitted offset/scale to this JSON file
+        #[arg(long)]
+        save: Option<String>,
+    },
+
+    /// Bundle a simulator run (CSVs, logs, config, profiles, summary) into one zip
+    Export {
+        /// Output archive, e.g. run.zip
+        output: String,
+
+        /// Run manifest to export (default: the newest logs/run_*.json)
+        #[arg(long)]
+        run: Option<String>,
+    },
+
+    /// Show the instrument's identity, channel ranges, setting resolution and optional features
+    DeviceInfo {
+        #[command(flatten)]
+        device: DeviceArgs,
+    },
+
+    /// Read a channel's voltage and current
+    Measure {
+        #[command(flatten)]
+        device: DeviceArgs,
+
+        /// Channel to measure (1-3)
+        #[arg(long)]
+        channel: u8,
+
+        /// Average several readings, dropping outliers, and report the spread
+        #[arg(long)]
+        settle: bool,
+
+        /// Number of readings with --settle
+        #[arg(long, default_value_t = 10)]
+        samples: usize,
+
+        /// Time the readings are spread over with --settle, in milliseconds
+        #[arg(long, default_value_t = 1000)]
+        window_ms: u64,
+    },
+
+    /// Program setpoints that change on a trigger, e.g. to sync with a DAQ or scope
+    ///
+    /// Falls back to applying them immediately if the firmware lacks trigger support.
+    Trigger {
+        #[command(flatten)]
+        device: DeviceArgs,
+
+        /// Channel to program (1-3)
+        #[arg(long)]
+        channel: u8,
+
+        /// Voltage applied on the trigger
+        #[arg(long)]
+        voltage: f64,
+
+        /// Current limit applied on the trigger
+        #[arg(long)]
+        current: f64,
+
+        /// What fires the trigger
+        #[arg(long, value_enum, default_value_t = TriggerSource::Bus)]
+        source: TriggerSource,
+
+        /// With --source bus, send *TRG right after arming
+        #[arg(long)]
+        fire: bool,
+    },
+
+    /// Re-send the commands of a raw SCPI log (--raw-scpi-log) to a real instrument
+    ///
+    /// This drives the outputs exactly as the recorded session did.
+    Replay {
+        #[command(flatten)]
+        device: DeviceArgs,
+
+        /// Raw SCPI log, e.g. logs/scpi_raw_20250101_120000.log
+        transcript: String,
+
+        /// Print what would be sent without connecting
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Playback speed relative to the recording; 0 sends without pauses
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+
+        /// Required to replay to hardware
+        #[arg(long)]
+        i_understand_this_drives_hardware: bool,
+    },
+
+    /// Run as a long-lived service: no TUI, line logging, health endpoint
+    ///
+    /// The bearer token is only read from the [serve] config section.
+    Serve {
+        #[command(flatten)]
+        device: DeviceArgs,
+
+        /// Address for the health endpoint, e.g. 127.0.0.1:8832
+        #[arg(long)]
+        listen: Option<String>,
+
+        /// Measurement interval in milliseconds
+        #[arg(long)]
+        poll_ms: Option<u64>,
+
+        /// What to do with the outputs on SIGTERM/SIGINT
+        #[arg(long, value_enum)]
+        on_exit: Option<ExitPolicy>,
+
+        #[arg(long, value_enum)]
+        log_format: Option<LogFormat>,
+
+        /// Unix socket path that streams the health JSON to local readers
+        #[arg(long)]
+        status_socket: Option<String>,
+    },
+
+    /// Check the config, profiles, logs directory and the connection to the instrument
+    ///
+    /// Prints a pass/fail checklist with hints; run this first when something is wrong.
+    Doctor {
+        #[command(flatten)]
+        device: DeviceArgs,
+
+        /// Profile to validate, in addition to the one under [battery] (can specify multiple)
+        #[arg(short, long)]
+        profile: Vec<String>,
+    },
+
+    /// Run a profile against a measured discharge and report how well it fits
+    ///
+    /// Offline: the model is driven with the reference current, no instrument
+    /// is needed. Use it to iterate on R, RC and OCV parameters.
+    Fit {
+        /// Measured CSV with time (s) and voltage columns, and optionally current (A)
+        #[arg(long)]
+        reference: String,
+
+        /// Profile JSON file
+        #[arg(short, long)]
+        profile: String,
+
+        /// Discharge current for references without a current column, in amperes
+        #[arg(long)]
+        current: Option<f64>,
+
+        /// State of charge at the first reference sample (0-1)
+        #[arg(long, default_value_t = 1.0)]
+        initial_soc: f64,
+
+        /// Cell temperature for profiles with temperature-tagged OCV curves
+        #[arg(long)]
+        temperature_c: Option<f64>,
+
+        /// Write time, measured, simulated and error voltage to this CSV
+        #[arg(long)]
+        output: Option<String>,
+
+        /// Only print the error, do not show the overlay chart
+        #[arg(long)]
+        no_chart: bool,
+    },
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let result = match args.command {
+        Command::NormalizeProfile { input, output, points } => normalize_profile(&input, &output, points),
+        Command::CalibrateCheck { device, channel, steps, current, load_ohm, settle_ms, tolerance_mv, save } => {
+            let sweep = Sweep { channel, steps, current, load_ohm, settle: Duration::from_millis(settle_ms) };
+            calibrate_check(&device, sweep, tolerance_mv, save.as_deref())
+        }
+        Command::Export { output, run } => export_run(&output, run.as_deref()),
+        Command::ShowProfile { input } => show_profile(&input),
+        Command::DeviceInfo { device } => device_info(&device),
+        Command::Measure { device, channel, settle, samples, window_ms } => {
+            measure(&device, channel, if settle { samples } else { 1 }, Duration::from_millis(window_ms))
+        }
+        Command::Trigger { device, channel, voltage, current, source, fire } => {
+            trigger(&device, channel, voltage, current, source, fire)
+        }
+        Command::Replay { device, transcript, dry_run, speed, i_understand_this_drives_hardware } => {
+            if dry_run || i_understand_this_drives_hardware {
+                replay(&device, &transcript, dry_run, speed)
+            } else {
+                Err("replaying switches outputs and setpoints on the instrument; \
+                     pass --i-understand-this-drives-hardware, or --dry-run to only print it".to_string())
+            }
+        }
+        Command::Serve { device, listen, poll_ms, on_exit, log_format, status_socket } => {
+            run_service(&device, listen, poll_ms, on_exit, log_format, status_socket)
+        }
+        Command::Doctor { device, profile } => doctor(&device, &profile),
+        Command::Fit { reference, profile, current, initial_soc, temperature_c, output, no_chart } => {
+            let options = FitOptions { current, initial_soc, temperature_c, output, chart: !no_chart };
+            fit(&reference, &profile, &options)
+        }
+    };
+
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Print the fully resolved profile and the values the simulator derives from it
+///
+/// Warnings go to stderr so the JSON on stdout stays machine-readable.
+fn show_profile(input: &str) -> Result<(), String> {
+    let (profile, notes) = load_profile_with_notes(input)?;
+
+    let mut warnings = notes;
+    if let Err(e) = profile.validate() {
+        warnings.push(e);
+    }
+    warnings.extend(ocv_curve_warnings(&profile.ocv_curve));
+    for (name, curve) in [("charge", &profile.ocv_curve_charge), ("discharge", &profile.ocv_curve_discharge)] {
+        if !curve.is_empty() {
+            warnings.extend(ocv_curve_warnings(curve).into_iter().map(|w| format!("{} ({} curve)", w, name)));
+        }
+    }
+    let mut pack = profile.clone();
+    pack.apply_series_cells()?;
+    pack.apply_temperature(None)?;
+
+    match ChannelLimits::for_channel(pack.channel) {
+        Some(limits) => {
+            if let Err(e) = limits.check_voltage(pack.channel, pack.max_voltage) {
+                warnings.push(format!("max_voltage {}", e));
+            }
+            if pack.current_limit_discharge_a > limits.max_current {
+                warnings.push(format!(
+                    "current limit {:.3} A exceeds the CH{} maximum and will be clamped to {:.3} A",
+                    pack.current_limit_discharge_a, pack.channel, limits.max_current
+                ));
+            }
+        }
+        None => warnings.push(format!("channel {} does not exist (use 1-3)", pack.channel)),
+    }
+    if let Err(e) = pack.rc_branches() {
+        warnings.push(e);
+    }
+
+    let soc_range = ocv_soc_range(&pack.ocv_curve).map(|(low, high)| [low, high]);
+    let temperatures: Vec<f64> = pack.ocv_curves.iter().map(|c| c.temperature_c).collect();
+    let resolved = serde_json::json!({
+        "profile": profile,
+        "derived": {
+            "series_cells": profile.series_cells.unwrap_or(1),
+            "pack_max_voltage": pack.max_voltage,
+            "pack_cutoff_voltage": pack.cutoff_voltage,
+            "pack_internal_resistance_ohm": pack.internal_resistance_ohm,
+            "ocv_soc_range": soc_range,
+            "ocv_curve_temperatures_c": temperatures,
+            "pack_ocv_curve_sorted": sort_ocv(&pack.ocv_curve),
+        },
+        "warnings": warnings,
+    });
+
+    for warning in &warnings {
+        eprintln!("Warning: {}", warning);
+    }
+    let json = serde_json::to_string_pretty(&resolved).map_err(|e| format!("Failed to serialize profile: {}", e))?;
+    println!("{}", json);
+    Ok(())
+}
+
+fn normalize_profile(input: &str, output: &str, points: usize) -> Result<(), String> {
+    let mut profile = load_profile(input)?;
+
+    for warning in ocv_curve_warnings(&profile.ocv_curve) {
+        eprintln!("Warning: {}", warning);
+    }
+
+    if profile.ocv_curve.is_empty() {
+        return Err(format!("Profile {} has no OCV curve to normalize", input));
+    }
+
+    let original_points = profile.ocv_curve.len();
+    profile.ocv_curve = normalize_ocv(&profile.ocv_curve, points);
+    profile.ocv_curve_charge = normalize_ocv(&profile.ocv_curve_charge, points);
+    profile.ocv_curve_discharge = normalize_ocv(&profile.ocv_curve_discharge, points);
+    for curve in &mut profile.ocv_curves {
+        curve.ocv_curve = normalize_ocv(&curve.ocv_curve, points);
+    }
+
+    let json = serde_json::to_string_pretty(&profile)
+        .map_err(|e| format!("Failed to serialize profile: {}", e))?;
+    std::fs::write(output, json + "\n")
+        .map_err(|e| format!("Failed to write {}: {}", output, e))?;
+
+    println!(
+        "Wrote '{}' to {} ({} OCV points resampled to {})",
+        profile.name,
+        output,
+        original_points,
+        profile.ocv_curve.len()
+    );
+    Ok(())
+}
+
+/// The steps `calibrate-check` takes a channel through
+struct Sweep {
+    channel: u8,
+    steps: usize,
+    /// Current limit while sweeping
+    current: f64,
+    /// Known load resistance, `None` for an open load
+    load_ohm: Option<f64>,
+    settle: Duration,
+}
+
+fn calibrate_check(device: &DeviceArgs, sweep: Sweep, tolerance_mv: f64, save: Option<&str>) -> Result<(), String> {
+    let channel = sweep.channel;
+    let limits = ChannelLimits::for_channel(channel).ok_or_else(|| format!("CH{} does not exist (use 1-3)", channel))?;
+    limits.check_current(channel, sweep.current)?;
+    if sweep.load_ohm.is_some_and(|r| r <= 0.0 || !r.is_finite()) {
+        return Err("--load-ohm must be above 0".to_string());
+    }
+
+    let (mut stream, id) = device.connect()?;
+    println!("{}", id);
+
+    // Remember the setpoints and output state so they can be restored
+    let appl = ask(&mut stream, &format!("APPL? CH{}", channel))?;
+    let previous = parse_scpi_tuple(&appl, 2).unwrap_or_else(|e| {
+        eprintln!("Warning: CH{} setpoints will not be restored: {}", channel, e);
+        Vec::new()
+    });
+    let was_on = parse_bool_scpi(&ask(&mut stream, &format!("OUTP? CH{}", channel))?) == Some(true);
+
+    let points = sweep_channel(&mut stream, &sweep, &limits);
+
+    // Restore the channel on every path, also when the sweep failed with the output on
+    let mut restore = Vec::new();
+    if !was_on {
+        restore.push(format!("OUTP CH{},OFF", channel));
+    }
+    if let [voltage, current, ..] = previous[..] {
+        restore.push(format!("APPL CH{},{:.3},{:.3}", channel, voltage, current));
+    }
+    // Each is tried even if one before it failed
+    let restored = restore.iter().map(|cmd| command(&mut stream, cmd)).fold(Ok(()), Result::and);
+    let points = match (points, restored) {
+        (Err(e), Err(restore_err)) => return Err(format!("{}; restoring CH{} also failed: {}", e, channel, restore_err)),
+        (Err(e), Ok(())) => return Err(e),
+        (Ok(_), Err(e)) => return Err(e),
+        (Ok(points), Ok(())) => points,
+    };
+
+    let (scale, offset) = fit_line(&points.iter().map(|p| (p.set, p.measured)).collect::<Vec<_>>());
+    let (current_scale, current_offset) =
+        fit_line(&points.iter().map(|p| (p.current_expected, p.current_measured)).collect::<Vec<_>>());
+
+    println!("CH{} readback check:", channel);
+    println!("  {:>9}  {:>9}  {:>9}  {:>9}  {:>9}", "Set (V)", "Read (V)", "Err (mV)", "Exp (A)", "Read (A)");
+    let mut max_error_mv: f64 = 0.0;
+    let mut flagged = 0;
+    for p in &points {
+        let error_mv = (p.measured - p.set) * 1000.0;
+        max_error_mv = max_error_mv.max(error_mv.abs());
+        let flag = if error_mv.abs() > tolerance_mv {
+            flagged += 1;
+            "  <-- out of tolerance"
+        } else {
+            ""
+        };
+        println!("  {:>9.3}  {:>9.4}  {:>+9.1}  {:>9.4}  {:>9.4}{}",
+                 p.set, p.measured, error_mv, p.current_expected, p.current_measured, flag);
+    }
+    println!("  Offset: {:+.1} mV, scale: {:.5}, max error: {:.1} mV", offset * 1000.0, scale, max_error_mv);
+    match sweep.load_ohm {
+        Some(r) => println!("  Current into {:.3} Ω: offset {:+.2} mA, scale {:.5}", r, current_offset * 1000.0, current_scale),
+        None => println!("  Current with an open load: offset {:+.2} mA", current_offset * 1000.0),
+    }
+
+    if flagged > 0 {
+        println!("  {} of {} points exceed {:.1} mV; this channel's readback may need calibration", flagged, points.len(), tolerance_mv);
+    } else {
+        println!("  All points within {:.1} mV", tolerance_mv);
+    }
+
+    if let Some(path) = save {
+        let report = CalibrationReport {
+            channel,
+            timestamp: chrono::Local::now().to_rfc3339(),
+            voltage_scale: scale,
+            voltage_offset: offset,
+            current_scale,
+            current_offset,
+            max_error_mv,
+            points,
+        };
+        let json = serde_json::to_string_pretty(&report)
+            .map_err(|e| format!("Failed to serialize report: {}", e))?;
+        std::fs::write(path, json + "\n").map_err(|e| format!("Failed to write {}: {}", path, e))?;
+        println!("Saved calibration report to {}", path);
+    }
+
+    Ok(())
+}
+
+/// Step the channel from 10% to 90% of its range and read back voltage and current
+fn sweep_channel(stream: &mut TcpStream, sweep: &Sweep, limits: &ChannelLimits) -> Result<Vec<CalibrationPoint>, String> {
+    let channel = sweep.channel;
+    command(stream, &format!(":SOUR{}:CURR {:.3}", channel, sweep.current))?;
+
+    let steps = sweep.steps.max(2);
+    let mut points = Vec::new();
+    for n in 0..steps {
+        let set = limits.max_voltage * (0.1 + 0.8 * n as f64 / (steps - 1) as f64);
+        command(stream, &format!(":SOUR{}:VOLT {:.3}", channel, set))?;
+        command(stream, &format!("OUTP CH{},ON", channel))?;
+        std::thread::sleep(sweep.settle);
+
+        let measured = query_f64(stream, &format!("MEAS:VOLT? CH{}", channel))
+            .map_err(|e| format!("Unparseable readback at {:.3} V: {}", set, e))?;
+        let current_measured = query_f64(stream, &format!("MEAS:CURR? CH{}", channel))
+            .map_err(|e| format!("Unparseable current readback at {:.3} V: {}", set, e))?;
+        let current_expected = sweep.load_ohm.map_or(0.0, |r| measured / r);
+        points.push(CalibrationPoint { set, measured, current_expected, current_measured });
+    }
+    Ok(points)
+}
+
+/// Schema notes placed at the root of every exported archive
+const EXPORT_README: &str = "\
+DP832 battery simulator run export
+
+run.json      Run manifest: device *IDN?, original file paths and one
+              summary per channel (reason, duration_s, final_soc,
+              final_voltage, charge_ah, energy_wh).
+config/       Config file used for the run, if any.
+profiles/     Profile files as given on the command line, plus
+              <name>.resolved.json with inheritance applied.
+csv/          CSV logs. Per-channel layout: time (s), soc (0-1, empty for
+              measurement-only channels), voltage (V), current (A),
+              power (W), marker (events at that sample, separated by \"; \").
+              Wide layout (--csv-layout wide): one file with time, then
+              chN_soc, chN_voltage, chN_current, chN_power for each channel
+              N in ascending order, then marker; a channel without a sample
+              in a row leaves its columns empty.
+logs/         Event log, SCPI log and (if enabled) the raw SCPI byte log.
+
+Files that share a name within a directory are stored as <n>_<name>,
+numbered from 2 in the order run.json lists them.
+";
+
+/// `dir/name` for the file at `path`, numbered if that entry is already taken
+fn unique_entry(used: &mut HashSet<String>, dir: &str, path: &str) -> String {
+    let name = std::path::Path::new(path)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| path.to_string());
+    let mut entry = format!("{}/{}", dir, name);
+    let mut n = 2;
+    while !used.insert(entry.clone()) {
+        entry = format!("{}/{}_{}", dir, n, name);
+        n += 1;
+    }
+    entry
+}
+
+fn export_run(output: &str, run: Option<&str>) -> Result<(), String> {
+    let manifest_path = match run {
+        Some(path) => path.to_string(),
+        None => latest_manifest()?,
+    };
+    let manifest = RunManifest::load(&manifest_path)?;
+
+    let mut zip = ZipWriter::create(output).map_err(|e| format!("Failed to create {}: {}", output, e))?;
+    let mut used = HashSet::new();
+    let mut profile_entries = Vec::new();
+    let mut add_file = |entry: &str, path: &str| -> Result<(), String> {
+        match std::fs::read(path) {
+            Ok(data) => zip.add(entry, &data).map_err(|e| format!("Failed to write {}: {}", output, e)),
+            Err(e) => {
+                eprintln!("Warning: skipping {}: {}", path, e);
+                Ok(())
+            }
+        }
+    };
+
+    if let Some(config) = &manifest.config {
+        add_file(&unique_entry(&mut used, "config", config), config)?;
+    }
+    for profile in &manifest.profiles {
+        let entry = unique_entry(&mut used, "profiles", profile);
+        add_file(&entry, profile)?;
+        profile_entries.push(entry);
+    }
+    for csv in &manifest.csv_files {
+        add_file(&unique_entry(&mut used, "csv", csv), csv)?;
+    }
+    let logs = [Some(&manifest.event_log), Some(&manifest.scpi_log), manifest.raw_scpi_log.as_ref()];
+    for log in logs.into_iter().flatten() {
+        add_file(&unique_entry(&mut used, "logs", log), log)?;
+    }
+
+    // Resolved profiles show what the simulator actually ran after "extends",
+    // named after the profile's own entry
+    for (path, entry) in manifest.profiles.iter().zip(&profile_entries) {
+        if let Ok(profile) = load_profile(path) {
+            let stem = std::path::Path::new(entry)
+                .file_stem()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_else(|| profile.name.clone());
+            let json = serde_json::to_string_pretty(&profile).map_err(|e| e.to_string())?;
+            let resolved = unique_entry(&mut used, "profiles", &format!("{}.resolved.json", stem));
+            zip.add(&resolved, json.as_bytes())
+                .map_err(|e| format!("Failed to write {}: {}", output, e))?;
+        }
+    }
+
+    let manifest_json = serde_json::to_string_pretty(&manifest).map_err(|e| e.to_string())?;
+    zip.add("run.json", manifest_json.as_bytes())
+        .and_then(|_| zip.add("README.txt", EXPORT_README.as_bytes()))
+        .and_then(|_| zip.finish())
+        .map_err(|e| format!("Failed to write {}: {}", output, e))?;
+
+    println!("Exported run {} to {}", manifest.run_id, output);
+    Ok(())
+}
+
+/// Newest `logs/run_*.json`; run ids are timestamps, so the names sort by age
+fn latest_manifest() -> Result<String, String> {
+    let entries = std::fs::read_dir("logs").map_err(|e| format!("Failed to read logs/: {}", e))?;
+    entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .filter(|name| name.starts_with("run_") && name.ends_with(".json"))
+        .max()
+        .map(|name| format!("logs/{}", name))
+        .ok_or_else(|| "No run manifest found in logs/ (run battery-sim first or pass --run)".to_string())
+}
+
+/// `dp832 device-info`: what the tools assume about each channel, and what the firmware supports
+fn device_info(device: &DeviceArgs) -> Result<(), String> {
+    let (mut stream, id) = device.connect()?;
+    println!("{}", id);
+    println!();
+    for (idx, limits) in DP832_CHANNEL_LIMITS.iter().enumerate() {
+        println!("CH{}: 0-{} V in {} mV steps, 0-{} A in {} mA steps",
+                 idx + 1, limits.max_voltage, limits.voltage_resolution * 1000.0,
+                 limits.max_current, limits.current_resolution * 1000.0);
+    }
+    println!("Setpoints are rounded to these steps before they are sent, so a readback matches what was stored.");
+    println!();
+    let capabilities = probe_capabilities(&mut stream).map_err(|e| format!("Probing failed: {}", e))?;
+    for line in capabilities.report() {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn measure(device: &DeviceArgs, channel: u8, samples: usize, window: Duration) -> Result<(), String> {
+    let cfg = device.load_config();
+    let (addr, options) = device.endpoint(&cfg);
+    let mut controller = DP832Controller::new(&addr, &options, false, device.ignore_idn(&cfg))
+        .map_err(|e| format!("Failed to connect to {}: {}", addr, e))?;
+    let reading = controller.measure_settled(channel, samples, window).map_err(|e| e.to_string())?;
+
+    if samples == 1 {
+        println!("CH{}: {:.4} V  {:.4} A", reading.channel, reading.voltage, reading.current);
+    } else {
+        println!("CH{}: {:.4} V ± {:.1} mV  {:.4} A ± {:.1} mA  ({} readings, {} rejected)",
+                 reading.channel, reading.voltage, reading.voltage_stddev * 1000.0,
+                 reading.current, reading.current_stddev * 1000.0, reading.samples, reading.rejected);
+    }
+    Ok(())
+}
+
+fn trigger(device: &DeviceArgs, channel: u8, voltage: f64, current: f64, source: TriggerSource, fire: bool) -> Result<(), String> {
+    let cfg = device.load_config();
+    let (addr, options) = device.endpoint(&cfg);
+    let mut controller = DP832Controller::new(&addr, &options, false, device.ignore_idn(&cfg))
+        .map_err(|e| format!("Failed to connect to {}: {}", addr, e))?;
+
+    match controller.arm_trigger(channel, voltage, current, source).map_err(|e| e.to_string())? {
+        TriggerOutcome::Armed => {
+            println!("CH{}: {:.3} V / {:.3} A armed, waiting for {:?} trigger", channel, voltage, current, source);
+            if fire && source == TriggerSource::Bus {
+                controller.bus_trigger().map_err(|e| format!("Failed to send *TRG: {}", e))?;
+                println!("Sent *TRG");
+            }
+        }
+        TriggerOutcome::AppliedImmediately => {
+            eprintln!("Warning: the instrument did not accept the trigger setup; applied immediately instead");
+            println!("CH{}: {:.3} V / {:.3} A applied", channel, voltage, current);
+        }
+    }
+    Ok(())
+}
+
+/// Re-send the recorded commands, one connection per recorded connection
+///
+/// Queries are sent too, since the recorded commands may depend on them
+/// being answered, but their responses come from the instrument and are
+/// printed next to the recorded ones.
+fn replay(device: &DeviceArgs, transcript: &str, dry_run: bool, speed: f64) -> Result<(), String> {
+    let text = std::fs::read_to_string(transcript).map_err(|e| format!("Failed to open {}: {}", transcript, e))?;
+    let entries: Vec<_> = text.lines().filter_map(parse_raw_log_line).collect();
+    let Some(first) = entries.iter().find(|e| e.sent) else {
+        return Err(format!("{} contains no sent commands", transcript));
+    };
+    let start = first.timestamp;
+
+    // Connect without the usual *CLS so only recorded bytes are sent
+    let (addr, options) = device.endpoint(&device.load_config());
+    let mut streams: HashMap<u16, TcpStream> = HashMap::new();
+    let replay_start = Instant::now();
+
+    for (n, entry) in entries.iter().enumerate().filter(|(_, e)| e.sent) {
+        let offset = (entry.timestamp - start).to_std().unwrap_or_default();
+        if speed > 0.0 && !dry_run {
+            let due = offset.div_f64(speed);
+            if let Some(wait) = due.checked_sub(replay_start.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+
+        let text = response_text(&entry.bytes);
+        println!("[+{:>9.3}s] :{} → {}", offset.as_secs_f64(), entry.port, text);
+        if dry_run {
+            continue;
+        }
+
+        let stream = match streams.entry(entry.port) {
+            std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+            std::collections::hash_map::Entry::Vacant(e) => e.insert(
+                connect(&addr, &options).map_err(|e| format!("Failed to connect to {}: {}", addr, e))?,
+            ),
+        };
+        send_raw(stream, &entry.bytes).map_err(|e| format!("Failed to send to :{}: {}", entry.port, e))?;
+        if text.contains('?') {
+            let recorded = entries[n + 1..]
+                .iter()
+                .find(|e| e.port == entry.port)
+                .filter(|e| !e.sent)
+                .map(|e| response_text(&e.bytes))
+                .unwrap_or_default();
+            let actual = response_text(&read_response(stream).map_err(|e| format!("Reading from :{} failed: {}", entry.port, e))?);
+            let note = if actual == recorded { "" } else { "   (differs)" };
+            println!("{:>14} ← {} (recorded: {}){}", "", actual, recorded, note);
+        }
+    }
+    Ok(())
+}
+
+/// Send a command, with a failed write as the error message
+fn command(stream: &mut TcpStream, cmd: &str) -> Result<(), String> {
+    send(stream, cmd).map_err(|e| format!("Failed to send '{}': {}", cmd, e))
+}
+
+/// Send a query, with a connection error as the error message
+fn ask(stream: &mut TcpStream, cmd: &str) -> Result<String, String> {
+    query(stream, cmd).map_err(|e| format!("'{}' failed: {}", cmd, e))
+}
+
+/// Read voltage, current and output state of all channels
+fn poll_channels(stream: &mut TcpStream) -> Result<Vec<ChannelReading>, String> {
+    (1..=3u8)
+        .map(|channel| {
+            let voltage = query_f64(stream, &format!("MEAS:VOLT? CH{}", channel))
+                .map_err(|e| format!("CH{} voltage {}", channel, e))?;
+            let current = query_f64(stream, &format!("MEAS:CURR? CH{}", channel))
+                .map_err(|e| format!("CH{} current {}", channel, e))?;
+            let output = parse_bool_scpi(&ask(stream, &format!("OUTP? CH{}", channel))?) == Some(true);
+            Ok(ChannelReading { channel, voltage, current, output })
+        })
+        .collect()
+}
+
+/// `dp832 serve`: poll the instrument until SIGTERM/SIGINT, reconnecting as needed
+fn run_service(
+    device: &DeviceArgs,
+    listen: Option<String>,
+    poll_ms: Option<u64>,
+    on_exit: Option<ExitPolicy>,
+    log_format: Option<LogFormat>,
+    status_socket: Option<String>,
+) -> Result<(), String> {
+    let cfg = device.load_config();
+    let serve_cfg = cfg.serve.clone().unwrap_or_default();
+    let format = log_format.or(serve_cfg.log_format).unwrap_or_default();
+    let on_exit = on_exit.or(serve_cfg.on_exit).unwrap_or_default();
+    let poll = Duration::from_millis(poll_ms.or(serve_cfg.poll_ms).unwrap_or(1000).max(100));
+    let stop = stop_on_signal();
+    let health = Arc::new(Mutex::new(Health::default()));
+
+    if let Some(addr) = listen.or(serve_cfg.listen.clone()) {
+        let access = Access {
+            token: serve_cfg.token.clone().filter(|t| !t.is_empty()),
+            anonymous_read: serve_cfg.anonymous_read.unwrap_or(false),
+        };
+        if access.token.is_none() && !access.anonymous_read {
+            return Err("the health endpoint needs a token or anonymous_read = true under [serve]".to_string());
+        }
+        let listener = TcpListener::bind(&addr).map_err(|e| format!("Failed to listen on {}: {}", addr, e))?;
+        if access.token.is_none() {
+            serve::log(format, Level::Warning, &format!("Health endpoint on {} has no authentication", addr));
+        }
+        serve::log(format, Level::Info, &format!("Serving GET /health on http://{}", addr));
+        let health = health.clone();
+        std::thread::spawn(move || serve::serve_http(listener, health, access, format));
+    }
+
+    let status_socket = status_socket.or(serve_cfg.status_socket.clone());
+    if let Some(path) = &status_socket {
+        #[cfg(unix)]
+        {
+            let listener = serve::bind_socket(path)?;
+            serve::log(format, Level::Info, &format!("Streaming status to {}", path));
+            let health = health.clone();
+            std::thread::spawn(move || serve::serve_socket(listener, health, poll, format));
+        }
+        #[cfg(not(unix))]
+        return Err(format!("status_socket {} needs Unix domain sockets, which this platform lacks", path));
+    }
+
+    let mut stream: Option<TcpStream> = None;
+    while !stop.load(Ordering::Relaxed) {
+        let started = Instant::now();
+
+        if stream.is_none() {
+            match device.connect_with(&cfg) {
+                Ok((s, id)) => {
+                    serve::log(format, Level::Info, &format!("Connected to {}", id));
+                    health.lock().unwrap().device_id = id;
+                    stream = Some(s);
+                }
+                Err(e) => serve::log(format, Level::Error, &e),
+            }
+        }
+
+        if let Some(s) = stream.as_mut() {
+            // A dropped connection fails the poll like any other error, so it reconnects
+            let result = poll_channels(s);
+            let mut h = health.lock().unwrap();
+            match result {
+                Ok(channels) => {
+                    h.connected = true;
+                    h.last_poll = Some(chrono::Local::now().to_rfc3339());
+                    h.total_power = total_power(channels.iter().map(|ch| (ch.output, ch.voltage * ch.current)));
+                    h.channels = channels;
+                }
+                Err(e) => {
+                    serve::log(format, Level::Error, &format!("Poll failed ({}), reconnecting", e));
+                    h.connected = false;
+                    h.reconnects += 1;
+                    stream = None;
+                }
+            }
+        }
+
+        // Sleep in short slices so a signal is handled promptly
+        while started.elapsed() < poll && !stop.load(Ordering::Relaxed) {
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    serve::log(format, Level::Info, "Shutting down");
+    if on_exit == ExitPolicy::Off {
+        match stream.as_mut() {
+            Some(s) => {
+                let mut all_off = true;
+                for channel in 1..=3 {
+                    if let Err(e) = send(s, &format!("OUTP CH{},OFF", channel)) {
+                        serve::log(format, Level::Warning, &format!("Failed to switch CH{} off: {}", channel, e));
+                        all_off = false;
+                    }
+                }
+                if all_off {
+                    serve::log(format, Level::Info, "All outputs switched off");
+                }
+            }
+            None => serve::log(format, Level::Warning, "Not connected; outputs were left as they are"),
+        }
+    }
+    if let Some(path) = &status_socket {
+        if let Err(e) = std::fs::remove_file(path) {
+            serve::log(format, Level::Warning, &format!("Failed to remove {}: {}", path, e));
+        }
+    }
+    Ok(())
+}
+
+struct FitOptions {
+    current: Option<f64>,
+    initial_soc: f64,
+    temperature_c: Option<f64>,
+    output: Option<String>,
+    chart: bool,
+}
+
+fn fit(reference: &str, profile_path: &str, options: &FitOptions) -> Result<(), String> {
+    let mut profile = load_profile(profile_path)?;
+    profile.validate()?;
+    profile.apply_series_cells()?;
+    profile.apply_temperature(options.temperature_c)?;
+    profile.rc_branches()?;
+    let samples = load_reference(reference)?;
+
+    let missing = samples.iter().filter(|s| s.current.is_none()).count();
+    let default_current = match options.current {
+        Some(current) => current,
+        None if missing == 0 => 0.0,
+        None => return Err(format!("{} of {} reference rows have no current; pass --current", missing, samples.len())),
+    };
+
+    let simulated = simulate_reference(&profile, &samples, options.initial_soc.clamp(0.0, 1.0), default_current, options.temperature_c);
+    let measured: Vec<f64> = samples.iter().map(|s| s.voltage).collect();
+    let error = fit_error(&measured, &simulated);
+
+    if let Some(path) = &options.output {
+        let mut writer = csv::Writer::from_path(path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+        writer.write_record(["time", "measured", "simulated", "error"]).map_err(|e| e.to_string())?;
+        for (s, v) in samples.iter().zip(&simulated) {
+            writer
+                .write_record([format!("{:.3}", s.time), format!("{:.4}", s.voltage), format!("{:.4}", v), format!("{:+.4}", v - s.voltage)])
+                .map_err(|e| e.to_string())?;
+        }
+        writer.flush().map_err(|e| e.to_string())?;
+    }
+
+    if options.chart && std::io::stdout().is_terminal() {
+        show_overlay(&profile.name, &samples.iter().map(|s| s.time).collect::<Vec<_>>(), &measured, &simulated, error.rmse)
+            .map_err(|e| format!("Failed to draw the overlay: {}", e))?;
+    }
+
+    let duration = samples.last().map_or(0.0, |s| s.time);
+    println!("Fit of '{}' against {} ({} samples, {:.0} s):", profile.name, reference, samples.len(), duration);
+    println!("  RMSE:      {:.1} mV", error.rmse * 1000.0);
+    println!("  Bias:      {:+.1} mV (simulated - measured)", error.bias * 1000.0);
+    println!("  Max error: {:.1} mV", error.max_abs * 1000.0);
+    if let Some(path) = &options.output {
+        println!("Wrote the overlay to {}", path);
+    }
+    Ok(())
+}
+
+/// Measured and simulated voltage on the same axes, until a key is pressed
+fn show_overlay(name: &str, times: &[f64], measured: &[f64], simulated: &[f64], rmse: f64) -> std::io::Result<()> {
+    use crossterm::event::{self, Event};
+    use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+    use ratatui::prelude::*;
+    use ratatui::widgets::{Axis, Block, Borders, Chart, Dataset, GraphType};
+
+    let measured_data: Vec<(f64, f64)> = times.iter().copied().zip(measured.iter().copied()).collect();
+    let simulated_data: Vec<(f64, f64)> = times.iter().copied().zip(simulated.iter().copied()).collect();
+    let t_max = times.last().copied().unwrap_or(1.0).max(1.0);
+    let (v_min, v_max) = measured
+        .iter()
+        .chain(simulated)
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &v| (lo.min(v), hi.max(v)));
+    let margin = ((v_max - v_min) * 0.05).max(0.01);
+    let (v_min, v_max) = (v_min - margin, v_max + margin);
+
+    enable_raw_mode()?;
+    crossterm::execute!(std::io::stdout(), EnterAlternateScreen)?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(std::io::stdout()))?;
+
+    let result = (|| -> std::io::Result<()> {
+        loop {
+            terminal.draw(|f| {
+                let datasets = vec![
+                    Dataset::default()
+                        .name("measured")
+                        .marker(symbols::Marker::Braille)
+                        .style(Style::default().fg(Color::Yellow))
+                        .graph_type(GraphType::Line)
+                        .data(&measured_data),
+                    Dataset::default()
+                        .name("simulated")
+                        .marker(symbols::Marker::Braille)
+                        .style(Style::default().fg(Color::Cyan))
+                        .graph_type(GraphType::Line)
+                        .data(&simulated_data),
+                ];
+                let chart = Chart::new(datasets)
+                    .block(
+                        Block::default()
+                            .borders(Borders::ALL)
+                            .title(format!("{} - RMSE {:.1} mV (any key to close)", name, rmse * 1000.0)),
+                    )
+                    .x_axis(
+                        Axis::default()
+                            .title("Time (s)")
+                            .style(Style::default().fg(Color::Gray))
+                            .bounds([0.0, t_max])
+                            .labels(vec![Span::raw("0"), Span::raw(format!("{:.0}", t_max))]),
+                    )
+                    .y_axis(
+                        Axis::default()
+                            .title("Voltage (V)")
+                            .style(Style::default().fg(Color::Gray))
+                            .bounds([v_min, v_max])
+                            .labels(vec![Span::raw(format!("{:.2}", v_min)), Span::raw(format!("{:.2}", v_max))]),
+                    );
+                f.render_widget(chart, f.size());
+            })?;
+            if let Event::Key(_) = event::read()? {
+                return Ok(());
+            }
+        }
+    })();
+
+    disable_raw_mode()?;
+    crossterm::execute!(std::io::stdout(), LeaveAlternateScreen)?;
+    result
+}
+
+/// Outcome of one `doctor` check
+enum Check {
+    Pass(String),
+    Fail { what: String, hint: String },
+    Skip(String),
+}
+
+fn doctor(device: &DeviceArgs, profiles: &[String]) -> Result<(), String> {
+    let mut checks = Vec::new();
+
+    // Config: parsed here rather than through load_config, which exits on errors
+    let mut cfg = ToolConfig::default();
+    let mut profile_paths = profiles.to_vec();
+    match dp832_battery_sim::common::resolve_config_path(device.config.as_deref()) {
+        None if device.config.is_some() => checks.push(Check::Fail {
+            what: format!("Config file {} not found", device.config.as_deref().unwrap_or_default()),
+            hint: "check the --config path".to_string(),
+        }),
+        None => checks.push(Check::Skip("No config file, using defaults".to_string())),
+        Some(path) => {
+            let parsed = std::fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|text| {
+                let tool: ToolConfig = toml::from_str(&text).map_err(|e| e.to_string())?;
+                let sim: dp832_battery_sim::battery_sim::Config = toml::from_str(&text).map_err(|e| e.to_string())?;
+                toml::from_str::<dp832_battery_sim::remote_control::Config>(&text).map_err(|e| e.to_string())?;
+                Ok((tool, sim))
+            });
+            match parsed {
+                Ok((tool, sim)) => {
+                    cfg = tool;
+                    profile_paths.extend(sim.battery.map(|b| b.profile));
+                    checks.push(Check::Pass(format!("Config {} parses", path.display())));
+                }
+                Err(e) => checks.push(Check::Fail {
+                    what: format!("Config {} does not parse: {}", path.display(), e.trim()),
+                    hint: "fix the line shown above; see examples/ for valid files".to_string(),
+                }),
+            }
+        }
+    }
+
+    // Profiles
+    if profile_paths.is_empty() {
+        checks.push(Check::Skip("No profiles to check (pass -p or set [battery] profile)".to_string()));
+    }
+    for path in &profile_paths {
+        let loaded = load_profile(path).and_then(|mut p| {
+            p.validate()?;
+            p.apply_series_cells()?;
+            let limits = ChannelLimits::for_channel(p.channel)
+                .ok_or_else(|| format!("channel {} does not exist (use 1-3)", p.channel))?;
+            limits.check_voltage(p.channel, p.max_voltage)?;
+            Ok(p)
+        });
+        checks.push(match loaded {
+            Ok(p) => Check::Pass(format!("Profile {}: '{}' on CH{}", path, p.name, p.channel)),
+            Err(e) => Check::Fail {
+                what: format!("Profile {}: {}", path, e),
+                hint: "run dp832 show-profile on it; see profiles/README.rst for the format".to_string(),
+            },
+        });
+    }
+
+    // Logs directory
+    let probe = std::path::Path::new("logs").join(format!(".doctor_{}", std::process::id()));
+    let writable = std::fs::create_dir_all("logs")
+        .and_then(|_| std::fs::write(&probe, b"ok"))
+        .and_then(|_| std::fs::remove_file(&probe));
+    checks.push(match writable {
+        Ok(()) => Check::Pass("logs/ is writable".to_string()),
+        Err(e) => Check::Fail {
+            what: format!("logs/ is not writable: {}", e),
+            hint: "run from a directory you can write to; the tools log to ./logs".to_string(),
+        },
+    });
+
+    // Instrument
+    let (addr, options) = device.endpoint(&cfg);
+    let started = Instant::now();
+    match connect(&addr, &options) {
+        Err(e) => {
+            checks.push(Check::Fail {
+                what: format!("Cannot reach {}: {}", addr, e),
+                hint: "check the IP, that LAN is enabled on the DP832 (Utility > IO > LAN) and that the SCPI port is 5555"
+                    .to_string(),
+            });
+            checks.push(Check::Skip("*IDN? not checked without a connection".to_string()));
+        }
+        Ok(mut stream) => {
+            checks.push(Check::Pass(format!("Reached {} in {} ms", addr, started.elapsed().as_millis())));
+            let started = Instant::now();
+            let id = query_idn(&mut stream);
+            let latency = started.elapsed().as_millis();
+            checks.push(match id.map_err(|e| e.to_string()).and_then(|id| check_idn(&id).map(|()| id)) {
+                Ok(id) => Check::Pass(format!("*IDN? {} ({} ms)", id, latency)),
+                Err(e) => Check::Fail {
+                    what: e,
+                    hint: "another service may be listening on this port; the DP832 uses 5555".to_string(),
+                },
+            });
+        }
+    }
+
+    let mut failed = 0;
+    for check in &checks {
+        match check {
+            Check::Pass(what) => println!("[PASS] {}", what),
+            Check::Skip(what) => println!("[SKIP] {}", what),
+            Check::Fail { what, hint } => {
+                failed += 1;
+                println!("[FAIL] {}", what);
+                println!("       hint: {}", hint);
+            }
+        }
+    }
+
+    if failed > 0 {
+        Err(format!("{} of {} checks failed", failed, checks.len()))
+    } else {
+        println!("All checks passed");
+        Ok(())
+    }
+}
@@ -1,13 +1,17 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 // Copyright (C) 2025 Marcus Folkesson
 
-/// DP832 Remote Control Interface
-/// 
-/// Complete remote control interface for the Rigol DP832 power supply
+//! DP832 Remote Control Interface
+//!
+//! Complete remote control interface for the Rigol DP832 power supply
 
 use clap::Parser;
-use dp832_battery_sim::remote_control::{Config, DP832Controller};
-use dp832_battery_sim::remote_control::ui::RemoteControlUI;
+use dp832_battery_sim::remote_control::{Config, DP832Controller, OutputGuard, PollMode, DEFAULT_SETTLE_TIME};
+use dp832_battery_sim::remote_control::ui::{RemoteControlUI, UiOptions, DEFAULT_CONFIRM_ABOVE_W, DEFAULT_PRESETS};
+use dp832_battery_sim::common::{new_run_id, EffectiveConfig};
+use dp832_battery_sim::keylog::KeyInput;
+use dp832_battery_sim::scpi::{enable_raw_log, set_read_only, ConnectOptions};
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(name = "dp832-remote")]
@@ -17,6 +21,10 @@ struct Args {
     #[arg(long)]
     config: Option<String>,
 
+    /// Print the resolved settings and where each one comes from, then exit
+    #[arg(long)]
+    print_effective_config: bool,
+
     /// DP832 IP address
     #[arg(long)]
     ip: Option<String>,
@@ -24,39 +32,155 @@ struct Args {
     /// SCPI port
     #[arg(long)]
     port: Option<u16>,
+
+    /// Skip the startup probe of optional SCPI features
+    #[arg(long)]
+    skip_probe: bool,
+
+    /// Continue even if *IDN? does not identify a Rigol DP800 series supply
+    #[arg(long)]
+    ignore_idn: bool,
+
+    /// TCP connect timeout in milliseconds
+    #[arg(long)]
+    connect_timeout_ms: Option<u64>,
+
+    /// Read timeout for query responses in milliseconds
+    #[arg(long)]
+    read_timeout_ms: Option<u64>,
+
+    /// Ask a timed-out query again up to this many times (only queries that are safe to repeat)
+    #[arg(long)]
+    query_retries: Option<u32>,
+
+    /// Log the exact bytes sent and received to logs/scpi_raw_*.log
+    #[arg(long)]
+    raw_scpi_log: bool,
+
+    /// Only watch: send queries, refuse every command that changes the instrument
+    #[arg(long)]
+    read_only: bool,
+
+    /// Turn all outputs off after this many seconds without a key press (0: never)
+    #[arg(long, value_name = "SECONDS")]
+    idle_off: Option<u64>,
+
+    /// Wait this long after a setpoint or output change before measuring the channel
+    #[arg(long, value_name = "MS")]
+    settle_ms: Option<u64>,
+
+    /// Record the key presses with their timing to this file
+    #[arg(long)]
+    record_input: Option<String>,
+
+    /// Replay key presses recorded with --record-input into the UI
+    #[arg(long)]
+    replay_input: Option<String>,
+
+    /// Channel polling strategy (toggle at runtime with P)
+    #[arg(long, value_enum)]
+    poll: Option<PollMode>,
+
+    /// Switch all outputs on once connected (asks first for setpoints above [output] confirm_above_w)
+    #[arg(long)]
+    enable_all_on_start: bool,
 }
 
 fn main() {
     let args = Args::parse();
 
     let cfg: Config = dp832_battery_sim::common::load_optional_config(args.config.as_deref());
-    
+    // Every setting below is resolved command line > config > default through this
+    let mut effective = EffectiveConfig::default();
+    let device = cfg.device.as_ref();
+
     // Resolve IP
-    let ip = args
-        .ip
-        .or_else(|| cfg.device.as_ref().map(|d| d.ip.clone()))
-        .unwrap_or_else(|| "192.168.1.100".to_string());
+    let ip = effective.resolve("device.ip", args.ip.clone(), device.map(|d| d.ip.clone()), "192.168.1.100".to_string());
 
     // Resolve port
-    let port = args
-        .port
-        .or_else(|| cfg.device.as_ref().and_then(|d| d.port))
-        .unwrap_or(5555);
+    let port = effective.resolve("device.port", args.port, device.and_then(|d| d.port), 5555);
+
+    // Resolve capability probe
+    let probe = effective.resolve("device.probe", args.skip_probe.then_some(false), device.and_then(|d| d.probe), true);
+    let ignore_idn = effective.flag("device.ignore_idn", args.ignore_idn, device.and_then(|d| d.ignore_idn), false);
+
+    // Resolve connection timeouts
+    let connect_options =
+        ConnectOptions::resolve(&mut effective, args.connect_timeout_ms, args.read_timeout_ms, args.query_retries, device);
+
+    let poll_mode = effective.choice("polling.mode", args.poll, cfg.polling.as_ref().and_then(|p| p.mode), PollMode::default());
+    let output = cfg.output.as_ref();
+    let mut enable_all_on_start =
+        effective.flag("output.enable_all_on_start", args.enable_all_on_start, output.and_then(|o| o.enable_all_on_start), false);
+    let idle_off = effective.resolve("output.idle_off_s", args.idle_off, output.and_then(|o| o.idle_off_s), 0);
+    let settle_ms = effective.resolve(
+        "output.settle_ms",
+        args.settle_ms,
+        output.and_then(|o| o.settle_ms),
+        DEFAULT_SETTLE_TIME.as_millis() as u64,
+    );
+    let min_on_ms = effective.resolve("output.min_on_ms", None, output.and_then(|o| o.min_on_ms), 0);
+    let min_off_ms = effective.resolve("output.min_off_ms", None, output.and_then(|o| o.min_off_ms), 0);
+    let confirm_above_w =
+        effective.resolve("output.confirm_above_w", None, output.and_then(|o| o.confirm_above_w), DEFAULT_CONFIRM_ABOVE_W);
+    let presets =
+        effective.resolve("presets.voltages", None, cfg.presets.as_ref().map(|p| p.voltages.clone()), DEFAULT_PRESETS.to_vec());
+    let read_only = effective.flag("read_only", args.read_only, None, false);
+    if args.print_effective_config {
+        let config_path = dp832_battery_sim::common::resolve_config_path(args.config.as_deref());
+        effective.print(config_path.as_deref());
+        return;
+    }
+
+    if args.raw_scpi_log {
+        match enable_raw_log(&new_run_id(None)) {
+            Ok(path) => println!("Raw SCPI log: {}", path),
+            Err(e) => eprintln!("Failed to open raw SCPI log: {}", e),
+        }
+    }
+
+    let input = KeyInput::open(args.record_input.as_deref(), args.replay_input.as_deref()).unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    });
+
+    if read_only {
+        set_read_only();
+        if enable_all_on_start {
+            println!("Read-only: not enabling the outputs on start");
+            enable_all_on_start = false;
+        }
+    }
 
     let addr = format!("{}:{}", ip, port);
     
     println!("Connecting to DP832 at {}...", addr);
     
-    let controller = DP832Controller::new(&addr)
+    let mut controller = DP832Controller::new(&addr, &connect_options, probe, ignore_idn)
         .unwrap_or_else(|e| {
             eprintln!("Failed to connect: {}", e);
             std::process::exit(1);
         });
+
+    controller.set_output_guard(OutputGuard::new(Duration::from_millis(min_on_ms), Duration::from_millis(min_off_ms)));
+    controller.set_settle_time(Duration::from_millis(settle_ms));
     
     println!("Connected: {}", controller.get_device_id());
+    for line in controller.capabilities.report() {
+        println!("{}", line);
+    }
     println!("Starting remote control interface...");
     
-    let mut ui = RemoteControlUI::new(controller);
+    let options = UiOptions {
+        poll_mode,
+        presets,
+        enable_all_on_start,
+        confirm_above_w,
+        input,
+        idle_off: Some(Duration::from_secs(idle_off)).filter(|t| !t.is_zero()),
+    };
+    
+    let mut ui = RemoteControlUI::new(controller, options);
     
     if let Err(e) = ui.run() {
         eprintln!("UI error: {}", e);
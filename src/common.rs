@@ -1,18 +1,163 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 // Copyright (C) 2025 Marcus Folkesson
 
-/// Common utilities and types shared across modules
+//! Common utilities and types shared across modules
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::fs::File;
 use std::io::{Read, Write};
 use std::collections::VecDeque;
+use std::time::Duration;
 
 /// Device configuration
 #[derive(Debug, Deserialize)]
 pub struct DeviceConfig {
     pub ip: String,
     pub port: Option<u16>,
+    /// Probe optional SCPI features at startup (default: true)
+    pub probe: Option<bool>,
+    /// TCP connect timeout in milliseconds (default: 3000)
+    pub connect_timeout_ms: Option<u64>,
+    /// Read timeout for query responses in milliseconds (default: 1000)
+    pub read_timeout_ms: Option<u64>,
+    /// Continue when `*IDN?` does not identify a Rigol DP800 (default: false)
+    pub ignore_idn: Option<bool>,
+    /// Ask a measurement or state query again this often when it times out (default: 0)
+    pub query_retries: Option<u32>,
+}
+
+/// Rated output range and programming resolution of one DP832 channel
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelLimits {
+    pub max_voltage: f64,
+    pub max_current: f64,
+    /// Smallest voltage step the channel accepts; finer setpoints are rounded by the instrument
+    pub voltage_resolution: f64,
+    pub current_resolution: f64,
+}
+
+/// DP832 output limits: CH1 and CH2 are 30 V / 3 A, CH3 is 5 V / 3 A, all set in 1 mV / 1 mA steps
+pub const DP832_CHANNEL_LIMITS: [ChannelLimits; 3] = [
+    ChannelLimits { max_voltage: 30.0, max_current: 3.0, voltage_resolution: SETPOINT_RESOLUTION, current_resolution: SETPOINT_RESOLUTION },
+    ChannelLimits { max_voltage: 30.0, max_current: 3.0, voltage_resolution: SETPOINT_RESOLUTION, current_resolution: SETPOINT_RESOLUTION },
+    ChannelLimits { max_voltage: 5.0, max_current: 3.0, voltage_resolution: SETPOINT_RESOLUTION, current_resolution: SETPOINT_RESOLUTION },
+];
+
+impl ChannelLimits {
+    /// Limits of channel 1-3, or `None` for a channel that does not exist
+    pub fn for_channel(channel: u8) -> Option<Self> {
+        DP832_CHANNEL_LIMITS.get((channel as usize).wrapping_sub(1)).copied()
+    }
+
+    /// Round a voltage setpoint to what the channel will actually be set to
+    pub fn round_voltage(&self, voltage: f64) -> f64 {
+        round_to(voltage, self.voltage_resolution)
+    }
+
+    pub fn round_current(&self, current: f64) -> f64 {
+        round_to(current, self.current_resolution)
+    }
+
+    /// Check a voltage setpoint, e.g. "CH3 max is 5 V"
+    pub fn check_voltage(&self, channel: u8, voltage: f64) -> Result<(), String> {
+        if !(0.0..=self.max_voltage).contains(&voltage) {
+            return Err(format!("{:.3} V is out of range: CH{} max is {} V", voltage, channel, self.max_voltage));
+        }
+        Ok(())
+    }
+
+    /// Check a current setpoint
+    pub fn check_current(&self, channel: u8, current: f64) -> Result<(), String> {
+        if !(0.0..=self.max_current).contains(&current) {
+            return Err(format!("{:.3} A is out of range: CH{} max is {} A", current, channel, self.max_current));
+        }
+        Ok(())
+    }
+}
+
+/// Programming resolution of DP832 voltage and current setpoints (1 mV / 1 mA)
+pub const SETPOINT_RESOLUTION: f64 = 0.001;
+
+/// Round a setpoint to the instrument's resolution
+///
+/// Setpoints are rounded once, before they are sent, and the rounded value
+/// is what gets stored. The `{:.3}` command formatting and `APPL?` readback
+/// then see exactly the same number, so comparisons don't drift by a step.
+pub fn round_setpoint(value: f64) -> f64 {
+    round_to(value, SETPOINT_RESOLUTION)
+}
+
+/// Round to a whole number of `resolution` steps
+///
+/// Dividing the step count by the steps per unit gives the double nearest
+/// the decimal, the same one parsing the `{:.3}` text gives back; multiplying
+/// by the resolution can land one ulp off (9 × 0.001 is 0.009000000000000001).
+fn round_to(value: f64, resolution: f64) -> f64 {
+    let steps_per_unit = (1.0 / resolution).round();
+    (value * steps_per_unit).round() / steps_per_unit
+}
+
+/// One step of `dp832 calibrate-check`: setpoint and readback
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalibrationPoint {
+    pub set: f64,
+    pub measured: f64,
+    /// Current the load should draw at the measured voltage (0 A with no `--load-ohm`)
+    pub current_expected: f64,
+    pub current_measured: f64,
+}
+
+/// Result of `dp832 calibrate-check`: `measured = scale * actual + offset`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalibrationReport {
+    pub channel: u8,
+    pub timestamp: String,
+    pub voltage_scale: f64,
+    pub voltage_offset: f64,
+    pub current_scale: f64,
+    pub current_offset: f64,
+    pub max_error_mv: f64,
+    pub points: Vec<CalibrationPoint>,
+}
+
+impl CalibrationReport {
+    /// Read a report saved with `calibrate-check --save`
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        let report: Self = serde_json::from_str(&text).map_err(|e| format!("{}: {}", path, e))?;
+        if report.current_scale <= 0.0 || !report.current_scale.is_finite() || !report.current_offset.is_finite() {
+            return Err(format!("{}: current_scale must be above 0", path));
+        }
+        Ok(report)
+    }
+
+    /// The actual current behind a `MEAS:CURR?` reading, undoing the fitted offset and scale
+    pub fn correct_current(&self, measured: f64) -> f64 {
+        (measured - self.current_offset) / self.current_scale
+    }
+}
+
+/// Least-squares line `y = scale * x + offset` through `(x, y)` points
+///
+/// When `x` does not vary (e.g. an open load, where every expected current
+/// is 0 A) the scale is taken as 1 and only the offset is fitted.
+pub fn fit_line(points: &[(f64, f64)]) -> (f64, f64) {
+    let n = points.len().max(1) as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let covariance: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+    let variance: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+    let scale = if variance > 0.0 { covariance / variance } else { 1.0 };
+    (scale, mean_y - scale * mean_x)
+}
+
+/// Combined output power of all channels, from `(output on, power)` pairs
+///
+/// The DP832 has no total-power query, so every tool sums the per-channel
+/// measurements here. Channels that are off are left out, as are readings
+/// that failed to parse, so the total is never NaN.
+pub fn total_power(channels: impl IntoIterator<Item = (bool, f64)>) -> f64 {
+    channels.into_iter().filter(|(on, power)| *on && power.is_finite()).map(|(_, power)| power).sum()
 }
 
 /// Channel state for UI display
@@ -25,40 +170,96 @@ pub struct ChannelState {
     pub ocv: f64,
     pub profile_name: String,
     pub enabled: bool,
+    /// Channel is only measured; the simulator never drives its output
+    pub monitor_only: bool,
+    /// RC time constant in use; the TUI adjusts it and the channel thread picks it up
+    pub rc_time_constant_ms: u64,
+    /// Set by the TUI to ask the channel thread to write a tuned profile
+    pub save_tuned: bool,
+    /// Seconds until empty at the recent average current; `None` while idle or charging
+    pub remaining_s: Option<f64>,
+    /// Current the channel may deliver, in amps; 0 when unknown (measurement-only)
+    pub current_limit: f64,
+    /// Charge and energy delivered since the channel started; never reset
+    pub charge_ah: f64,
+    pub energy_wh: f64,
+    /// `charge_ah` and `energy_wh` when the TUI last zeroed the interval counters
+    pub counter_base: (f64, f64),
+    /// SoC range of an incomplete OCV curve; outside it the TUI marks the OCV as
+    /// held or extrapolated, so the voltage there is not over-trusted
+    pub ocv_covered: Option<(f64, f64)>,
+    /// The OCV outside `ocv_covered` is extrapolated rather than held
+    pub ocv_extrapolated: bool,
+    /// Next queued profile, waiting for the user to swap the cell and press Enter
+    pub swap_prompt: Option<String>,
 }
 
 /// Log file writers for event and SCPI logs
 pub struct LogWriters {
     event_log: Option<File>,
     scpi_log: Option<File>,
+    run_id: String,
+}
+
+/// Id for the log files of a run starting now, e.g. "20250101_120000_bench-a"
+///
+/// The timestamp comes first so ids still sort by age. The name is reduced to
+/// characters that are safe in a file name.
+pub fn new_run_id(name: Option<&str>) -> String {
+    let timestamp = chrono::Local::now().format("%Y%m%d_%H%M%S").to_string();
+    match name.map(str::trim).filter(|n| !n.is_empty()) {
+        Some(name) => {
+            let name: String = name
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+                .collect();
+            format!("{}_{}", timestamp, name)
+        }
+        None => timestamp,
+    }
 }
 
 impl LogWriters {
     pub fn new() -> Self {
+        Self::with_run_id(new_run_id(None))
+    }
+
+    pub fn with_run_id(run_id: String) -> Self {
         // Create logs directory if it doesn't exist
         let _ = std::fs::create_dir_all("logs");
         
-        // Create timestamped log files
-        let timestamp = chrono::Local::now().format("%Y%m%d_%H%M%S");
-        
         let event_log = std::fs::OpenOptions::new()
             .create(true)
             .append(true)
-            .open(format!("logs/event_{}.log", timestamp))
+            .open(format!("logs/event_{}.log", run_id))
             .ok();
             
         let scpi_log = std::fs::OpenOptions::new()
             .create(true)
             .append(true)
-            .open(format!("logs/scpi_{}.log", timestamp))
+            .open(format!("logs/scpi_{}.log", run_id))
             .ok();
         
         Self {
             event_log,
             scpi_log,
+            run_id,
         }
     }
     
+    /// Id shared by this run's log files, see `new_run_id`
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+    
+    pub fn event_log_path(&self) -> String {
+        format!("logs/event_{}.log", self.run_id)
+    }
+    
+    pub fn scpi_log_path(&self) -> String {
+        format!("logs/scpi_{}.log", self.run_id)
+    }
+    
     pub fn write_event(&mut self, message: &str) {
         if let Some(ref mut f) = self.event_log {
             let timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
@@ -76,6 +277,59 @@ impl LogWriters {
     }
 }
 
+impl Default for LogWriters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Rolling window of SCPI round-trip times
+#[derive(Clone, Default)]
+pub struct LatencyWindow {
+    samples: VecDeque<Duration>,
+}
+
+impl LatencyWindow {
+    /// Number of round trips kept in the window
+    const SIZE: usize = 20;
+
+    pub fn push(&mut self, rtt: Duration) {
+        self.samples.push_back(rtt);
+        if self.samples.len() > Self::SIZE {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+
+    pub fn average(&self) -> Option<Duration> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        Some(self.samples.iter().sum::<Duration>() / self.samples.len() as u32)
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.samples.iter().max().copied()
+    }
+
+    /// All round trips of several windows, for a summary across connections
+    pub fn combined<'a>(windows: impl IntoIterator<Item = &'a LatencyWindow>) -> Self {
+        Self { samples: windows.into_iter().flat_map(|w| w.samples.iter().copied()).collect() }
+    }
+
+    /// Short summary for pane titles, e.g. "avg 62ms, max 310ms"
+    pub fn summary(&self) -> Option<String> {
+        Some(format!(
+            "avg {}ms, max {}ms",
+            self.average()?.as_millis(),
+            self.max()?.as_millis()
+        ))
+    }
+}
+
 /// Runtime state for UI
 #[derive(Clone, Default)]
 pub struct RuntimeState {
@@ -83,10 +337,23 @@ pub struct RuntimeState {
     pub running: bool,
     pub log_messages: VecDeque<String>,
     pub scpi_log_messages: VecDeque<String>,
+    /// One window per channel, as each channel has its own connection
+    pub scpi_latency: [LatencyWindow; 3],
+    /// Also print event messages to stdout (line-output mode without the TUI)
+    pub echo_logs: bool,
+    /// Labels added with `m`, in order; channel threads copy new ones into their CSV
+    pub markers: Vec<String>,
+    /// All channels stop at this time (`--max-runtime`)
+    pub deadline: Option<std::time::Instant>,
+    /// The error that paused all channels (`--freeze-on-error`); cleared to resume
+    pub frozen: Option<String>,
 }
 
 impl RuntimeState {
     pub fn add_log(&mut self, message: String) {
+        if self.echo_logs {
+            println!("[{}] {}", chrono::Local::now().format("%H:%M:%S"), message);
+        }
         self.log_messages.push_back(message);
         // Keep last 100 messages
         if self.log_messages.len() > 100 {
@@ -94,6 +361,13 @@ impl RuntimeState {
         }
     }
     
+    /// Labels added since `seen`, advancing `seen` past them
+    pub fn new_markers(&self, seen: &mut usize) -> String {
+        let labels = self.markers.get(*seen..).unwrap_or_default().join("; ");
+        *seen = self.markers.len();
+        labels
+    }
+
     pub fn add_scpi_log(&mut self, message: String) {
         self.scpi_log_messages.push_back(message);
         // Keep last 200 SCPI messages (more detailed)
@@ -103,6 +377,26 @@ impl RuntimeState {
     }
 }
 
+/// Flag that becomes true on SIGINT or SIGTERM
+///
+/// Used by the line-output modes, where there is no `q` key to stop cleanly.
+pub fn stop_on_signal() -> std::sync::Arc<std::sync::atomic::AtomicBool> {
+    let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        let _ = signal_hook::flag::register(signal, stop.clone());
+    }
+    stop
+}
+
+/// Path of the config file `load_optional_config` reads, if it exists
+pub fn resolve_config_path(path: Option<&str>) -> Option<std::path::PathBuf> {
+    let path = match path {
+        Some(p) => Some(std::path::PathBuf::from(p)),
+        None => default_config_path(),
+    };
+    path.filter(|p| p.exists())
+}
+
 /// Load optional configuration file
 pub fn load_optional_config<T: for<'de> Deserialize<'de> + Default>(path: Option<&str>) -> T {
     let path = if let Some(p) = path {
@@ -128,7 +422,159 @@ pub fn load_optional_config<T: for<'de> Deserialize<'de> + Default>(path: Option
     }
 }
 
+/// Where a resolved setting came from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Origin {
+    Cli,
+    Config,
+    Default,
+}
+
+/// Settings as resolved from the command line, the config file and the
+/// defaults, with the origin of each, for `--print-effective-config`
+///
+/// The binaries resolve their settings through `resolve` and friends, so
+/// what is printed is exactly what the run would use.
+#[derive(Default)]
+pub struct EffectiveConfig {
+    entries: Vec<(String, String, Origin)>,
+}
+
+impl EffectiveConfig {
+    /// `cli`, else `config`, else `default`; `key` names the config field
+    pub fn resolve<T: std::fmt::Debug>(&mut self, key: &str, cli: Option<T>, config: Option<T>, default: T) -> T {
+        let (value, origin) = pick(cli, config, default);
+        self.record(key, format!("{:?}", value), origin);
+        value
+    }
+
+    /// As `resolve`, showing the value as it is spelled in the config file
+    pub fn choice<T: clap::ValueEnum>(&mut self, key: &str, cli: Option<T>, config: Option<T>, default: T) -> T {
+        let (value, origin) = pick(cli, config, default);
+        let name = value.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default();
+        self.record(key, format!("\"{}\"", name), origin);
+        value
+    }
+
+    /// As `resolve`, for settings that are off unless given
+    pub fn resolve_opt<T: std::fmt::Debug>(&mut self, key: &str, cli: Option<T>, config: Option<T>) -> Option<T> {
+        let (value, origin) = match (cli, config) {
+            (Some(v), _) => (Some(v), Origin::Cli),
+            (None, Some(v)) => (Some(v), Origin::Config),
+            (None, None) => (None, Origin::Default),
+        };
+        let shown = value.as_ref().map_or_else(|| "unset".to_string(), |v| format!("{:?}", v));
+        self.record(key, shown, origin);
+        value
+    }
+
+    /// As `resolve`, for a CLI switch that can only turn the setting on
+    pub fn flag(&mut self, key: &str, cli: bool, config: Option<bool>, default: bool) -> bool {
+        self.resolve(key, cli.then_some(true), config, default)
+    }
+
+    /// Note a setting resolved some other way
+    pub fn record(&mut self, key: &str, value: String, origin: Origin) {
+        self.entries.push((key.to_string(), value, origin));
+    }
+
+    /// One line per setting, e.g. `device.port = 5555  (default)`
+    pub fn lines(&self) -> Vec<String> {
+        let width = self.entries.iter().map(|(key, ..)| key.len()).max().unwrap_or(0);
+        let value_width = self.entries.iter().map(|(_, value, _)| value.len()).max().unwrap_or(0);
+        self.entries
+            .iter()
+            .map(|(key, value, origin)| {
+                let origin = match origin {
+                    Origin::Cli => "command line",
+                    Origin::Config => "config",
+                    Origin::Default => "default",
+                };
+                format!("{:width$} = {:value_width$}  ({})", key, value, origin)
+            })
+            .collect()
+    }
+
+    /// Print the config file and every setting, for `--print-effective-config`
+    pub fn print(&self, config_path: Option<&std::path::Path>) {
+        match config_path {
+            Some(path) => println!("Config file: {}", path.display()),
+            None => println!("Config file: none"),
+        }
+        for line in self.lines() {
+            println!("{}", line);
+        }
+    }
+}
+
+fn pick<T>(cli: Option<T>, config: Option<T>, default: T) -> (T, Origin) {
+    match (cli, config) {
+        (Some(v), _) => (v, Origin::Cli),
+        (None, Some(v)) => (v, Origin::Config),
+        (None, None) => (default, Origin::Default),
+    }
+}
+
 fn default_config_path() -> Option<std::path::PathBuf> {
     let base = dirs_next::config_dir()?;
     Some(base.join("dp832-battery").join("config.toml"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fit_line_recovers_scale_and_offset() {
+        let points: Vec<(f64, f64)> = (0..5).map(|n| n as f64 * 6.0).map(|x| (x, 1.002 * x - 0.015)).collect();
+        let (scale, offset) = fit_line(&points);
+        assert!((scale - 1.002).abs() < 1e-12);
+        assert!((offset + 0.015).abs() < 1e-12);
+    }
+
+    #[test]
+    fn fit_line_without_spread_fits_the_offset() {
+        assert_eq!(fit_line(&[(0.0, 0.002), (0.0, 0.004)]), (1.0, 0.003));
+    }
+
+    #[test]
+    fn calibration_corrects_the_current_reading() {
+        let report = CalibrationReport {
+            channel: 1,
+            timestamp: String::new(),
+            voltage_scale: 1.0,
+            voltage_offset: 0.0,
+            current_scale: 1.01,
+            current_offset: 0.002,
+            max_error_mv: 0.0,
+            points: Vec::new(),
+        };
+        assert!((report.correct_current(1.01 * 0.5 + 0.002) - 0.5).abs() < 1e-12);
+        assert!((report.correct_current(0.002)).abs() < 1e-12);
+    }
+
+    #[test]
+    fn rounded_setpoint_survives_command_formatting() {
+        let stored = round_setpoint(3.3005);
+        let sent: f64 = format!("{:.3}", stored).parse().unwrap();
+        assert_eq!(sent, stored);
+
+        for step in 0..=30_000 {
+            let stored = round_setpoint(step as f64 * SETPOINT_RESOLUTION + 0.0004);
+            let sent: f64 = format!("{:.3}", stored).parse().unwrap();
+            assert_eq!(sent, stored, "step {}", step);
+        }
+    }
+
+    #[test]
+    fn readback_compares_equal_to_the_stored_setpoint() {
+        // As the controller checks a `:VOLT?` readback against what it sent
+        let limits = ChannelLimits::for_channel(1).unwrap();
+        for voltage in [0.1 + 0.2, 3.3005, 1.1 * 3.0, 12.0 - 0.001, 29.999] {
+            let stored = limits.round_voltage(voltage);
+            let readback: f64 = format!("{:.3}", stored).parse().unwrap();
+            assert_eq!(limits.round_voltage(readback), stored, "{}", voltage);
+            assert_eq!(round_setpoint(readback), stored, "{}", voltage);
+        }
+    }
+}
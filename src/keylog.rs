@@ -0,0 +1,176 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (C) 2025 Marcus Folkesson
+
+//! Recording and replaying TUI key presses (`--record-input`, `--replay-input`)
+//!
+//! A recording is a text file with one key press per line: the milliseconds
+//! since the UI read its first key, then the key with its modifiers, e.g.
+//!
+//! ```text
+//! 1520 m
+//! 1890 Space
+//! 2400 Enter
+//! 5100 ctrl+c
+//! ```
+//!
+//! Lines starting with `#` and empty lines are ignored, so a script can be
+//! written or annotated by hand.
+
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// Where the TUIs read their keys from: the keyboard, plus a replayed script
+///
+/// The keyboard always works, so a replay can be interrupted with `q`. Key
+/// presses are appended to the recording as they are handed to the UI,
+/// replayed ones included.
+#[derive(Default)]
+pub struct KeyInput {
+    /// Set by the first `next_key`; replay times count from here
+    start: Option<Instant>,
+    recorder: Option<File>,
+    replay: VecDeque<(Duration, KeyEvent)>,
+}
+
+impl KeyInput {
+    /// Open the recording and load the replay script, either one optional
+    pub fn open(record: Option<&str>, replay: Option<&str>) -> Result<Self, String> {
+        let replay = match replay {
+            Some(path) => load_script(path)?,
+            None => VecDeque::new(),
+        };
+        let recorder = record
+            .map(|path| File::create(path).map_err(|e| format!("Failed to create {}: {}", path, e)))
+            .transpose()?;
+        Ok(Self { start: None, recorder, replay })
+    }
+
+    /// The next key event within `timeout`, like `event::poll` and `event::read`
+    ///
+    /// A replayed key is returned once its time has come; until then the
+    /// keyboard is polled for at most the time left.
+    pub fn next_key(&mut self, timeout: Duration) -> std::io::Result<Option<KeyEvent>> {
+        let elapsed = self.start.get_or_insert_with(Instant::now).elapsed();
+        let mut timeout = timeout;
+        if let Some(&(at, key)) = self.replay.front() {
+            if at <= elapsed {
+                self.replay.pop_front();
+                self.record(&key);
+                return Ok(Some(key));
+            }
+            timeout = timeout.min(at - elapsed);
+        }
+
+        if event::poll(timeout)? {
+            if let Event::Key(key) = event::read()? {
+                if key.kind == KeyEventKind::Press {
+                    self.record(&key);
+                }
+                return Ok(Some(key));
+            }
+        }
+        Ok(None)
+    }
+
+    fn record(&mut self, key: &KeyEvent) {
+        let (Some(file), Some(start)) = (self.recorder.as_mut(), self.start) else {
+            return;
+        };
+        let Some(text) = format_key(key) else {
+            return;
+        };
+        // Flushed per key so a crash still leaves the keys that led to it
+        let _ = writeln!(file, "{} {}", start.elapsed().as_millis(), text);
+        let _ = file.flush();
+    }
+}
+
+fn load_script(path: &str) -> Result<VecDeque<(Duration, KeyEvent)>, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path, e))?;
+    let mut keys = VecDeque::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parsed = line
+            .split_once(' ')
+            .and_then(|(ms, key)| Some((Duration::from_millis(ms.parse().ok()?), parse_key(key.trim())?)));
+        match parsed {
+            Some(entry) => keys.push_back(entry),
+            None => return Err(format!("{}:{}: expected '<ms> <key>', got '{}'", path, n + 1, line)),
+        }
+    }
+    if keys.iter().zip(keys.iter().skip(1)).any(|(a, b)| b.0 < a.0) {
+        return Err(format!("{}: times must not go backwards", path));
+    }
+    Ok(keys)
+}
+
+const NAMED_KEYS: [(&str, KeyCode); 15] = [
+    ("Space", KeyCode::Char(' ')),
+    ("Enter", KeyCode::Enter),
+    ("Esc", KeyCode::Esc),
+    ("Backspace", KeyCode::Backspace),
+    ("Tab", KeyCode::Tab),
+    ("BackTab", KeyCode::BackTab),
+    ("Up", KeyCode::Up),
+    ("Down", KeyCode::Down),
+    ("Left", KeyCode::Left),
+    ("Right", KeyCode::Right),
+    ("Home", KeyCode::Home),
+    ("End", KeyCode::End),
+    ("PageUp", KeyCode::PageUp),
+    ("PageDown", KeyCode::PageDown),
+    ("Delete", KeyCode::Delete),
+];
+
+const MODIFIERS: [(&str, KeyModifiers); 3] =
+    [("ctrl", KeyModifiers::CONTROL), ("alt", KeyModifiers::ALT), ("shift", KeyModifiers::SHIFT)];
+
+/// `ctrl+c`, `Enter`, `m`; `None` for keys the UIs don't use
+fn format_key(key: &KeyEvent) -> Option<String> {
+    let name = match NAMED_KEYS.iter().find(|(_, code)| *code == key.code) {
+        Some((name, _)) => name.to_string(),
+        None => match key.code {
+            KeyCode::Char(c) => c.to_string(),
+            _ => return None,
+        },
+    };
+    let mut text = String::new();
+    for (prefix, modifier) in MODIFIERS {
+        if key.modifiers.contains(modifier) {
+            text.push_str(prefix);
+            text.push('+');
+        }
+    }
+    text.push_str(&name);
+    Some(text)
+}
+
+fn parse_key(text: &str) -> Option<KeyEvent> {
+    let mut modifiers = KeyModifiers::NONE;
+    let mut rest = text;
+    // A lone "+" is the key itself, not a modifier separator
+    while let Some((prefix, key)) = rest.split_once('+').filter(|(_, key)| !key.is_empty()) {
+        let (_, modifier) = MODIFIERS.iter().find(|(name, _)| *name == prefix)?;
+        modifiers |= *modifier;
+        rest = key;
+    }
+
+    let code = match NAMED_KEYS.iter().find(|(name, _)| *name == rest) {
+        Some((_, code)) => *code,
+        None => {
+            let mut chars = rest.chars();
+            let c = chars.next()?;
+            if chars.next().is_some() {
+                return None;
+            }
+            KeyCode::Char(c)
+        }
+    };
+    Some(KeyEvent::new(code, modifiers))
+}
@@ -5,3 +5,7 @@ pub mod scpi;
 pub mod common;
 pub mod battery_sim;
 pub mod remote_control;
+pub mod archive;
+pub mod serve;
+pub mod units;
+pub mod keylog;
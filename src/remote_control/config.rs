@@ -1,12 +1,55 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 // Copyright (C) 2025 Marcus Folkesson
 
-/// Remote control configuration
+//! Remote control configuration
 
 use serde::Deserialize;
 use crate::common::DeviceConfig;
+use crate::units;
 
 #[derive(Debug, Deserialize, Default)]
 pub struct Config {
     pub device: Option<DeviceConfig>,
+    pub polling: Option<PollingConfig>,
+    pub presets: Option<PresetsConfig>,
+    pub output: Option<OutputConfig>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OutputConfig {
+    /// Minimum time an output stays on before it may be turned off again
+    pub min_on_ms: Option<u64>,
+    /// Minimum time an output stays off before it may be turned on again
+    pub min_off_ms: Option<u64>,
+    /// Switch all outputs on right after connecting
+    pub enable_all_on_start: Option<bool>,
+    /// Ask before auto-enabling a channel set up for more than this many watts (default: 10)
+    pub confirm_above_w: Option<f64>,
+    /// Turn all outputs off after this many seconds without a key press (default: 0, disabled)
+    pub idle_off_s: Option<u64>,
+    /// Wait after a setpoint or output change before measuring that channel (default: 200)
+    pub settle_ms: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PresetsConfig {
+    /// Voltages bound to keys 1-9, in order
+    #[serde(deserialize_with = "units::volts_list")]
+    pub voltages: Vec<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PollingConfig {
+    pub mode: Option<PollMode>,
+}
+
+/// Which channels the remote UI refreshes on each polling cycle
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PollMode {
+    /// Refresh every channel at the same rate
+    #[default]
+    All,
+    /// Refresh the selected channel quickly and the others slowly
+    Selected,
 }
@@ -1,20 +1,147 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 // Copyright (C) 2025 Marcus Folkesson
 
-/// DP832 Controller
-/// 
-/// Manages communication and control of the DP832 power supply
+//! DP832 Controller
+//!
+//! Manages communication and control of the DP832 power supply
 
 use std::net::TcpStream;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::sync::mpsc::Sender;
-use crate::scpi::{send, query};
+use crate::common::{ChannelLimits, LatencyWindow};
+use crate::scpi::{send, query_idn, query_raw_retry, is_read_only, writable, response_text, parse_f64, parse_bool_scpi, parse_error_entry, parse_scpi_tuple, probe_capabilities, check_idn, connect, Capabilities, ConnectOptions};
+
+/// Time to wait after `*RST` before the next command
+const RESET_SETTLE_TIME: Duration = Duration::from_millis(500);
+
+/// Most entries `read_error_queue` reads in one go, in case the queue never reports empty
+const ERROR_QUEUE_MAX: usize = 32;
+
+/// Default wait between a setpoint or output change and the next measurement of that channel
+pub const DEFAULT_SETTLE_TIME: Duration = Duration::from_millis(200);
 
 pub struct DP832Controller {
     stream: TcpStream,
     pub channels: [ChannelState; 3],
     pub device_id: String,
+    pub capabilities: Capabilities,
+    pub timer: TimerStatus,
     scpi_logger: Option<Sender<String>>,
+    latency: LatencyWindow,
+    output_guard: OutputGuard,
+    query_retries: u32,
+    settle_time: Duration,
+    /// Per channel: the output is still settling after a change until then
+    settling_until: [Option<Instant>; 3],
+}
+
+/// Minimum time an output must stay in a state before it may be toggled again
+///
+/// Protects relays and connected loads against output chatter. A zero
+/// duration disables the corresponding check.
+#[derive(Debug, Clone, Default)]
+pub struct OutputGuard {
+    pub min_on_time: Duration,
+    pub min_off_time: Duration,
+    last_change: [Option<Instant>; 3],
+}
+
+impl OutputGuard {
+    pub fn new(min_on_time: Duration, min_off_time: Duration) -> Self {
+        Self {
+            min_on_time,
+            min_off_time,
+            last_change: [None; 3],
+        }
+    }
+
+    /// Check whether a channel currently `enabled` may change state now
+    fn check(&self, ch_idx: usize, enabled: bool) -> Result<(), std::io::Error> {
+        let min = if enabled { self.min_on_time } else { self.min_off_time };
+        let Some(changed) = self.last_change[ch_idx] else {
+            return Ok(());
+        };
+        let elapsed = changed.elapsed();
+        if elapsed < min {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                format!(
+                    "CH{} turned {} {:.1}s ago, minimum {} time is {:.1}s",
+                    ch_idx + 1,
+                    if enabled { "on" } else { "off" },
+                    elapsed.as_secs_f64(),
+                    if enabled { "on" } else { "off" },
+                    min.as_secs_f64()
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn record(&mut self, ch_idx: usize) {
+        self.last_change[ch_idx] = Some(Instant::now());
+    }
+}
+
+/// Front-panel timer and delayer configuration, read with the `:TIMEr` and `:DELAY` queries
+///
+/// When the timer or delayer runs, the instrument switches outputs and
+/// setpoints on its own.
+#[derive(Clone, Default)]
+pub struct TimerStatus {
+    /// The firmware answered `:TIMEr:STATe?`
+    pub supported: bool,
+    pub timer_on: bool,
+    /// Number of cycles, e.g. "N,10" or "I" for infinite
+    pub cycles: String,
+    /// Output state after the last group, "OFF" or "LAST"
+    pub end_state: String,
+    /// `None` when the firmware has no delayer
+    pub delay_on: Option<bool>,
+}
+
+/// What starts a triggered setpoint change (`TRIG:SOUR`)
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum TriggerSource {
+    /// Apply as soon as the trigger is initiated
+    Immediate,
+    /// Wait for `*TRG` on the bus
+    Bus,
+    /// Wait for the rear-panel digital trigger input
+    External,
+}
+
+impl TriggerSource {
+    fn scpi(self) -> &'static str {
+        match self {
+            TriggerSource::Immediate => "IMM",
+            TriggerSource::Bus => "BUS",
+            TriggerSource::External => "EXT",
+        }
+    }
+}
+
+/// How `arm_trigger` ended up applying the setpoints
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriggerOutcome {
+    /// Armed; the setpoints change on the next trigger
+    Armed,
+    /// The firmware rejected the trigger commands, so the setpoints were applied now
+    AppliedImmediately,
+}
+
+/// Mean and spread of several readings, from `measure_settled`
+#[derive(Debug, Clone)]
+pub struct SettledReading {
+    pub channel: u8,
+    pub voltage: f64,
+    pub voltage_stddev: f64,
+    pub current: f64,
+    pub current_stddev: f64,
+    /// Readings that went into the mean
+    pub samples: usize,
+    /// Readings dropped as outliers or because they did not parse
+    pub rejected: usize,
 }
 
 #[derive(Clone)]
@@ -42,23 +169,49 @@ impl Default for ChannelState {
 
 impl DP832Controller {
     /// Create a new controller and connect to the device
-    pub fn new(addr: &str) -> Result<Self, std::io::Error> {
-        let mut stream = TcpStream::connect(addr)?;
-        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    ///
+    /// With `probe` set, optional SCPI features are detected before the
+    /// first state read; otherwise the default command set is assumed. An
+    /// `*IDN?` reply from something other than a DP800 is an error unless
+    /// `ignore_idn` is set, in which case it is only printed as a warning.
+    pub fn new(addr: &str, options: &ConnectOptions, probe: bool, ignore_idn: bool) -> Result<Self, std::io::Error> {
+        let mut stream = connect(addr, options)?;
+        
+        // Initialize connection; a read-only connection leaves the status to its owner
+        if !is_read_only() {
+            send(&mut stream, "*CLS")?;
+        }
+        let device_id = query_idn(&mut stream)?;
+        if let Err(e) = check_idn(&device_id) {
+            if !ignore_idn {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} (pass --ignore-idn to continue anyway)", e)));
+            }
+            eprintln!("Warning: {} (continuing, IDN check disabled)", e);
+        }
         
-        // Initialize connection
-        send(&mut stream, "*CLS");
-        let device_id = query(&mut stream, "*IDN?");
+        let capabilities = if probe {
+            probe_capabilities(&mut stream)?
+        } else {
+            Capabilities::default()
+        };
         
         let mut controller = Self {
             stream,
             channels: Default::default(),
             device_id,
+            capabilities,
+            timer: TimerStatus::default(),
             scpi_logger: None,
+            latency: LatencyWindow::default(),
+            output_guard: OutputGuard::default(),
+            query_retries: options.query_retries,
+            settle_time: DEFAULT_SETTLE_TIME,
+            settling_until: [None; 3],
         };
         
         // Read initial state
         controller.update_all_channels()?;
+        controller.update_timer()?;
         
         Ok(controller)
     }
@@ -68,6 +221,21 @@ impl DP832Controller {
         self.scpi_logger = Some(sender);
     }
     
+    /// Set the minimum on/off times enforced by `set_output` and `enable_all_channels`
+    pub fn set_output_guard(&mut self, guard: OutputGuard) {
+        self.output_guard = guard;
+    }
+    
+    /// Set how long a channel settles after a change before it is measured again
+    pub fn set_settle_time(&mut self, settle_time: Duration) {
+        self.settle_time = settle_time;
+    }
+    
+    /// Note that channel `ch_idx` (0-2) was just changed
+    fn changed(&mut self, ch_idx: usize) {
+        self.settling_until[ch_idx] = Some(Instant::now() + self.settle_time);
+    }
+    
     /// Log SCPI command
     fn log_scpi(&mut self, cmd: &str) {
         if let Some(ref sender) = self.scpi_logger {
@@ -75,6 +243,50 @@ impl DP832Controller {
         }
     }
     
+    /// Log and send a command that changes the instrument's state
+    ///
+    /// Refused with `PermissionDenied` on a read-only connection, before
+    /// anything is logged or sent.
+    fn command(&mut self, cmd: &str) -> Result<(), std::io::Error> {
+        writable(cmd)?;
+        self.log_scpi(cmd);
+        send(&mut self.stream, cmd)
+    }
+    
+    /// Log and send a query, recording its round-trip time
+    ///
+    /// A timed-out query is asked again up to `query_retries` times if that is safe.
+    fn timed_query_raw(&mut self, cmd: &str) -> Result<Vec<u8>, std::io::Error> {
+        self.log_scpi(cmd);
+        let start = Instant::now();
+        let resp = query_raw_retry(&mut self.stream, cmd, self.query_retries)?;
+        self.latency.push(start.elapsed());
+        Ok(resp)
+    }
+    
+    fn timed_query(&mut self, cmd: &str) -> Result<String, std::io::Error> {
+        Ok(response_text(&self.timed_query_raw(cmd)?))
+    }
+    
+    /// Query a numeric value, logging the raw response if it does not parse
+    ///
+    /// Only a connection error is an `Err`; an unparseable reply is `None`.
+    fn query_number(&mut self, cmd: &str) -> Result<Option<f64>, std::io::Error> {
+        let raw = self.timed_query_raw(cmd)?;
+        match parse_f64(&raw) {
+            Ok(v) => Ok(Some(v)),
+            Err(e) => {
+                self.log_scpi(&format!("{} ← unparseable response {}", cmd, e));
+                Ok(None)
+            }
+        }
+    }
+    
+    /// Rolling SCPI round-trip statistics for this connection
+    pub fn latency(&self) -> &LatencyWindow {
+        &self.latency
+    }
+    
     /// Update measurements for all channels
     pub fn update_all_channels(&mut self) -> Result<(), std::io::Error> {
         for ch in 1..=3 {
@@ -91,22 +303,36 @@ impl DP832Controller {
             return Ok(());
         }
         
-        let ch_name = format!("CH{}", channel);
-        
-        // Read actual voltage (no channel switch needed)
-        let cmd = format!("MEAS:VOLT? {}", ch_name);
-        self.log_scpi(&cmd);
-        let v_act_str = query(&mut self.stream, &cmd);
-        if let Ok(v) = v_act_str.trim().parse::<f64>() {
-            self.channels[ch_idx].voltage_actual = v;
+        // Right after a change the output still moves; measure the new value, not the old one
+        if let Some(until) = self.settling_until[ch_idx].take() {
+            std::thread::sleep(until.saturating_duration_since(Instant::now()));
         }
         
-        // Read actual current (no channel switch needed)
-        let cmd = format!("MEAS:CURR? {}", ch_name);
-        self.log_scpi(&cmd);
-        let i_act_str = query(&mut self.stream, &cmd);
-        if let Ok(i) = i_act_str.trim().parse::<f64>() {
-            self.channels[ch_idx].current_actual = i;
+        let ch_name = format!("CH{}", channel);
+        
+        if self.capabilities.meas_all {
+            // MEAS:ALL? returns "voltage,current,power" in a single round trip
+            let cmd = format!("MEAS:ALL? {}", ch_name);
+            let resp = self.timed_query(&cmd)?;
+            match parse_scpi_tuple(&resp, 2) {
+                Ok(values) => {
+                    self.channels[ch_idx].voltage_actual = values[0];
+                    self.channels[ch_idx].current_actual = values[1];
+                }
+                Err(e) => self.log_scpi(&format!("{} ← unparseable response: {}", cmd, e)),
+            }
+        } else {
+            // Read actual voltage (no channel switch needed)
+            let cmd = format!("MEAS:VOLT? {}", ch_name);
+            if let Some(v) = self.query_number(&cmd)? {
+                self.channels[ch_idx].voltage_actual = v;
+            }
+            
+            // Read actual current (no channel switch needed)
+            let cmd = format!("MEAS:CURR? {}", ch_name);
+            if let Some(i) = self.query_number(&cmd)? {
+                self.channels[ch_idx].current_actual = i;
+            }
         }
         
         // Calculate power
@@ -114,115 +340,440 @@ impl DP832Controller {
             self.channels[ch_idx].voltage_actual * self.channels[ch_idx].current_actual;
         
         // Read output state (no channel switch needed)
-        let cmd = format!("OUTP? {}", ch_name);
-        self.log_scpi(&cmd);
-        let out_str = query(&mut self.stream, &cmd);
-        self.channels[ch_idx].enabled = out_str.trim() == "ON";
+        // Without OUTP? the state last commanded by this controller is kept
+        if self.capabilities.output_query {
+            let cmd = format!("OUTP? {}", ch_name);
+            let out_str = self.timed_query(&cmd)?;
+            self.channels[ch_idx].enabled = parse_bool_scpi(&out_str) == Some(true);
+        }
         
         // Read voltage and current setpoints using APPL? command
         // This avoids switching the active channel on the PSU
-        // APPL? returns format: "CH1,3.300,2.000,ON" or similar
-        let cmd = format!("APPL? {}", ch_name);
-        self.log_scpi(&cmd);
-        let appl_str = query(&mut self.stream, &cmd);
-        let parts: Vec<&str> = appl_str.split(',').collect();
-        if parts.len() >= 3 {
-            if let Ok(v) = parts[1].trim().parse::<f64>() {
-                self.channels[ch_idx].voltage_set = v;
-            }
-            if let Ok(i) = parts[2].trim().parse::<f64>() {
-                self.channels[ch_idx].current_set = i;
+        // APPL? returns format: "CH1:30V/3A,3.300,2.000" or similar
+        if self.capabilities.appl_query {
+            let cmd = format!("APPL? {}", ch_name);
+            let appl_str = self.timed_query(&cmd)?;
+            match parse_scpi_tuple(&appl_str, 2) {
+                Ok(values) => {
+                    self.channels[ch_idx].voltage_set = values[0];
+                    self.channels[ch_idx].current_set = values[1];
+                }
+                Err(e) => self.log_scpi(&format!("{} ← unparseable response: {}", cmd, e)),
             }
         }
         
         Ok(())
     }
     
-    /// Set voltage for a channel
-    pub fn set_voltage(&mut self, channel: u8, voltage: f64) -> Result<(), std::io::Error> {
-        if channel < 1 || channel > 3 {
+    /// Re-read the timer/delayer configuration
+    ///
+    /// Firmware without the timer leaves `supported` false; the error this
+    /// leaves in the queue is cleared with `*CLS`.
+    pub fn update_timer(&mut self) -> Result<(), std::io::Error> {
+        let Some(timer_on) = parse_bool_scpi(&self.timed_query(":TIMEr:STATe?")?) else {
+            self.timer = TimerStatus::default();
+            self.command("*CLS").ok();
             return Ok(());
+        };
+        
+        let cycles = self.timed_query(":TIMEr:CYCLe?")?;
+        let end_state = self.timed_query(":TIMEr:ENDState?")?;
+        let delay_on = parse_bool_scpi(&self.timed_query(":DELAY:STATe?")?);
+        if delay_on.is_none() {
+            self.command("*CLS").ok();
         }
         
-        // Use APPL command to set voltage without switching channel
-        // APPL CH1,<voltage>,<current>
-        let ch_idx = (channel - 1) as usize;
-        let current = self.channels[ch_idx].current_set;
+        self.timer = TimerStatus {
+            supported: true,
+            timer_on,
+            cycles,
+            end_state,
+            delay_on,
+        };
+        Ok(())
+    }
+    
+    /// Start or stop the front-panel timer
+    pub fn set_timer(&mut self, enabled: bool) -> Result<(), std::io::Error> {
+        if !self.timer.supported {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "the instrument did not answer :TIMEr:STATe?",
+            ));
+        }
+        
+        let cmd = format!(":TIMEr:STATe {}", if enabled { "ON" } else { "OFF" });
+        self.command(&cmd)?;
+        
+        self.update_timer()
+    }
+    
+    /// Program setpoints that take effect on a trigger instead of immediately
+    ///
+    /// Sets the triggered voltage/current, the trigger source and sends
+    /// `INIT`. The source and triggered voltage are read back; when the
+    /// firmware does not confirm them (no trigger subsystem, or the source
+    /// needs an option that is not installed), the error queue is cleared
+    /// and the setpoints are applied immediately with `apply`.
+    pub fn arm_trigger(&mut self, channel: u8, voltage: f64, current: f64, source: TriggerSource) -> Result<TriggerOutcome, std::io::Error> {
+        if ChannelLimits::for_channel(channel).is_none() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("CH{} does not exist (use 1-3)", channel)));
+        }
+        let voltage = round_voltage(channel, voltage);
+        let current = round_current(channel, current);
+        check_voltage(channel, voltage)?;
+        check_current(channel, current)?;
+
+        for cmd in [
+            format!(":SOUR{}:VOLT:TRIG {:.3}", channel, voltage),
+            format!(":SOUR{}:CURR:TRIG {:.3}", channel, current),
+            format!(":TRIG:SOUR {}", source.scpi()),
+        ] {
+            self.command(&cmd)?;
+        }
+
+        let source_ok = self.timed_query(":TRIG:SOUR?")?.to_uppercase().starts_with(source.scpi());
+        let voltage_ok = self
+            .query_number(&format!(":SOUR{}:VOLT:TRIG?", channel))?
+            .is_some_and(|v| round_voltage(channel, v) == voltage);
+        if !(source_ok && voltage_ok) {
+            self.command("*CLS").ok();
+            self.apply(channel, voltage, current)?;
+            return Ok(TriggerOutcome::AppliedImmediately);
+        }
+
+        self.command(":INIT")?;
+        Ok(TriggerOutcome::Armed)
+    }
+    
+    /// Send a bus trigger (`*TRG`) to fire setpoints armed with `TriggerSource::Bus`
+    pub fn bus_trigger(&mut self) -> Result<(), std::io::Error> {
+        self.command("*TRG")
+    }
+    
+    /// Set voltage and current limit together with a single `APPL` command
+    ///
+    /// Use this when both values should change atomically.
+    pub fn apply(&mut self, channel: u8, voltage: f64, current: f64) -> Result<(), std::io::Error> {
+        if !(1..=3).contains(&channel) {
+            return Ok(());
+        }
+        
+        let voltage = round_voltage(channel, voltage);
+        let current = round_current(channel, current);
+        check_voltage(channel, voltage)?;
+        check_current(channel, current)?;
+        
+        // APPL CH1,<voltage>,<current> sets both without switching channel
         let cmd = format!("APPL CH{},{:.3},{:.3}", channel, voltage, current);
-        self.log_scpi(&cmd);
-        send(&mut self.stream, &cmd);
+        self.command(&cmd)?;
         
+        let ch_idx = (channel - 1) as usize;
         self.channels[ch_idx].voltage_set = voltage;
+        self.channels[ch_idx].current_set = current;
+        self.changed(ch_idx);
         
         Ok(())
     }
     
-    /// Set current for a channel
+    /// Set voltage for a channel via `APPL`, re-sending the stored current limit
+    ///
+    /// If `current_set` is stale this also changes the current limit; prefer
+    /// `set_voltage_only` for interactive edits.
+    pub fn set_voltage(&mut self, channel: u8, voltage: f64) -> Result<(), std::io::Error> {
+        if !(1..=3).contains(&channel) {
+            return Ok(());
+        }
+        
+        let current = self.channels[(channel - 1) as usize].current_set;
+        self.apply(channel, voltage, current)
+    }
+    
+    /// Set current for a channel via `APPL`, re-sending the stored voltage
+    ///
+    /// If `voltage_set` is stale this also changes the voltage; prefer
+    /// `set_current_limit` for interactive edits.
     pub fn set_current(&mut self, channel: u8, current: f64) -> Result<(), std::io::Error> {
-        if channel < 1 || channel > 3 {
+        if !(1..=3).contains(&channel) {
             return Ok(());
         }
         
-        // Use APPL command to set current without switching channel
-        // APPL CH1,<voltage>,<current>
-        let ch_idx = (channel - 1) as usize;
-        let voltage = self.channels[ch_idx].voltage_set;
-        let cmd = format!("APPL CH{},{:.3},{:.3}", channel, voltage, current);
-        self.log_scpi(&cmd);
-        send(&mut self.stream, &cmd);
+        let voltage = self.channels[(channel - 1) as usize].voltage_set;
+        self.apply(channel, voltage, current)
+    }
+    
+    /// Set only the voltage of a channel, leaving the current limit untouched
+    pub fn set_voltage_only(&mut self, channel: u8, voltage: f64) -> Result<(), std::io::Error> {
+        if !(1..=3).contains(&channel) {
+            return Ok(());
+        }
         
-        self.channels[ch_idx].current_set = current;
+        let voltage = round_voltage(channel, voltage);
+        check_voltage(channel, voltage)?;
+        
+        // :SOURn:VOLT targets the channel without switching the active one
+        let cmd = format!(":SOUR{}:VOLT {:.3}", channel, voltage);
+        self.command(&cmd)?;
+        
+        self.channels[(channel - 1) as usize].voltage_set = voltage;
+        self.changed((channel - 1) as usize);
+        
+        Ok(())
+    }
+    
+    /// Set only the current limit of a channel, leaving the voltage untouched
+    pub fn set_current_limit(&mut self, channel: u8, current: f64) -> Result<(), std::io::Error> {
+        if !(1..=3).contains(&channel) {
+            return Ok(());
+        }
+        
+        let current = round_current(channel, current);
+        check_current(channel, current)?;
+        
+        // :SOURn:CURR targets the channel without switching the active one
+        let cmd = format!(":SOUR{}:CURR {:.3}", channel, current);
+        self.command(&cmd)?;
+        
+        self.channels[(channel - 1) as usize].current_set = current;
+        self.changed((channel - 1) as usize);
         
         Ok(())
     }
     
     /// Enable or disable a channel
+    ///
+    /// A toggle sooner than the configured minimum on/off time is refused
+    /// with a `WouldBlock` error and nothing is sent.
     pub fn set_output(&mut self, channel: u8, enabled: bool) -> Result<(), std::io::Error> {
-        if channel < 1 || channel > 3 {
+        if !(1..=3).contains(&channel) {
             return Ok(());
         }
         
+        let ch_idx = (channel - 1) as usize;
+        let changing = self.channels[ch_idx].enabled != enabled;
+        if changing {
+            self.output_guard.check(ch_idx, self.channels[ch_idx].enabled)?;
+        }
+        
         let state = if enabled { "ON" } else { "OFF" };
         let cmd = format!("OUTP CH{},{}", channel, state);
-        self.log_scpi(&cmd);
-        send(&mut self.stream, &cmd);
+        self.command(&cmd)?;
         
-        let ch_idx = (channel - 1) as usize;
         self.channels[ch_idx].enabled = enabled;
+        self.changed(ch_idx);
+        if changing {
+            self.output_guard.record(ch_idx);
+        }
         
         Ok(())
     }
     
     /// Enable all channels at once
+    ///
+    /// Refused if any channel that is off has not met its minimum off time.
     pub fn enable_all_channels(&mut self) -> Result<(), std::io::Error> {
-        let cmd = "OUTP ALL,ON";
-        self.log_scpi(cmd);
-        send(&mut self.stream, cmd);
+        for ch in 0..3 {
+            if !self.channels[ch].enabled {
+                self.output_guard.check(ch, false)?;
+            }
+        }
+        
+        self.command("OUTP ALL,ON")?;
         
         // Update all channel states
         for ch in 0..3 {
+            if !self.channels[ch].enabled {
+                self.output_guard.record(ch);
+            }
             self.channels[ch].enabled = true;
+            self.changed(ch);
         }
         
         Ok(())
     }
     
     /// Disable all channels at once
+    ///
+    /// This is a safety action and ignores the minimum on time.
     pub fn disable_all_channels(&mut self) -> Result<(), std::io::Error> {
-        let cmd = "OUTP ALL,OFF";
-        self.log_scpi(cmd);
-        send(&mut self.stream, cmd);
+        self.command("OUTP ALL,OFF")?;
         
         // Update all channel states
         for ch in 0..3 {
+            if self.channels[ch].enabled {
+                self.output_guard.record(ch);
+            }
             self.channels[ch].enabled = false;
+            self.changed(ch);
         }
         
         Ok(())
     }
     
+    /// Reset the instrument to its power-on defaults
+    ///
+    /// `*RST` turns all outputs off and returns setpoints to the factory
+    /// defaults; `*CLS` then clears any status left over from before the
+    /// reset. Channel state is re-read afterwards.
+    pub fn reset(&mut self) -> Result<(), std::io::Error> {
+        self.command("*RST")?;
+        
+        // Give the instrument time to finish the reset before talking to it again
+        std::thread::sleep(RESET_SETTLE_TIME);
+        
+        self.command("*CLS").ok();
+        
+        // The reset switched every output off
+        for ch in 0..3 {
+            if self.channels[ch].enabled {
+                self.output_guard.record(ch);
+            }
+        }
+        
+        self.update_all_channels()?;
+        self.update_timer()
+    }
+    
+    /// Drain the instrument's SCPI error queue, oldest entry first
+    ///
+    /// `SYST:ERR?` is asked until it answers `0,"No error"`, at most
+    /// `ERROR_QUEUE_MAX` times. Reading an entry removes it, so this is
+    /// refused with `PermissionDenied` on a read-only connection.
+    pub fn read_error_queue(&mut self) -> Result<Vec<(i32, String)>, std::io::Error> {
+        const CMD: &str = "SYST:ERR?";
+        writable(CMD)?;
+        if !self.capabilities.error_queue {
+            return Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "the instrument did not answer SYST:ERR? when probed"));
+        }
+        
+        let mut errors = Vec::new();
+        while errors.len() < ERROR_QUEUE_MAX {
+            let resp = self.timed_query(CMD)?;
+            match parse_error_entry(&resp) {
+                Some((0, _)) => break,
+                Some(entry) => errors.push(entry),
+                None => {
+                    return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("unexpected {} reply '{}'", CMD, resp)));
+                }
+            }
+        }
+        Ok(errors)
+    }
+    
+    /// Take `samples` readings spread over `window` and average them
+    ///
+    /// Readings further than three (scaled) median absolute deviations from
+    /// the median, but at least 1 mV / 1 mA, are dropped before averaging, so
+    /// a single glitch does not move the result.
+    pub fn measure_settled(&mut self, channel: u8, samples: usize, window: Duration) -> Result<SettledReading, std::io::Error> {
+        if ChannelLimits::for_channel(channel).is_none() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("CH{} does not exist (use 1-3)", channel)));
+        }
+        let samples = samples.max(1);
+        let spacing = window / samples.saturating_sub(1).max(1) as u32;
+
+        let mut readings = Vec::new();
+        for n in 0..samples {
+            if n > 0 {
+                std::thread::sleep(spacing);
+            }
+            let voltage = self.query_number(&format!("MEAS:VOLT? CH{}", channel))?;
+            let current = self.query_number(&format!("MEAS:CURR? CH{}", channel))?;
+            if let (Some(v), Some(i)) = (voltage, current) {
+                readings.push((v, i));
+            }
+        }
+
+        let voltages: Vec<f64> = readings.iter().map(|r| r.0).collect();
+        let currents: Vec<f64> = readings.iter().map(|r| r.1).collect();
+        let (v_median, v_limit) = outlier_limit(&voltages, 0.001);
+        let (i_median, i_limit) = outlier_limit(&currents, 0.001);
+        readings.retain(|&(v, i)| (v - v_median).abs() <= v_limit && (i - i_median).abs() <= i_limit);
+        if readings.is_empty() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("no valid readings from CH{}", channel)));
+        }
+
+        let (voltage, voltage_stddev) = mean_stddev(readings.iter().map(|r| r.0));
+        let (current, current_stddev) = mean_stddev(readings.iter().map(|r| r.1));
+        Ok(SettledReading {
+            channel,
+            voltage,
+            voltage_stddev,
+            current,
+            current_stddev,
+            samples: readings.len(),
+            rejected: samples - readings.len(),
+        })
+    }
+    
+    /// Total measured current of all enabled channels
+    pub fn total_current(&self) -> f64 {
+        self.channels.iter().filter(|ch| ch.enabled).map(|ch| ch.current_actual).sum()
+    }
+    
+    /// Total measured power of all enabled channels
+    pub fn total_power(&self) -> f64 {
+        crate::common::total_power(self.channels.iter().map(|ch| (ch.enabled, ch.power_actual)))
+    }
+    
     /// Get device identification
     pub fn get_device_id(&self) -> &str {
         &self.device_id
     }
 }
+
+/// Round a voltage setpoint to the channel's programming resolution
+///
+/// The rounded value is what gets sent and stored, so a readback of the
+/// setpoint compares equal instead of differing by the part the instrument
+/// dropped.
+fn round_voltage(channel: u8, voltage: f64) -> f64 {
+    ChannelLimits::for_channel(channel).map_or(voltage, |limits| limits.round_voltage(voltage))
+}
+
+fn round_current(channel: u8, current: f64) -> f64 {
+    ChannelLimits::for_channel(channel).map_or(current, |limits| limits.round_current(current))
+}
+
+/// Reject a voltage setpoint outside the channel's rated range before it is sent
+fn check_voltage(channel: u8, voltage: f64) -> Result<(), std::io::Error> {
+    match ChannelLimits::for_channel(channel) {
+        Some(limits) => limits
+            .check_voltage(channel, voltage)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e)),
+        None => Ok(()),
+    }
+}
+
+/// Reject a current setpoint outside the channel's rated range before it is sent
+fn check_current(channel: u8, current: f64) -> Result<(), std::io::Error> {
+    match ChannelLimits::for_channel(channel) {
+        Some(limits) => limits
+            .check_current(channel, current)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e)),
+        None => Ok(()),
+    }
+}
+
+/// Median of `values` and the largest deviation from it that is not an outlier
+fn outlier_limit(values: &[f64], floor: f64) -> (f64, f64) {
+    let median = |v: &mut Vec<f64>| {
+        v.sort_by(f64::total_cmp);
+        match v.len() {
+            0 => 0.0,
+            n if n % 2 == 1 => v[n / 2],
+            n => (v[n / 2 - 1] + v[n / 2]) / 2.0,
+        }
+    };
+    let center = median(&mut values.to_vec());
+    let mad = median(&mut values.iter().map(|v| (v - center).abs()).collect());
+    // 1.4826 * MAD estimates the standard deviation of normally distributed readings
+    (center, (3.0 * 1.4826 * mad).max(floor))
+}
+
+fn mean_stddev(values: impl Iterator<Item = f64> + Clone) -> (f64, f64) {
+    let n = values.clone().count() as f64;
+    let mean = values.clone().sum::<f64>() / n;
+    let variance = values.map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+    (mean, variance.sqrt())
+}
@@ -1,10 +1,10 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 // Copyright (C) 2025 Marcus Folkesson
 
-/// Remote Control UI for DP832
+//! Remote Control UI for DP832
 
 use crossterm::{
-    event::{self, Event, KeyCode, KeyEventKind},
+    event::{KeyCode, KeyEventKind},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
@@ -16,18 +16,78 @@ use ratatui::{
     Terminal, Frame,
     text::{Line, Span},
 };
-use std::io;
+use std::io::{self, IsTerminal};
+use std::sync::atomic::Ordering;
 use std::time::{Duration, Instant};
 use std::collections::VecDeque;
 use std::sync::mpsc::{channel, Receiver};
 
+use super::config::PollMode;
 use super::controller::DP832Controller;
-use crate::common::LogWriters;
+use crate::common::{stop_on_signal, ChannelLimits, LogWriters};
+use crate::keylog::KeyInput;
+
+/// Polling period for every channel in `PollMode::All`
+const POLL_ALL_INTERVAL: Duration = Duration::from_secs(2);
+/// Polling periods for the selected and other channels in `PollMode::Selected`
+const POLL_SELECTED_INTERVAL: Duration = Duration::from_millis(500);
+const POLL_BACKGROUND_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Voltage presets used when the config has no `[presets]` section
+pub const DEFAULT_PRESETS: [f64; 3] = [3.3, 5.0, 12.0];
+
+/// Setpoint power above which `enable_all_on_start` asks first, unless configured
+pub const DEFAULT_CONFIRM_ABOVE_W: f64 = 10.0;
+
+/// Number of setpoint changes remembered per channel for undo
+const UNDO_DEPTH: usize = 10;
+
+/// Keys that send a command; refused in read-only mode (space toggles the
+/// output, reading the error queue with E empties it)
+const WRITE_KEYS: &str = "vcatuxe 123456789";
+
+/// Startup options for the remote control UI
+pub struct UiOptions {
+    pub poll_mode: PollMode,
+    /// Voltages bound to keys 1-9 (extra entries are ignored)
+    pub presets: Vec<f64>,
+    /// Switch all outputs on once the UI is up
+    pub enable_all_on_start: bool,
+    /// Confirm the start-up enable when a channel's V × I setpoint exceeds this
+    pub confirm_above_w: f64,
+    /// Keyboard, optionally recorded or fed from a replayed script
+    pub input: KeyInput,
+    /// Turn all outputs off when no key has been pressed for this long
+    pub idle_off: Option<Duration>,
+}
+
+impl Default for UiOptions {
+    fn default() -> Self {
+        Self {
+            poll_mode: PollMode::default(),
+            presets: DEFAULT_PRESETS.to_vec(),
+            enable_all_on_start: false,
+            confirm_above_w: DEFAULT_CONFIRM_ABOVE_W,
+            input: KeyInput::default(),
+            idle_off: None,
+        }
+    }
+}
 
 enum InputMode {
     Normal,
     EditingVoltage(u8),  // channel number
     EditingCurrent(u8),  // channel number
+    ConfirmReset,
+    ConfirmEnableAll,
+}
+
+/// A setting as it was before a change, for undo
+#[derive(Clone, Copy)]
+enum Setpoint {
+    Voltage(f64),
+    Current(f64),
+    Output(bool),
 }
 
 pub struct RemoteControlUI {
@@ -36,16 +96,27 @@ pub struct RemoteControlUI {
     input_mode: InputMode,
     input_buffer: String,
     status_message: String,
-    last_update: Instant,
-    update_interval: Duration,
+    last_polls: [Instant; 3],
+    poll_mode: PollMode,
+    presets: Vec<f64>,
+    enable_all_on_start: bool,
+    confirm_above_w: f64,
+    undo_history: [VecDeque<Setpoint>; 3],
     event_log: VecDeque<String>,
     scpi_log: VecDeque<String>,
     log_writers: LogWriters,
     scpi_receiver: Receiver<String>,
+    /// The connection refuses commands (`--read-only`); keys that would send one are blocked
+    read_only: bool,
+    input: KeyInput,
+    idle_off: Option<Duration>,
+    last_input: Instant,
+    /// Set when the idle timeout switched the outputs off, until the next key
+    idle_tripped: bool,
 }
 
 impl RemoteControlUI {
-    pub fn new(mut controller: DP832Controller) -> Self {
+    pub fn new(mut controller: DP832Controller, options: UiOptions) -> Self {
         let (tx, rx) = channel();
         controller.set_scpi_logger(tx);
         
@@ -55,15 +126,32 @@ impl RemoteControlUI {
             input_mode: InputMode::Normal,
             input_buffer: String::new(),
             status_message: String::from("Ready. Use ↑/↓ to select channel, V/C to edit, SPACE to toggle output, A to enable all, R to refresh, Q to quit"),
-            last_update: Instant::now(),
-            update_interval: Duration::from_secs(2), // Update every 2 seconds instead of constantly
+            last_polls: [Instant::now(); 3],
+            poll_mode: options.poll_mode,
+            presets: options.presets.into_iter().take(9).collect(),
+            enable_all_on_start: options.enable_all_on_start,
+            confirm_above_w: options.confirm_above_w,
+            undo_history: Default::default(),
             event_log: VecDeque::new(),
             scpi_log: VecDeque::new(),
             log_writers: LogWriters::new(),
             scpi_receiver: rx,
+            read_only: crate::scpi::is_read_only(),
+            input: options.input,
+            // A read-only monitor cannot switch anything off
+            idle_off: options.idle_off.filter(|_| !crate::scpi::is_read_only()),
+            last_input: Instant::now(),
+            idle_tripped: false,
         };
         
         ui.add_event_log("Remote Control started".to_string());
+        if ui.read_only {
+            ui.add_event_log("MONITOR (read-only): only queries are sent".to_string());
+            ui.status_message = "MONITOR (read-only). Use ↑/↓ to select channel, R to refresh, P to change polling, Q to quit".to_string();
+        }
+        for line in ui.controller.capabilities.report() {
+            ui.add_event_log(line);
+        }
         ui
     }
     
@@ -83,6 +171,197 @@ impl RemoteControlUI {
         self.log_writers.write_scpi(&message);
     }
     
+    /// Read-only line-output mode: print the channel table on every poll until SIGINT/SIGTERM
+    fn run_headless(&mut self) -> Result<(), io::Error> {
+        let stop = stop_on_signal();
+        println!("{}", self.controller.get_device_id());
+        if self.enable_all_on_start {
+            println!("Line output is read-only; not enabling the outputs");
+        }
+        
+        while !stop.load(Ordering::Relaxed) {
+            // Drop SCPI log lines to the log file only
+            while let Ok(msg) = self.scpi_receiver.try_recv() {
+                self.log_writers.write_scpi(&msg);
+            }
+            
+            let before = self.last_polls;
+            self.poll_channels();
+            if self.last_polls != before {
+                let timestamp = chrono::Local::now().format("%H:%M:%S");
+                for (idx, ch) in self.controller.channels.iter().enumerate() {
+                    println!("[{}] CH{} {:>3}  set {:.3}V {:.3}A  meas {:.3}V {:.3}A {:.3}W",
+                             timestamp, idx + 1, if ch.enabled { "ON" } else { "OFF" },
+                             ch.voltage_set, ch.current_set, ch.voltage_actual, ch.current_actual, ch.power_actual);
+                }
+                println!("[{}] Total {:.3}W", timestamp, self.controller.total_power());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        Ok(())
+    }
+    
+    /// Switch all outputs on, with undo entries for the channels that were off
+    fn enable_all(&mut self, done: &str) {
+        let was_off: Vec<u8> = (1..=3u8)
+            .filter(|&ch| !self.controller.channels[(ch - 1) as usize].enabled)
+            .collect();
+        if let Err(e) = self.controller.enable_all_channels() {
+            let msg = format!("Error enabling all channels: {}", e);
+            self.status_message = msg.clone();
+            self.add_event_log(msg);
+        } else {
+            for ch in was_off {
+                self.push_undo(ch, Setpoint::Output(false));
+            }
+            self.status_message = done.to_string();
+            self.add_event_log(done.to_string());
+            // Update all channel states immediately
+            self.controller.update_all_channels().ok();
+        }
+    }
+    
+    /// `--enable-all-on-start`: enable everything unless a setpoint needs a second look
+    ///
+    /// Setpoints outside a channel's rated range refuse the enable. Setpoints
+    /// above `confirm_above_w`, or that could not be read back because the
+    /// firmware lacks `APPL?`, ask for confirmation first.
+    fn enable_all_on_start(&mut self) {
+        let mut high_power = Vec::new();
+        for (idx, ch) in self.controller.channels.iter().enumerate() {
+            let channel = idx as u8 + 1;
+            let Some(limits) = ChannelLimits::for_channel(channel) else {
+                continue;
+            };
+            if let Err(e) = limits.check_voltage(channel, ch.voltage_set).and(limits.check_current(channel, ch.current_set)) {
+                let msg = format!("Not auto-enabling outputs: {}", e);
+                self.status_message = msg.clone();
+                self.add_event_log(msg);
+                return;
+            }
+            if ch.voltage_set * ch.current_set > self.confirm_above_w {
+                high_power.push(format!("CH{} {:.3}V/{:.3}A", channel, ch.voltage_set, ch.current_set));
+            }
+        }
+
+        let reason = if !self.controller.capabilities.appl_query {
+            Some("the setpoints could not be read back".to_string())
+        } else if !high_power.is_empty() {
+            Some(format!("{} above {} W", high_power.join(", "), self.confirm_above_w))
+        } else {
+            None
+        };
+        match reason {
+            Some(reason) => {
+                self.input_mode = InputMode::ConfirmEnableAll;
+                self.status_message = format!("Enable all outputs on start? {}. Press Y to confirm, any other key to cancel", reason);
+                self.add_event_log(format!("Auto-enable on start waiting for confirmation: {}", reason));
+            }
+            None => self.enable_all("Auto-enabled all channels on start"),
+        }
+    }
+    
+    /// Remember a setting of channel `ch` (1-3) before it is changed
+    fn push_undo(&mut self, ch: u8, previous: Setpoint) {
+        let history = &mut self.undo_history[(ch - 1) as usize];
+        history.push_back(previous);
+        if history.len() > UNDO_DEPTH {
+            history.pop_front();
+        }
+    }
+    
+    /// Re-apply the setting from before the last change on the selected channel
+    fn undo(&mut self) {
+        let ch = (self.selected_channel + 1) as u8;
+        let Some(previous) = self.undo_history[self.selected_channel].pop_back() else {
+            self.status_message = format!("Nothing to undo on CH{}", ch);
+            return;
+        };
+        
+        let (result, description) = match previous {
+            Setpoint::Voltage(v) => (self.controller.set_voltage_only(ch, v), format!("voltage back to {:.3}V", v)),
+            Setpoint::Current(i) => (self.controller.set_current_limit(ch, i), format!("current back to {:.3}A", i)),
+            Setpoint::Output(on) => (self.controller.set_output(ch, on), format!("output back {}", if on { "ON" } else { "OFF" })),
+        };
+        
+        let msg = match result {
+            Ok(()) => {
+                self.controller.update_channel(ch).ok();
+                format!("Undo CH{}: {}", ch, description)
+            }
+            Err(e) => {
+                // Keep the entry so the undo can be retried
+                self.undo_history[self.selected_channel].push_back(previous);
+                format!("Undo CH{} failed: {}", ch, e)
+            }
+        };
+        self.status_message = msg.clone();
+        self.add_event_log(msg);
+    }
+    
+    /// How often a channel is refreshed under the current polling mode
+    fn poll_interval(&self, ch_idx: usize) -> Duration {
+        match self.poll_mode {
+            PollMode::All => POLL_ALL_INTERVAL,
+            PollMode::Selected if ch_idx == self.selected_channel => POLL_SELECTED_INTERVAL,
+            PollMode::Selected => POLL_BACKGROUND_INTERVAL,
+        }
+    }
+    
+    /// Refresh every channel whose polling interval has elapsed
+    fn poll_channels(&mut self) {
+        let now = Instant::now();
+        for ch_idx in 0..3 {
+            if now.duration_since(self.last_polls[ch_idx]) < self.poll_interval(ch_idx) {
+                continue;
+            }
+            if let Err(e) = self.controller.update_channel((ch_idx + 1) as u8) {
+                let msg = format!("Error updating: {}", e);
+                self.status_message = msg.clone();
+                self.add_event_log(msg);
+            }
+            self.last_polls[ch_idx] = now;
+        }
+    }
+    
+    /// Switch all outputs off once no key has been pressed for `idle_off`
+    ///
+    /// Only acts while an output is on. On failure it tries again after
+    /// another full timeout.
+    fn check_idle(&mut self) {
+        let Some(timeout) = self.idle_off else {
+            return;
+        };
+        if self.last_input.elapsed() < timeout || !self.controller.channels.iter().any(|ch| ch.enabled) {
+            return;
+        }
+        let msg = match self.controller.disable_all_channels() {
+            Ok(()) => format!("IDLE: no key pressed for {} s, all outputs turned OFF", timeout.as_secs()),
+            Err(e) => format!("IDLE: no key pressed for {} s, turning the outputs OFF failed: {}", timeout.as_secs(), e),
+        };
+        self.status_message = msg.clone();
+        self.add_event_log(msg);
+        self.idle_tripped = true;
+        self.last_input = Instant::now();
+        self.controller.update_all_channels().ok();
+    }
+    
+    /// Drain the instrument's error queue into the event log
+    fn read_error_queue(&mut self) {
+        let msg = match self.controller.read_error_queue() {
+            Ok(errors) if errors.is_empty() => "Error queue empty".to_string(),
+            Ok(errors) => {
+                for (code, message) in &errors {
+                    self.add_event_log(format!("SCPI error {}: {}", code, message));
+                }
+                format!("{} error(s) read from the instrument, see the event log", errors.len())
+            }
+            Err(e) => format!("Error reading the error queue: {}", e),
+        };
+        self.status_message = msg.clone();
+        self.add_event_log(msg);
+    }
+    
     fn process_scpi_logs(&mut self) {
         while let Ok(msg) = self.scpi_receiver.try_recv() {
             self.add_scpi_log(msg);
@@ -90,160 +369,240 @@ impl RemoteControlUI {
     }
     
     pub fn run(&mut self) -> Result<(), io::Error> {
+        if !io::stdout().is_terminal() {
+            println!("stdout is not a terminal; the interactive UI is unavailable, printing measurements instead");
+            return self.run_headless();
+        }
+        if let Err(e) = enable_raw_mode() {
+            println!("Cannot enable raw terminal mode ({}); printing measurements instead", e);
+            return self.run_headless();
+        }
+        
         // Setup terminal
-        enable_raw_mode()?;
         let mut stdout = io::stdout();
         execute!(stdout, EnterAlternateScreen)?;
         let backend = CrosstermBackend::new(stdout);
         let mut terminal = Terminal::new(backend)?;
 
+        if self.enable_all_on_start {
+            self.enable_all_on_start();
+        }
+
         loop {
             // Process any pending SCPI logs
             self.process_scpi_logs();
             
             // Only update measurements periodically or on explicit refresh
-            let now = Instant::now();
-            if now.duration_since(self.last_update) >= self.update_interval {
-                if let Err(e) = self.controller.update_all_channels() {
-                    let msg = format!("Error updating: {}", e);
-                    self.status_message = msg.clone();
-                    self.add_event_log(msg);
-                }
-                self.last_update = now;
-            }
+            self.poll_channels();
+            self.check_idle();
             
             terminal.draw(|f| self.render(f))?;
 
             // Check for user input with shorter timeout for responsiveness
-            if event::poll(Duration::from_millis(100))? {
-                if let Event::Key(key) = event::read()? {
-			if key.kind != KeyEventKind::Press {
-        continue;
-    }
-                    match &self.input_mode {
-                        InputMode::Normal => {
-                            match key.code {
-                                KeyCode::Char('q') | KeyCode::Char('Q') => break,
-                                KeyCode::Up => {
-                                    if self.selected_channel > 0 {
-                                        self.selected_channel -= 1;
-                                    }
-                                }
-                                KeyCode::Down => {
-                                    if self.selected_channel < 2 {
-                                        self.selected_channel += 1;
-                                    }
-                                }
-                                KeyCode::Char('r') | KeyCode::Char('R') => {
-                                    // Explicit refresh
-                                    if let Err(e) = self.controller.update_all_channels() {
-                                        let msg = format!("Error updating: {}", e);
-                                        self.status_message = msg.clone();
-                                        self.add_event_log(msg);
-                                    } else {
-                                        self.status_message = "Refreshed all channels".to_string();
-                                        self.add_event_log("Manual refresh requested".to_string());
-                                    }
-                                    self.last_update = Instant::now();
-                                }
-                                KeyCode::Char('v') | KeyCode::Char('V') => {
-                                    let ch = (self.selected_channel + 1) as u8;
-                                    self.input_buffer = format!("{:.3}", self.controller.channels[self.selected_channel].voltage_set);
-                                    self.input_mode = InputMode::EditingVoltage(ch);
-                                    self.status_message = format!("Enter voltage for CH{} (V): ", ch);
+            if let Some(key) = self.input.next_key(Duration::from_millis(100))? {
+                self.last_input = Instant::now();
+                self.idle_tripped = false;
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match &self.input_mode {
+                    InputMode::Normal => {
+                        match key.code {
+                            KeyCode::Char('q') | KeyCode::Char('Q') => break,
+                            KeyCode::Char(c) if self.read_only && WRITE_KEYS.contains(c.to_ascii_lowercase()) => {
+                                self.status_message = "MONITOR (read-only): changing the instrument is disabled".to_string();
+                            }
+                            KeyCode::Up if self.selected_channel > 0 => {
+                                self.selected_channel -= 1;
+                            }
+                            KeyCode::Down if self.selected_channel < 2 => {
+                                self.selected_channel += 1;
+                            }
+                            KeyCode::Char('r') | KeyCode::Char('R') => {
+                                // Explicit refresh
+                                if let Err(e) = self.controller.update_all_channels().and_then(|()| self.controller.update_timer()) {
+                                    let msg = format!("Error updating: {}", e);
+                                    self.status_message = msg.clone();
+                                    self.add_event_log(msg);
+                                } else {
+                                    self.status_message = "Refreshed all channels".to_string();
+                                    self.add_event_log("Manual refresh requested".to_string());
                                 }
-                                KeyCode::Char('c') | KeyCode::Char('C') => {
-                                    let ch = (self.selected_channel + 1) as u8;
-                                    self.input_buffer = format!("{:.3}", self.controller.channels[self.selected_channel].current_set);
-                                    self.input_mode = InputMode::EditingCurrent(ch);
-                                    self.status_message = format!("Enter current for CH{} (A): ", ch);
+                                self.last_polls = [Instant::now(); 3];
+                            }
+                            KeyCode::Char('v') | KeyCode::Char('V') => {
+                                let ch = (self.selected_channel + 1) as u8;
+                                self.input_buffer = format!("{:.3}", self.controller.channels[self.selected_channel].voltage_set);
+                                self.input_mode = InputMode::EditingVoltage(ch);
+                                self.status_message = format!("Enter voltage for CH{} (V): ", ch);
+                            }
+                            KeyCode::Char('c') | KeyCode::Char('C') => {
+                                let ch = (self.selected_channel + 1) as u8;
+                                self.input_buffer = format!("{:.3}", self.controller.channels[self.selected_channel].current_set);
+                                self.input_mode = InputMode::EditingCurrent(ch);
+                                self.status_message = format!("Enter current for CH{} (A): ", ch);
+                            }
+                            KeyCode::Char(' ') => {
+                                let ch = (self.selected_channel + 1) as u8;
+                                let new_state = !self.controller.channels[self.selected_channel].enabled;
+                                if let Err(e) = self.controller.set_output(ch, new_state) {
+                                    let msg = format!("Error toggling CH{}: {}", ch, e);
+                                    self.status_message = msg.clone();
+                                    self.add_event_log(msg);
+                                } else {
+                                    self.push_undo(ch, Setpoint::Output(!new_state));
+                                    let msg = format!("CH{} output {}", ch, if new_state { "ON" } else { "OFF" });
+                                    self.status_message = msg.clone();
+                                    self.add_event_log(msg);
+                                    // Update state immediately
+                                    self.controller.update_channel(ch).ok();
                                 }
-                                KeyCode::Char(' ') => {
-                                    let ch = (self.selected_channel + 1) as u8;
-                                    let new_state = !self.controller.channels[self.selected_channel].enabled;
-                                    if let Err(e) = self.controller.set_output(ch, new_state) {
-                                        let msg = format!("Error toggling CH{}: {}", ch, e);
+                            }
+                            KeyCode::Char('a') | KeyCode::Char('A') => self.enable_all("All channels enabled"),
+                            KeyCode::Char('p') | KeyCode::Char('P') => {
+                                self.poll_mode = match self.poll_mode {
+                                    PollMode::All => PollMode::Selected,
+                                    PollMode::Selected => PollMode::All,
+                                };
+                                let msg = match self.poll_mode {
+                                    PollMode::All => "Polling all channels every 2s".to_string(),
+                                    PollMode::Selected => "Polling selected channel every 0.5s, others every 10s".to_string(),
+                                };
+                                self.status_message = msg.clone();
+                                self.add_event_log(msg);
+                            }
+                            KeyCode::Char(c @ '1'..='9') => {
+                                let ch = (self.selected_channel + 1) as u8;
+                                let idx = (c as u8 - b'1') as usize;
+                                if let Some(&voltage) = self.presets.get(idx) {
+                                    let previous = self.controller.channels[self.selected_channel].voltage_set;
+                                    let msg = format!("Preset {}: setting CH{} voltage to {:.3}V", c, ch, voltage);
+                                    self.add_event_log(msg.clone());
+                                    if let Err(e) = self.controller.set_voltage_only(ch, voltage) {
+                                        let msg = format!("Error: {}", e);
                                         self.status_message = msg.clone();
                                         self.add_event_log(msg);
                                     } else {
-                                        let msg = format!("CH{} output {}", ch, if new_state { "ON" } else { "OFF" });
-                                        self.status_message = msg.clone();
-                                        self.add_event_log(msg);
-                                        // Update state immediately
+                                        self.push_undo(ch, Setpoint::Voltage(previous));
+                                        self.status_message = msg;
                                         self.controller.update_channel(ch).ok();
                                     }
                                 }
-                                KeyCode::Char('a') | KeyCode::Char('A') => {
-                                    if let Err(e) = self.controller.enable_all_channels() {
-                                        let msg = format!("Error enabling all channels: {}", e);
-                                        self.status_message = msg.clone();
-                                        self.add_event_log(msg);
-                                    } else {
-                                        let msg = "All channels enabled".to_string();
-                                        self.status_message = msg.clone();
-                                        self.add_event_log(msg);
-                                        // Update all channel states immediately
-                                        self.controller.update_all_channels().ok();
-                                    }
-                                }
-                                KeyCode::Char('l') | KeyCode::Char('L') => {
-                                    self.event_log.clear();
-                                    self.status_message = "Event log cleared".to_string();
-                                }
-                                KeyCode::Char('s') | KeyCode::Char('S') => {
-                                    self.scpi_log.clear();
-                                    self.status_message = "SCPI log cleared".to_string();
-                                }
-                                _ => {}
                             }
+                            KeyCode::Char('t') | KeyCode::Char('T') => {
+                                let enable = !self.controller.timer.timer_on;
+                                let msg = match self.controller.set_timer(enable) {
+                                    Ok(()) => format!("Timer {}", if self.controller.timer.timer_on { "started" } else { "stopped" }),
+                                    Err(e) => format!("Error setting timer: {}", e),
+                                };
+                                self.status_message = msg.clone();
+                                self.add_event_log(msg);
+                            }
+                            KeyCode::Char('u') | KeyCode::Char('U') => {
+                                self.undo();
+                            }
+                            KeyCode::Char('x') | KeyCode::Char('X') => {
+                                self.input_mode = InputMode::ConfirmReset;
+                                self.status_message = "Reset instrument (*RST)? All outputs turn OFF and setpoints return to defaults. Press Y to confirm, any other key to cancel".to_string();
+                            }
+                            KeyCode::Char('e') | KeyCode::Char('E') => {
+                                self.read_error_queue();
+                            }
+                            KeyCode::Char('l') | KeyCode::Char('L') => {
+                                self.event_log.clear();
+                                self.status_message = "Event log cleared".to_string();
+                            }
+                            KeyCode::Char('s') | KeyCode::Char('S') => {
+                                self.scpi_log.clear();
+                                self.status_message = "SCPI log cleared".to_string();
+                            }
+                            _ => {}
+                        }
+                    }
+                    InputMode::ConfirmEnableAll => {
+                        self.input_mode = InputMode::Normal;
+                        if let KeyCode::Char('y') | KeyCode::Char('Y') = key.code {
+                            self.enable_all("Auto-enabled all channels on start (confirmed)");
+                        } else {
+                            let msg = "Auto-enable on start cancelled".to_string();
+                            self.status_message = msg.clone();
+                            self.add_event_log(msg);
+                        }
+                    }
+                    InputMode::ConfirmReset => {
+                        self.input_mode = InputMode::Normal;
+                        if let KeyCode::Char('y') | KeyCode::Char('Y') = key.code {
+                            self.add_event_log("Resetting instrument (*RST)".to_string());
+                            if let Err(e) = self.controller.reset() {
+                                let msg = format!("Error resetting instrument: {}", e);
+                                self.status_message = msg.clone();
+                                self.add_event_log(msg);
+                            } else {
+                                let msg = "Instrument reset to defaults".to_string();
+                                self.status_message = msg.clone();
+                                self.add_event_log(msg);
+                            }
+                            self.last_polls = [Instant::now(); 3];
+                        } else {
+                            self.status_message = "Reset cancelled".to_string();
                         }
-                        InputMode::EditingVoltage(ch) | InputMode::EditingCurrent(ch) => {
-                            let ch_copy = *ch; // Copy before match to avoid borrow issues
-                            match key.code {
-                                KeyCode::Enter => {
-                                    if let Ok(value) = self.input_buffer.parse::<f64>() {
-                                        let result = match &self.input_mode {
-                                            InputMode::EditingVoltage(_) => {
-                                                let msg = format!("Setting CH{} voltage to {:.3}V", ch_copy, value);
-                                                self.add_event_log(msg);
-                                                self.controller.set_voltage(ch_copy, value)
-                                            }
-                                            InputMode::EditingCurrent(_) => {
-                                                let msg = format!("Setting CH{} current to {:.3}A", ch_copy, value);
-                                                self.add_event_log(msg);
-                                                self.controller.set_current(ch_copy, value)
-                                            }
-                                            _ => Ok(()),
-                                        };
-                                        
-                                        if let Err(e) = result {
-                                            let msg = format!("Error: {}", e);
-                                            self.status_message = msg.clone();
+                    }
+                    InputMode::EditingVoltage(ch) | InputMode::EditingCurrent(ch) => {
+                        let ch_copy = *ch; // Copy before match to avoid borrow issues
+                        match key.code {
+                            KeyCode::Enter => {
+                                let input = self.input_buffer.trim().to_string();
+                                if matches!(input.as_str(), "" | "-" | "." | "-.") {
+                                    // Nothing usable typed: keep the current value
+                                    self.status_message = format!("CH{} unchanged", ch_copy);
+                                } else if let Ok(value) = input.parse::<f64>() {
+                                    let ch_state = &self.controller.channels[(ch_copy - 1) as usize];
+                                    let previous = match &self.input_mode {
+                                        InputMode::EditingCurrent(_) => Setpoint::Current(ch_state.current_set),
+                                        _ => Setpoint::Voltage(ch_state.voltage_set),
+                                    };
+                                    let result = match &self.input_mode {
+                                        InputMode::EditingVoltage(_) => {
+                                            let msg = format!("Setting CH{} voltage to {:.3}V", ch_copy, value);
+                                            self.add_event_log(msg);
+                                            self.controller.set_voltage_only(ch_copy, value)
+                                        }
+                                        InputMode::EditingCurrent(_) => {
+                                            let msg = format!("Setting CH{} current to {:.3}A", ch_copy, value);
                                             self.add_event_log(msg);
-                                        } else {
-                                            self.status_message = format!("CH{} updated", ch_copy);
-                                            // Update channel state immediately after change
-                                            self.controller.update_channel(ch_copy).ok();
+                                            self.controller.set_current_limit(ch_copy, value)
                                         }
+                                        _ => Ok(()),
+                                    };
+                                    
+                                    if let Err(e) = result {
+                                        let msg = format!("Error: {}", e);
+                                        self.status_message = msg.clone();
+                                        self.add_event_log(msg);
                                     } else {
-                                        self.status_message = "Invalid number".to_string();
+                                        self.push_undo(ch_copy, previous);
+                                        self.status_message = format!("CH{} updated", ch_copy);
+                                        // Update channel state immediately after change
+                                        self.controller.update_channel(ch_copy).ok();
                                     }
-                                    self.input_buffer.clear();
-                                    self.input_mode = InputMode::Normal;
-                                }
-                                KeyCode::Esc => {
-                                    self.input_buffer.clear();
-                                    self.input_mode = InputMode::Normal;
-                                    self.status_message = "Cancelled".to_string();
-                                }
-                                KeyCode::Char(c) => {
-                                    self.input_buffer.push(c);
-                                }
-                                KeyCode::Backspace => {
-                                    self.input_buffer.pop();
+                                } else {
+                                    self.status_message = format!("Invalid number '{}'", input);
                                 }
-                                _ => {}
+                                self.input_buffer.clear();
+                                self.input_mode = InputMode::Normal;
                             }
+                            KeyCode::Esc => {
+                                self.input_buffer.clear();
+                                self.input_mode = InputMode::Normal;
+                                self.status_message = "Cancelled".to_string();
+                            }
+                            KeyCode::Char(c) if c.is_ascii_digit() || c == '.' || c == '-' => {
+                                self.input_buffer.push(c);
+                            }
+                            KeyCode::Backspace => {
+                                self.input_buffer.pop();
+                            }
+                            _ => {}
                         }
                     }
                 }
@@ -278,7 +637,7 @@ impl RemoteControlUI {
     }
     
     fn render_header(&self, f: &mut Frame, area: Rect) {
-        let text = vec![
+        let mut text = vec![
             Line::from(vec![
                 Span::styled("╔═══════════════════════════════════════╗", Style::default().fg(Color::Cyan)),
             ]),
@@ -287,10 +646,22 @@ impl RemoteControlUI {
                 Span::styled("DP832 Remote Control", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
                 Span::styled("             ║", Style::default().fg(Color::Cyan)),
             ]),
+            Line::from(vec![
+                Span::styled("║  ", Style::default().fg(Color::Cyan)),
+                Span::styled(format!("Total output {:>9.3} W", self.controller.total_power()),
+                             Style::default().fg(Color::Magenta).add_modifier(Modifier::BOLD)),
+                Span::styled("         ║", Style::default().fg(Color::Cyan)),
+            ]),
             Line::from(vec![
                 Span::styled("╚═══════════════════════════════════════╝", Style::default().fg(Color::Cyan)),
             ]),
         ];
+        if self.read_only {
+            text.push(Line::from(Span::styled(
+                "MONITOR (read-only)",
+                Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+            )));
+        }
         let paragraph = Paragraph::new(text)
             .alignment(Alignment::Center)
             .block(Block::default().borders(Borders::NONE));
@@ -317,17 +688,38 @@ impl RemoteControlUI {
                 Cell::from(Span::styled("○ OFF", Style::default().fg(Color::DarkGray)))
             };
             
+            // Measurements of a disabled output are greyed out so an OFF channel
+            // can't be mistaken for an unloaded one; setpoints stay as programmed
+            let (measured_style, power_style) = if ch.enabled {
+                (Style::default().fg(Color::Green), Style::default().fg(Color::Magenta))
+            } else {
+                (Style::default().fg(Color::DarkGray), Style::default().fg(Color::DarkGray))
+            };
+            
             Row::new(vec![
                 Cell::from(Span::styled(format!(" {} ", i + 1), Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD))),
                 Cell::from(format!("{:>7.3} V", ch.voltage_set)),
                 Cell::from(format!("{:>7.3} A", ch.current_set)),
-                Cell::from(Span::styled(format!("{:>7.3} V", ch.voltage_actual), Style::default().fg(Color::Green))),
-                Cell::from(Span::styled(format!("{:>7.3} A", ch.current_actual), Style::default().fg(Color::Green))),
-                Cell::from(Span::styled(format!("{:>7.3} W", ch.power_actual), Style::default().fg(Color::Magenta))),
+                Cell::from(Span::styled(format!("{:>7.3} V", ch.voltage_actual), measured_style)),
+                Cell::from(Span::styled(format!("{:>7.3} A", ch.current_actual), measured_style)),
+                Cell::from(Span::styled(format!("{:>7.3} W", ch.power_actual), power_style)),
                 output_cell,
             ]).style(style).height(2)
         });
         
+        // Totals across enabled channels
+        let enabled_count = self.controller.channels.iter().filter(|ch| ch.enabled).count();
+        let total_row = Row::new(vec![
+            Cell::from(Span::styled(" Σ ", Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD))),
+            Cell::from(""),
+            Cell::from(""),
+            Cell::from(""),
+            Cell::from(Span::styled(format!("{:>7.3} A", self.controller.total_current()), Style::default().fg(Color::Green).add_modifier(Modifier::BOLD))),
+            Cell::from(Span::styled(format!("{:>7.3} W", self.controller.total_power()), Style::default().fg(Color::Magenta).add_modifier(Modifier::BOLD))),
+            Cell::from(Span::styled(format!("{} ON", enabled_count), Style::default().fg(Color::DarkGray))),
+        ]).height(1);
+        let rows = rows.chain(std::iter::once(total_row));
+        
         let table = Table::new(rows, [
             Constraint::Length(5),
             Constraint::Length(13),
@@ -341,21 +733,56 @@ impl RemoteControlUI {
         .block(Block::default()
             .borders(Borders::ALL)
             .border_type(BorderType::Rounded)
-            .title(Span::styled(" Channel Status ", Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD)))
+            .title(self.status_title())
             .title_alignment(Alignment::Center));
         
         f.render_widget(table, area);
     }
     
+    /// Channel table title with the timer/delayer state
+    ///
+    /// A running timer is highlighted because it changes outputs without
+    /// any command from this tool.
+    fn status_title(&self) -> Line<'static> {
+        let title_style = Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD);
+        let timer = &self.controller.timer;
+        if !timer.supported {
+            return Line::from(Span::styled(" Channel Status ", title_style));
+        }
+        
+        let on_off = |on: bool| if on { "ON" } else { "OFF" };
+        let mut spans = vec![Span::styled(" Channel Status │ ", title_style)];
+        if timer.timer_on {
+            spans.push(Span::styled(
+                format!("Timer: ON ({} cycles, end {})", timer.cycles, timer.end_state),
+                Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+            ));
+        } else {
+            spans.push(Span::styled("Timer: OFF", Style::default().fg(Color::DarkGray)));
+        }
+        if let Some(delay_on) = timer.delay_on {
+            let style = if delay_on {
+                Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)
+            } else {
+                Style::default().fg(Color::DarkGray)
+            };
+            spans.push(Span::styled(format!("  Delayer: {}", on_off(delay_on)), style));
+        }
+        spans.push(Span::raw(" "));
+        Line::from(spans)
+    }
+    
     fn render_help(&self, f: &mut Frame, area: Rect) {
-        let help_text = vec![
+        let mut help_text = vec![
             Line::from(vec![
                 Span::styled("  ↑/↓  ", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
                 Span::raw("Select Channel     "),
                 Span::styled("  V  ", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
                 Span::raw("Set Voltage     "),
                 Span::styled("  C  ", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
-                Span::raw("Set Current"),
+                Span::raw("Set Current     "),
+                Span::styled("  E  ", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
+                Span::raw("Error Queue"),
             ]),
             Line::from(vec![
                 Span::styled(" SPC  ", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
@@ -364,6 +791,8 @@ impl RemoteControlUI {
                 Span::raw("Enable All      "),
                 Span::styled("  R  ", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
                 Span::raw("Refresh         "),
+                Span::styled("  P  ", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
+                Span::raw("Poll Mode       "),
                 Span::styled("  Q  ", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
                 Span::raw("Quit"),
             ]),
@@ -371,10 +800,28 @@ impl RemoteControlUI {
                 Span::styled("  L  ", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
                 Span::raw("Clear Event Log    "),
                 Span::styled("  S  ", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
-                Span::raw("Clear SCPI Log"),
+                Span::raw("Clear SCPI Log  "),
+                Span::styled("  U  ", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
+                Span::raw("Undo            "),
+                Span::styled("  T  ", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
+                Span::raw("Timer On/Off    "),
+                Span::styled("  X  ", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
+                Span::raw("Reset (*RST)"),
             ]),
         ];
         
+        if !self.presets.is_empty() {
+            let mut spans = vec![
+                Span::styled(" 1-9  ", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
+                Span::raw("Presets:"),
+            ];
+            for (i, v) in self.presets.iter().enumerate() {
+                spans.push(Span::styled(format!("  {}", i + 1), Style::default().fg(Color::Yellow)));
+                spans.push(Span::raw(format!("={}V", v)));
+            }
+            help_text.push(Line::from(spans));
+        }
+        
         let paragraph = Paragraph::new(help_text)
             .block(Block::default()
                 .borders(Borders::ALL)
@@ -433,12 +880,17 @@ impl RemoteControlUI {
             .map(|msg| format!("{}\n", msg))
             .collect();
         
+        let scpi_title = match self.controller.latency().summary() {
+            Some(summary) => format!(" SCPI Commands ({}) ", summary),
+            None => " SCPI Commands ".to_string(),
+        };
+        
         f.render_widget(
             Paragraph::new(scpi_log_text)
                 .block(Block::default()
                     .borders(Borders::ALL)
                     .border_type(BorderType::Rounded)
-                    .title(Span::styled(" SCPI Commands ", Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD)))
+                    .title(Span::styled(scpi_title, Style::default().fg(Color::Cyan).add_modifier(Modifier::BOLD)))
                     .title_alignment(Alignment::Center))
                 .style(Style::default().fg(Color::DarkGray))
                 .scroll((scpi_scroll, 0)),
@@ -448,12 +900,24 @@ impl RemoteControlUI {
     
     fn render_status(&self, f: &mut Frame, area: Rect) {
         let (text, style) = match &self.input_mode {
+            InputMode::Normal if self.idle_tripped => {
+                (vec![Line::from(vec![
+                    Span::styled("⏻ ", Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)),
+                    Span::styled(&self.status_message, Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)),
+                ])], Style::default().fg(Color::Red))
+            }
             InputMode::Normal => {
                 (vec![Line::from(vec![
                     Span::styled("● ", Style::default().fg(Color::Green)),
                     Span::raw(&self.status_message),
                 ])], Style::default())
             }
+            InputMode::ConfirmReset | InputMode::ConfirmEnableAll => {
+                (vec![Line::from(vec![
+                    Span::styled("⚠ ", Style::default().fg(Color::Red).add_modifier(Modifier::BOLD)),
+                    Span::styled(&self.status_message, Style::default().fg(Color::Red)),
+                ])], Style::default().fg(Color::Red))
+            }
             InputMode::EditingVoltage(_) | InputMode::EditingCurrent(_) => {
                 (vec![Line::from(vec![
                     Span::styled("✎ ", Style::default().fg(Color::Yellow).add_modifier(Modifier::BOLD)),
@@ -1,38 +1,987 @@
 // SPDX-License-Identifier: GPL-2.0-or-later
 // Copyright (C) 2025 Marcus Folkesson
 
-/// SCPI Communication Module
-/// 
-/// Provides low-level SCPI communication primitives for the DP832 power supply.
+//! SCPI Communication Module
+//!
+//! Provides low-level SCPI communication primitives for the DP832 power supply.
 
+use crate::common::{DeviceConfig, EffectiveConfig};
+use crate::units::parse_quantity;
+use std::fmt;
+use std::fs::File;
 use std::io::{Read, Write};
-use std::net::TcpStream;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Optional log of the exact bytes exchanged with the instrument
+static RAW_LOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Set by `set_read_only`; never cleared
+static READ_ONLY: AtomicBool = AtomicBool::new(false);
+
+/// Refuse every command that could change the instrument's state from now on
+///
+/// For watching a supply that another program or the front panel drives.
+/// Only queries go out; `SYST:ERR?` is refused too, since reading the error
+/// queue empties it for the other controller, and so is `*CLS`.
+pub fn set_read_only() {
+    READ_ONLY.store(true, Ordering::Relaxed);
+}
+
+pub fn is_read_only() -> bool {
+    READ_ONLY.load(Ordering::Relaxed)
+}
+
+/// `Err` if read-only mode refuses `cmd`
+pub fn check_writable(cmd: &str) -> Result<(), String> {
+    if !is_read_only() {
+        return Ok(());
+    }
+    let header = cmd.split_whitespace().next().unwrap_or_default().to_uppercase();
+    if header.ends_with('?') && !header.contains("ERR") {
+        Ok(())
+    } else {
+        Err(format!("'{}' refused, the connection is read-only", cmd.trim()))
+    }
+}
+
+/// `check_writable` for the transport: a refused command is a `PermissionDenied` error
+pub fn writable(cmd: &str) -> std::io::Result<()> {
+    check_writable(cmd).map_err(|e| std::io::Error::new(std::io::ErrorKind::PermissionDenied, e))
+}
+
+/// Start logging raw SCPI traffic to `logs/scpi_raw_<timestamp>.log`
+///
+/// Bytes are written before any trimming or lossy UTF-8 conversion, with
+/// control and non-ASCII bytes escaped, so stray `\r`, missing `\n` and
+/// garbage bytes are visible. Returns the path of the log file,
+/// `logs/scpi_raw_<run_id>.log`.
+pub fn enable_raw_log(run_id: &str) -> std::io::Result<String> {
+    std::fs::create_dir_all("logs")?;
+    let path = format!("logs/scpi_raw_{}.log", run_id);
+    let file = std::fs::OpenOptions::new().create(true).append(true).open(&path)?;
+    let _ = RAW_LOG.set(Mutex::new(file));
+    Ok(path)
+}
+
+fn log_raw(stream: &TcpStream, direction: &str, bytes: &[u8]) {
+    let Some(log) = RAW_LOG.get() else {
+        return;
+    };
+
+    let mut escaped = String::new();
+    for &b in bytes {
+        match b {
+            b'\n' => escaped.push_str("\\n"),
+            b'\r' => escaped.push_str("\\r"),
+            b'\t' => escaped.push_str("\\t"),
+            b'\\' => escaped.push_str("\\\\"),
+            0x20..=0x7e => escaped.push(b as char),
+            _ => escaped.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+
+    // The local port tells apart the per-channel connections
+    let port = stream.local_addr().map(|a| a.port()).unwrap_or(0);
+    if let Ok(mut f) = log.lock() {
+        let timestamp = chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
+        let _ = writeln!(f, "[{}] :{} {} \"{}\"", timestamp, port, direction, escaped);
+        let _ = f.flush();
+    }
+}
+
+/// One line of a raw SCPI log written by `enable_raw_log`
+#[derive(Debug, Clone)]
+pub struct RawLogEntry {
+    pub timestamp: chrono::NaiveDateTime,
+    /// Local port of the connection, one per simulator channel
+    pub port: u16,
+    /// `true` for bytes sent to the instrument, `false` for responses
+    pub sent: bool,
+    pub bytes: Vec<u8>,
+}
+
+/// Parse a `[timestamp] :port → "escaped bytes"` line; `None` if it isn't one
+pub fn parse_raw_log_line(line: &str) -> Option<RawLogEntry> {
+    let (timestamp, rest) = line.strip_prefix('[')?.split_once("] :")?;
+    let timestamp = chrono::NaiveDateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S%.3f").ok()?;
+    let (port, rest) = rest.split_once(' ')?;
+    let (direction, quoted) = rest.split_once(' ')?;
+    let escaped = quoted.strip_prefix('"')?.strip_suffix('"')?;
+
+    let mut bytes = Vec::new();
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            bytes.push(c as u8);
+            continue;
+        }
+        match chars.next()? {
+            'n' => bytes.push(b'\n'),
+            'r' => bytes.push(b'\r'),
+            't' => bytes.push(b'\t'),
+            '\\' => bytes.push(b'\\'),
+            'x' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                bytes.push(u8::from_str_radix(&hex, 16).ok()?);
+            }
+            _ => return None,
+        }
+    }
+
+    Some(RawLogEntry {
+        timestamp,
+        port: port.parse().ok()?,
+        sent: match direction {
+            "→" => true,
+            "←" => false,
+            _ => return None,
+        },
+        bytes,
+    })
+}
+
+/// Timeouts used when opening a connection to the instrument
+#[derive(Debug, Clone)]
+pub struct ConnectOptions {
+    /// Maximum time to wait for the TCP connection to be established
+    pub connect_timeout: Duration,
+    /// Maximum time to wait for a query response; `QueryOptions::for_stream` picks it up
+    pub read_timeout: Duration,
+    /// How often `query_raw_retry` asks again after a timeout
+    pub query_retries: u32,
+}
+
+impl Default for ConnectOptions {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(3),
+            read_timeout: Duration::from_secs(1),
+            query_retries: 0,
+        }
+    }
+}
+
+impl ConnectOptions {
+    /// Timeouts and retries from the command line, else the `[device]`
+    /// config section, else the defaults; each is recorded in `effective`
+    pub fn resolve(
+        effective: &mut EffectiveConfig,
+        connect_timeout_ms: Option<u64>,
+        read_timeout_ms: Option<u64>,
+        query_retries: Option<u32>,
+        device: Option<&DeviceConfig>,
+    ) -> Self {
+        let defaults = Self::default();
+        Self {
+            connect_timeout: Duration::from_millis(effective.resolve(
+                "device.connect_timeout_ms",
+                connect_timeout_ms,
+                device.and_then(|d| d.connect_timeout_ms),
+                defaults.connect_timeout.as_millis() as u64,
+            )),
+            read_timeout: Duration::from_millis(effective.resolve(
+                "device.read_timeout_ms",
+                read_timeout_ms,
+                device.and_then(|d| d.read_timeout_ms),
+                defaults.read_timeout.as_millis() as u64,
+            )),
+            query_retries: effective.resolve("device.query_retries", query_retries, device.and_then(|d| d.query_retries), defaults.query_retries),
+        }
+    }
+}
+
+/// Connect to the instrument, failing after `connect_timeout` instead of the OS TCP timeout
+pub fn connect(addr: &str, options: &ConnectOptions) -> std::io::Result<TcpStream> {
+    let mut last_err = None;
+    for sock_addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&sock_addr, options.connect_timeout) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(options.read_timeout))?;
+                return Ok(stream);
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                last_err = Some(std::io::Error::new(
+                    e.kind(),
+                    format!(
+                        "no response from {} within {:.1}s (check the IP address and port)",
+                        addr,
+                        options.connect_timeout.as_secs_f64()
+                    ),
+                ));
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{} did not resolve to an address", addr))
+    }))
+}
+
+/// A response that could not be parsed as the expected value
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    /// Response as decoded and trimmed by `query`
+    pub response: String,
+    /// Bytes exactly as received from the instrument
+    pub raw: Vec<u8>,
+}
+
+impl ParseError {
+    /// Non-ASCII or control bytes usually mean a framing/termination problem
+    fn has_suspicious_bytes(&self) -> bool {
+        self.raw
+            .iter()
+            .any(|&b| !b.is_ascii() || (b.is_ascii_control() && b != b'\r' && b != b'\n'))
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "'{}'", self.response)?;
+        if self.has_suspicious_bytes() {
+            let hex: Vec<String> = self.raw.iter().map(|b| format!("{:02x}", b)).collect();
+            write!(f, " (raw bytes: {})", hex.join(" "))?;
+        }
+        Ok(())
+    }
+}
 
 /// Send a SCPI command to the device
-pub fn send(stream: &mut TcpStream, cmd: &str) {
+///
+/// In read-only mode a command that `check_writable` refuses is not sent
+/// and fails with `PermissionDenied`, naming the command. A failed write is
+/// returned to the caller as well; when the instrument dropped the
+/// connection its message starts with "connection lost", which the UIs show
+/// as is.
+pub fn send(stream: &mut TcpStream, cmd: &str) -> std::io::Result<()> {
+    writable(cmd)?;
     let cmd = format!("{}\n", cmd);
-    stream.write_all(cmd.as_bytes()).unwrap();
+    log_raw(stream, "→", cmd.as_bytes());
+    stream.write_all(cmd.as_bytes()).map_err(connection_lost)
 }
 
-/// Send a SCPI query and read the response
-pub fn query(stream: &mut TcpStream, cmd: &str) -> String {
-    send(stream, cmd);
+/// Write bytes exactly as given, without adding a terminator
+///
+/// Refused in read-only mode like `send`.
+pub fn send_raw(stream: &mut TcpStream, bytes: &[u8]) -> std::io::Result<()> {
+    writable(&String::from_utf8_lossy(bytes))?;
+    log_raw(stream, "→", bytes);
+    stream.write_all(bytes).map_err(connection_lost)
+}
+
+/// Voltage setting that names its channel, e.g. `:SOUR2:VOLT 3.300`
+///
+/// `INST:NSEL` is instrument-wide, so a bare `VOLT` after a select can land
+/// on whatever channel another connection selected in between.
+pub fn set_voltage_command(channel: u8, voltage: f64) -> String {
+    format!(":SOUR{}:VOLT {:.3}", channel, voltage)
+}
+
+/// Current setting that names its channel, e.g. `:SOUR2:CURR 0.500`
+pub fn set_current_command(channel: u8, current: f64) -> String {
+    format!(":SOUR{}:CURR {:.3}", channel, current)
+}
+
+/// Name a closed connection as such, keeping the error kind
+pub fn connection_lost(e: std::io::Error) -> std::io::Error {
+    use std::io::ErrorKind::*;
+    match e.kind() {
+        BrokenPipe | ConnectionReset | ConnectionAborted | NotConnected | UnexpectedEof => {
+            std::io::Error::new(e.kind(), format!("connection lost ({})", e))
+        }
+        _ => e,
+    }
+}
+
+/// How long a query waits for its reply
+#[derive(Debug, Clone, Copy)]
+pub struct QueryOptions {
+    /// Time from sending the query until the reply must be complete
+    pub timeout: Duration,
+    /// Pause before reading again while a reply is incomplete, so a slow
+    /// instrument's reply is collected in fewer reads (zero: read at once)
+    pub inter_read_delay: Duration,
+}
+
+impl Default for QueryOptions {
+    fn default() -> Self {
+        Self {
+            timeout: ConnectOptions::default().read_timeout,
+            inter_read_delay: Duration::ZERO,
+        }
+    }
+}
+
+impl QueryOptions {
+    /// The read timeout the stream was opened with (`ConnectOptions::read_timeout`)
+    pub fn for_stream(stream: &TcpStream) -> Self {
+        match stream.read_timeout() {
+            Ok(Some(timeout)) => Self { timeout, ..Self::default() },
+            _ => Self::default(),
+        }
+    }
+}
+
+/// `*IDN?` is the first query on a connection; a supply that just woke up may take this long
+const IDN_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Send a SCPI query and return the response bytes exactly as received
+///
+/// A timeout is not an error: it returns what arrived, usually nothing. A
+/// failed write or read is, so callers can tell a slow instrument from a
+/// lost connection.
+pub fn query_raw(stream: &mut TcpStream, cmd: &str) -> std::io::Result<Vec<u8>> {
+    let options = QueryOptions::for_stream(stream);
+    query_raw_with(stream, cmd, &options)
+}
+
+/// `query_raw` with its own timeout, see `read_response_with`
+pub fn query_raw_with(stream: &mut TcpStream, cmd: &str, options: &QueryOptions) -> std::io::Result<Vec<u8>> {
+    // A refused query gets no reply, so don't wait out the timeout for one
+    if check_writable(cmd).is_err() {
+        return Ok(Vec::new());
+    }
+    send(stream, cmd)?;
+    read_response_with(stream, options)
+}
+
+/// Whether a command may be sent again when its reply did not arrive
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Idempotency {
+    /// Sending it twice leaves the instrument as sending it once: plain
+    /// queries and setpoints with an absolute value
+    Safe,
+    /// A repeat could change the state again: relative steps (`UP`/`DOWN`),
+    /// triggers, resets, saving setups, and `SYST:ERR?`, which consumes the
+    /// entry it reads
+    Unsafe,
+}
+
+/// Classify `cmd` for retries; anything not known to be unsafe is safe
+pub fn idempotency(cmd: &str) -> Idempotency {
+    let mut words = cmd.split_whitespace();
+    let header = words.next().unwrap_or_default().trim_start_matches(':').to_uppercase();
+    let relative = words.flat_map(|w| w.split(',')).any(|arg| matches!(arg.to_uppercase().as_str(), "UP" | "DOWN"));
+    let segments: Vec<&str> = header.trim_end_matches('?').split(':').collect();
+    let first = segments[0];
+
+    let unsafe_header = matches!(first, "*SAV" | "*RCL" | "*RST" | "*TRG")
+        || first.starts_with("INIT")
+        || (first.starts_with("TRIG") && segments.get(1).is_none_or(|s| s.starts_with("IMM")))
+        || ((first.starts_with("MEM") || first.starts_with("MMEM")) && !header.ends_with('?'))
+        || (first.starts_with("SYST") && segments.get(1).is_some_and(|s| s.starts_with("ERR")));
+    if unsafe_header || relative {
+        Idempotency::Unsafe
+    } else {
+        Idempotency::Safe
+    }
+}
+
+/// Send a query, asking again up to `retries` times while no reply arrives
+///
+/// A read timeout shows up as an empty reply. Only `Idempotency::Safe`
+/// commands are repeated, and a safe query still without a reply after the
+/// last retry returns it empty for the caller to handle. Any other query
+/// gets one attempt and fails with `TimedOut` when nothing arrives. Replies
+/// that arrive late are dropped before every attempt, so a query never reads
+/// the answer to an earlier one. A connection error is returned at once;
+/// asking again would not help.
+pub fn query_raw_retry(stream: &mut TcpStream, cmd: &str, retries: u32) -> std::io::Result<Vec<u8>> {
+    let safe = idempotency(cmd) == Idempotency::Safe;
+    let retries = if safe { retries } else { 0 };
+    let mut resp = Vec::new();
+    for attempt in 0..=retries {
+        drain_input(stream)?;
+        resp = query_raw(stream, cmd)?;
+        // The late reply to an earlier attempt can come in with the retry's
+        // own; both answer `cmd`, so only the last line is kept
+        if attempt > 0 {
+            resp = last_line(resp);
+        }
+        if !resp.is_empty() {
+            return Ok(resp);
+        }
+    }
+    if !safe {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!("no reply to '{}', which is not asked again", cmd.trim()),
+        ));
+    }
+    Ok(resp)
+}
+
+/// The last newline-terminated line of `resp`, or all of it when there is only one
+fn last_line(resp: Vec<u8>) -> Vec<u8> {
+    let body = resp.strip_suffix(b"\n").unwrap_or(&resp);
+    match body.iter().rposition(|&b| b == b'\n') {
+        Some(i) => resp[i + 1..].to_vec(),
+        None => resp,
+    }
+}
+
+/// Drop whatever the instrument sent that no query is waiting for, e.g. a
+/// reply that came after its query timed out
+fn drain_input(stream: &mut TcpStream) -> std::io::Result<()> {
+    stream.set_nonblocking(true)?;
+    let mut stale = Vec::new();
+    let mut buf = [0u8; 256];
+    let result = loop {
+        match stream.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(n) => stale.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break Ok(()),
+            Err(e) => break Err(connection_lost(e)),
+        }
+    };
+    stream.set_nonblocking(false)?;
+    if !stale.is_empty() {
+        log_raw(stream, "← (dropped)", &stale);
+    }
+    result
+}
+
+/// Read one response, up to a newline or the stream's read timeout
+pub fn read_response(stream: &mut TcpStream) -> std::io::Result<Vec<u8>> {
+    let options = QueryOptions::for_stream(stream);
+    read_response_with(stream, &options)
+}
+
+/// Read one response, up to a newline or until `options.timeout` has passed
+///
+/// When the timeout runs out in the middle of a reply, the bytes read so
+/// far are returned as they are; `query_with` trims them like any reply, so
+/// the caller sees a truncated value rather than an error. The peer closing
+/// the connection before anything arrived is an `UnexpectedEof` error. The
+/// stream's own read timeout is restored afterwards, also on an error.
+pub fn read_response_with(stream: &mut TcpStream, options: &QueryOptions) -> std::io::Result<Vec<u8>> {
+    let stream_timeout = stream.read_timeout()?;
+    let resp = read_until_deadline(stream, options);
+    stream.set_read_timeout(stream_timeout)?;
+    let resp = resp?;
+    log_raw(stream, "←", &resp);
+    Ok(resp)
+}
+
+/// The read loop of `read_response_with`, which shortens the stream's read timeout as it goes
+fn read_until_deadline(stream: &mut TcpStream, options: &QueryOptions) -> std::io::Result<Vec<u8>> {
+    let deadline = Instant::now() + options.timeout;
     let mut resp = Vec::new();
     let mut buf = [0u8; 64];
 
     loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            break;
+        }
+        stream.set_read_timeout(Some(remaining))?;
         match stream.read(&mut buf) {
+            Ok(0) if resp.is_empty() => {
+                let eof = std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "closed by the instrument");
+                return Err(connection_lost(eof));
+            }
             Ok(0) => break,
             Ok(n) => {
                 resp.extend_from_slice(&buf[..n]);
                 if resp.ends_with(b"\n") {
                     break;
                 }
+                std::thread::sleep(options.inter_read_delay.min(deadline.saturating_duration_since(Instant::now())));
             }
-            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
-            Err(e) => panic!("{}", e),
+            Err(ref e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => break,
+            Err(e) => return Err(connection_lost(e)),
         }
     }
+    Ok(resp)
+}
 
-    String::from_utf8_lossy(&resp).trim().to_string()
+/// Send a SCPI query and read the response
+pub fn query(stream: &mut TcpStream, cmd: &str) -> std::io::Result<String> {
+    let options = QueryOptions::for_stream(stream);
+    query_with(stream, cmd, &options)
+}
+
+/// `query` with its own timeout, e.g. a longer one for a slow command
+pub fn query_with(stream: &mut TcpStream, cmd: &str, options: &QueryOptions) -> std::io::Result<String> {
+    Ok(response_text(&query_raw_with(stream, cmd, options)?))
+}
+
+/// Ask `*IDN?`, waiting at least `IDN_TIMEOUT` for the reply
+pub fn query_idn(stream: &mut TcpStream) -> std::io::Result<String> {
+    let options = QueryOptions::for_stream(stream);
+    query_with(stream, "*IDN?", &QueryOptions { timeout: options.timeout.max(IDN_TIMEOUT), ..options })
+}
+
+/// Send a SCPI query and parse the response as a number
+///
+/// The error is the connection error or the unparseable reply, as text.
+pub fn query_f64(stream: &mut TcpStream, cmd: &str) -> Result<f64, String> {
+    let raw = query_raw(stream, cmd).map_err(|e| e.to_string())?;
+    parse_f64(&raw).map_err(|e| e.to_string())
+}
+
+/// Decode a raw response into normalized text
+///
+/// Every response goes through here, so comparisons and parsing never see
+/// the terminator: surrounding whitespace (`\r\n`, `\n`, padding) and
+/// trailing NUL bytes some firmware sends are removed.
+pub fn response_text(raw: &[u8]) -> String {
+    String::from_utf8_lossy(raw)
+        .trim_matches(|c: char| c.is_whitespace() || c == '\0')
+        .to_string()
+}
+
+/// Split a comma-separated response such as `APPL?` into trimmed fields
+pub fn response_fields(text: &str) -> Vec<&str> {
+    text.split(',').map(str::trim).collect()
+}
+
+/// The numbers in a comma-separated reply such as `MEAS:ALL?` or `APPL?`
+///
+/// Firmware revisions differ in how they frame these: `APPL? CH1` may answer
+/// `CH1:30V/3A,3.300,2.000`, `CH1,3.300,2.000,ON` or just `3.300,2.000`.
+/// Quotes are stripped and fields that are not numbers (channel tokens,
+/// `ON`/`OFF`) are dropped; units are accepted as in `parse_number`, so the
+/// values come back in order whatever the framing. Fewer than `expected`
+/// numbers is an error that quotes the reply, instead of a partial result.
+pub fn parse_scpi_tuple(resp: &str, expected: usize) -> Result<Vec<f64>, String> {
+    let values: Vec<f64> = response_fields(resp)
+        .into_iter()
+        .filter_map(|field| parse_number(field.trim_matches(|c| c == '"' || c == '\'')))
+        .collect();
+    if values.len() < expected {
+        return Err(format!("expected {} values, got {} in '{}'", expected, values.len(), resp));
+    }
+    Ok(values)
+}
+
+/// Parse a `SYST:ERR?` reply such as `-113,"Undefined header"` into its
+/// code and message; `+0,"No error"` means the queue is empty. `None` if the
+/// reply does not start with a number.
+pub fn parse_error_entry(resp: &str) -> Option<(i32, String)> {
+    let (code, message) = resp.split_once(',').unwrap_or((resp, ""));
+    let code = code.trim().parse().ok()?;
+    Some((code, message.trim().trim_matches('"').to_string()))
+}
+
+/// Parse a boolean response: `ON`/`OFF`, `1`/`0` or `TRUE`/`FALSE`, in any
+/// case. Firmware versions differ in which they use for `OUTP?` and the
+/// `:STATe?` queries. `None` for anything else (e.g. a timeout).
+pub fn parse_bool_scpi(resp: &str) -> Option<bool> {
+    match resp.trim().to_uppercase().as_str() {
+        "ON" | "1" | "TRUE" => Some(true),
+        "OFF" | "0" | "FALSE" => Some(false),
+        _ => None,
+    }
+}
+
+/// Parse a raw response as a number, keeping the raw bytes on failure
+pub fn parse_f64(raw: &[u8]) -> Result<f64, ParseError> {
+    let response = response_text(raw);
+    parse_number(&response).ok_or(ParseError {
+        response,
+        raw: raw.to_vec(),
+    })
+}
+
+/// Parse a numeric response field, e.g. `3.300`, `3.300V`, `1.5E-03` or `12.3mA`
+///
+/// Some firmware revisions append the unit (V, A or W), optionally with an SI
+/// prefix, to measurement replies.
+pub fn parse_number(text: &str) -> Option<f64> {
+    parse_quantity(text, &["V", "A", "W", "v", "a", "w"])
+}
+
+/// Optional SCPI features, detected by `probe_capabilities`
+///
+/// Firmware revisions differ in which queries they accept. The defaults
+/// describe the command set the tools relied on before probing existed.
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    /// `MEAS:ALL? CHx` returns voltage, current and power in one reply
+    pub meas_all: bool,
+    /// `APPL? CHx` reports the programmed setpoints
+    pub appl_query: bool,
+    /// `OUTP? CHx` reports the output state
+    pub output_query: bool,
+    /// `SYST:ERR?` reads the error queue
+    pub error_queue: bool,
+    /// Set when the values come from a probe rather than the defaults
+    pub probed: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            meas_all: false,
+            appl_query: true,
+            output_query: true,
+            error_queue: true,
+            probed: false,
+        }
+    }
+}
+
+impl Capabilities {
+    /// One line per capability for the startup log
+    pub fn report(&self) -> Vec<String> {
+        let entry = |name: &str, ok: bool| {
+            format!("  {:<12} {}", name, if ok { "supported" } else { "not supported" })
+        };
+        let header = if self.probed { "Capabilities:" } else { "Capabilities (assumed, not probed):" };
+        vec![
+            header.to_string(),
+            entry("MEAS:ALL?", self.meas_all),
+            entry("APPL?", self.appl_query),
+            entry("OUTP?", self.output_query),
+            entry("SYST:ERR?", self.error_queue),
+        ]
+    }
+}
+
+/// Check that an `*IDN?` reply comes from a Rigol DP800 series supply
+///
+/// A wrong port or another service answering on it otherwise only shows up
+/// later as a cascade of unparseable replies.
+pub fn check_idn(idn: &str) -> Result<(), String> {
+    let upper = idn.to_uppercase();
+    if upper.contains("RIGOL") || upper.contains("DP8") {
+        Ok(())
+    } else if idn.is_empty() {
+        Err("*IDN? got no reply; is this the SCPI port of a Rigol DP832?".to_string())
+    } else {
+        Err(format!("*IDN? returned '{}', which is not a Rigol DP800 series supply; check the IP and port", idn))
+    }
+}
+
+/// Try each optional query once on CH1 and record which ones answer sensibly
+///
+/// Unsupported queries usually time out and leave an error in the queue,
+/// so the status is cleared with `*CLS` afterwards, unless the connection
+/// is read-only.
+pub fn probe_capabilities(stream: &mut TcpStream) -> std::io::Result<Capabilities> {
+    let meas_all = {
+        let resp = query(stream, "MEAS:ALL? CH1")?;
+        let fields = response_fields(&resp);
+        fields.len() >= 2 && fields.iter().all(|f| parse_number(f).is_some())
+    };
+
+    let appl_query = parse_scpi_tuple(&query(stream, "APPL? CH1")?, 2).is_ok();
+
+    let output_query = parse_bool_scpi(&query(stream, "OUTP? CH1")?).is_some();
+
+    let error_queue = response_fields(&query(stream, "SYST:ERR?")?)
+        .first()
+        .is_some_and(|code| code.parse::<i32>().is_ok());
+
+    if !is_read_only() {
+        send(stream, "*CLS")?;
+    }
+
+    Ok(Capabilities {
+        meas_all,
+        appl_query,
+        output_query,
+        error_queue,
+        probed: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// A connected client stream and the server side of the same connection
+    fn loopback() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn send_to_closed_connection_is_an_error() {
+        let (mut client, server) = loopback();
+        drop(server);
+
+        // The first write after the close may still be accepted by the kernel
+        let err = (0..100)
+            .find_map(|_| {
+                std::thread::sleep(Duration::from_millis(10));
+                send(&mut client, "*CLS").err()
+            })
+            .expect("writes to a closed connection kept succeeding");
+        assert!(err.to_string().starts_with("connection lost"), "{}", err);
+    }
+
+    #[test]
+    fn read_from_reset_connection_is_an_error() {
+        let (mut client, server) = loopback();
+        send(&mut client, "MEAS:VOLT? CH1").unwrap();
+
+        // Closing with unread data makes the kernel reset the connection
+        let mut byte = [0u8; 1];
+        server.peek(&mut byte).unwrap();
+        drop(server);
+
+        let err = read_response(&mut client).expect_err("a reset connection returned a reply");
+        assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+        assert!(err.to_string().starts_with("connection lost"), "{}", err);
+    }
+
+    /// Serve one reply in chunks with `pause` between them, after the query arrived
+    fn slow_reply(server: TcpStream, chunks: &'static [&'static str], pause: Duration) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            let mut server = server;
+            let mut query = [0u8; 64];
+            let _ = server.read(&mut query);
+            for chunk in chunks {
+                std::thread::sleep(pause);
+                if server.write_all(chunk.as_bytes()).is_err() {
+                    return;
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn slow_reply_within_timeout_is_complete() {
+        let (mut client, server) = loopback();
+        let server = slow_reply(server, &["1.", "23", "4\r\n"], Duration::from_millis(30));
+        let options = QueryOptions { timeout: Duration::from_secs(2), ..QueryOptions::default() };
+        assert_eq!(query_with(&mut client, "MEAS:VOLT? CH1", &options).unwrap(), "1.234");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn slow_reply_past_timeout_is_truncated() {
+        let (mut client, server) = loopback();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let server = slow_reply(server, &[" 1.2", "34\n"], Duration::from_millis(300));
+        let options = QueryOptions { timeout: Duration::from_millis(500), ..QueryOptions::default() };
+        assert_eq!(query_with(&mut client, "MEAS:VOLT? CH1", &options).unwrap(), "1.2");
+        assert_eq!(client.read_timeout().unwrap(), Some(Duration::from_secs(5)));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn read_timeout_is_restored_after_an_error() {
+        let (mut client, server) = loopback();
+        client.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        drop(server);
+        let options = QueryOptions { timeout: Duration::from_millis(500), ..QueryOptions::default() };
+        assert!(read_response_with(&mut client, &options).is_err());
+        assert_eq!(client.read_timeout().unwrap(), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn error_entry_empty_queue() {
+        assert_eq!(parse_error_entry("0,\"No error\""), Some((0, "No error".to_string())));
+        assert_eq!(parse_error_entry("+0,\"No error\""), Some((0, "No error".to_string())));
+    }
+
+    #[test]
+    fn error_entry_negative_code_with_quoted_message() {
+        assert_eq!(
+            parse_error_entry("-113,\"Undefined header\""),
+            Some((-113, "Undefined header".to_string()))
+        );
+        assert_eq!(
+            parse_error_entry(" -222 , \"Data out of range; CH1\" "),
+            Some((-222, "Data out of range; CH1".to_string()))
+        );
+    }
+
+    #[test]
+    fn error_entry_malformed_reply() {
+        assert_eq!(parse_error_entry(""), None);
+        assert_eq!(parse_error_entry("No error"), None);
+        assert_eq!(parse_error_entry("\"No error\",0"), None);
+    }
+
+    #[test]
+    fn scpi_tuple_skips_the_channel_token() {
+        assert_eq!(parse_scpi_tuple("CH1,30.000,3.000", 2), Ok(vec![30.0, 3.0]));
+        assert_eq!(parse_scpi_tuple("CH1:30V/3A,3.300,2.000", 2), Ok(vec![3.3, 2.0]));
+        assert_eq!(parse_scpi_tuple("CH2,3.300,2.000,ON", 2), Ok(vec![3.3, 2.0]));
+    }
+
+    #[test]
+    fn scpi_tuple_strips_quotes() {
+        assert_eq!(parse_scpi_tuple("\"3.300\",\"2.000\"", 2), Ok(vec![3.3, 2.0]));
+        assert_eq!(parse_scpi_tuple("'3.300','2.000'", 2), Ok(vec![3.3, 2.0]));
+    }
+
+    #[test]
+    fn scpi_tuple_accepts_unit_suffixes() {
+        let values = parse_scpi_tuple("3.300V,150mA,0.495W", 3).unwrap();
+        assert_eq!(values.len(), 3);
+        assert!((values[0] - 3.3).abs() < 1e-12);
+        assert!((values[1] - 0.15).abs() < 1e-12);
+        assert!((values[2] - 0.495).abs() < 1e-12);
+    }
+
+    #[test]
+    fn scpi_tuple_too_few_fields_quotes_the_reply() {
+        let err = parse_scpi_tuple("CH1,3.300", 2).unwrap_err();
+        assert_eq!(err, "expected 2 values, got 1 in 'CH1,3.300'");
+    }
+
+    #[test]
+    fn bool_accepts_every_spelling_in_any_case() {
+        for on in ["ON", "on", "On", "1", "TRUE", "true", "True", " ON "] {
+            assert_eq!(parse_bool_scpi(on), Some(true), "{:?}", on);
+        }
+        for off in ["OFF", "off", "Off", "0", "FALSE", "false", "False", "OFF\r\n"] {
+            assert_eq!(parse_bool_scpi(off), Some(false), "{:?}", off);
+        }
+    }
+
+    #[test]
+    fn bool_rejects_garbage() {
+        for garbage in ["", "2", "-1", "ONN", "YES", "1.0", "ON,OFF"] {
+            assert_eq!(parse_bool_scpi(garbage), None, "{:?}", garbage);
+        }
+    }
+
+    #[test]
+    fn response_text_strips_terminators() {
+        assert_eq!(response_text(b"ON\r"), "ON");
+        assert_eq!(response_text(b"1.234\r\n"), "1.234");
+        assert_eq!(response_text(b"3.300\n\0\0"), "3.300");
+    }
+
+    #[test]
+    fn response_text_of_numeric_output_state() {
+        assert_eq!(parse_bool_scpi(&response_text(b"1\n")), Some(true));
+        assert_eq!(parse_bool_scpi(&response_text(b"0\r\n")), Some(false));
+    }
+
+    #[test]
+    fn response_text_keeps_embedded_line_breaks() {
+        // Only the ends are trimmed; a reply that spans lines stays whole
+        assert_eq!(response_text(b"CH1\r\n3.300\r\n"), "CH1\r\n3.300");
+    }
+
+    /// A fake instrument that applies settings the way the DP832 does
+    ///
+    /// `INST:NSEL n` selects a channel, `:SOURn:VOLT x` / `:SOURn:CURR x` set
+    /// channel n and bare `VOLT x` / `CURR x` set the selected one. Returns
+    /// each setting with the channel it landed on, e.g. `(2, "CURR 0.500")`.
+    fn fake_instrument() -> (std::net::SocketAddr, std::thread::JoinHandle<Vec<(u8, String)>>) {
+        use std::io::{BufRead, BufReader};
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut selected = 1u8;
+            let mut applied = Vec::new();
+            for line in BufReader::new(stream).lines() {
+                let line = line.unwrap();
+                let (header, arg) = line.split_once(' ').unwrap_or((&line, ""));
+                let (channel, setting) = match header.strip_prefix(":SOUR").and_then(|h| h.split_once(':')) {
+                    Some((channel, setting)) => (channel.parse().unwrap(), setting),
+                    None => (selected, header),
+                };
+                match setting {
+                    "INST:NSEL" => selected = arg.parse().unwrap(),
+                    "VOLT" | "CURR" => applied.push((channel, format!("{} {}", setting, arg))),
+                    _ => panic!("unexpected command '{}'", line),
+                }
+            }
+            applied
+        });
+        (addr, server)
+    }
+
+    #[test]
+    fn settings_land_on_their_own_channel_over_a_shared_connection() {
+        use crate::battery_sim::DriveMode;
+        use std::sync::{Arc, Barrier};
+        const STEPS: usize = 1000;
+
+        let (addr, server) = fake_instrument();
+        let stream = Arc::new(Mutex::new(TcpStream::connect(addr).unwrap()));
+        let start = Arc::new(Barrier::new(3));
+
+        // Three channels share one connection and take turns per command.
+        // Before each setting a channel selects another one, as a thread
+        // relying on INST:NSEL would, so a setting that did not name its
+        // channel would land on the wrong one.
+        let threads: Vec<_> = (1..=3u8)
+            .map(|channel| {
+                let (stream, start) = (Arc::clone(&stream), Arc::clone(&start));
+                std::thread::spawn(move || {
+                    let drive = if channel == 2 { DriveMode::Current } else { DriveMode::Voltage };
+                    let limit = channel as f64 * 0.5;
+                    let setpoints: Vec<f64> = (0..STEPS).map(|n| channel as f64 + n as f64 * 0.001).collect();
+
+                    let mut commands = drive.setup_commands(channel, 4.2, limit);
+                    commands.extend(setpoints.iter().map(|&setpoint| drive.step_command(channel, setpoint)));
+                    start.wait();
+                    for cmd in commands {
+                        send(&mut stream.lock().unwrap(), &format!("INST:NSEL {}", channel % 3 + 1)).unwrap();
+                        send(&mut stream.lock().unwrap(), &cmd).unwrap();
+                    }
+
+                    let (setup, step) = match drive {
+                        DriveMode::Voltage => (vec![format!("CURR {:.3}", limit)], "VOLT"),
+                        DriveMode::Current => (vec!["VOLT 4.200".to_string(), "CURR 0.000".to_string()], "CURR"),
+                    };
+                    let steps = setpoints.iter().map(|setpoint| format!("{} {:.3}", step, setpoint));
+                    setup.into_iter().chain(steps).collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let expected: Vec<Vec<String>> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        drop(stream);
+
+        let applied = server.join().unwrap();
+        for (channel, expected) in (1..=3u8).zip(expected) {
+            let landed: Vec<String> =
+                applied.iter().filter(|(ch, _)| *ch == channel).map(|(_, setting)| setting.clone()).collect();
+            assert_eq!(landed, expected, "settings on CH{}", channel);
+        }
+    }
+
+    #[test]
+    fn late_reply_is_not_read_by_the_next_query() {
+        use std::io::{BufRead, BufReader};
+
+        let (mut client, server) = loopback();
+        client.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        let instrument = std::thread::spawn(move || {
+            let mut writer = server.try_clone().unwrap();
+            let mut lines = BufReader::new(server).lines();
+            lines.next();
+            // The first reply comes after the query timed out, and the retry is answered too
+            std::thread::sleep(Duration::from_millis(150));
+            writer.write_all(b"1.000\n").unwrap();
+            lines.next();
+            writer.write_all(b"1.001\n").unwrap();
+            lines.next();
+            writer.write_all(b"0.500\n").unwrap();
+        });
+
+        let first = query_raw_retry(&mut client, "MEAS:VOLT? CH1", 1).unwrap();
+        assert!(matches!(response_text(&first).as_str(), "1.000" | "1.001"), "{:?}", first);
+        std::thread::sleep(Duration::from_millis(50));
+        let second = query_raw_retry(&mut client, "MEAS:CURR? CH1", 1).unwrap();
+        assert_eq!(response_text(&second), "0.500");
+        instrument.join().unwrap();
+    }
+
+    #[test]
+    fn unanswered_unsafe_query_is_an_error() {
+        let (mut client, _server) = loopback();
+        client.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let err = query_raw_retry(&mut client, "SYST:ERR?", 3).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+        assert!(err.to_string().contains("SYST:ERR?"), "{}", err);
+    }
 }
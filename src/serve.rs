@@ -0,0 +1,242 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (C) 2025 Marcus Folkesson
+
+//! Service mode for `dp832 serve`
+//!
+//! Polls the instrument without a TUI and answers a read-only health
+//! endpoint, and optionally streams the same JSON over a Unix socket for
+//! local supervisors. Follows the rules in *Network Control Security*: nothing is
+//! exposed unless `listen` is configured, and anonymous reads must be
+//! enabled explicitly.
+
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// What happens to the outputs when the service stops
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ExitPolicy {
+    /// Leave the outputs as they are
+    #[default]
+    Keep,
+    /// Switch all outputs off
+    Off,
+}
+
+/// How log lines are written to stdout
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// `<timestamp> <LEVEL> <message>`
+    #[default]
+    Text,
+    /// One JSON object per line
+    Json,
+    /// `<priority>message`, as understood by journald and syslog wrappers
+    Syslog,
+}
+
+/// `[serve]` configuration
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ServeConfig {
+    /// Address for the health endpoint, e.g. "127.0.0.1:8832" (default: no listener)
+    pub listen: Option<String>,
+    /// Bearer token required on every request
+    pub token: Option<String>,
+    /// Answer read-only requests without a token
+    pub anonymous_read: Option<bool>,
+    /// Measurement interval in milliseconds (default: 1000)
+    pub poll_ms: Option<u64>,
+    pub on_exit: Option<ExitPolicy>,
+    pub log_format: Option<LogFormat>,
+    /// Unix socket streaming the health JSON to local readers (default: none)
+    pub status_socket: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Level {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Info => "INFO",
+            Level::Warning => "WARNING",
+            Level::Error => "ERROR",
+        }
+    }
+
+    fn syslog_priority(self) -> u8 {
+        match self {
+            Level::Info => 6,
+            Level::Warning => 4,
+            Level::Error => 3,
+        }
+    }
+}
+
+/// Write one log line in the configured format
+pub fn log(format: LogFormat, level: Level, msg: &str) {
+    let now = chrono::Local::now();
+    let line = match format {
+        LogFormat::Text => format!("{} {:<7} {}", now.format("%Y-%m-%d %H:%M:%S%.3f"), level.name(), msg),
+        LogFormat::Json => serde_json::json!({
+            "timestamp": now.to_rfc3339(),
+            "level": level.name().to_lowercase(),
+            "message": msg,
+        })
+        .to_string(),
+        LogFormat::Syslog => format!("<{}>{}", level.syslog_priority(), msg),
+    };
+    let mut out = std::io::stdout().lock();
+    let _ = writeln!(out, "{}", line);
+    let _ = out.flush();
+}
+
+/// Last measurement of one channel
+#[derive(Debug, Clone, Serialize)]
+pub struct ChannelReading {
+    pub channel: u8,
+    pub voltage: f64,
+    pub current: f64,
+    pub output: bool,
+}
+
+/// State reported by `GET /health`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Health {
+    pub connected: bool,
+    pub device_id: String,
+    /// RFC 3339 time of the last successful poll
+    pub last_poll: Option<String>,
+    /// Connections lost since the service started
+    pub reconnects: u64,
+    pub channels: Vec<ChannelReading>,
+    /// Combined power of the channels that are on, in watts
+    pub total_power: f64,
+}
+
+/// Who may read the health endpoint
+#[derive(Debug, Clone)]
+pub struct Access {
+    pub token: Option<String>,
+    pub anonymous_read: bool,
+}
+
+impl Access {
+    fn allows(&self, authorization: Option<&str>) -> bool {
+        if self.anonymous_read {
+            return true;
+        }
+        match (&self.token, authorization) {
+            (Some(token), Some(auth)) => auth.strip_prefix("Bearer ").is_some_and(|t| t.trim() == token),
+            _ => false,
+        }
+    }
+}
+
+/// Answer HTTP requests on `listener` until the process exits
+pub fn serve_http(listener: TcpListener, health: Arc<Mutex<Health>>, access: Access, format: LogFormat) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = handle_request(stream, &health, &access) {
+                    log(format, Level::Warning, &format!("HTTP request failed: {}", e));
+                }
+            }
+            Err(e) => log(format, Level::Warning, &format!("HTTP accept failed: {}", e)),
+        }
+    }
+}
+
+fn handle_request(mut stream: TcpStream, health: &Mutex<Health>, access: &Access) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+
+    // Only the request line and headers matter; the health endpoint takes no body
+    let mut request = Vec::new();
+    let mut buf = [0u8; 512];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut lines = request.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let path = request_line.next().unwrap_or_default();
+    let authorization = lines
+        .take_while(|l| !l.is_empty())
+        .find_map(|l| l.split_once(':').filter(|(k, _)| k.eq_ignore_ascii_case("authorization")))
+        .map(|(_, v)| v.trim());
+
+    let (status, body) = if path != "/health" {
+        ("404 Not Found", r#"{"error":"not found"}"#.to_string())
+    } else if method != "GET" {
+        ("405 Method Not Allowed", r#"{"error":"only GET is supported"}"#.to_string())
+    } else if !access.allows(authorization) {
+        ("401 Unauthorized", r#"{"error":"missing or invalid bearer token"}"#.to_string())
+    } else {
+        let health = health.lock().unwrap().clone();
+        let status = if health.connected { "200 OK" } else { "503 Service Unavailable" };
+        (status, serde_json::to_string(&health).unwrap_or_default())
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())
+}
+
+/// Bind a Unix socket at `path`, replacing a stale one left by a crashed run
+///
+/// A socket that still accepts connections belongs to a running service and
+/// is left alone.
+#[cfg(unix)]
+pub fn bind_socket(path: &str) -> Result<UnixListener, String> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        if UnixStream::connect(path).is_ok() {
+            return Err(format!("{} is in use by another process", path));
+        }
+        std::fs::remove_file(path).map_err(|e| format!("Failed to remove stale socket {}: {}", path, e))?;
+    }
+    UnixListener::bind(path).map_err(|e| format!("Failed to bind {}: {}", path, e))
+}
+
+/// Send every client on `listener` the health JSON, one line per `interval`
+///
+/// Each client gets its own thread and is dropped once a write fails, so a
+/// reader that stalls or goes away does not hold up the others.
+#[cfg(unix)]
+pub fn serve_socket(listener: UnixListener, health: Arc<Mutex<Health>>, interval: Duration, format: LogFormat) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                let health = health.clone();
+                std::thread::spawn(move || loop {
+                    let line = serde_json::to_string(&*health.lock().unwrap()).unwrap_or_default();
+                    if writeln!(stream, "{}", line).is_err() {
+                        break;
+                    }
+                    std::thread::sleep(interval);
+                });
+            }
+            Err(e) => log(format, Level::Warning, &format!("Status socket accept failed: {}", e)),
+        }
+    }
+}
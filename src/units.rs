@@ -0,0 +1,144 @@
+// SPDX-License-Identifier: GPL-2.0-or-later
+// Copyright (C) 2025 Marcus Folkesson
+
+//! Values with units, in profiles, config files and instrument replies
+//!
+//! A quantity is a number optionally followed by an SI prefix and the unit,
+//! e.g. `3.3V`, `500mA`, `10Ah` or `45 mΩ`. Without a unit the number is taken
+//! in the base unit. Profile and config fields use the `deserialize_with`
+//! helpers below, so every field accepts both a bare number and a string.
+
+use serde::{Deserialize, Deserializer};
+
+/// SI prefixes accepted in front of a unit
+const PREFIXES: [(&str, f64); 6] = [("n", 1e-9), ("u", 1e-6), ("µ", 1e-6), ("m", 1e-3), ("k", 1e3), ("M", 1e6)];
+
+/// Parse `text` as a quantity in one of `units`, in the base unit
+///
+/// `units` lists the spellings of the unit, longest first where one is a
+/// suffix of another. NaN and infinities are rejected, however spelled.
+pub fn parse_quantity(text: &str, units: &[&str]) -> Option<f64> {
+    parse_any(text, units).filter(|value| value.is_finite())
+}
+
+fn parse_any(text: &str, units: &[&str]) -> Option<f64> {
+    let text = text.trim();
+    if let Ok(value) = text.parse() {
+        return Some(value);
+    }
+
+    let number = units
+        .iter()
+        .find_map(|unit| text.strip_suffix(unit))
+        .unwrap_or(text)
+        .trim_end();
+    if let Ok(value) = number.parse() {
+        return Some(value);
+    }
+    PREFIXES.iter().find_map(|(prefix, scale)| {
+        let value: f64 = number.strip_suffix(prefix)?.trim_end().parse().ok()?;
+        Some(value * scale)
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum NumberOrText {
+    Number(f64),
+    Text(String),
+}
+
+fn quantity<'de, D: Deserializer<'de>>(d: D, units: &[&str]) -> Result<f64, D::Error> {
+    match NumberOrText::deserialize(d)? {
+        NumberOrText::Number(value) if value.is_finite() => Ok(value),
+        NumberOrText::Number(value) => Err(serde::de::Error::custom(format!("invalid quantity {}, expected a finite number", value))),
+        NumberOrText::Text(text) => parse_quantity(&text, units).ok_or_else(|| {
+            serde::de::Error::custom(format!("invalid quantity \"{}\", expected a number or e.g. \"1.5{}\"", text, units[0]))
+        }),
+    }
+}
+
+const VOLTS: &[&str] = &["V"];
+const AMPS: &[&str] = &["A"];
+const AMP_HOURS: &[&str] = &["Ah"];
+const OHMS: &[&str] = &["Ω", "ohm", "Ohm"];
+const FARADS: &[&str] = &["F"];
+
+pub fn volts<'de, D: Deserializer<'de>>(d: D) -> Result<f64, D::Error> {
+    quantity(d, VOLTS)
+}
+
+pub fn amps<'de, D: Deserializer<'de>>(d: D) -> Result<f64, D::Error> {
+    quantity(d, AMPS)
+}
+
+pub fn amp_hours<'de, D: Deserializer<'de>>(d: D) -> Result<f64, D::Error> {
+    quantity(d, AMP_HOURS)
+}
+
+pub fn ohms<'de, D: Deserializer<'de>>(d: D) -> Result<f64, D::Error> {
+    quantity(d, OHMS)
+}
+
+/// For `Option<f64>` fields; use together with `#[serde(default)]`
+pub fn amps_opt<'de, D: Deserializer<'de>>(d: D) -> Result<Option<f64>, D::Error> {
+    amps(d).map(Some)
+}
+
+pub fn volts_opt<'de, D: Deserializer<'de>>(d: D) -> Result<Option<f64>, D::Error> {
+    volts(d).map(Some)
+}
+
+pub fn ohms_opt<'de, D: Deserializer<'de>>(d: D) -> Result<Option<f64>, D::Error> {
+    ohms(d).map(Some)
+}
+
+pub fn farads_opt<'de, D: Deserializer<'de>>(d: D) -> Result<Option<f64>, D::Error> {
+    quantity(d, FARADS).map(Some)
+}
+
+pub fn volts_list<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<f64>, D::Error> {
+    #[derive(Deserialize)]
+    struct Volts(#[serde(deserialize_with = "volts")] f64);
+    Ok(Vec::<Volts>::deserialize(d)?.into_iter().map(|v| v.0).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixed_quantities_are_scaled_to_the_base_unit() {
+        assert_eq!(parse_quantity("500mA", AMPS), Some(0.5));
+        assert_eq!(parse_quantity("45 mΩ", OHMS), Some(0.045));
+        assert_eq!(parse_quantity("3200mV", VOLTS), Some(3.2));
+        assert_eq!(parse_quantity("2.5 Ah", AMP_HOURS), Some(2.5));
+    }
+
+    #[test]
+    fn bare_numbers_are_taken_in_the_base_unit() {
+        assert_eq!(parse_quantity("3.3", VOLTS), Some(3.3));
+        assert_eq!(parse_quantity(" -0.25 ", AMPS), Some(-0.25));
+        assert_eq!(parse_quantity("1e-3", AMPS), Some(0.001));
+    }
+
+    #[test]
+    fn non_finite_and_malformed_quantities_are_rejected() {
+        for text in ["NaN", "nan V", "inf", "-infinity", "infA", "1e400", "", "V", "3.3 volts", "5 mX"] {
+            assert_eq!(parse_quantity(text, VOLTS), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn non_finite_numbers_in_config_files_are_rejected() {
+        #[derive(Debug, Deserialize)]
+        struct Field {
+            #[serde(deserialize_with = "volts")]
+            v: f64,
+        }
+        for text in ["v = nan", "v = inf", "v = -inf", "v = \"NaN V\""] {
+            assert!(toml::from_str::<Field>(text).is_err(), "{}", text);
+        }
+        assert_eq!(toml::from_str::<Field>("v = \"3200mV\"").unwrap().v, 3.2);
+    }
+}